@@ -0,0 +1,463 @@
+// Lays a program out as a staircase of lanes, one per basic block, with every lane running left to right
+// one row of codels high.  Instructions are the colour changes between neighbouring blocks of a lane, so
+// each block only needs to differ from the one before it, and the block size before a push is the constant.
+//
+// Jumps leave their lane through a white corridor heading down to a bus row below all of the lanes, follow
+// it left to the target's column in a band on the left of the image, then climb up to the target's row and
+// run right into its first block.  In white, a blocked codel turns the direction pointer clockwise, so each
+// corner of a corridor is a black codel placed where travel must turn.  Lanes are staggered so that each one
+// starts to the right of every corridor coming down from the lanes above, which keeps corridors from
+// running through blocks.
+
+use crate::error::AsmError;
+use crate::syntax::{Line, Statement};
+use image::RgbImage;
+use parser::decode::DecodeInstruction;
+use parser::render::Renderer;
+use piet_core::color::{Hue::*, Lightness, Lightness::*};
+use piet_core::instruction::Instruction;
+use piet_core::program::PietSource;
+use std::collections::{BTreeSet, HashMap};
+
+/// Largest constant pushed with a single block; larger ones are built up arithmetically to keep lanes short
+const MAX_LITERAL: i64 = 16;
+
+const COLOURS: [Lightness; 18] = [
+    Light(Red),
+    Light(Yellow),
+    Light(Green),
+    Light(Cyan),
+    Light(Blue),
+    Light(Magenta),
+    Reg(Red),
+    Reg(Yellow),
+    Reg(Green),
+    Reg(Cyan),
+    Reg(Blue),
+    Reg(Magenta),
+    Dark(Red),
+    Dark(Yellow),
+    Dark(Green),
+    Dark(Cyan),
+    Dark(Blue),
+    Dark(Magenta),
+];
+
+/// Colour of the first block of every run of blocks
+const START: Lightness = Light(Red);
+
+struct Encoder;
+
+impl DecodeInstruction for Encoder {}
+
+/// Picks the colour to move to from `curr` so that the transition decodes to `instr`
+fn encode(curr: Lightness, instr: Instruction) -> Lightness {
+    COLOURS
+        .into_iter()
+        .find(|&next| Encoder::decode_instr(curr, next) == Some(instr))
+        .expect("every instruction has a colour transition")
+}
+
+/// A run of adjacent blocks.  Each step is an instruction along with the size of the block it leaves, which
+/// only matters for pushes.  A run that ends in a conditional jump leaves its last block downwards when the
+/// jump is taken.
+#[derive(Debug, Default)]
+struct Run {
+    steps: Vec<(Instruction, u32)>,
+    branch: Option<Target>,
+}
+
+#[derive(Debug, Clone)]
+struct Target {
+    label: String,
+    line: usize,
+}
+
+#[derive(Debug)]
+enum End {
+    Jump(Target),
+    /// Jumps to the lane after it
+    Fallthrough,
+    Halt,
+}
+
+#[derive(Debug, Default)]
+struct Lane {
+    runs: Vec<Run>,
+    end: Option<End>,
+}
+
+impl Lane {
+    fn is_empty(&self) -> bool {
+        self.runs.iter().all(|run| run.steps.is_empty())
+    }
+
+    fn step(&mut self, instr: Instruction, size: u32) {
+        match self.runs.last_mut() {
+            Some(run) if run.branch.is_none() => run.steps.push((instr, size)),
+            _ => self.runs.push(Run {
+                steps: vec![(instr, size)],
+                branch: None,
+            }),
+        }
+    }
+
+    fn push(&mut self, n: i64) {
+        match n {
+            0 => {
+                self.push(1);
+                self.step(Instruction::Not, 1);
+            }
+            1..=MAX_LITERAL => self.step(Instruction::Push, n as u32),
+            n if n > 0 => {
+                let root = (n as f64).sqrt() as i64;
+                self.push(n / root);
+                self.push(root);
+                self.step(Instruction::Mul, 1);
+                if n % root != 0 {
+                    self.push(n % root);
+                    self.step(Instruction::Add, 1);
+                }
+            }
+            // 0 - |n|, since only positive constants can be pushed directly
+            n => {
+                self.push(0);
+                self.push(n.unsigned_abs() as i64);
+                self.step(Instruction::Sub, 1);
+            }
+        }
+    }
+
+    fn branch(&mut self, target: Target) {
+        self.step(Instruction::Ptr, 1);
+        if let Some(run) = self.runs.last_mut() {
+            run.branch = Some(target);
+        }
+    }
+}
+
+/// Splits the program into lanes, which start at labels and end at jumps or halts
+fn split_lanes(lines: &[Line]) -> Result<(Vec<Lane>, HashMap<String, usize>), AsmError> {
+    let mut lanes = vec![Lane::default()];
+    let mut labels = HashMap::new();
+
+    for Line { number, statement } in lines {
+        let lane = lanes.last_mut().unwrap();
+        let target = |label: &String| Target {
+            label: label.clone(),
+            line: *number,
+        };
+
+        // Code after a jump or halt is unreachable unless it's labelled, but it still gets a lane of its own
+        if lane.end.is_some() || (matches!(statement, Statement::Label(_)) && !lane.is_empty()) {
+            lane.end.get_or_insert(End::Fallthrough);
+            lanes.push(Lane::default());
+        }
+        let lane = lanes.last_mut().unwrap();
+
+        match statement {
+            Statement::Label(label) => {
+                if labels.insert(label.clone(), lanes.len() - 1).is_some() {
+                    return Err(AsmError::new(
+                        *number,
+                        format!("label `{label}` is defined more than once"),
+                    ));
+                }
+            }
+            Statement::Push(n) => {
+                if *n == i64::MIN {
+                    return Err(AsmError::new(
+                        *number,
+                        format!("constant {n} is out of range"),
+                    ));
+                }
+                lane.push(*n)
+            }
+            Statement::Instr(instr) => lane.step(*instr, 1),
+            Statement::Jmp(label) => lane.end = Some(End::Jump(target(label))),
+            Statement::Jz(label) => {
+                lane.step(Instruction::Not, 1);
+                lane.branch(target(label));
+            }
+            Statement::Jnz(label) => {
+                lane.step(Instruction::Not, 1);
+                lane.step(Instruction::Not, 1);
+                lane.branch(target(label));
+            }
+            Statement::Halt => lane.end = Some(End::Halt),
+        }
+    }
+
+    Ok((lanes, labels))
+}
+
+/// An assembled program, one colour per codel
+#[derive(Debug, Clone)]
+pub struct Assembled {
+    /// Program height in codels
+    pub height: u32,
+    /// Program width in codels
+    pub width: u32,
+    pub codels: Vec<Lightness>,
+}
+
+impl Assembled {
+    /// The program with each codel drawn as a `codel_size` by `codel_size` square of pixels
+    pub fn pixels(&self, codel_size: u32) -> Vec<Lightness> {
+        (0..self.height * codel_size)
+            .flat_map(|y| {
+                (0..self.width * codel_size).map(move |x| (y / codel_size, x / codel_size))
+            })
+            .map(|(r, c)| self.codels[(r * self.width + c) as usize])
+            .collect()
+    }
+
+    pub fn source(&self) -> PietSource<'_> {
+        PietSource::new(&self.codels, self.height, self.width)
+    }
+
+    pub fn to_image(&self, codel_size: u32) -> RgbImage {
+        Renderer::render(&self.source(), 1, codel_size)
+    }
+}
+
+struct Grid {
+    width: usize,
+    codels: Vec<Lightness>,
+}
+
+impl Grid {
+    fn set(&mut self, r: usize, c: usize, codel: Lightness) {
+        self.codels[r * self.width + c] = codel;
+    }
+
+    fn white_row(&mut self, r: usize, cols: impl Iterator<Item = usize>) {
+        cols.for_each(|c| self.set(r, c, White));
+    }
+
+    fn white_col(&mut self, c: usize, rows: impl Iterator<Item = usize>) {
+        rows.for_each(|r| self.set(r, c, White));
+    }
+}
+
+/// Where a lane's codels go, with the columns its jumps leave it from
+struct Placement {
+    row: usize,
+    start: usize,
+    codels: Vec<Lightness>,
+    /// Columns jumps head down from and the lanes they go to
+    exits: Vec<(usize, usize)>,
+}
+
+/// Lays out the codels of a lane from its first block, leaving white gaps after conditional jumps
+fn lane_codels(lane: &Lane, lanes: &HashMap<String, usize>) -> Result<Placement, AsmError> {
+    let mut codels = Vec::new();
+    let mut exits = Vec::new();
+
+    for run in lane.runs.iter().filter(|run| !run.steps.is_empty()) {
+        let mut colour = START;
+        for &(instr, size) in &run.steps {
+            codels.extend((0..size).map(|_| colour));
+            colour = encode(colour, instr);
+        }
+        codels.push(colour);
+
+        if let Some(target) = &run.branch {
+            exits.push((codels.len() - 1, resolve(target, lanes)?));
+            codels.push(White);
+        }
+    }
+
+    Ok(Placement {
+        row: 0,
+        start: 0,
+        codels,
+        exits,
+    })
+}
+
+fn resolve(target: &Target, lanes: &HashMap<String, usize>) -> Result<usize, AsmError> {
+    lanes
+        .get(&target.label)
+        .copied()
+        .ok_or_else(|| AsmError::new(target.line, format!("undefined label `{}`", target.label)))
+}
+
+/// Lays out the program as a grid of codels
+pub fn layout(lines: &[Line]) -> Result<Assembled, AsmError> {
+    let (lanes, labels) = split_lanes(lines)?;
+
+    let mut placements = Vec::with_capacity(lanes.len());
+    for lane in &lanes {
+        placements.push(lane_codels(lane, &labels)?);
+    }
+    let ends = lanes
+        .iter()
+        .enumerate()
+        .map(|(idx, lane)| match &lane.end {
+            Some(End::Jump(target)) => resolve(target, &labels).map(Some),
+            Some(End::Fallthrough) => Ok(Some(idx + 1)),
+            Some(End::Halt) | None => Ok(None),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Every lane that can be jumped to gets a column in the band on the left and a bus row at the bottom.
+    // Execution starts by jumping to the first lane.
+    let targets = std::iter::once(0)
+        .chain(ends.iter().flatten().copied())
+        .chain(
+            placements
+                .iter()
+                .flat_map(|p| p.exits.iter().map(|&(_, lane)| lane)),
+        )
+        .collect::<BTreeSet<_>>();
+    let target_idx = targets
+        .iter()
+        .enumerate()
+        .map(|(idx, &lane)| (lane, idx))
+        .collect::<HashMap<_, _>>();
+    let band = |lane: usize| 2 + 2 * target_idx[&lane];
+    let entry_col = 2 + 2 * targets.len();
+
+    // Lanes are three rows apart, which leaves room for the black codels around a halt
+    let mut start = entry_col + 2;
+    let mut exits = vec![(0, entry_col, 0)];
+    for (idx, (placement, end)) in placements.iter_mut().zip(&ends).enumerate() {
+        placement.row = 2 + 3 * idx;
+        placement.start = start;
+        exits.extend(
+            placement
+                .exits
+                .iter()
+                .map(|&(offset, lane)| (placement.row, start + offset, lane)),
+        );
+
+        let tail = start + placement.codels.len();
+        start = match end {
+            Some(lane) => {
+                exits.push((placement.row, tail, *lane));
+                tail + 2
+            }
+            None => tail + 4,
+        };
+    }
+    let bus_base = 2 + 3 * lanes.len();
+    let bus = |lane: usize| bus_base + 2 * target_idx[&lane];
+
+    let (height, width) = (bus_base + 2 * targets.len(), start - 1);
+    let mut grid = Grid {
+        width,
+        codels: vec![Black; height * width],
+    };
+
+    grid.set(0, 0, START);
+    grid.white_row(0, 1..=entry_col);
+    for (placement, end) in placements.iter().zip(&ends) {
+        let (row, start) = (placement.row, placement.start);
+        for (offset, &codel) in placement.codels.iter().enumerate() {
+            grid.set(row, start + offset, codel);
+        }
+
+        let tail = start + placement.codels.len();
+        grid.set(row, tail, White);
+        if end.is_none() {
+            // Entered from the white codel before it, this block has no exit that isn't black
+            grid.set(row, tail + 1, White);
+            grid.set(row, tail + 2, START);
+            grid.set(row + 1, tail + 1, START);
+            grid.set(row + 1, tail + 2, START);
+        }
+    }
+    for (row, col, lane) in exits {
+        let (bus, band, target) = (bus(lane), band(lane), &placements[lane]);
+        grid.white_col(col, row + 1..=bus);
+        grid.white_row(bus, band..=col);
+        grid.white_col(band, target.row..=bus);
+        grid.white_row(target.row, band..target.start);
+    }
+
+    Ok(Assembled {
+        height: height as u32,
+        width: width as u32,
+        codels: grid.codels,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::assemble;
+    use interpreter::interpreter::Interpreter;
+    use parser::cfg::CFGBuilder;
+    use piet_core::program::PietSource;
+    use piet_core::settings::{CodelSettings, InterpreterSettings};
+    use piet_core::state::ExecutionStatus;
+
+    fn run(source: &str, input: &str, codel_size: u32) -> String {
+        let assembled = assemble(source).unwrap();
+        let pixels = assembled.pixels(codel_size);
+        let program = PietSource::new(
+            &pixels,
+            assembled.height * codel_size,
+            assembled.width * codel_size,
+        );
+        let codel_settings = CodelSettings::Width(codel_size);
+        let mut cfg_builder = CFGBuilder::new(&program, codel_settings, false);
+        cfg_builder.build();
+        let cfg = cfg_builder.get_cfg();
+
+        let settings = InterpreterSettings {
+            codel_settings,
+            max_steps: Some(100000),
+            print: false,
+            ..Default::default()
+        };
+        let mut interpreter = Interpreter::new(&cfg, settings).with_input(input);
+        let state = interpreter.run();
+        assert_eq!(state.status, ExecutionStatus::Completed);
+        state.stdout.iter().map(|out| out.to_string()).collect()
+    }
+
+    #[test]
+    fn test_straight_line() {
+        assert_eq!(run("push 5\ndup\nmul\nout(int)", "", 1), "25");
+        assert_eq!(run("push 'H'; out(char); push 105; out(char)", "", 3), "Hi");
+        assert_eq!(
+            run(
+                "push -7; out(int); push 0; out(int); push 1000; out(int)",
+                "",
+                1
+            ),
+            "-701000"
+        );
+    }
+
+    #[test]
+    fn test_jumps() {
+        // Counts down from the number read, then jumps over the code after the loop
+        let countdown = "
+            in(int)
+        loop:
+            dup; out(int)
+            push 1; sub
+            dup; jnz loop
+            jmp end
+            push 9; out(int)
+        end:
+            pop
+            push 'x'; out(char)
+        ";
+        assert_eq!(run(countdown, "3\n", 1), "321x");
+        assert_eq!(run(countdown, "1\n", 2), "1x");
+
+        let parity =
+            "in(int); push 2; mod; jz even; push 'o'; out(char); halt; even: push 'e'; out(char)";
+        assert_eq!(run(parity, "4\n", 1), "e");
+        assert_eq!(run(parity, "7\n", 1), "o");
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(assemble("jmp nowhere").unwrap_err().line, 1);
+        assert_eq!(assemble("a: pop\na: pop").unwrap_err().line, 2);
+    }
+}
@@ -0,0 +1,11 @@
+pub mod error;
+pub mod layout;
+pub mod syntax;
+
+use error::AsmError;
+use layout::Assembled;
+
+/// Assembles a textual program into a Piet program that runs it
+pub fn assemble(source: &str) -> Result<Assembled, AsmError> {
+    layout::layout(&syntax::parse(source)?)
+}
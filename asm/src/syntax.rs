@@ -0,0 +1,218 @@
+use crate::error::AsmError;
+use piet_core::instruction::Instruction;
+
+/// One statement of a textual Piet program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Statement {
+    Push(i64),
+    /// Any instruction that takes no argument and doesn't change the direction of travel
+    Instr(Instruction),
+    Label(String),
+    Jmp(String),
+    /// Jumps if the top of the stack is zero, popping it either way
+    Jz(String),
+    /// Jumps if the top of the stack is non-zero, popping it either way
+    Jnz(String),
+    Halt,
+}
+
+/// A statement along with the line it came from, for error reporting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub number: usize,
+    pub statement: Statement,
+}
+
+fn parse_label(number: usize, label: Option<&str>) -> Result<String, AsmError> {
+    match label {
+        Some(label) if is_identifier(label) => Ok(label.to_string()),
+        Some(label) => Err(AsmError::new(number, format!("invalid label `{label}`"))),
+        None => Err(AsmError::new(number, "expected a label")),
+    }
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_constant(number: usize, constant: Option<&str>) -> Result<i64, AsmError> {
+    let constant = constant.ok_or_else(|| AsmError::new(number, "expected a constant"))?;
+    let mut chars = constant.chars();
+    match (chars.next(), chars.next(), chars.next(), chars.next()) {
+        // Character literals push their code point
+        (Some('\''), Some(c), Some('\''), None) => Ok(c as i64),
+        _ => constant
+            .parse()
+            .map_err(|_| AsmError::new(number, format!("invalid constant `{constant}`"))),
+    }
+}
+
+// The length of the character literal `text` starts with, if it starts with one
+fn literal_len(text: &str) -> Option<usize> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('\''), Some(c), Some('\'')) => Some(c.len_utf8() + 2),
+        _ => None,
+    }
+}
+
+// Splits a statement into words.  A character literal is a single word, even if its character is whitespace.
+fn split_words(text: &str) -> Vec<&str> {
+    let mut words = vec![];
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let len = literal_len(rest)
+            .unwrap_or_else(|| rest.find(char::is_whitespace).unwrap_or(rest.len()));
+        words.push(&rest[..len]);
+        rest = rest[len..].trim_start();
+    }
+    words
+}
+
+fn parse_statement(number: usize, text: &str) -> Result<Statement, AsmError> {
+    // `out (char)` is accepted as well as `out(char)`
+    let text = text.replace(" (", "(");
+    let mut words = split_words(&text).into_iter();
+    let mnemonic = words.next().unwrap_or_default().to_ascii_lowercase();
+
+    let statement = match mnemonic.as_str() {
+        "push" => Statement::Push(parse_constant(number, words.next())?),
+        "jmp" => Statement::Jmp(parse_label(number, words.next())?),
+        "jz" => Statement::Jz(parse_label(number, words.next())?),
+        "jnz" => Statement::Jnz(parse_label(number, words.next())?),
+        "halt" => Statement::Halt,
+        "ptr" | "swi" => {
+            return Err(AsmError::new(
+                number,
+                format!("`{mnemonic}` changes the direction of travel, use `jmp`, `jz` or `jnz`"),
+            ))
+        }
+        mnemonic => Statement::Instr(match mnemonic {
+            "pop" => Instruction::Pop,
+            "add" => Instruction::Add,
+            "sub" => Instruction::Sub,
+            "mul" => Instruction::Mul,
+            "div" => Instruction::Div,
+            "mod" => Instruction::Mod,
+            "not" => Instruction::Not,
+            "gt" => Instruction::Gt,
+            "dup" => Instruction::Dup,
+            "roll" => Instruction::Roll,
+            "in(int)" => Instruction::IntIn,
+            "in(char)" => Instruction::CharIn,
+            "out(int)" => Instruction::IntOut,
+            "out(char)" => Instruction::CharOut,
+            _ => {
+                return Err(AsmError::new(
+                    number,
+                    format!("unknown instruction `{mnemonic}`"),
+                ))
+            }
+        }),
+    };
+
+    match words.next() {
+        Some(extra) => Err(AsmError::new(
+            number,
+            format!("unexpected `{extra}` after `{mnemonic}`"),
+        )),
+        None => Ok(statement),
+    }
+}
+
+// Splits a line into its statements, leaving out any comment.  A `;` or `#` in a character literal is just
+// the character.
+fn split_statements(text: &str) -> Vec<&str> {
+    let mut statements = vec![];
+    let mut start = 0;
+    let mut chars = text.char_indices();
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            // Skips the literal's character and closing quote
+            '\'' if chars.clone().nth(1).is_some_and(|(_, c)| c == '\'') => {
+                chars.nth(1);
+            }
+            ';' => {
+                statements.push(&text[start..idx]);
+                start = idx + 1;
+            }
+            '#' => {
+                statements.push(&text[start..idx]);
+                return statements;
+            }
+            _ => (),
+        }
+    }
+    statements.push(&text[start..]);
+    statements
+}
+
+/// Parses a program with one statement per line.  Statements can also be separated with `;`, `#` starts a
+/// comment, and `name:` defines a label, which can share its line with a statement.
+pub fn parse(source: &str) -> Result<Vec<Line>, AsmError> {
+    let mut lines = Vec::new();
+
+    for (idx, text) in source.lines().enumerate() {
+        let number = idx + 1;
+
+        for mut text in split_statements(text).into_iter().map(str::trim) {
+            if let Some((label, rest)) = text.split_once(':') {
+                // A colon inside a character literal isn't a label
+                if is_identifier(label.trim()) {
+                    lines.push(Line {
+                        number,
+                        statement: Statement::Label(label.trim().to_string()),
+                    });
+                    text = rest.trim();
+                }
+            }
+            if !text.is_empty() {
+                lines.push(Line {
+                    number,
+                    statement: parse_statement(number, text)?,
+                });
+            }
+        }
+    }
+
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let source = "start: push 'H' # comment\n  out (char); push -3\nloop:\n jnz loop\npush '#'; push ';' # ';'\npush ' '\n";
+        let statements = parse(source)
+            .unwrap()
+            .into_iter()
+            .map(|line| line.statement)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statements,
+            [
+                Statement::Label("start".to_string()),
+                Statement::Push(72),
+                Statement::Instr(Instruction::CharOut),
+                Statement::Push(-3),
+                Statement::Label("loop".to_string()),
+                Statement::Jnz("loop".to_string()),
+                Statement::Push('#' as i64),
+                Statement::Push(';' as i64),
+                Statement::Push(' ' as i64),
+            ]
+        );
+
+        let err = parse("push 1\nptr\n").unwrap_err();
+        assert_eq!(err.line, 2);
+        assert!(parse("push").is_err());
+        assert!(parse("dup 3").is_err());
+        assert!(parse("push ' 'x").is_err());
+    }
+}
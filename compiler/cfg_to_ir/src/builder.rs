@@ -1,23 +1,37 @@
 mod complete_result;
+mod coverage;
+mod debug_info;
 mod globals;
+mod input;
 mod instructions;
 mod io;
 mod io_stack;
 mod main;
 mod partial_result;
 mod print_stack;
+mod residual;
+mod runtime_error;
 mod setvbuf;
 mod terminate;
+mod trace;
 mod transitions;
+mod wasm;
 
 pub(crate) use complete_result::build_complete;
+pub(crate) use coverage::*;
+pub(crate) use debug_info::*;
 pub(crate) use globals::*;
+pub(crate) use input::*;
 pub(crate) use instructions::*;
 pub(crate) use io::*;
 pub(crate) use io_stack::*;
 pub(crate) use main::*;
 pub(crate) use partial_result::build_partial;
 pub(crate) use print_stack::*;
+pub(crate) use residual::build_residual;
+pub(crate) use runtime_error::*;
 pub(crate) use setvbuf::*;
 pub(crate) use terminate::*;
+pub(crate) use trace::*;
 pub(crate) use transitions::*;
+pub(crate) use wasm::*;
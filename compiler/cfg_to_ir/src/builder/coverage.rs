@@ -0,0 +1,215 @@
+use crate::builder::declare_function;
+use crate::lowering_ctx::LoweringCtx;
+use inkwell::{module::Linkage, values::PointerValue, AddressSpace};
+use piet_core::cfg::CFG;
+use std::collections::HashMap;
+
+/// Counter indices for every block and every edge between blocks, ordered by label so the counters
+/// declared and the counters incremented agree
+pub(crate) fn counter_indices(cfg: &CFG) -> (HashMap<&str, u64>, HashMap<(&str, &str), u64>) {
+    let mut blocks = cfg
+        .keys()
+        .map(|node| node.get_label().as_str())
+        .collect::<Vec<_>>();
+    blocks.sort();
+
+    let mut edges = cfg
+        .iter()
+        .flat_map(|(node, adjs)| {
+            adjs.keys()
+                .map(move |adj| (node.get_label().as_str(), adj.get_label().as_str()))
+        })
+        .collect::<Vec<_>>();
+    edges.sort();
+
+    (
+        blocks.into_iter().zip(0..).collect(),
+        edges.into_iter().zip(0..).collect(),
+    )
+}
+
+fn counter_addr<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, name: &str, index: u64) -> PointerValue<'b> {
+    let i64_type = ctx.llvm_context.i64_type();
+    let counters = ctx.module.get_global(name).unwrap();
+    unsafe {
+        ctx.builder
+            .build_in_bounds_gep(
+                counters.get_value_type().into_array_type(),
+                counters.as_pointer_value(),
+                &[i64_type.const_zero(), i64_type.const_int(index, false)],
+                "counter_addr",
+            )
+            .unwrap()
+    }
+}
+
+/// Adds one to counter `index` of the counter array `name`
+pub(crate) fn build_count<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, name: &str, index: u64) {
+    let i64_type = ctx.llvm_context.i64_type();
+    let counter_addr = counter_addr(ctx, name, index);
+    let count = ctx
+        .builder
+        .build_load(i64_type, counter_addr, "load_count")
+        .unwrap()
+        .into_int_value();
+    let incremented = ctx
+        .builder
+        .build_int_add(count, i64_type.const_int(1, false), "increment_count")
+        .unwrap();
+    ctx.builder.build_store(counter_addr, incremented).unwrap();
+}
+
+/// Declares a counter for every block and edge of `cfg`, and builds `dump_coverage`, which writes them to
+/// the file named by `PIET_COVERAGE` as `block <label> <count>` and `edge <from> <to> <count>` lines.  Main
+/// registers it with `atexit` so the counts are written however the program ends.
+pub(crate) fn build_coverage<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, cfg: &CFG) {
+    let ptr_type = ctx.llvm_context.ptr_type(AddressSpace::default());
+    let i32_type = ctx.llvm_context.i32_type();
+    let i64_type = ctx.llvm_context.i64_type();
+    let void_type = ctx.llvm_context.void_type();
+    let (blocks, edges) = counter_indices(cfg);
+
+    // Counters
+    for (name, len) in [("block_counts", blocks.len()), ("edge_counts", edges.len())] {
+        let counters_type = i64_type.array_type(len as u32);
+        let counters = ctx.module.add_global(counters_type, None, name);
+        counters.set_linkage(Linkage::Internal);
+        counters.set_initializer(&counters_type.const_zero());
+    }
+
+    // extern C file io
+    let getenv_fn = declare_function(ctx, "getenv", ptr_type.fn_type(&[ptr_type.into()], false));
+    let fopen_fn = declare_function(
+        ctx,
+        "fopen",
+        ptr_type.fn_type(&[ptr_type.into(), ptr_type.into()], false),
+    );
+    let fprintf_fn = declare_function(
+        ctx,
+        "fprintf",
+        i32_type.fn_type(&[ptr_type.into(), ptr_type.into()], true),
+    );
+    let fclose_fn =
+        ctx.module
+            .add_function("fclose", i32_type.fn_type(&[ptr_type.into()], false), None);
+    ctx.module
+        .add_function("atexit", i32_type.fn_type(&[ptr_type.into()], false), None);
+
+    let dump_fn = ctx
+        .module
+        .add_function("dump_coverage", void_type.fn_type(&[], false), None);
+    let basic_block = ctx.llvm_context.append_basic_block(dump_fn, "");
+    let open_block = ctx.llvm_context.append_basic_block(dump_fn, "open_profile");
+    let write_block = ctx
+        .llvm_context
+        .append_basic_block(dump_fn, "write_profile");
+    let ret_block = ctx.llvm_context.append_basic_block(dump_fn, "ret");
+
+    // Nothing is written unless PIET_COVERAGE names a file that can be opened
+    ctx.builder.position_at_end(basic_block);
+    let env_var = ctx
+        .builder
+        .build_global_string_ptr("PIET_COVERAGE", "coverage_env_var")
+        .unwrap();
+    let path = ctx
+        .builder
+        .build_call(getenv_fn, &[env_var.as_pointer_value().into()], "path")
+        .unwrap()
+        .try_as_basic_value()
+        .unwrap_basic()
+        .into_pointer_value();
+    let no_path = ctx.builder.build_is_null(path, "no_path").unwrap();
+    ctx.builder
+        .build_conditional_branch(no_path, ret_block, open_block)
+        .unwrap();
+
+    ctx.builder.position_at_end(open_block);
+    let mode = ctx
+        .builder
+        .build_global_string_ptr("w", "coverage_mode")
+        .unwrap();
+    let file = ctx
+        .builder
+        .build_call(
+            fopen_fn,
+            &[path.into(), mode.as_pointer_value().into()],
+            "file",
+        )
+        .unwrap()
+        .try_as_basic_value()
+        .unwrap_basic()
+        .into_pointer_value();
+    let not_opened = ctx.builder.build_is_null(file, "not_opened").unwrap();
+    ctx.builder
+        .build_conditional_branch(not_opened, ret_block, write_block)
+        .unwrap();
+
+    ctx.builder.position_at_end(write_block);
+    let block_fmt = ctx
+        .builder
+        .build_global_string_ptr("block %s %ld\n", "block_count_fmt")
+        .unwrap();
+    let edge_fmt = ctx
+        .builder
+        .build_global_string_ptr("edge %s %s %ld\n", "edge_count_fmt")
+        .unwrap();
+    let mut labels = HashMap::new();
+    for &label in blocks.keys() {
+        let label_str = ctx
+            .builder
+            .build_global_string_ptr(label, &format!("label_{label}"))
+            .unwrap();
+        labels.insert(label, label_str.as_pointer_value());
+    }
+
+    let load_count = |name: &str, index: u64| {
+        ctx.builder
+            .build_load(i64_type, counter_addr(ctx, name, index), "load_count")
+            .unwrap()
+    };
+
+    let mut blocks = blocks.into_iter().collect::<Vec<_>>();
+    blocks.sort_by_key(|&(_, index)| index);
+    for (label, index) in blocks {
+        let count = load_count("block_counts", index);
+        ctx.builder
+            .build_call(
+                fprintf_fn,
+                &[
+                    file.into(),
+                    block_fmt.as_pointer_value().into(),
+                    labels[label].into(),
+                    count.into(),
+                ],
+                "",
+            )
+            .unwrap();
+    }
+
+    let mut edges = edges.into_iter().collect::<Vec<_>>();
+    edges.sort_by_key(|&(_, index)| index);
+    for ((from, to), index) in edges {
+        let count = load_count("edge_counts", index);
+        ctx.builder
+            .build_call(
+                fprintf_fn,
+                &[
+                    file.into(),
+                    edge_fmt.as_pointer_value().into(),
+                    labels[from].into(),
+                    labels[to].into(),
+                    count.into(),
+                ],
+                "",
+            )
+            .unwrap();
+    }
+
+    ctx.builder
+        .build_call(fclose_fn, &[file.into()], "")
+        .unwrap();
+    ctx.builder.build_unconditional_branch(ret_block).unwrap();
+
+    ctx.builder.position_at_end(ret_block);
+    ctx.builder.build_return(None).unwrap();
+}
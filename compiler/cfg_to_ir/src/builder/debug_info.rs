@@ -0,0 +1,145 @@
+use inkwell::context::Context;
+use inkwell::debug_info::{
+    debug_metadata_version, AsDIScope, DICompileUnit, DIFlags, DIFlagsConstants, DILocation,
+    DISubprogram, DWARFEmissionKind, DWARFSourceLanguage, DebugInfoBuilder,
+};
+use inkwell::module::{FlagBehavior, Module};
+use inkwell::values::FunctionValue;
+use inkwell::AddressSpace;
+use piet_core::cfg::Node;
+use std::borrow::Cow;
+use std::fs;
+use std::path::PathBuf;
+
+// DW_ATE_signed, the encoding of a signed integer type
+const DW_ATE_SIGNED: u32 = 0x05;
+
+/// DWARF debug info mapping the compiled program back to its image.  The image is the source file, and a
+/// colour block's code is on the line of its top-left codel's row and in the column of its column, both
+/// counted from 1, so debuggers and profilers point at pixels rather than at anonymous blocks.
+pub(crate) struct DebugInfo<'b> {
+    builder: DebugInfoBuilder<'b>,
+    compile_unit: DICompileUnit<'b>,
+    is_optimized: bool,
+}
+
+impl<'b> DebugInfo<'b> {
+    pub(crate) fn new(module: &Module<'b>, image: &str, is_optimized: bool) -> Self {
+        let path = fs::canonicalize(image).unwrap_or_else(|_| PathBuf::from(image));
+        let filename = path
+            .file_name()
+            .map_or(Cow::from(image), |name| name.to_string_lossy());
+        let directory = path
+            .parent()
+            .map(|dir| dir.to_string_lossy())
+            .unwrap_or_default();
+
+        let version = module
+            .get_context()
+            .i32_type()
+            .const_int(debug_metadata_version() as u64, false);
+        module.add_basic_value_flag("Debug Info Version", FlagBehavior::Warning, version);
+        // Described as C, so that debuggers evaluate expressions like `*piet_stack@stack_size`
+        let (builder, compile_unit) = module.create_debug_info_builder(
+            true,
+            DWARFSourceLanguage::C,
+            &filename,
+            &directory,
+            "pietcc",
+            is_optimized,
+            "",
+            0,
+            "",
+            DWARFEmissionKind::Full,
+            0,
+            false,
+            false,
+            "",
+            "",
+        );
+        Self {
+            builder,
+            compile_unit,
+            is_optimized,
+        }
+    }
+
+    /// Describes `function`, which takes no arguments, and attaches the description to it
+    pub(crate) fn build_subprogram(&self, function: FunctionValue<'b>) -> DISubprogram<'b> {
+        let file = self.compile_unit.get_file();
+        let subroutine_type = self
+            .builder
+            .create_subroutine_type(file, None, &[], DIFlags::PUBLIC);
+        let subprogram = self.builder.create_function(
+            self.compile_unit.as_debug_info_scope(),
+            &function.get_name().to_string_lossy(),
+            None,
+            file,
+            0,
+            subroutine_type,
+            false,
+            true,
+            0,
+            DIFlags::PUBLIC,
+            self.is_optimized,
+        );
+        function.set_subprogram(subprogram);
+        subprogram
+    }
+
+    /// Where `node`'s code is, within `scope`.  The halt block has no codels, so it's on line 0, which
+    /// debuggers take to be code with no source of its own, as is any code in `scope` without a node.
+    pub(crate) fn location(
+        &self,
+        context: &'b Context,
+        scope: DISubprogram<'b>,
+        node: Option<&Node>,
+    ) -> DILocation<'b> {
+        let (line, column) = node
+            .filter(|node| !node.is_halt())
+            .and_then(|node| node.get_region().iter().min())
+            .map_or((0, 0), |&(r, c)| (r + 1, c + 1));
+        self.builder
+            .create_debug_location(context, line, column, scope.as_debug_info_scope(), None)
+    }
+
+    /// Describes `piet_stack`, as a pointer to 64-bit integers, and `stack_size`, so a debugger can print
+    /// the stack with `print *piet_stack@stack_size`
+    pub(crate) fn build_stack_variables(&self, module: &Module<'b>) {
+        let file = self.compile_unit.get_file();
+        let i64_type = self
+            .builder
+            .create_basic_type("int64_t", 64, DW_ATE_SIGNED, DIFlags::PUBLIC)
+            .unwrap()
+            .as_type();
+        let ptr_type = self
+            .builder
+            .create_pointer_type("", i64_type, 64, 64, AddressSpace::default())
+            .as_type();
+        let dbg_kind = module.get_context().get_kind_id("dbg");
+
+        for (name, di_type) in [("piet_stack", ptr_type), ("stack_size", i64_type)] {
+            let expression = self.builder.create_global_variable_expression(
+                self.compile_unit.as_debug_info_scope(),
+                name,
+                name,
+                file,
+                0,
+                di_type,
+                true,
+                Some(self.builder.create_expression(vec![])),
+                None,
+                64,
+            );
+            module
+                .get_global(name)
+                .unwrap()
+                .set_metadata(expression.as_metadata_value(module.get_context()), dbg_kind);
+        }
+    }
+
+    /// Resolves what's been described, which has to happen before the module is verified
+    pub(crate) fn finalize(&self) {
+        self.builder.finalize();
+    }
+}
@@ -1,11 +1,27 @@
+use crate::builder::build_wasm_runtime;
 use crate::lowering_ctx::LoweringCtx;
-use inkwell::{module::Linkage, AddressSpace};
-use piet_core::{instruction::Instruction, state::ExecutionState};
+use inkwell::{module::Linkage, types::FunctionType, values::FunctionValue, AddressSpace};
+use piet_core::{instruction::Instruction, settings::Platform, state::ExecutionState};
 use strum::IntoEnumIterator;
 
 pub(crate) fn build_globals(ctx: &LoweringCtx) {
     build_global_definitions(ctx);
     build_literals(ctx);
+    if let Some(debug_info) = &ctx.debug_info {
+        debug_info.build_stack_variables(&ctx.module);
+    }
+}
+
+/// The function `name`, declared with `fn_type` unless something else already needs it, e.g. the C file
+/// functions that both coverage and tracing call
+pub(crate) fn declare_function<'a, 'b>(
+    ctx: &LoweringCtx<'a, 'b>,
+    name: &str,
+    fn_type: FunctionType<'b>,
+) -> FunctionValue<'b> {
+    ctx.module
+        .get_function(name)
+        .unwrap_or_else(|| ctx.module.add_function(name, fn_type, None))
 }
 
 pub(crate) fn build_global_definitions(ctx: &LoweringCtx) {
@@ -29,41 +45,41 @@ pub(crate) fn build_global_definitions(ctx: &LoweringCtx) {
     global_retries.set_linkage(Linkage::Internal);
     global_retries.set_initializer(&i8_type.const_zero());
 
-    // extern C io
-    let printf_type = i32_type.fn_type(&[ptr_type.into()], true);
-    ctx.module.add_function("printf", printf_type, None);
-
-    let scanf_type = i32_type.fn_type(&[ptr_type.into()], true);
-    ctx.module.add_function("scanf", scanf_type, None);
-
-    let exit_type = void_type.fn_type(&[i64_type.into()], false);
-    ctx.module.add_function("exit", exit_type, None);
-
-    let fdopen_type = ptr_type.fn_type(&[i32_type.into(), ptr_type.into()], false);
-    ctx.module.add_function("fdopen", fdopen_type, None);
-
-    // malloc type
-    let malloc_fn_type = ctx
-        .llvm_context
-        .ptr_type(AddressSpace::default())
-        .fn_type(&[ctx.llvm_context.i64_type().into()], false);
-
-    ctx.module.add_function("malloc", malloc_fn_type, None);
-
-    // setvbuf to disable buffering
-    let i32_type = ctx.llvm_context.i32_type();
-    let void_type = ctx.llvm_context.void_type();
+    // extern C io, which WebAssembly has to build for itself out of what the host provides
+    if ctx.settings.platform == Platform::Native {
+        let printf_type = i32_type.fn_type(&[ptr_type.into()], true);
+        ctx.module.add_function("printf", printf_type, None);
+
+        let getchar_type = i32_type.fn_type(&[], false);
+        ctx.module.add_function("getchar", getchar_type, None);
+
+        let exit_type = void_type.fn_type(&[i64_type.into()], false);
+        ctx.module.add_function("exit", exit_type, None);
+
+        let fdopen_type = ptr_type.fn_type(&[i32_type.into(), ptr_type.into()], false);
+        ctx.module.add_function("fdopen", fdopen_type, None);
+
+        // malloc type
+        let malloc_fn_type = ctx
+            .llvm_context
+            .ptr_type(AddressSpace::default())
+            .fn_type(&[ctx.llvm_context.i64_type().into()], false);
+
+        ctx.module.add_function("malloc", malloc_fn_type, None);
+
+        // setvbuf to disable buffering
+        let setvbuf_type = i32_type.fn_type(
+            &[
+                ptr_type.into(),
+                ptr_type.into(),
+                i32_type.into(),
+                i64_type.into(),
+            ],
+            false,
+        );
+        ctx.module.add_function("setvbuf", setvbuf_type, None);
+    }
 
-    let setvbuf_type = i32_type.fn_type(
-        &[
-            ptr_type.into(),
-            ptr_type.into(),
-            i32_type.into(),
-            i64_type.into(),
-        ],
-        false,
-    );
-    ctx.module.add_function("setvbuf", setvbuf_type, None);
     // Main functions
     let main_fn_type = i64_type.fn_type(&[], false);
     ctx.module.add_function("main", main_fn_type, None);
@@ -136,6 +152,10 @@ pub(crate) fn build_global_definitions(ctx: &LoweringCtx) {
         .add_function(Instruction::CharIn.to_llvm_name(), void_fn_type, None);
     ctx.module.add_function("piet_intout", void_fn_type, None);
     ctx.module.add_function("piet_charout", void_fn_type, None);
+
+    if ctx.settings.platform != Platform::Native {
+        build_wasm_runtime(ctx);
+    }
 }
 
 pub(crate) fn build_dp_cc<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, execution_state: &ExecutionState) {
@@ -171,7 +191,7 @@ pub(crate) fn build_literals<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
             .build_global_string("%ld \0", "stack_fmt")
             .unwrap();
         ctx.builder
-            .build_global_string("\nStack (size %d): ", "stack_id")
+            .build_global_string("\nStack (size %ld): ", "stack_id")
             .unwrap();
         ctx.builder
             .build_global_string("\nStack empty", "stack_id_empty")
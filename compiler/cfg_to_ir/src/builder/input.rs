@@ -0,0 +1,654 @@
+use crate::lowering_ctx::LoweringCtx;
+use inkwell::{
+    intrinsics::Intrinsic,
+    module::Linkage,
+    values::{FunctionValue, IntValue, StructValue},
+    AddressSpace, IntPredicate,
+};
+
+/// What `piet_read_int` and `piet_read_char` return, alongside the value they store
+pub(crate) const READ_VALUE: u64 = 0;
+pub(crate) const READ_EOF: u64 = 1;
+pub(crate) const READ_BAD: u64 = 2;
+
+// Marks the pushback slot as empty, since -1 is EOF
+const NO_PUSHBACK: u64 = -2i64 as u64;
+
+/// Builds `piet_read_int(ptr)` and `piet_read_char(ptr)`, which read input the same way as the interpreter
+/// (see `InputSettings`), store what they read through the pointer and return `READ_VALUE`, `READ_EOF` or
+/// `READ_BAD`.  Input is read a byte at a time with getchar, through `piet_getc`, which holds the byte of
+/// lookahead the readers need.
+pub(crate) fn build_read_input<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
+    let i32_type = ctx.llvm_context.i32_type();
+
+    let pushback = ctx.module.add_global(i32_type, None, "input_pushback");
+    pushback.set_linkage(Linkage::Internal);
+    pushback.set_initializer(&i32_type.const_int(NO_PUSHBACK, true));
+
+    build_getc(ctx);
+    build_read_int(ctx);
+    build_read_char(ctx);
+}
+
+// piet_getc() returns the byte put back by the last reader if there is one, and calls getchar otherwise
+fn build_getc<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
+    let i32_type = ctx.llvm_context.i32_type();
+    let getchar_fn = ctx.module.get_function("getchar").unwrap();
+    let pushback = ctx
+        .module
+        .get_global("input_pushback")
+        .unwrap()
+        .as_pointer_value();
+    let no_pushback = i32_type.const_int(NO_PUSHBACK, true);
+
+    let getc_fn = ctx
+        .module
+        .add_function("piet_getc", i32_type.fn_type(&[], false), None);
+    let entry = ctx.llvm_context.append_basic_block(getc_fn, "");
+    let pushed_back_block = ctx.llvm_context.append_basic_block(getc_fn, "pushed_back");
+    let getchar_block = ctx.llvm_context.append_basic_block(getc_fn, "getchar");
+
+    ctx.builder.position_at_end(entry);
+    let byte = ctx
+        .builder
+        .build_load(i32_type, pushback, "pushback")
+        .unwrap()
+        .into_int_value();
+    let is_pushed_back = ctx
+        .builder
+        .build_int_compare(IntPredicate::NE, byte, no_pushback, "is_pushed_back")
+        .unwrap();
+    ctx.builder
+        .build_conditional_branch(is_pushed_back, pushed_back_block, getchar_block)
+        .unwrap();
+
+    ctx.builder.position_at_end(pushed_back_block);
+    ctx.builder.build_store(pushback, no_pushback).unwrap();
+    ctx.builder.build_return(Some(&byte)).unwrap();
+
+    ctx.builder.position_at_end(getchar_block);
+    let byte = ctx
+        .builder
+        .build_call(getchar_fn, &[], "getchar")
+        .unwrap()
+        .try_as_basic_value()
+        .unwrap_basic();
+    ctx.builder.build_return(Some(&byte)).unwrap();
+}
+
+fn build_getc_call<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) -> IntValue<'b> {
+    let getc_fn = ctx.module.get_function("piet_getc").unwrap();
+    ctx.builder
+        .build_call(getc_fn, &[], "byte")
+        .unwrap()
+        .try_as_basic_value()
+        .unwrap_basic()
+        .into_int_value()
+}
+
+// Puts `byte` back to be read again by the next piet_getc
+fn build_ungetc<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, byte: IntValue<'b>) {
+    let pushback = ctx.module.get_global("input_pushback").unwrap();
+    ctx.builder
+        .build_store(pushback.as_pointer_value(), byte)
+        .unwrap();
+}
+
+// C's isspace: space, \t, \n, \v, \f and \r
+fn build_is_space<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, byte: IntValue<'b>) -> IntValue<'b> {
+    let i32_type = ctx.llvm_context.i32_type();
+    let is_blank = ctx
+        .builder
+        .build_int_compare(
+            IntPredicate::EQ,
+            byte,
+            i32_type.const_int(b' ' as u64, false),
+            "is_blank",
+        )
+        .unwrap();
+    let offset = ctx
+        .builder
+        .build_int_sub(byte, i32_type.const_int(b'\t' as u64, false), "offset")
+        .unwrap();
+    let is_control = ctx
+        .builder
+        .build_int_compare(
+            IntPredicate::ULT,
+            offset,
+            i32_type.const_int(5, false),
+            "is_control_space",
+        )
+        .unwrap();
+    ctx.builder
+        .build_or(is_blank, is_control, "is_space")
+        .unwrap()
+}
+
+fn build_is<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, byte: IntValue<'b>, value: i64) -> IntValue<'b> {
+    let i32_type = ctx.llvm_context.i32_type();
+    ctx.builder
+        .build_int_compare(
+            IntPredicate::EQ,
+            byte,
+            i32_type.const_int(value as u64, true),
+            "is",
+        )
+        .unwrap()
+}
+
+fn build_ret_status<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, status: u64) {
+    let status = ctx.llvm_context.i32_type().const_int(status, false);
+    ctx.builder.build_return(Some(&status)).unwrap();
+}
+
+fn read_fn<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, name: &str) -> FunctionValue<'b> {
+    let ptr_type = ctx.llvm_context.ptr_type(AddressSpace::default());
+    let fn_type = ctx
+        .llvm_context
+        .i32_type()
+        .fn_type(&[ptr_type.into()], false);
+    ctx.module.add_function(name, fn_type, None)
+}
+
+// Returns the result and overflow flag of an llvm.*.with.overflow intrinsic
+fn build_overflowing<'a, 'b>(
+    ctx: &LoweringCtx<'a, 'b>,
+    intrinsic: &str,
+    lhs: IntValue<'b>,
+    rhs: IntValue<'b>,
+) -> (IntValue<'b>, IntValue<'b>) {
+    let i64_type = ctx.llvm_context.i64_type();
+    let intrinsic_fn = Intrinsic::find(intrinsic)
+        .unwrap()
+        .get_declaration(&ctx.module, &[i64_type.into()])
+        .unwrap();
+    let result: StructValue = ctx
+        .builder
+        .build_call(intrinsic_fn, &[lhs.into(), rhs.into()], "")
+        .unwrap()
+        .try_as_basic_value()
+        .unwrap_basic()
+        .into_struct_value();
+    let value = ctx.builder.build_extract_value(result, 0, "value").unwrap();
+    let overflow = ctx
+        .builder
+        .build_extract_value(result, 1, "overflow")
+        .unwrap();
+    (value.into_int_value(), overflow.into_int_value())
+}
+
+fn build_read_int<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
+    let i1_type = ctx.llvm_context.bool_type();
+    let i32_type = ctx.llvm_context.i32_type();
+    let i64_type = ctx.llvm_context.i64_type();
+    let read_int_fn = read_fn(ctx, "piet_read_int");
+    let out = read_int_fn.get_nth_param(0).unwrap().into_pointer_value();
+
+    let entry = ctx.llvm_context.append_basic_block(read_int_fn, "");
+    let skip_space_block = ctx
+        .llvm_context
+        .append_basic_block(read_int_fn, "skip_space");
+    let first_block = ctx.llvm_context.append_basic_block(read_int_fn, "first");
+    let sign_block = ctx.llvm_context.append_basic_block(read_int_fn, "sign");
+    let digit_block = ctx.llvm_context.append_basic_block(read_int_fn, "digit");
+    let accumulate_block = ctx
+        .llvm_context
+        .append_basic_block(read_int_fn, "accumulate");
+    let next_digit_block = ctx
+        .llvm_context
+        .append_basic_block(read_int_fn, "next_digit");
+    let end_block = ctx.llvm_context.append_basic_block(read_int_fn, "end");
+    let delimiter_block = ctx
+        .llvm_context
+        .append_basic_block(read_int_fn, "delimiter");
+    let trailing_block = ctx
+        .llvm_context
+        .append_basic_block(read_int_fn, "trailing_space");
+    let trailing_space_block = ctx
+        .llvm_context
+        .append_basic_block(read_int_fn, "more_space");
+    let put_back_block = ctx.llvm_context.append_basic_block(read_int_fn, "put_back");
+    let value_block = ctx.llvm_context.append_basic_block(read_int_fn, "value");
+    let discard_block = ctx.llvm_context.append_basic_block(read_int_fn, "discard");
+    let discard_next_block = ctx
+        .llvm_context
+        .append_basic_block(read_int_fn, "discard_next");
+    let eof_block = ctx.llvm_context.append_basic_block(read_int_fn, "eof");
+    let bad_block = ctx.llvm_context.append_basic_block(read_int_fn, "bad");
+
+    // The byte being looked at, the value so far (accumulated with the number's sign, so that i64::MIN can
+    // be read), and whether a digit has been seen
+    ctx.builder.position_at_end(entry);
+    let byte_addr = ctx.builder.build_alloca(i32_type, "byte_addr").unwrap();
+    let value_addr = ctx.builder.build_alloca(i64_type, "value_addr").unwrap();
+    let negative_addr = ctx.builder.build_alloca(i1_type, "negative_addr").unwrap();
+    let seen_digit_addr = ctx
+        .builder
+        .build_alloca(i1_type, "seen_digit_addr")
+        .unwrap();
+    ctx.builder
+        .build_store(value_addr, i64_type.const_zero())
+        .unwrap();
+    ctx.builder
+        .build_store(seen_digit_addr, i1_type.const_zero())
+        .unwrap();
+    ctx.builder
+        .build_unconditional_branch(skip_space_block)
+        .unwrap();
+
+    // Leading whitespace, including newlines, is skipped
+    ctx.builder.position_at_end(skip_space_block);
+    let byte = build_getc_call(ctx);
+    ctx.builder.build_store(byte_addr, byte).unwrap();
+    let is_space = build_is_space(ctx, byte);
+    ctx.builder
+        .build_conditional_branch(is_space, skip_space_block, first_block)
+        .unwrap();
+
+    ctx.builder.position_at_end(first_block);
+    let is_eof = build_is(ctx, byte, -1);
+    let is_minus = build_is(ctx, byte, b'-' as i64);
+    let is_plus = build_is(ctx, byte, b'+' as i64);
+    let is_sign = ctx.builder.build_or(is_minus, is_plus, "is_sign").unwrap();
+    ctx.builder.build_store(negative_addr, is_minus).unwrap();
+    let not_eof_block = ctx.llvm_context.append_basic_block(read_int_fn, "not_eof");
+    ctx.builder
+        .build_conditional_branch(is_eof, eof_block, not_eof_block)
+        .unwrap();
+    ctx.builder.position_at_end(not_eof_block);
+    ctx.builder
+        .build_conditional_branch(is_sign, sign_block, digit_block)
+        .unwrap();
+
+    ctx.builder.position_at_end(sign_block);
+    let byte = build_getc_call(ctx);
+    ctx.builder.build_store(byte_addr, byte).unwrap();
+    ctx.builder.build_unconditional_branch(digit_block).unwrap();
+
+    ctx.builder.position_at_end(digit_block);
+    let byte = ctx
+        .builder
+        .build_load(i32_type, byte_addr, "byte")
+        .unwrap()
+        .into_int_value();
+    let digit = ctx
+        .builder
+        .build_int_sub(byte, i32_type.const_int(b'0' as u64, false), "digit")
+        .unwrap();
+    let is_digit = ctx
+        .builder
+        .build_int_compare(
+            IntPredicate::ULT,
+            digit,
+            i32_type.const_int(10, false),
+            "is_digit",
+        )
+        .unwrap();
+    ctx.builder
+        .build_conditional_branch(is_digit, accumulate_block, end_block)
+        .unwrap();
+
+    // An overflowing number is bad input, starting from the digit that overflowed
+    ctx.builder.position_at_end(accumulate_block);
+    let digit = ctx
+        .builder
+        .build_int_z_extend(digit, i64_type, "digit")
+        .unwrap();
+    let value = ctx
+        .builder
+        .build_load(i64_type, value_addr, "value")
+        .unwrap()
+        .into_int_value();
+    let negative = ctx
+        .builder
+        .build_load(i1_type, negative_addr, "negative")
+        .unwrap()
+        .into_int_value();
+    let (scaled, mul_overflow) = build_overflowing(
+        ctx,
+        "llvm.smul.with.overflow",
+        value,
+        i64_type.const_int(10, false),
+    );
+    let (sum, add_overflow) = build_overflowing(ctx, "llvm.sadd.with.overflow", scaled, digit);
+    let (difference, sub_overflow) =
+        build_overflowing(ctx, "llvm.ssub.with.overflow", scaled, digit);
+    let value = ctx
+        .builder
+        .build_select(negative, difference, sum, "value")
+        .unwrap();
+    let overflow = ctx
+        .builder
+        .build_select(negative, sub_overflow, add_overflow, "overflow")
+        .unwrap()
+        .into_int_value();
+    let overflow = ctx
+        .builder
+        .build_or(mul_overflow, overflow, "overflow")
+        .unwrap();
+    ctx.builder.build_store(value_addr, value).unwrap();
+    ctx.builder
+        .build_store(seen_digit_addr, i1_type.const_int(1, false))
+        .unwrap();
+    ctx.builder
+        .build_conditional_branch(overflow, discard_block, next_digit_block)
+        .unwrap();
+
+    ctx.builder.position_at_end(next_digit_block);
+    let byte = build_getc_call(ctx);
+    ctx.builder.build_store(byte_addr, byte).unwrap();
+    ctx.builder.build_unconditional_branch(digit_block).unwrap();
+
+    // The number ends at EOF or a newline, or at other whitespace, which is consumed up to the end of the
+    // line.  Anything else makes it bad.
+    ctx.builder.position_at_end(end_block);
+    let seen_digit = ctx
+        .builder
+        .build_load(i1_type, seen_digit_addr, "seen_digit")
+        .unwrap()
+        .into_int_value();
+    ctx.builder
+        .build_conditional_branch(seen_digit, delimiter_block, discard_block)
+        .unwrap();
+
+    ctx.builder.position_at_end(delimiter_block);
+    let byte = ctx
+        .builder
+        .build_load(i32_type, byte_addr, "byte")
+        .unwrap()
+        .into_int_value();
+    let is_eof = build_is(ctx, byte, -1);
+    let is_newline = build_is(ctx, byte, b'\n' as i64);
+    let is_end = ctx.builder.build_or(is_eof, is_newline, "is_end").unwrap();
+    let is_space = build_is_space(ctx, byte);
+    let not_end_block = ctx.llvm_context.append_basic_block(read_int_fn, "not_end");
+    ctx.builder
+        .build_conditional_branch(is_end, value_block, not_end_block)
+        .unwrap();
+    ctx.builder.position_at_end(not_end_block);
+    ctx.builder
+        .build_conditional_branch(is_space, trailing_block, discard_block)
+        .unwrap();
+
+    ctx.builder.position_at_end(trailing_block);
+    let byte = build_getc_call(ctx);
+    let is_newline = build_is(ctx, byte, b'\n' as i64);
+    ctx.builder
+        .build_conditional_branch(is_newline, value_block, trailing_space_block)
+        .unwrap();
+
+    ctx.builder.position_at_end(trailing_space_block);
+    let is_space = build_is_space(ctx, byte);
+    ctx.builder
+        .build_conditional_branch(is_space, trailing_block, put_back_block)
+        .unwrap();
+
+    ctx.builder.position_at_end(put_back_block);
+    build_ungetc(ctx, byte);
+    ctx.builder.build_unconditional_branch(value_block).unwrap();
+
+    ctx.builder.position_at_end(value_block);
+    let value = ctx
+        .builder
+        .build_load(i64_type, value_addr, "value")
+        .unwrap();
+    ctx.builder.build_store(out, value).unwrap();
+    build_ret_status(ctx, READ_VALUE);
+
+    // Bad input discards the rest of its line, including the newline
+    ctx.builder.position_at_end(discard_block);
+    let byte = ctx
+        .builder
+        .build_load(i32_type, byte_addr, "byte")
+        .unwrap()
+        .into_int_value();
+    let is_eof = build_is(ctx, byte, -1);
+    let is_newline = build_is(ctx, byte, b'\n' as i64);
+    let is_end = ctx.builder.build_or(is_eof, is_newline, "is_end").unwrap();
+    ctx.builder
+        .build_conditional_branch(is_end, bad_block, discard_next_block)
+        .unwrap();
+
+    ctx.builder.position_at_end(discard_next_block);
+    let byte = build_getc_call(ctx);
+    ctx.builder.build_store(byte_addr, byte).unwrap();
+    ctx.builder
+        .build_unconditional_branch(discard_block)
+        .unwrap();
+
+    ctx.builder.position_at_end(eof_block);
+    build_ret_status(ctx, READ_EOF);
+
+    ctx.builder.position_at_end(bad_block);
+    build_ret_status(ctx, READ_BAD);
+}
+
+fn build_read_char<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
+    let i32_type = ctx.llvm_context.i32_type();
+    let i64_type = ctx.llvm_context.i64_type();
+    let read_char_fn = read_fn(ctx, "piet_read_char");
+    let out = read_char_fn.get_nth_param(0).unwrap().into_pointer_value();
+
+    let entry = ctx.llvm_context.append_basic_block(read_char_fn, "");
+    let lead_block = ctx.llvm_context.append_basic_block(read_char_fn, "lead");
+    let continuation_block = ctx
+        .llvm_context
+        .append_basic_block(read_char_fn, "continuation");
+    let next_byte_block = ctx
+        .llvm_context
+        .append_basic_block(read_char_fn, "next_byte");
+    let accept_block = ctx.llvm_context.append_basic_block(read_char_fn, "accept");
+    let validate_block = ctx
+        .llvm_context
+        .append_basic_block(read_char_fn, "validate");
+    let value_block = ctx.llvm_context.append_basic_block(read_char_fn, "value");
+    let put_back_block = ctx
+        .llvm_context
+        .append_basic_block(read_char_fn, "put_back");
+    let eof_block = ctx.llvm_context.append_basic_block(read_char_fn, "eof");
+    let bad_block = ctx.llvm_context.append_basic_block(read_char_fn, "bad");
+
+    // The code point so far, how many continuation bytes are still to come, and the smallest code point
+    // that needs this many bytes
+    ctx.builder.position_at_end(entry);
+    let code_point_addr = ctx
+        .builder
+        .build_alloca(i32_type, "code_point_addr")
+        .unwrap();
+    let remaining_addr = ctx
+        .builder
+        .build_alloca(i32_type, "remaining_addr")
+        .unwrap();
+    let min_addr = ctx.builder.build_alloca(i32_type, "min_addr").unwrap();
+    let lead = build_getc_call(ctx);
+    let is_eof = build_is(ctx, lead, -1);
+    ctx.builder
+        .build_conditional_branch(is_eof, eof_block, lead_block)
+        .unwrap();
+
+    // The lead byte gives the length of the sequence.  A stray continuation byte or a byte that can't
+    // start one is bad on its own.
+    ctx.builder.position_at_end(lead_block);
+    let sequences = [
+        (0x80, 0x7f, 0, 0),
+        (0xc0, 0, 0, 0),
+        (0xe0, 0x1f, 1, 0x80),
+        (0xf0, 0x0f, 2, 0x800),
+        (0xf8, 0x07, 3, 0x10000),
+    ];
+    for (i, (below, mask, remaining, min)) in sequences.into_iter().enumerate() {
+        let matched_block = match i {
+            1 => bad_block,
+            _ => ctx
+                .llvm_context
+                .append_basic_block(read_char_fn, "sequence"),
+        };
+        let next_block = match i {
+            4 => bad_block,
+            _ => ctx.llvm_context.append_basic_block(read_char_fn, "lead"),
+        };
+        let is_below = ctx
+            .builder
+            .build_int_compare(
+                IntPredicate::ULT,
+                lead,
+                i32_type.const_int(below, false),
+                "is_below",
+            )
+            .unwrap();
+        ctx.builder
+            .build_conditional_branch(is_below, matched_block, next_block)
+            .unwrap();
+
+        if i != 1 {
+            ctx.builder.position_at_end(matched_block);
+            let code_point = ctx
+                .builder
+                .build_and(lead, i32_type.const_int(mask, false), "code_point")
+                .unwrap();
+            ctx.builder
+                .build_store(code_point_addr, code_point)
+                .unwrap();
+            ctx.builder
+                .build_store(remaining_addr, i32_type.const_int(remaining, false))
+                .unwrap();
+            ctx.builder
+                .build_store(min_addr, i32_type.const_int(min, false))
+                .unwrap();
+            ctx.builder
+                .build_unconditional_branch(continuation_block)
+                .unwrap();
+        }
+        ctx.builder.position_at_end(next_block);
+    }
+
+    ctx.builder.position_at_end(continuation_block);
+    let remaining = ctx
+        .builder
+        .build_load(i32_type, remaining_addr, "remaining")
+        .unwrap()
+        .into_int_value();
+    let is_done = ctx
+        .builder
+        .build_int_compare(
+            IntPredicate::EQ,
+            remaining,
+            i32_type.const_zero(),
+            "is_done",
+        )
+        .unwrap();
+    ctx.builder
+        .build_conditional_branch(is_done, validate_block, next_byte_block)
+        .unwrap();
+
+    // A sequence cut short is bad, and the byte that cut it short is left to be read again
+    ctx.builder.position_at_end(next_byte_block);
+    let byte = build_getc_call(ctx);
+    let tag = ctx
+        .builder
+        .build_and(byte, i32_type.const_int(0xc0, false), "tag")
+        .unwrap();
+    let is_continuation = build_is(ctx, tag, 0x80);
+    ctx.builder
+        .build_conditional_branch(is_continuation, accept_block, put_back_block)
+        .unwrap();
+
+    ctx.builder.position_at_end(accept_block);
+    let code_point = ctx
+        .builder
+        .build_load(i32_type, code_point_addr, "code_point")
+        .unwrap()
+        .into_int_value();
+    let shifted = ctx
+        .builder
+        .build_left_shift(code_point, i32_type.const_int(6, false), "shifted")
+        .unwrap();
+    let bits = ctx
+        .builder
+        .build_and(byte, i32_type.const_int(0x3f, false), "bits")
+        .unwrap();
+    let code_point = ctx.builder.build_or(shifted, bits, "code_point").unwrap();
+    ctx.builder
+        .build_store(code_point_addr, code_point)
+        .unwrap();
+    let remaining = ctx
+        .builder
+        .build_int_sub(remaining, i32_type.const_int(1, false), "remaining")
+        .unwrap();
+    ctx.builder.build_store(remaining_addr, remaining).unwrap();
+    ctx.builder
+        .build_unconditional_branch(continuation_block)
+        .unwrap();
+
+    ctx.builder.position_at_end(put_back_block);
+    build_ungetc(ctx, byte);
+    ctx.builder.build_unconditional_branch(bad_block).unwrap();
+
+    // Overlong encodings, surrogates and code points past U+10FFFF are bad
+    ctx.builder.position_at_end(validate_block);
+    let code_point = ctx
+        .builder
+        .build_load(i32_type, code_point_addr, "code_point")
+        .unwrap()
+        .into_int_value();
+    let min = ctx
+        .builder
+        .build_load(i32_type, min_addr, "min")
+        .unwrap()
+        .into_int_value();
+    let is_overlong = ctx
+        .builder
+        .build_int_compare(IntPredicate::ULT, code_point, min, "is_overlong")
+        .unwrap();
+    let surrogate_offset = ctx
+        .builder
+        .build_int_sub(
+            code_point,
+            i32_type.const_int(0xd800, false),
+            "surrogate_offset",
+        )
+        .unwrap();
+    let is_surrogate = ctx
+        .builder
+        .build_int_compare(
+            IntPredicate::ULT,
+            surrogate_offset,
+            i32_type.const_int(0x800, false),
+            "is_surrogate",
+        )
+        .unwrap();
+    let is_too_large = ctx
+        .builder
+        .build_int_compare(
+            IntPredicate::UGT,
+            code_point,
+            i32_type.const_int(0x10ffff, false),
+            "is_too_large",
+        )
+        .unwrap();
+    let is_bad = ctx
+        .builder
+        .build_or(is_overlong, is_surrogate, "is_bad")
+        .unwrap();
+    let is_bad = ctx
+        .builder
+        .build_or(is_bad, is_too_large, "is_bad")
+        .unwrap();
+    ctx.builder
+        .build_conditional_branch(is_bad, bad_block, value_block)
+        .unwrap();
+
+    ctx.builder.position_at_end(value_block);
+    let value = ctx
+        .builder
+        .build_int_z_extend(code_point, i64_type, "value")
+        .unwrap();
+    ctx.builder.build_store(out, value).unwrap();
+    build_ret_status(ctx, READ_VALUE);
+
+    ctx.builder.position_at_end(eof_block);
+    build_ret_status(ctx, READ_EOF);
+
+    ctx.builder.position_at_end(bad_block);
+    build_ret_status(ctx, READ_BAD);
+}
@@ -1,6 +1,130 @@
+use crate::builder::build_error_block;
 use crate::lowering_ctx::LoweringCtx;
-use inkwell::IntPredicate;
+use inkwell::{values::IntValue, IntPredicate};
 use piet_core::instruction::Instruction;
+use piet_core::settings::DivisionSemantics;
+
+// `b / a` or `b mod a` for a nonzero `a`, following the division semantics being compiled for.  `sdiv` and
+// `srem` are undefined for i64::MIN / -1, so a divisor of -1 never reaches them.
+fn build_division<'a, 'b>(
+    ctx: &LoweringCtx<'a, 'b>,
+    instr: Instruction,
+    b: IntValue<'b>,
+    a: IntValue<'b>,
+) -> IntValue<'b> {
+    let i64_type = ctx.llvm_context.i64_type();
+    let const_0 = i64_type.const_zero();
+    let const_1 = i64_type.const_int(1, false);
+    let const_minus_1 = i64_type.const_all_ones();
+    let select = |cond, then: IntValue<'b>, otherwise: IntValue<'b>, name| {
+        ctx.builder
+            .build_select(cond, then, otherwise, name)
+            .unwrap()
+            .into_int_value()
+    };
+
+    let divisor_minus_1 = ctx
+        .builder
+        .build_int_compare(IntPredicate::EQ, a, const_minus_1, "divisor_minus_1")
+        .unwrap();
+    let safe_divisor = select(divisor_minus_1, const_1, a, "safe_divisor");
+
+    // Dividing by -1 negates, wrapping i64::MIN around to itself, and never leaves a remainder
+    let quot = ctx
+        .builder
+        .build_int_signed_div(b, safe_divisor, "quot")
+        .unwrap();
+    let negated = ctx.builder.build_int_sub(const_0, b, "negated").unwrap();
+    let quot = select(divisor_minus_1, negated, quot, "trunc_quot");
+    let rem = ctx
+        .builder
+        .build_int_signed_rem(b, safe_divisor, "trunc_rem")
+        .unwrap();
+
+    let rem_negative = ctx
+        .builder
+        .build_int_compare(IntPredicate::SLT, rem, const_0, "rem_negative")
+        .unwrap();
+    let divisor_negative = ctx
+        .builder
+        .build_int_compare(IntPredicate::SLT, a, const_0, "divisor_negative")
+        .unwrap();
+
+    match (ctx.settings.division, instr) {
+        (DivisionSemantics::Npiet, Instruction::Div) => quot,
+        (DivisionSemantics::Npiet, _) => {
+            // A nonzero remainder on the other side of zero from the divisor is moved over by the divisor
+            let rem_nonzero = ctx
+                .builder
+                .build_int_compare(IntPredicate::NE, rem, const_0, "rem_nonzero")
+                .unwrap();
+            let signs_differ = ctx
+                .builder
+                .build_xor(rem_negative, divisor_negative, "signs_differ")
+                .unwrap();
+            let adjust = ctx
+                .builder
+                .build_and(rem_nonzero, signs_differ, "adjust_rem")
+                .unwrap();
+            let adjusted = ctx.builder.build_int_add(rem, a, "adjusted_rem").unwrap();
+            select(adjust, adjusted, rem, "rem")
+        }
+        (DivisionSemantics::Euclid, Instruction::Div) => {
+            // A negative remainder is made positive by rounding the quotient away from the divisor's sign
+            let step = select(divisor_negative, const_minus_1, const_1, "quot_step");
+            let adjusted = ctx
+                .builder
+                .build_int_sub(quot, step, "adjusted_quot")
+                .unwrap();
+            select(rem_negative, adjusted, quot, "quot")
+        }
+        (DivisionSemantics::Euclid, _) => {
+            let negated_divisor = ctx
+                .builder
+                .build_int_sub(const_0, a, "negated_divisor")
+                .unwrap();
+            let abs_divisor = select(divisor_negative, negated_divisor, a, "abs_divisor");
+            let adjusted = ctx
+                .builder
+                .build_int_add(rem, abs_divisor, "adjusted_rem")
+                .unwrap();
+            select(rem_negative, adjusted, rem, "rem")
+        }
+    }
+}
+
+/// `b op a` for the binary operation `instr`, where `a` is the top of the stack.  The divisor of a `Div` or
+/// `Mod` must be nonzero.
+pub(crate) fn build_binop<'a, 'b>(
+    ctx: &LoweringCtx<'a, 'b>,
+    instr: Instruction,
+    b: IntValue<'b>,
+    a: IntValue<'b>,
+) -> IntValue<'b> {
+    match instr {
+        Instruction::Add => ctx.builder.build_int_add(b, a, "add").unwrap(),
+        Instruction::Sub => ctx.builder.build_int_sub(b, a, "sub").unwrap(),
+        Instruction::Mul => ctx.builder.build_int_mul(b, a, "mul").unwrap(),
+        Instruction::Div | Instruction::Mod => build_division(ctx, instr, b, a),
+        Instruction::Gt => {
+            // 1 if second top > top otherwise 0
+            let diff = ctx.builder.build_int_sub(b, a, "sub").unwrap();
+            let value_cmp = ctx
+                .builder
+                .build_int_compare(
+                    IntPredicate::SGT,
+                    diff,
+                    ctx.llvm_context.i64_type().const_zero(),
+                    "check_next_gt_top",
+                )
+                .unwrap();
+            ctx.builder
+                .build_int_z_extend(value_cmp, ctx.llvm_context.i64_type(), "zero_extend_cmp")
+                .unwrap()
+        }
+        _ => panic!("Not a binary operation!"),
+    }
+}
 
 pub(crate) fn build_binops<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, instr: Instruction) {
     let binop_fn = match instr {
@@ -21,9 +145,7 @@ pub(crate) fn build_binops<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, instr: Instruction
     let dividend_nonzero = ctx
         .llvm_context
         .append_basic_block(binop_fn, "dividend_nonzero");
-    let then_block = ctx.llvm_context.append_basic_block(binop_fn, "");
-    let else_block = ctx.llvm_context.append_basic_block(binop_fn, "");
-    let ret_block = ctx.llvm_context.insert_basic_block_after(else_block, "ret");
+    let ret_block = ctx.llvm_context.append_basic_block(binop_fn, "ret");
 
     let stack_addr = ctx
         .module
@@ -63,8 +185,9 @@ pub(crate) fn build_binops<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, instr: Instruction
         )
         .unwrap();
 
+    let underflow_block = build_error_block(ctx, binop_fn, instr, "stack underflow", ret_block);
     ctx.builder
-        .build_conditional_branch(cmp, cont_block, ret_block)
+        .build_conditional_branch(cmp, cont_block, underflow_block)
         .unwrap();
 
     // Enough elems on stack
@@ -113,135 +236,25 @@ pub(crate) fn build_binops<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, instr: Instruction
         .unwrap()
         .into_int_value();
 
-    let result = match instr {
-        Instruction::Add => {
-            unsafe { then_block.delete().ok() };
-            unsafe { else_block.delete().ok() };
-            unsafe { dividend_nonzero.delete().ok() };
-            ctx.builder
-                .build_int_add(next_ptr_val, top_ptr_val, "add")
-                .unwrap()
-        }
-        Instruction::Sub => {
-            unsafe { then_block.delete().ok() };
-            unsafe { else_block.delete().ok() };
-            unsafe { dividend_nonzero.delete().ok() };
-            ctx.builder
-                .build_int_sub(next_ptr_val, top_ptr_val, "sub")
-                .unwrap()
-        }
-        Instruction::Mul => {
-            unsafe { then_block.delete().ok() };
-            unsafe { else_block.delete().ok() };
-            unsafe { dividend_nonzero.delete().ok() };
-            ctx.builder
-                .build_int_mul(next_ptr_val, top_ptr_val, "mul")
-                .unwrap()
-        }
-        Instruction::Div => {
-            let cmp = ctx.builder.build_int_compare(
-                IntPredicate::NE,
-                top_ptr_val,
-                const_0,
-                "check_dividend_nonzero",
-            );
-            ctx.builder
-                .build_conditional_branch(cmp.unwrap(), dividend_nonzero, ret_block)
-                .unwrap();
-
-            // Set names for blocks and delete unused
-            unsafe { then_block.delete().ok() };
-            unsafe { else_block.delete().ok() };
-
-            ctx.builder.position_at_end(dividend_nonzero);
-            ctx.builder
-                .build_int_signed_div(next_ptr_val, top_ptr_val, "div")
-                .unwrap()
-        }
-        Instruction::Mod => {
-            let i64_type = ctx.llvm_context.i64_type();
-
-            // Calculate the absolute value without a branch
-            let shift_amount = i64_type.const_int(63, false);
-            let a_asr = ctx
-                .builder
-                .build_right_shift(top_ptr_val, shift_amount, true, "arithmetic_right_shift")
-                .unwrap();
-            let a_xor = ctx.builder.build_xor(top_ptr_val, a_asr, "a_xor");
-            let top_ptr_val = ctx
-                .builder
-                .build_int_sub(a_xor.unwrap(), a_asr, "abs_a")
-                .unwrap();
-
-            let cmp = ctx.builder.build_int_compare(
-                IntPredicate::NE,
-                top_ptr_val,
-                const_0,
-                "check_dividend_nonzero",
-            );
-
-            ctx.builder
-                .build_conditional_branch(cmp.unwrap(), dividend_nonzero, ret_block)
-                .unwrap();
-
-            ctx.builder.position_at_end(dividend_nonzero);
-            let rem = ctx
-                .builder
-                .build_int_signed_rem(next_ptr_val, top_ptr_val, "mod")
-                .unwrap();
-
-            let store_rem_result = ctx
-                .builder
-                .build_alloca(ctx.llvm_context.i64_type(), "rem_result")
-                .unwrap();
-            ctx.builder.build_store(store_rem_result, rem).unwrap();
-
-            let cmp =
-                ctx.builder
-                    .build_int_compare(IntPredicate::SGE, rem, const_0, "check_mod_sign");
-
-            then_block.set_name("lz");
-            else_block.set_name("gez");
-
-            ctx.builder
-                .build_conditional_branch(cmp.unwrap(), else_block, then_block)
-                .unwrap();
-
-            ctx.builder.position_at_end(then_block);
-            let rem_lz = ctx.builder.build_int_add(top_ptr_val, rem, "rem_lz");
-            ctx.builder
-                .build_store(store_rem_result, rem_lz.unwrap())
-                .unwrap();
-            ctx.builder.build_unconditional_branch(else_block).unwrap();
-            ctx.builder.position_at_end(else_block);
-            ctx.builder
-                .build_load(ctx.llvm_context.i64_type(), store_rem_result, "load_result")
-                .unwrap()
-                .into_int_value()
-        }
-        Instruction::Gt => {
-            // Pushes 1 to stack if second top > top otherwise 0
-            unsafe { then_block.delete().ok() };
-            unsafe { else_block.delete().ok() };
-            unsafe { dividend_nonzero.delete().ok() };
-
-            let diff = ctx.builder.build_int_sub(next_ptr_val, top_ptr_val, "sub");
-            let value_cmp = ctx.builder.build_int_compare(
-                IntPredicate::SGT,
-                diff.unwrap(),
-                const_0,
-                "check_next_gt_top",
-            );
-            let res = ctx.builder.build_int_z_extend(
-                value_cmp.unwrap(),
-                ctx.llvm_context.i64_type(),
-                "zero_extend_cmp",
-            );
-
-            res.unwrap()
-        }
-        _ => panic!("Not a binary operation!"),
-    };
+    if let Instruction::Div | Instruction::Mod = instr {
+        // A zero divisor leaves the stack as it was
+        let cmp = ctx.builder.build_int_compare(
+            IntPredicate::NE,
+            top_ptr_val,
+            const_0,
+            "check_divisor_nonzero",
+        );
+        let zero_divisor_block =
+            build_error_block(ctx, binop_fn, instr, "division by zero", ret_block);
+        ctx.builder
+            .build_conditional_branch(cmp.unwrap(), dividend_nonzero, zero_divisor_block)
+            .unwrap();
+
+        ctx.builder.position_at_end(dividend_nonzero);
+    } else {
+        unsafe { dividend_nonzero.delete().ok() };
+    }
+    let result = build_binop(ctx, instr, next_ptr_val, top_ptr_val);
 
     let updated_stack_size =
         ctx.builder
@@ -1,3 +1,4 @@
+use crate::builder::build_error_block;
 use crate::lowering_ctx::LoweringCtx;
 use inkwell::{
     values::{BasicValue, PointerValue},
@@ -53,8 +54,10 @@ pub(crate) fn build_dup<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
         )
         .unwrap();
 
+    let underflow_block =
+        build_error_block(ctx, dup_fn, Instruction::Dup, "stack underflow", ret_block);
     ctx.builder
-        .build_conditional_branch(stack_size_cmp, then_block, ret_block);
+        .build_conditional_branch(stack_size_cmp, then_block, underflow_block);
     ctx.builder.position_at_end(then_block);
 
     let top_idx = ctx
@@ -1,3 +1,4 @@
+use crate::builder::build_error_block;
 use crate::lowering_ctx::LoweringCtx;
 use inkwell::IntPredicate;
 use piet_core::instruction::Instruction;
@@ -56,8 +57,15 @@ pub(crate) fn build_switch<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
         )
         .unwrap();
 
+    let underflow_block = build_error_block(
+        ctx,
+        switch_fn,
+        Instruction::Swi,
+        "stack underflow",
+        ret_block,
+    );
     ctx.builder
-        .build_conditional_branch(stack_size_cmp, then_block, ret_block)
+        .build_conditional_branch(stack_size_cmp, then_block, underflow_block)
         .unwrap();
     ctx.builder.position_at_end(then_block);
 
@@ -191,8 +199,15 @@ pub(crate) fn build_rotate<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
         )
         .unwrap();
 
+    let underflow_block = build_error_block(
+        ctx,
+        rotate_fn,
+        Instruction::Ptr,
+        "stack underflow",
+        ret_block,
+    );
     ctx.builder
-        .build_conditional_branch(stack_size_cmp, then_block, ret_block)
+        .build_conditional_branch(stack_size_cmp, then_block, underflow_block)
         .unwrap();
     ctx.builder.position_at_end(then_block);
 
@@ -1,6 +1,7 @@
 use inkwell::IntPredicate;
 use piet_core::instruction::Instruction;
 
+use crate::builder::build_error_block;
 use crate::lowering_ctx::LoweringCtx;
 
 #[allow(unused)]
@@ -39,8 +40,10 @@ pub(crate) fn build_pop<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
         )
         .unwrap();
 
+    let underflow_block =
+        build_error_block(ctx, pop_fn, Instruction::Pop, "stack underflow", ret_block);
     ctx.builder
-        .build_conditional_branch(cmp, then_block, ret_block);
+        .build_conditional_branch(cmp, then_block, underflow_block);
 
     ctx.builder.position_at_end(then_block);
 
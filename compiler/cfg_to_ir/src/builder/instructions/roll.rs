@@ -5,6 +5,7 @@ use inkwell::{
 };
 use piet_core::instruction::Instruction;
 
+use crate::builder::build_error_block;
 use crate::lowering_ctx::LoweringCtx;
 pub(crate) fn build_roll<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
     // Roll function type
@@ -77,8 +78,10 @@ pub(crate) fn build_roll<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
         .builder
         .build_int_compare(IntPredicate::SLT, stack_size_val, const_2, "")
         .unwrap();
+    let underflow_block =
+        build_error_block(ctx, roll_fn, Instruction::Roll, "stack underflow", block_55);
     ctx.builder
-        .build_conditional_branch(cmp, block_55, block_3)
+        .build_conditional_branch(cmp, underflow_block, block_3)
         .unwrap();
 
     // Block 3
@@ -148,8 +151,9 @@ pub(crate) fn build_roll<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
         .build_int_compare(IntPredicate::SLT, next_elem_val, const_0, "")
         .unwrap();
     let or = ctx.builder.build_or(left, right, "").unwrap();
+    // A depth out of range still pops both arguments
     ctx.builder
-        .build_conditional_branch(or, block_55, block_14)
+        .build_conditional_branch(or, underflow_block, block_14)
         .unwrap();
 
     // Block 14
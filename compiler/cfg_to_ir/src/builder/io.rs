@@ -1,25 +1,38 @@
+use crate::builder::{build_error_block, global_string, READ_BAD, READ_EOF};
 use crate::lowering_ctx::LoweringCtx;
 use inkwell::{
-    values::{AnyValue, BasicValue, IntValue},
+    basic_block::BasicBlock,
+    values::{AnyValue, BasicValue, FunctionValue, IntValue, PointerValue},
     IntPredicate,
 };
-use piet_core::instruction::Instruction;
+use piet_core::{
+    instruction::Instruction,
+    settings::{InputPolicy, InvalidCharPolicy},
+    state::EXIT_INPUT_FAILED,
+};
 
+/// Builds `piet_charin` and `piet_intin`, which prompt for input and push what's read.  At EOF or on bad
+/// input they do what the `InputSettings` policy says.
 #[allow(unused)]
 pub(crate) fn build_input<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, instr: Instruction) {
-    let in_fn = match instr {
-        Instruction::IntIn => ctx
-            .module
-            .get_function(Instruction::IntIn.to_llvm_name())
-            .unwrap(),
-        Instruction::CharIn => ctx
-            .module
-            .get_function(Instruction::CharIn.to_llvm_name())
-            .unwrap(),
+    let (in_fn, read_fn) = match instr {
+        Instruction::IntIn => (
+            ctx.module
+                .get_function(Instruction::IntIn.to_llvm_name())
+                .unwrap(),
+            ctx.module.get_function("piet_read_int").unwrap(),
+        ),
+        Instruction::CharIn => (
+            ctx.module
+                .get_function(Instruction::CharIn.to_llvm_name())
+                .unwrap(),
+            ctx.module.get_function("piet_read_char").unwrap(),
+        ),
         _ => panic!("Not an input instruction!"),
     };
 
     let printf_fn = ctx.module.get_function("printf").unwrap();
+    let i32_type = ctx.llvm_context.i32_type();
 
     // Consts
     let const_0 = ctx.llvm_context.i64_type().const_zero();
@@ -27,6 +40,7 @@ pub(crate) fn build_input<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, instr: Instruction)
 
     // Labels
     let basic_block = ctx.llvm_context.append_basic_block(in_fn, "");
+    let push_block = ctx.llvm_context.append_basic_block(in_fn, "push");
     ctx.builder.position_at_end(basic_block);
 
     // Local variable to store our input
@@ -35,21 +49,6 @@ pub(crate) fn build_input<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, instr: Instruction)
         .build_alloca(ctx.llvm_context.i64_type(), "stack_alloc")
         .unwrap();
 
-    // The stack may not necessarily be zero'd out, which may cause problems when printing
-    // Since %c only reads in at one-byte boundaries, if the higher bits of our value are nonzero
-    // Then printf("%c", val) could print garbage and we would like this not to happen
-    ctx.builder.build_store(read_addr, const_0).unwrap();
-
-    let fmt = match instr {
-        Instruction::IntIn => ctx.module.get_global("dec_fmt").unwrap().as_pointer_value(),
-        Instruction::CharIn => ctx
-            .module
-            .get_global("char_fmt")
-            .unwrap()
-            .as_pointer_value(),
-        _ => panic!("Not an input instruction"),
-    };
-
     let input_message_fmt = match instr {
         Instruction::IntIn => ctx
             .module
@@ -79,36 +78,40 @@ pub(crate) fn build_input<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, instr: Instruction)
         .build_call(printf_fn, &[input_message_fmt_gep.into()], "")
         .unwrap();
 
-    // %ld or %c
-    let const_fmt_gep = unsafe {
-        ctx.builder
-            .build_gep(fmt.get_type(), fmt, &[const_0, const_0], "")
-            .unwrap()
-    };
-
-    // Build scanf call
-    // scanf reads into our local variable, so we need to load it next
-    let scanf_fn = ctx.module.get_function("scanf").unwrap();
-    let scanf = ctx
+    // Read into our local variable, then decide what to do from the status
+    let status = ctx
         .builder
-        .build_call(scanf_fn, &[const_fmt_gep.into(), read_addr.into()], "scanf")
-        .unwrap();
-
-    // Loads local var and sets alignment
-    let load_scanf_elem = ctx
-        .builder
-        .build_load(ctx.llvm_context.i64_type(), read_addr, "scanf_elem")
+        .build_call(read_fn, &[read_addr.into()], "status")
         .unwrap()
-        .as_instruction_value()
+        .try_as_basic_value()
+        .unwrap_basic()
+        .into_int_value();
+    let eof_block =
+        build_input_policy_block(ctx, in_fn, read_addr, push_block, ctx.settings.input.on_eof);
+    let bad_block = build_input_policy_block(
+        ctx,
+        in_fn,
+        read_addr,
+        push_block,
+        ctx.settings.input.on_bad_input,
+    );
+    ctx.builder
+        .build_switch(
+            status,
+            push_block,
+            &[
+                (i32_type.const_int(READ_EOF, false), eof_block),
+                (i32_type.const_int(READ_BAD, false), bad_block),
+            ],
+        )
         .unwrap();
 
-    load_scanf_elem.set_alignment(8);
-
-    let x: IntValue = load_scanf_elem.try_into().unwrap();
+    ctx.builder.position_at_end(push_block);
     let result = ctx
         .builder
-        .build_int_s_extend(x, ctx.llvm_context.i64_type(), "sext_to_i64")
-        .unwrap();
+        .build_load(ctx.llvm_context.i64_type(), read_addr, "read_elem")
+        .unwrap()
+        .into_int_value();
 
     // &stack_size
     let stack_size_addr = ctx
@@ -175,6 +178,50 @@ pub(crate) fn build_input<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, instr: Instruction)
     store.set_alignment(8).ok();
     ctx.builder.build_return(None).unwrap();
 }
+
+// A block that carries out `policy` for an input instruction that couldn't read a value: storing the value
+// to push in `read_addr` and going on to `push_block`, returning without pushing, or ending the program
+fn build_input_policy_block<'a, 'b>(
+    ctx: &LoweringCtx<'a, 'b>,
+    in_fn: FunctionValue<'b>,
+    read_addr: PointerValue<'b>,
+    push_block: BasicBlock<'b>,
+    policy: InputPolicy,
+) -> BasicBlock<'b> {
+    let insert_block = ctx.builder.get_insert_block();
+    let policy_block = ctx.llvm_context.append_basic_block(in_fn, "no_input");
+    ctx.builder.position_at_end(policy_block);
+
+    match (policy, policy.value()) {
+        (_, Some(value)) => {
+            let value = ctx.llvm_context.i64_type().const_int(value as u64, true);
+            ctx.builder.build_store(read_addr, value).unwrap();
+            ctx.builder.build_unconditional_branch(push_block).unwrap();
+        }
+        (InputPolicy::Terminate, None) => {
+            let print_stack_fn = ctx.module.get_function("print_piet_stack").unwrap();
+            let exit_fn = ctx.module.get_function("exit").unwrap();
+            ctx.builder.build_call(print_stack_fn, &[], "").unwrap();
+            let exit_code = ctx
+                .llvm_context
+                .i64_type()
+                .const_int(EXIT_INPUT_FAILED as u64, false);
+            ctx.builder
+                .build_call(exit_fn, &[exit_code.into()], "")
+                .unwrap();
+            ctx.builder.build_unreachable().unwrap();
+        }
+        (_, None) => {
+            ctx.builder.build_return(None).unwrap();
+        }
+    }
+
+    if let Some(insert_block) = insert_block {
+        ctx.builder.position_at_end(insert_block);
+    }
+    policy_block
+}
+
 pub(crate) fn build_output<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, instr: Instruction) {
     let out_fn = match instr {
         Instruction::IntOut => ctx.module.get_function("piet_intout").unwrap(),
@@ -223,8 +270,9 @@ pub(crate) fn build_output<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, instr: Instruction
         )
         .unwrap();
 
+    let underflow_block = build_error_block(ctx, out_fn, instr, "stack underflow", ret_block);
     ctx.builder
-        .build_conditional_branch(stack_size_cmp, then_block, ret_block)
+        .build_conditional_branch(stack_size_cmp, then_block, underflow_block)
         .unwrap();
     ctx.builder.position_at_end(then_block);
 
@@ -253,16 +301,6 @@ pub(crate) fn build_output<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, instr: Instruction
     };
     let printf_fn = ctx.module.get_function("printf").unwrap();
 
-    let fmt = match instr {
-        Instruction::IntOut => ctx.module.get_global("dec_fmt").unwrap().as_pointer_value(),
-        Instruction::CharOut => ctx
-            .module
-            .get_global("char_fmt")
-            .unwrap()
-            .as_pointer_value(),
-        _ => panic!("Not an output instruction"),
-    };
-
     let top_ptr_load_instr = ctx
         .builder
         .build_load(ctx.llvm_context.i64_type(), top_ptr_gep, "top_elem_val")
@@ -274,18 +312,28 @@ pub(crate) fn build_output<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, instr: Instruction
 
     let top_ptr_val = top_ptr_load_instr.as_any_value_enum().into_int_value();
 
-    let const_fmt_gep = unsafe {
-        ctx.builder
-            .build_gep(fmt.get_type(), fmt, &[const_0, const_0], "")
-            .unwrap()
-    };
-
-    let _printf = ctx.builder.build_call(
-        printf_fn,
-        &[const_fmt_gep.into(), top_ptr_val.into()],
-        "printf",
-    );
+    let pop_block = ctx.llvm_context.append_basic_block(out_fn, "pop");
+    match instr {
+        Instruction::IntOut => {
+            let fmt = ctx.module.get_global("dec_fmt").unwrap().as_pointer_value();
+            let const_fmt_gep = unsafe {
+                ctx.builder
+                    .build_gep(fmt.get_type(), fmt, &[const_0, const_0], "")
+                    .unwrap()
+            };
+
+            let _printf = ctx.builder.build_call(
+                printf_fn,
+                &[const_fmt_gep.into(), top_ptr_val.into()],
+                "printf",
+            );
+            ctx.builder.build_unconditional_branch(pop_block).unwrap();
+        }
+        Instruction::CharOut => build_char_output(ctx, out_fn, top_ptr_val, pop_block),
+        _ => panic!("Not an output instruction"),
+    }
 
+    ctx.builder.position_at_end(pop_block);
     let updated_stack_size = ctx
         .builder
         .build_int_sub(stack_size_val, const_1, "decrement_stack_size")
@@ -302,3 +350,167 @@ pub(crate) fn build_output<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, instr: Instruction
     ctx.builder.position_at_end(ret_block);
     ctx.builder.build_return(None).unwrap();
 }
+
+// Writes `value` as UTF-8 if it's a character, and otherwise does what the `InvalidCharPolicy` says, going
+// on to `pop_block` either way
+fn build_char_output<'a, 'b>(
+    ctx: &LoweringCtx<'a, 'b>,
+    out_fn: FunctionValue<'b>,
+    value: IntValue<'b>,
+    pop_block: BasicBlock<'b>,
+) {
+    let i64_type = ctx.llvm_context.i64_type();
+    let write_fn = ctx.module.get_function("piet_write_utf8").unwrap();
+
+    let invalid_block = match ctx.settings.invalid_char {
+        InvalidCharPolicy::Skip => pop_block,
+        InvalidCharPolicy::Replace => {
+            let insert_block = ctx.builder.get_insert_block().unwrap();
+            let replace_block = ctx.llvm_context.append_basic_block(out_fn, "replace");
+            ctx.builder.position_at_end(replace_block);
+            let replacement = i64_type.const_int(char::REPLACEMENT_CHARACTER as u64, false);
+            ctx.builder
+                .build_call(write_fn, &[replacement.into()], "")
+                .unwrap();
+            ctx.builder.build_unconditional_branch(pop_block).unwrap();
+            ctx.builder.position_at_end(insert_block);
+            replace_block
+        }
+        InvalidCharPolicy::Error => build_error_block(
+            ctx,
+            out_fn,
+            Instruction::CharOut,
+            "invalid character",
+            pop_block,
+        ),
+    };
+
+    // Negative values compare as too large, and surrogates are D800 to DFFF
+    let in_range = ctx
+        .builder
+        .build_int_compare(
+            IntPredicate::ULE,
+            value,
+            i64_type.const_int(0x10ffff, false),
+            "in_range",
+        )
+        .unwrap();
+    let surrogate_offset = ctx
+        .builder
+        .build_int_sub(value, i64_type.const_int(0xd800, false), "surrogate_offset")
+        .unwrap();
+    let not_surrogate = ctx
+        .builder
+        .build_int_compare(
+            IntPredicate::UGE,
+            surrogate_offset,
+            i64_type.const_int(0x800, false),
+            "not_surrogate",
+        )
+        .unwrap();
+    let is_char = ctx
+        .builder
+        .build_and(in_range, not_surrogate, "is_char")
+        .unwrap();
+
+    let write_block = ctx.llvm_context.append_basic_block(out_fn, "write");
+    ctx.builder
+        .build_conditional_branch(is_char, write_block, invalid_block)
+        .unwrap();
+    ctx.builder.position_at_end(write_block);
+    ctx.builder
+        .build_call(write_fn, &[value.into()], "")
+        .unwrap();
+    ctx.builder.build_unconditional_branch(pop_block).unwrap();
+}
+
+/// Builds `piet_write_utf8(code_point)`, which writes a character to stdout encoded as UTF-8
+pub(crate) fn build_write_utf8<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
+    let i32_type = ctx.llvm_context.i32_type();
+    let i64_type = ctx.llvm_context.i64_type();
+    let printf_fn = ctx.module.get_function("printf").unwrap();
+    let write_fn = ctx.module.add_function(
+        "piet_write_utf8",
+        ctx.llvm_context
+            .void_type()
+            .fn_type(&[i64_type.into()], false),
+        None,
+    );
+
+    let entry = ctx.llvm_context.append_basic_block(write_fn, "");
+    ctx.builder.position_at_end(entry);
+    let code_point = write_fn.get_nth_param(0).unwrap().into_int_value();
+    let code_point = ctx
+        .builder
+        .build_int_truncate(code_point, i32_type, "code_point")
+        .unwrap();
+
+    // Each length gets its own block, picked by the largest code point the length before it can hold
+    let lead_bits = [0x00, 0xc0, 0xe0, 0xf0];
+    let limits = [0x80, 0x800, 0x10000];
+    for len in 1..=4 {
+        let write_block = ctx
+            .llvm_context
+            .append_basic_block(write_fn, &format!("write_{len}"));
+        if let Some(&limit) = limits.get(len - 1) {
+            let next_block = ctx.llvm_context.append_basic_block(write_fn, "");
+            let fits = ctx
+                .builder
+                .build_int_compare(
+                    IntPredicate::ULT,
+                    code_point,
+                    i32_type.const_int(limit, false),
+                    "fits",
+                )
+                .unwrap();
+            ctx.builder
+                .build_conditional_branch(fits, write_block, next_block)
+                .unwrap();
+            ctx.builder.position_at_end(write_block);
+            build_write_bytes(ctx, printf_fn, code_point, len, lead_bits[len - 1]);
+            ctx.builder.position_at_end(next_block);
+        } else {
+            ctx.builder.build_unconditional_branch(write_block).unwrap();
+            ctx.builder.position_at_end(write_block);
+            build_write_bytes(ctx, printf_fn, code_point, len, lead_bits[len - 1]);
+        }
+    }
+}
+
+// Writes `code_point` as a `len` byte sequence whose lead byte starts with `lead_bits`
+fn build_write_bytes<'a, 'b>(
+    ctx: &LoweringCtx<'a, 'b>,
+    printf_fn: FunctionValue<'b>,
+    code_point: IntValue<'b>,
+    len: usize,
+    lead_bits: u64,
+) {
+    let i32_type = ctx.llvm_context.i32_type();
+    let fmt = global_string(ctx, &"%c".repeat(len), &format!("utf8_fmt_{len}"));
+
+    let mut args = vec![fmt.into()];
+    for i in 0..len {
+        let shift = 6 * (len - 1 - i) as u64;
+        let bits = ctx
+            .builder
+            .build_right_shift(code_point, i32_type.const_int(shift, false), false, "")
+            .unwrap();
+        // Shifting leaves only the lead byte's bits, while continuation bytes keep their low six
+        let (bits, tag) = match i {
+            0 => (bits, lead_bits),
+            _ => (
+                ctx.builder
+                    .build_and(bits, i32_type.const_int(0x3f, false), "")
+                    .unwrap(),
+                0x80,
+            ),
+        };
+        let byte = ctx
+            .builder
+            .build_or(bits, i32_type.const_int(tag, false), "byte")
+            .unwrap();
+        args.push(byte.into());
+    }
+    ctx.builder.build_call(printf_fn, &args, "").unwrap();
+    ctx.builder.build_return(None).unwrap();
+}
@@ -1,4 +1,4 @@
-use piet_core::state::ExecutionState;
+use piet_core::state::{ExecutionState, EXIT_COMPLETED};
 
 use crate::{consts::STACK_SIZE, lowering_ctx::LoweringCtx};
 
@@ -10,6 +10,14 @@ pub(crate) fn build_main(ctx: &LoweringCtx, execution_state: &ExecutionState) {
     let set_stdout_unbuffered_fn = ctx.module.get_function("set_stdout_unbuffered").unwrap();
     let init_block = ctx.llvm_context.append_basic_block(main_fn, "");
 
+    // main has no codels of its own, but it's described so that backtraces go through it, and so that
+    // start keeps its locations if it's inlined
+    if let Some(debug_info) = &ctx.debug_info {
+        let scope = debug_info.build_subprogram(main_fn);
+        ctx.builder
+            .set_current_debug_location(debug_info.location(ctx.llvm_context, scope, None));
+    }
+
     if let Some(init_globals_fn) = ctx.module.get_function("init_globals") {
         ctx.builder.position_at_end(init_block);
         ctx.builder
@@ -18,6 +26,21 @@ pub(crate) fn build_main(ctx: &LoweringCtx, execution_state: &ExecutionState) {
     }
     let malloc_fn = ctx.module.get_function("malloc").unwrap();
 
+    // Coverage counts are written out on exit, including when the program is terminated early
+    if let Some(dump_coverage_fn) = ctx.module.get_function("dump_coverage") {
+        let atexit_fn = ctx.module.get_function("atexit").unwrap();
+        ctx.builder
+            .build_call(
+                atexit_fn,
+                &[dump_coverage_fn
+                    .as_global_value()
+                    .as_pointer_value()
+                    .into()],
+                "register_dump_coverage",
+            )
+            .unwrap();
+    }
+
     let size_value = ctx
         .llvm_context
         .i64_type()
@@ -46,13 +69,23 @@ pub(crate) fn build_main(ctx: &LoweringCtx, execution_state: &ExecutionState) {
             .unwrap();
     }
 
+    // Residual code from partial evaluation runs before the generic transitions take over
+    if let Some(run_residual_fn) = ctx.module.get_function("run_residual") {
+        ctx.builder
+            .build_call(run_residual_fn, &[], "run_residual")
+            .unwrap();
+    }
+
     ctx.builder.build_call(start_fn, &[], "start").unwrap();
 
     ctx.builder
         .build_call(print_stack_fn, &[], "print_stack_fn")
         .unwrap();
 
-    ctx.builder
-        .build_return(Some(&ctx.llvm_context.i64_type().const_zero()))
-        .unwrap();
+    let exit_code = ctx
+        .llvm_context
+        .i64_type()
+        .const_int(EXIT_COMPLETED as u64, false);
+    ctx.builder.build_return(Some(&exit_code)).unwrap();
+    ctx.builder.unset_current_debug_location();
 }
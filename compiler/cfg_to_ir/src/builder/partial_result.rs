@@ -1,10 +1,12 @@
 use crate::{builder, lowering_ctx::LoweringCtx};
-use piet_core::{cfg::CFG, instruction::Instruction, state::ExecutionState};
+use piet_core::{cfg::CFG, instruction::Instruction, settings::ErrorPolicy, state::ExecutionState};
+use piet_optimizer::analysis::AbstractStates;
 
 pub(crate) fn build_partial<'a, 'b>(
     ctx: &LoweringCtx<'a, 'b>,
     cfg: &mut CFG,
     execution_state: &ExecutionState,
+    states: Option<&AbstractStates>,
 ) {
     // Initialize dp/cc with execution state
     builder::build_dp_cc(ctx, execution_state);
@@ -13,14 +15,19 @@ pub(crate) fn build_partial<'a, 'b>(
     builder::build_print_stack(ctx);
     builder::build_terminate(ctx);
     builder::build_stack_size_check(ctx);
+    if ctx.settings.error_policy != ErrorPolicy::Ignore {
+        builder::build_runtime_error(ctx);
+    }
     builder::build_binops(ctx, Instruction::Add);
     builder::build_binops(ctx, Instruction::Sub);
     builder::build_binops(ctx, Instruction::Div);
     builder::build_binops(ctx, Instruction::Mul);
     builder::build_binops(ctx, Instruction::Mod);
     builder::build_binops(ctx, Instruction::Gt);
+    builder::build_read_input(ctx);
     builder::build_input(ctx, Instruction::CharIn);
     builder::build_input(ctx, Instruction::IntIn);
+    builder::build_write_utf8(ctx);
     builder::build_output(ctx, Instruction::CharOut);
     builder::build_output(ctx, Instruction::IntOut);
     builder::build_roll(ctx);
@@ -31,7 +38,13 @@ pub(crate) fn build_partial<'a, 'b>(
     builder::build_switch(ctx);
     builder::build_rotate(ctx);
     builder::build_retry(ctx);
-    builder::build_transitions(ctx, &cfg, &execution_state.cb_label);
+    if ctx.settings.coverage {
+        builder::build_coverage(ctx, cfg);
+    }
+    if ctx.settings.trace {
+        builder::build_trace(ctx);
+    }
+    builder::build_transitions(ctx, &cfg, &execution_state.cb_label, states);
     builder::build_stack_io(ctx, execution_state);
     builder::build_main(ctx, execution_state);
 }
@@ -0,0 +1,53 @@
+use crate::{builder, lowering_ctx::LoweringCtx};
+use piet_core::{cfg::CFG, instruction::Instruction};
+use piet_optimizer::analysis::AbstractStates;
+use piet_optimizer::result::{ResidualOp, ResidualProgram};
+
+// Emits the residual code left over by partial evaluation as straight-line calls into the runtime, run by main
+// before control is handed to the generic transition code at the residual's resume state.
+pub(crate) fn build_residual<'a, 'b>(
+    ctx: &LoweringCtx<'a, 'b>,
+    cfg: &mut CFG,
+    residual: &ResidualProgram,
+    states: Option<&AbstractStates>,
+) {
+    let void_fn_type = ctx.llvm_context.void_type().fn_type(&[], false);
+    let residual_fn = ctx.module.add_function("run_residual", void_fn_type, None);
+
+    // The runtime is built first, so that output is written by the same function `CharOut` writes with
+    builder::build_partial(ctx, cfg, &residual.resume_state, states);
+    let write_fn = ctx.module.get_function("piet_write_utf8").unwrap();
+    let push_fn = ctx
+        .module
+        .get_function(Instruction::Push.to_llvm_name())
+        .unwrap();
+
+    let basic_block = ctx.llvm_context.append_basic_block(residual_fn, "");
+    ctx.builder.position_at_end(basic_block);
+
+    let i64_type = ctx.llvm_context.i64_type();
+    for op in &residual.ops {
+        match op {
+            ResidualOp::Push(val) => {
+                let val = i64_type.const_int(*val as u64, false);
+                ctx.builder
+                    .build_call(push_fn, &[val.into()], "residual_push")
+                    .unwrap();
+            }
+            ResidualOp::Exec(instr) => {
+                let instr_fn = ctx.module.get_function(instr.to_llvm_name()).unwrap();
+                ctx.builder.build_call(instr_fn, &[], "").unwrap();
+            }
+            // One character at a time, since the output may contain NUL
+            ResidualOp::Print(output) => {
+                for c in output.chars() {
+                    let code_point = i64_type.const_int(c as u64, false);
+                    ctx.builder
+                        .build_call(write_fn, &[code_point.into()], "")
+                        .unwrap();
+                }
+            }
+        }
+    }
+    ctx.builder.build_return(None).unwrap();
+}
@@ -0,0 +1,170 @@
+use crate::builder::declare_function;
+use crate::lowering_ctx::LoweringCtx;
+use inkwell::{
+    basic_block::BasicBlock,
+    module::Linkage,
+    values::{FunctionValue, PointerValue},
+    AddressSpace,
+};
+use piet_core::{instruction::Instruction, settings::ErrorPolicy, state::EXIT_ABORTED};
+
+/// The string constant `name`, created holding `value` the first time it's asked for
+pub(crate) fn global_string<'a, 'b>(
+    ctx: &LoweringCtx<'a, 'b>,
+    value: &str,
+    name: &str,
+) -> PointerValue<'b> {
+    match ctx.module.get_global(name) {
+        Some(global) => global.as_pointer_value(),
+        None => ctx
+            .builder
+            .build_global_string_ptr(value, name)
+            .unwrap()
+            .as_pointer_value(),
+    }
+}
+
+/// Declares `current_block` and `step_count`, which the transitions keep up to date, and builds
+/// `piet_error(kind, instr)` for instructions that can't be executed.  It prints the same
+/// `<warning|error>: <kind> on <instr> at step <n> in block <label>` line to stderr as the interpreter, and
+/// exits with `EXIT_ABORTED` under `ErrorPolicy::Abort`.
+pub(crate) fn build_runtime_error<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
+    let ptr_type = ctx.llvm_context.ptr_type(AddressSpace::default());
+    let i32_type = ctx.llvm_context.i32_type();
+    let i64_type = ctx.llvm_context.i64_type();
+    let void_type = ctx.llvm_context.void_type();
+
+    let current_block = ctx.module.add_global(ptr_type, None, "current_block");
+    current_block.set_linkage(Linkage::Internal);
+    current_block.set_initializer(&ptr_type.const_null());
+
+    let step_count = ctx.module.add_global(i64_type, None, "step_count");
+    step_count.set_linkage(Linkage::Internal);
+    step_count.set_initializer(&i64_type.const_zero());
+
+    // Written straight to stderr's file descriptor, since there's no portable way to name stderr's FILE
+    let dprintf_fn = declare_function(
+        ctx,
+        "dprintf",
+        i32_type.fn_type(&[i32_type.into(), ptr_type.into()], true),
+    );
+    let exit_fn = ctx.module.get_function("exit").unwrap();
+
+    let error_fn = ctx.module.add_function(
+        "piet_error",
+        void_type.fn_type(&[ptr_type.into(), ptr_type.into()], false),
+        None,
+    );
+    let basic_block = ctx.llvm_context.append_basic_block(error_fn, "");
+    ctx.builder.position_at_end(basic_block);
+
+    let severity = match ctx.settings.error_policy {
+        ErrorPolicy::Abort => "error",
+        _ => "warning",
+    };
+    let fmt = global_string(
+        ctx,
+        &format!("{severity}: %s on %s at step %ld in block %s\n"),
+        "runtime_error_fmt",
+    );
+    let block = ctx
+        .builder
+        .build_load(ptr_type, current_block.as_pointer_value(), "block")
+        .unwrap();
+    let steps = ctx
+        .builder
+        .build_load(i64_type, step_count.as_pointer_value(), "steps")
+        .unwrap();
+    let kind = error_fn.get_nth_param(0).unwrap();
+    let instr = error_fn.get_nth_param(1).unwrap();
+    ctx.builder
+        .build_call(
+            dprintf_fn,
+            &[
+                i32_type.const_int(2, false).into(),
+                fmt.into(),
+                kind.into(),
+                instr.into(),
+                steps.into(),
+                block.into(),
+            ],
+            "",
+        )
+        .unwrap();
+
+    if ctx.settings.error_policy == ErrorPolicy::Abort {
+        ctx.builder
+            .build_call(
+                exit_fn,
+                &[i64_type.const_int(EXIT_ABORTED as u64, false).into()],
+                "",
+            )
+            .unwrap();
+        ctx.builder.build_unreachable().unwrap();
+    } else {
+        ctx.builder.build_return(None).unwrap();
+    }
+}
+
+/// A block of `function` that reports a `kind` of error on `instr`, then carries on to `ret_block`.  When
+/// errors are ignored there's no `piet_error` to call, and this is just `ret_block`.  The builder is left
+/// where it was.
+pub(crate) fn build_error_block<'a, 'b>(
+    ctx: &LoweringCtx<'a, 'b>,
+    function: FunctionValue<'b>,
+    instr: Instruction,
+    kind: &str,
+    ret_block: BasicBlock<'b>,
+) -> BasicBlock<'b> {
+    let Some(error_fn) = ctx.module.get_function("piet_error") else {
+        return ret_block;
+    };
+    let insert_block = ctx.builder.get_insert_block();
+
+    let error_block = ctx.llvm_context.append_basic_block(function, "error");
+    ctx.builder.position_at_end(error_block);
+    let kind_str = global_string(ctx, kind, &format!("error_{}", kind.replace(' ', "_")));
+    let instr_str = global_string(
+        ctx,
+        instr.mnemonic(),
+        &format!("{}_mnemonic", instr.to_llvm_name()),
+    );
+    ctx.builder
+        .build_call(error_fn, &[kind_str.into(), instr_str.into()], "")
+        .unwrap();
+    ctx.builder.build_unconditional_branch(ret_block).unwrap();
+
+    if let Some(insert_block) = insert_block {
+        ctx.builder.position_at_end(insert_block);
+    }
+    error_block
+}
+
+/// Records that an instruction is about to be executed in the block labelled `label`, for `piet_error` to
+/// report.  Does nothing when errors are ignored.
+pub(crate) fn build_step<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, label: &str) {
+    let (Some(current_block), Some(step_count)) = (
+        ctx.module.get_global("current_block"),
+        ctx.module.get_global("step_count"),
+    ) else {
+        return;
+    };
+    let i64_type = ctx.llvm_context.i64_type();
+
+    let label_str = global_string(ctx, label, &format!("label_{label}"));
+    ctx.builder
+        .build_store(current_block.as_pointer_value(), label_str)
+        .unwrap();
+    let steps = ctx
+        .builder
+        .build_load(i64_type, step_count.as_pointer_value(), "load_steps")
+        .unwrap()
+        .into_int_value();
+    let incremented = ctx
+        .builder
+        .build_int_add(steps, i64_type.const_int(1, false), "increment_steps")
+        .unwrap();
+    ctx.builder
+        .build_store(step_count.as_pointer_value(), incremented)
+        .unwrap();
+}
@@ -1,5 +1,6 @@
 use crate::lowering_ctx::LoweringCtx;
 use inkwell::AddressSpace;
+use piet_core::settings::Platform;
 // ...
 
 pub(crate) fn build_stdout_unbuffered<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
@@ -9,6 +10,12 @@ pub(crate) fn build_stdout_unbuffered<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
     let basic_block = ctx
         .llvm_context
         .append_basic_block(set_stdout_unbuffered_fn, "");
+    // WebAssembly's writes go straight to the host
+    if ctx.settings.platform != Platform::Native {
+        ctx.builder.position_at_end(basic_block);
+        ctx.builder.build_return(None).ok();
+        return;
+    }
     // Constants
     let const_0 = ctx.llvm_context.i64_type().const_zero();
     let setvbuf_fn = ctx.module.get_function("setvbuf").unwrap();
@@ -1,4 +1,5 @@
 use crate::{consts::STACK_SIZE, lowering_ctx::LoweringCtx};
+use piet_core::state::EXIT_STACK_EXHAUSTED;
 
 // Terminates the program and prints the stack
 pub(crate) fn build_stack_size_check<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
@@ -60,7 +61,10 @@ pub(crate) fn build_terminate<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
     let printf_fn = ctx.module.get_function("printf").unwrap();
     // Constants
     let const_0 = ctx.llvm_context.i64_type().const_zero();
-    let const_1 = ctx.llvm_context.i64_type().const_int(1, false);
+    let exit_code = ctx
+        .llvm_context
+        .i64_type()
+        .const_int(EXIT_STACK_EXHAUSTED as u64, false);
 
     // Basic blocks
     let basic_block = ctx.llvm_context.append_basic_block(terminate_fn, "");
@@ -87,7 +91,7 @@ pub(crate) fn build_terminate<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
         .unwrap();
     ctx.builder.build_call(print_stack_fn, &[], "").unwrap();
     ctx.builder
-        .build_call(exit_fn, &[const_1.into()], "call_exit")
+        .build_call(exit_fn, &[exit_code.into()], "call_exit")
         .unwrap();
-    ctx.builder.build_return(Some(&const_1)).unwrap();
+    ctx.builder.build_return(Some(&exit_code)).unwrap();
 }
@@ -0,0 +1,190 @@
+use crate::builder::{declare_function, global_string};
+use crate::lowering_ctx::LoweringCtx;
+use inkwell::{module::Linkage, AddressSpace, IntPredicate};
+use piet_core::trace::EMPTY_STACK;
+
+/// Declares `trace_file` and builds `piet_trace(line)`, which writes a transition's `line`, made by
+/// `piet_core::trace::transition_line`, followed by the stack top.  The trace goes to the file named by
+/// `PIET_TRACE`, opened on the first call, or to stderr if that's unset or can't be opened.  It's line
+/// buffered, so a program that exits early still leaves a complete trace.
+pub(crate) fn build_trace<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
+    let ptr_type = ctx.llvm_context.ptr_type(AddressSpace::default());
+    let i32_type = ctx.llvm_context.i32_type();
+    let i64_type = ctx.llvm_context.i64_type();
+    let void_type = ctx.llvm_context.void_type();
+
+    let trace_file = ctx.module.add_global(ptr_type, None, "trace_file");
+    trace_file.set_linkage(Linkage::Internal);
+    trace_file.set_initializer(&ptr_type.const_null());
+
+    // extern C file io
+    let getenv_fn = declare_function(ctx, "getenv", ptr_type.fn_type(&[ptr_type.into()], false));
+    let fopen_fn = declare_function(
+        ctx,
+        "fopen",
+        ptr_type.fn_type(&[ptr_type.into(), ptr_type.into()], false),
+    );
+    let fprintf_fn = declare_function(
+        ctx,
+        "fprintf",
+        i32_type.fn_type(&[ptr_type.into(), ptr_type.into()], true),
+    );
+    let fdopen_fn = ctx.module.get_function("fdopen").unwrap();
+    let setvbuf_fn = ctx.module.get_function("setvbuf").unwrap();
+
+    let trace_fn = ctx.module.add_function(
+        "piet_trace",
+        void_type.fn_type(&[ptr_type.into()], false),
+        None,
+    );
+    let basic_block = ctx.llvm_context.append_basic_block(trace_fn, "");
+    let open_block = ctx.llvm_context.append_basic_block(trace_fn, "open_trace");
+    let fopen_block = ctx.llvm_context.append_basic_block(trace_fn, "fopen_trace");
+    let stderr_block = ctx.llvm_context.append_basic_block(trace_fn, "use_stderr");
+    let opened_block = ctx
+        .llvm_context
+        .append_basic_block(trace_fn, "opened_trace");
+    let write_block = ctx.llvm_context.append_basic_block(trace_fn, "write_trace");
+    let top_block = ctx.llvm_context.append_basic_block(trace_fn, "write_top");
+    let empty_block = ctx.llvm_context.append_basic_block(trace_fn, "write_empty");
+
+    ctx.builder.position_at_end(basic_block);
+    let file = ctx
+        .builder
+        .build_load(ptr_type, trace_file.as_pointer_value(), "file")
+        .unwrap()
+        .into_pointer_value();
+    let not_open = ctx.builder.build_is_null(file, "not_open").unwrap();
+    ctx.builder
+        .build_conditional_branch(not_open, open_block, write_block)
+        .unwrap();
+
+    ctx.builder.position_at_end(open_block);
+    let env_var = global_string(ctx, "PIET_TRACE", "trace_env_var");
+    let path = ctx
+        .builder
+        .build_call(getenv_fn, &[env_var.into()], "path")
+        .unwrap()
+        .try_as_basic_value()
+        .unwrap_basic()
+        .into_pointer_value();
+    let no_path = ctx.builder.build_is_null(path, "no_path").unwrap();
+    ctx.builder
+        .build_conditional_branch(no_path, stderr_block, fopen_block)
+        .unwrap();
+
+    ctx.builder.position_at_end(fopen_block);
+    let mode = global_string(ctx, "w", "trace_mode");
+    let opened = ctx
+        .builder
+        .build_call(fopen_fn, &[path.into(), mode.into()], "opened")
+        .unwrap()
+        .try_as_basic_value()
+        .unwrap_basic()
+        .into_pointer_value();
+    let not_opened = ctx.builder.build_is_null(opened, "not_opened").unwrap();
+    ctx.builder
+        .build_conditional_branch(not_opened, stderr_block, opened_block)
+        .unwrap();
+
+    ctx.builder.position_at_end(stderr_block);
+    let stderr = ctx
+        .builder
+        .build_call(
+            fdopen_fn,
+            &[i32_type.const_int(2, false).into(), mode.into()],
+            "stderr",
+        )
+        .unwrap()
+        .try_as_basic_value()
+        .unwrap_basic();
+    ctx.builder
+        .build_unconditional_branch(opened_block)
+        .unwrap();
+
+    ctx.builder.position_at_end(opened_block);
+    let opened_file = ctx.builder.build_phi(ptr_type, "opened_file").unwrap();
+    opened_file.add_incoming(&[(&opened, fopen_block), (&stderr, stderr_block)]);
+    let line_buffered = i32_type.const_int(1, false);
+    ctx.builder
+        .build_call(
+            setvbuf_fn,
+            &[
+                opened_file.as_basic_value().into(),
+                ptr_type.const_null().into(),
+                line_buffered.into(),
+                i64_type.const_zero().into(),
+            ],
+            "",
+        )
+        .unwrap();
+    ctx.builder
+        .build_store(trace_file.as_pointer_value(), opened_file.as_basic_value())
+        .unwrap();
+    ctx.builder.build_unconditional_branch(write_block).unwrap();
+
+    // The stack top, if there is one, is the last value below stack_size
+    ctx.builder.position_at_end(write_block);
+    let file = ctx
+        .builder
+        .build_load(ptr_type, trace_file.as_pointer_value(), "file")
+        .unwrap();
+    let stack_size_addr = ctx.module.get_global("stack_size").unwrap();
+    let stack_size = ctx
+        .builder
+        .build_load(i64_type, stack_size_addr.as_pointer_value(), "stack_size")
+        .unwrap()
+        .into_int_value();
+    let nonempty = ctx
+        .builder
+        .build_int_compare(
+            IntPredicate::SGT,
+            stack_size,
+            i64_type.const_zero(),
+            "nonempty",
+        )
+        .unwrap();
+    ctx.builder
+        .build_conditional_branch(nonempty, top_block, empty_block)
+        .unwrap();
+
+    let line = trace_fn.get_nth_param(0).unwrap();
+
+    ctx.builder.position_at_end(top_block);
+    let piet_stack = ctx.module.get_global("piet_stack").unwrap();
+    let stack = ctx
+        .builder
+        .build_load(ptr_type, piet_stack.as_pointer_value(), "stack")
+        .unwrap()
+        .into_pointer_value();
+    let top_idx = ctx
+        .builder
+        .build_int_sub(stack_size, i64_type.const_int(1, false), "top_idx")
+        .unwrap();
+    let top_ptr = unsafe {
+        ctx.builder
+            .build_gep(i64_type, stack, &[top_idx], "top_ptr")
+            .unwrap()
+    };
+    let top = ctx.builder.build_load(i64_type, top_ptr, "top").unwrap();
+    let top_fmt = global_string(ctx, "%s %ld\n", "trace_top_fmt");
+    ctx.builder
+        .build_call(
+            fprintf_fn,
+            &[file.into(), top_fmt.into(), line.into(), top.into()],
+            "",
+        )
+        .unwrap();
+    ctx.builder.build_return(None).unwrap();
+
+    ctx.builder.position_at_end(empty_block);
+    let empty_fmt = global_string(ctx, &format!("%s {EMPTY_STACK}\n"), "trace_empty_fmt");
+    ctx.builder
+        .build_call(
+            fprintf_fn,
+            &[file.into(), empty_fmt.into(), line.into()],
+            "",
+        )
+        .unwrap();
+    ctx.builder.build_return(None).unwrap();
+}
@@ -1,10 +1,135 @@
+use crate::builder::{build_binop, build_count, build_step, counter_indices, global_string};
 use crate::lowering_ctx::LoweringCtx;
-use inkwell::{basic_block::BasicBlock, values::AnyValue};
+use inkwell::values::{AnyValue, PointerValue};
+use inkwell::{basic_block::BasicBlock, IntPredicate};
 use piet_core::cfg::CFG;
+use piet_core::flow::PointerState;
 use piet_core::instruction::Instruction;
+use piet_core::trace::transition_line;
+use piet_optimizer::analysis::{AbstractStates, Certainty, TransitionFacts};
 use std::collections::HashMap;
 
-pub(crate) fn build_transitions<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, cfg: &CFG, entry_label: &str) {
+// Runs `instr` in place of its runtime function when the abstract interpreter proved the function's checks
+// unnecessary, returning whether it did.  A `Ptr` or `Swi` whose argument is constant stores the pointers it
+// leaves, which are known from the transition's.
+fn build_proven_instruction<'a, 'b>(
+    ctx: &LoweringCtx<'a, 'b>,
+    instr: Instruction,
+    facts: TransitionFacts,
+    pointers: PointerState,
+    (dp_addr, cc_addr): (PointerValue<'b>, PointerValue<'b>),
+) -> bool {
+    let proven = facts.underflow == Certainty::Never
+        && match instr {
+            Instruction::Pop | Instruction::Not => true,
+            Instruction::Add | Instruction::Sub | Instruction::Mul | Instruction::Gt => true,
+            Instruction::Div | Instruction::Mod => facts.zero_divisor == Certainty::Never,
+            Instruction::Ptr | Instruction::Swi => facts.pointer_arg.is_some(),
+            _ => false,
+        };
+    if !proven {
+        return false;
+    }
+
+    let i8_type = ctx.llvm_context.i8_type();
+    let i64_type = ctx.llvm_context.i64_type();
+    let stack_size_addr = ctx
+        .module
+        .get_global("stack_size")
+        .unwrap()
+        .as_pointer_value();
+    let stack_addr = ctx
+        .module
+        .get_global("piet_stack")
+        .unwrap()
+        .as_pointer_value();
+    let stack_size_val = ctx
+        .builder
+        .build_load(i64_type, stack_size_addr, "stack_size")
+        .unwrap()
+        .into_int_value();
+    let load_piet_stack = ctx
+        .builder
+        .build_load(stack_addr.get_type(), stack_addr, "load_piet_stack")
+        .unwrap()
+        .into_pointer_value();
+    let elem_ptr = |depth: u64, name| {
+        let idx = ctx
+            .builder
+            .build_int_sub(stack_size_val, i64_type.const_int(depth, false), name)
+            .unwrap();
+        unsafe {
+            ctx.builder
+                .build_gep(i64_type, load_piet_stack, &[idx], "")
+                .unwrap()
+        }
+    };
+    let load = |ptr, name| {
+        ctx.builder
+            .build_load(i64_type, ptr, name)
+            .unwrap()
+            .into_int_value()
+    };
+    let pop = || {
+        let decremented = ctx
+            .builder
+            .build_int_sub(
+                stack_size_val,
+                i64_type.const_int(1, false),
+                "decrement_stack_size",
+            )
+            .unwrap();
+        ctx.builder
+            .build_store(stack_size_addr, decremented)
+            .unwrap();
+    };
+
+    match (instr, facts.pointer_arg) {
+        (Instruction::Ptr | Instruction::Swi, Some(arg)) => {
+            pop();
+            let exit = match instr {
+                Instruction::Ptr => PointerState::new(pointers.dp.rotate(arg), pointers.cc),
+                _ => PointerState::new(pointers.dp, pointers.cc.switch(arg)),
+            };
+            let dp_as_const = i8_type.const_int(exit.dp as i8 as u64, false);
+            let cc_as_const = i8_type.const_int(exit.cc as i8 as u64, false);
+            ctx.builder.build_store(dp_addr, dp_as_const).unwrap();
+            ctx.builder.build_store(cc_addr, cc_as_const).unwrap();
+        }
+        (Instruction::Pop, _) => pop(),
+        (Instruction::Not, _) => {
+            let top_ptr = elem_ptr(1, "top_elem_idx");
+            let top_val = load(top_ptr, "top_elem_val");
+            let is_zero = ctx
+                .builder
+                .build_int_compare(IntPredicate::EQ, top_val, i64_type.const_zero(), "is_zero")
+                .unwrap();
+            let not = ctx
+                .builder
+                .build_int_z_extend(is_zero, i64_type, "not")
+                .unwrap();
+            ctx.builder.build_store(top_ptr, not).unwrap();
+        }
+        _ => {
+            let top_val = load(elem_ptr(1, "top_elem_idx"), "top_elem_val");
+            let next_ptr = elem_ptr(2, "next_elem_idx");
+            let next_val = load(next_ptr, "next_elem_val");
+            let result = build_binop(ctx, instr, next_val, top_val);
+            ctx.builder.build_store(next_ptr, result).unwrap();
+            pop();
+        }
+    }
+    true
+}
+
+/// Builds `start`, which runs the program from `entry_label` by jumping between the blocks' transitions.
+/// With `states`, instructions the abstract interpreter proved can't fail run without their checks.
+pub(crate) fn build_transitions<'a, 'b>(
+    ctx: &LoweringCtx<'a, 'b>,
+    cfg: &CFG,
+    entry_label: &str,
+    states: Option<&AbstractStates>,
+) {
     let i8_type = ctx.llvm_context.i8_type();
     let i64_type = ctx.llvm_context.i64_type();
     let start_fn = ctx.module.get_function("start").unwrap();
@@ -33,6 +158,13 @@ pub(crate) fn build_transitions<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, cfg: &CFG, en
     let const_0 = i64_type.const_zero();
     // Functions
     let retry_fn = ctx.module.get_function("retry").unwrap();
+    // Logs each transition before its instruction runs, if the program is traced
+    let trace_fn = ctx.module.get_function("piet_trace");
+    // Places each block's code at its codels, if the program is compiled with debug info
+    let debug_scope = ctx
+        .debug_info
+        .as_ref()
+        .map(|debug_info| (debug_info, debug_info.build_subprogram(start_fn)));
     // Generate all basic blocks
     for node in cfg.keys() {
         let block = ctx
@@ -43,8 +175,17 @@ pub(crate) fn build_transitions<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, cfg: &CFG, en
     let entry = block_lookup_table.get(entry_label).unwrap().to_owned();
     let ret_block = ctx.llvm_context.append_basic_block(start_fn, "ret");
 
+    // Coverage counters, if the program is instrumented
+    let counters = ctx
+        .module
+        .get_global("block_counts")
+        .map(|_| counter_indices(cfg));
+
     // Init (jumps to entry block)
     ctx.builder.position_at_end(start_adj_basic_block);
+    if let Some((block_counters, _)) = &counters {
+        build_count(ctx, "block_counts", block_counters[entry_label]);
+    }
     ctx.builder.build_unconditional_branch(entry).unwrap();
 
     // For every node, we want to get its adjacencies and generate the correct instructions depending on DP / CC
@@ -52,6 +193,13 @@ pub(crate) fn build_transitions<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, cfg: &CFG, en
     // increment the retries counter until we find one that matches.
     for node in cfg.keys() {
         let adjs = cfg.get(node).unwrap();
+        if let Some((debug_info, scope)) = debug_scope {
+            ctx.builder.set_current_debug_location(debug_info.location(
+                ctx.llvm_context,
+                scope,
+                Some(node),
+            ));
+        }
         let block_size = i64_type.const_int(node.get_region_size(), false);
 
         let color_block_start = block_lookup_table
@@ -150,26 +298,40 @@ pub(crate) fn build_transitions<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, cfg: &CFG, en
 
                 // Calls the correct instruction
                 ctx.builder.position_at_end(call_instr);
+                if let Some((block_counters, edge_counters)) = &counters {
+                    let edge = (node.get_label().as_str(), adj.get_label().as_str());
+                    build_count(ctx, "block_counts", block_counters[edge.1]);
+                    build_count(ctx, "edge_counts", edge_counters[&edge]);
+                }
+                if let Some(trace_fn) = trace_fn {
+                    let line = transition_line(
+                        node,
+                        adj,
+                        transition.instruction,
+                        transition.exit_state,
+                    );
+                    let line_str = global_string(ctx, &line, &format!("trace {line}"));
+                    ctx.builder
+                        .build_call(trace_fn, &[line_str.into()], "")
+                        .unwrap();
+                }
                 if let Some(instr) = transition.instruction {
+                    build_step(ctx, node.get_label());
+                    let facts = states.and_then(|states| states.facts(node, transition));
+                    let proven = facts.is_some_and(|facts| {
+                        build_proven_instruction(
+                            ctx,
+                            instr,
+                            facts,
+                            transition.exit_state,
+                            (dp_addr, cc_addr),
+                        )
+                    });
                     // Rotate by n
                     let instr_fn = ctx.module.get_function(instr.to_llvm_name()).unwrap();
-                    let instr_str_addr = ctx
-                        .module
-                        .get_global(&(instr.to_llvm_name().to_owned() + "_fmt"))
-                        .unwrap()
-                        .as_any_value_enum()
-                        .into_pointer_value();
-
-                    let _instr_str = unsafe {
-                        ctx.builder.build_gep(
-                            instr_fn.as_global_value().as_pointer_value().get_type(),
-                            instr_str_addr,
-                            &[const_0, const_0],
-                            "",
-                        )
-                    };
-
-                    if instr == Instruction::Push {
+                    if proven {
+                        // Already run in place
+                    } else if instr == Instruction::Push {
                         ctx.builder
                             .build_call(instr_fn, &[block_size.into()], "")
                             .unwrap();
@@ -217,5 +379,10 @@ pub(crate) fn build_transitions<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, cfg: &CFG, en
         .move_after(*block_lookup_table.values().last().unwrap())
         .ok();
     ctx.builder.position_at_end(ret_block);
+    if let Some((debug_info, scope)) = debug_scope {
+        ctx.builder
+            .set_current_debug_location(debug_info.location(ctx.llvm_context, scope, None));
+    }
     ctx.builder.build_return(None).unwrap();
+    ctx.builder.unset_current_debug_location();
 }
@@ -0,0 +1,791 @@
+use crate::consts::STACK_SIZE;
+use crate::lowering_ctx::LoweringCtx;
+use inkwell::{
+    attributes::{Attribute, AttributeLoc},
+    intrinsics::Intrinsic,
+    module::Linkage,
+    types::FunctionType,
+    values::FunctionValue,
+    AddressSpace, IntPredicate,
+};
+use piet_core::settings::Platform;
+
+const WASI_MODULE: &str = "wasi_snapshot_preview1";
+
+/// Builds the C library functions the rest of the module calls (`printf`, `dprintf`, `getchar`, `exit` and
+/// `malloc`) for WebAssembly, where there's no C library to link against.  They only need to handle what
+/// the module asks of them, and are written on top of three functions which, under `Platform::Wasm32`, are
+/// imported from the embedder:
+///
+/// - `piet.write(fd: i32, buf: i32, len: i32)`, which writes `len` bytes of memory to stdout (1) or stderr (2)
+/// - `piet.read_byte() -> i32`, which returns the next byte of stdin, or a negative number at its end
+/// - `piet.exit(status: i32)`, which mustn't return
+///
+/// Under `Platform::Wasi` they're written with `fd_write`, `fd_read` and `proc_exit`, and `_start` runs
+/// `main` and exits with what it returns.
+pub(crate) fn build_wasm_runtime<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
+    match ctx.settings.platform {
+        Platform::Wasi => build_wasi_io(ctx),
+        _ => build_host_io(ctx),
+    }
+    build_write_int(ctx);
+    build_write_str(ctx);
+    build_vdprintf(ctx);
+    build_printf(ctx);
+    build_getchar(ctx);
+    build_exit(ctx);
+    build_malloc(ctx);
+    if ctx.settings.platform == Platform::Wasi {
+        build_start(ctx);
+    }
+}
+
+// Declares `symbol`, imported from the host as `module.name`
+fn import<'a, 'b>(
+    ctx: &LoweringCtx<'a, 'b>,
+    module: &str,
+    name: &str,
+    symbol: &str,
+    fn_type: FunctionType<'b>,
+) -> FunctionValue<'b> {
+    let function = ctx.module.add_function(symbol, fn_type, None);
+    for (key, value) in [("wasm-import-module", module), ("wasm-import-name", name)] {
+        function.add_attribute(
+            AttributeLoc::Function,
+            ctx.llvm_context.create_string_attribute(key, value),
+        );
+    }
+    function
+}
+
+// Defines `name`, and leaves the builder at the start of its body.  The C library's names are kept so that
+// nothing calling them has to change, and they're marked `nobuiltin` so that LLVM doesn't take them for
+// the real thing and, say, turn a call to printf into one to puts, which isn't there.
+fn define_function<'a, 'b>(
+    ctx: &LoweringCtx<'a, 'b>,
+    name: &str,
+    fn_type: FunctionType<'b>,
+) -> FunctionValue<'b> {
+    let function = ctx
+        .module
+        .add_function(name, fn_type, Some(Linkage::Internal));
+    function.add_attribute(AttributeLoc::Function, enum_attribute(ctx, "nobuiltin"));
+    let entry = ctx.llvm_context.append_basic_block(function, "");
+    ctx.builder.position_at_end(entry);
+    function
+}
+
+fn enum_attribute<'a, 'b>(ctx: &LoweringCtx<'a, 'b>, name: &str) -> Attribute {
+    ctx.llvm_context
+        .create_enum_attribute(Attribute::get_named_enum_kind_id(name), 0)
+}
+
+fn build_host_io<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
+    let ptr_type = ctx.llvm_context.ptr_type(AddressSpace::default());
+    let i32_type = ctx.llvm_context.i32_type();
+    let void_type = ctx.llvm_context.void_type();
+
+    import(
+        ctx,
+        "piet",
+        "write",
+        "piet_host_write",
+        void_type.fn_type(&[i32_type.into(), ptr_type.into(), i32_type.into()], false),
+    );
+    import(
+        ctx,
+        "piet",
+        "read_byte",
+        "piet_host_read_byte",
+        i32_type.fn_type(&[], false),
+    );
+    let exit_fn = import(
+        ctx,
+        "piet",
+        "exit",
+        "piet_host_exit",
+        void_type.fn_type(&[i32_type.into()], false),
+    );
+    exit_fn.add_attribute(AttributeLoc::Function, enum_attribute(ctx, "noreturn"));
+}
+
+// piet_host_write, piet_host_read_byte and piet_host_exit, as the host would provide them, out of WASI's
+// calls
+fn build_wasi_io<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
+    let ptr_type = ctx.llvm_context.ptr_type(AddressSpace::default());
+    let i8_type = ctx.llvm_context.i8_type();
+    let i32_type = ctx.llvm_context.i32_type();
+    let void_type = ctx.llvm_context.void_type();
+    let const_0 = i32_type.const_zero();
+    let const_1 = i32_type.const_int(1, false);
+
+    // fd_write and fd_read take a list of buffers, each a pointer and a length
+    let iovec_type = ctx
+        .llvm_context
+        .struct_type(&[ptr_type.into(), i32_type.into()], false);
+    let fd_io_type = i32_type.fn_type(
+        &[
+            i32_type.into(),
+            ptr_type.into(),
+            i32_type.into(),
+            ptr_type.into(),
+        ],
+        false,
+    );
+    let fd_write_fn = import(ctx, WASI_MODULE, "fd_write", "fd_write", fd_io_type);
+    let fd_read_fn = import(ctx, WASI_MODULE, "fd_read", "fd_read", fd_io_type);
+    let proc_exit_fn = import(
+        ctx,
+        WASI_MODULE,
+        "proc_exit",
+        "proc_exit",
+        void_type.fn_type(&[i32_type.into()], false),
+    );
+    proc_exit_fn.add_attribute(AttributeLoc::Function, enum_attribute(ctx, "noreturn"));
+
+    // A single call can write less than it's asked to, so this carries on until it's all written or
+    // fd_write fails
+    let write_fn = define_function(
+        ctx,
+        "piet_host_write",
+        void_type.fn_type(&[i32_type.into(), ptr_type.into(), i32_type.into()], false),
+    );
+    let loop_block = ctx.llvm_context.append_basic_block(write_fn, "loop");
+    let write_block = ctx.llvm_context.append_basic_block(write_fn, "write");
+    let advance_block = ctx.llvm_context.append_basic_block(write_fn, "advance");
+    let done_block = ctx.llvm_context.append_basic_block(write_fn, "done");
+
+    let fd = write_fn.get_nth_param(0).unwrap().into_int_value();
+    let iovec = ctx.builder.build_alloca(iovec_type, "iovec").unwrap();
+    let written = ctx.builder.build_alloca(i32_type, "written").unwrap();
+    let buf = ctx.builder.build_alloca(ptr_type, "buf").unwrap();
+    let len = ctx.builder.build_alloca(i32_type, "len").unwrap();
+    ctx.builder
+        .build_store(buf, write_fn.get_nth_param(1).unwrap())
+        .unwrap();
+    ctx.builder
+        .build_store(len, write_fn.get_nth_param(2).unwrap())
+        .unwrap();
+    ctx.builder.build_unconditional_branch(loop_block).unwrap();
+
+    ctx.builder.position_at_end(loop_block);
+    let len_val = ctx
+        .builder
+        .build_load(i32_type, len, "len")
+        .unwrap()
+        .into_int_value();
+    let more = ctx
+        .builder
+        .build_int_compare(IntPredicate::NE, len_val, const_0, "more")
+        .unwrap();
+    ctx.builder
+        .build_conditional_branch(more, write_block, done_block)
+        .unwrap();
+
+    ctx.builder.position_at_end(write_block);
+    let buf_val = ctx
+        .builder
+        .build_load(ptr_type, buf, "buf")
+        .unwrap()
+        .into_pointer_value();
+    let iovec_buf = ctx
+        .builder
+        .build_struct_gep(iovec_type, iovec, 0, "iovec_buf")
+        .unwrap();
+    let iovec_len = ctx
+        .builder
+        .build_struct_gep(iovec_type, iovec, 1, "iovec_len")
+        .unwrap();
+    ctx.builder.build_store(iovec_buf, buf_val).unwrap();
+    ctx.builder.build_store(iovec_len, len_val).unwrap();
+    ctx.builder.build_store(written, const_0).unwrap();
+    let errno = ctx
+        .builder
+        .build_call(
+            fd_write_fn,
+            &[fd.into(), iovec.into(), const_1.into(), written.into()],
+            "errno",
+        )
+        .unwrap()
+        .try_as_basic_value()
+        .unwrap_basic()
+        .into_int_value();
+    let written_val = ctx
+        .builder
+        .build_load(i32_type, written, "written")
+        .unwrap()
+        .into_int_value();
+    let failed = ctx
+        .builder
+        .build_or(
+            ctx.builder
+                .build_int_compare(IntPredicate::NE, errno, const_0, "")
+                .unwrap(),
+            ctx.builder
+                .build_int_compare(IntPredicate::EQ, written_val, const_0, "")
+                .unwrap(),
+            "failed",
+        )
+        .unwrap();
+    ctx.builder
+        .build_conditional_branch(failed, done_block, advance_block)
+        .unwrap();
+
+    ctx.builder.position_at_end(advance_block);
+    let next = unsafe {
+        ctx.builder
+            .build_gep(i8_type, buf_val, &[written_val], "next")
+            .unwrap()
+    };
+    ctx.builder.build_store(buf, next).unwrap();
+    let remaining = ctx
+        .builder
+        .build_int_sub(len_val, written_val, "remaining")
+        .unwrap();
+    ctx.builder.build_store(len, remaining).unwrap();
+    ctx.builder.build_unconditional_branch(loop_block).unwrap();
+
+    ctx.builder.position_at_end(done_block);
+    ctx.builder.build_return(None).unwrap();
+
+    // A byte of stdin, or -1 if there's none left or it can't be read
+    define_function(ctx, "piet_host_read_byte", i32_type.fn_type(&[], false));
+    let byte = ctx.builder.build_alloca(i8_type, "byte").unwrap();
+    let iovec = ctx.builder.build_alloca(iovec_type, "iovec").unwrap();
+    let read = ctx.builder.build_alloca(i32_type, "read").unwrap();
+    ctx.builder.build_store(byte, i8_type.const_zero()).unwrap();
+    let iovec_buf = ctx
+        .builder
+        .build_struct_gep(iovec_type, iovec, 0, "iovec_buf")
+        .unwrap();
+    let iovec_len = ctx
+        .builder
+        .build_struct_gep(iovec_type, iovec, 1, "iovec_len")
+        .unwrap();
+    ctx.builder.build_store(iovec_buf, byte).unwrap();
+    ctx.builder.build_store(iovec_len, const_1).unwrap();
+    ctx.builder.build_store(read, const_0).unwrap();
+    let errno = ctx
+        .builder
+        .build_call(
+            fd_read_fn,
+            &[const_0.into(), iovec.into(), const_1.into(), read.into()],
+            "errno",
+        )
+        .unwrap()
+        .try_as_basic_value()
+        .unwrap_basic()
+        .into_int_value();
+    let read_val = ctx
+        .builder
+        .build_load(i32_type, read, "read")
+        .unwrap()
+        .into_int_value();
+    let failed = ctx
+        .builder
+        .build_or(
+            ctx.builder
+                .build_int_compare(IntPredicate::NE, errno, const_0, "")
+                .unwrap(),
+            ctx.builder
+                .build_int_compare(IntPredicate::EQ, read_val, const_0, "")
+                .unwrap(),
+            "failed",
+        )
+        .unwrap();
+    let byte_val = ctx
+        .builder
+        .build_load(i8_type, byte, "byte")
+        .unwrap()
+        .into_int_value();
+    let byte_val = ctx
+        .builder
+        .build_int_z_extend(byte_val, i32_type, "")
+        .unwrap();
+    let result = ctx
+        .builder
+        .build_select(failed, i32_type.const_all_ones(), byte_val, "result")
+        .unwrap();
+    ctx.builder.build_return(Some(&result)).unwrap();
+
+    let exit_fn = define_function(
+        ctx,
+        "piet_host_exit",
+        void_type.fn_type(&[i32_type.into()], false),
+    );
+    ctx.builder
+        .build_call(
+            proc_exit_fn,
+            &[exit_fn.get_nth_param(0).unwrap().into()],
+            "",
+        )
+        .unwrap();
+    ctx.builder.build_unreachable().unwrap();
+}
+
+// piet_write_int(fd, value) writes `value` in decimal, as printf's %ld does
+fn build_write_int<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
+    let i8_type = ctx.llvm_context.i8_type();
+    let i32_type = ctx.llvm_context.i32_type();
+    let i64_type = ctx.llvm_context.i64_type();
+    let void_type = ctx.llvm_context.void_type();
+    let host_write_fn = ctx.module.get_function("piet_host_write").unwrap();
+
+    // Room for the 19 digits of i64::MIN and its sign
+    let buf_len = i32_type.const_int(20, false);
+    let buf_type = i8_type.array_type(20);
+    let const_0 = i32_type.const_zero();
+    let const_1 = i32_type.const_int(1, false);
+    let const_10 = i64_type.const_int(10, false);
+
+    let write_int_fn = define_function(
+        ctx,
+        "piet_write_int",
+        void_type.fn_type(&[i32_type.into(), i64_type.into()], false),
+    );
+    let digit_block = ctx.llvm_context.append_basic_block(write_int_fn, "digit");
+    let sign_block = ctx.llvm_context.append_basic_block(write_int_fn, "sign");
+    let write_block = ctx.llvm_context.append_basic_block(write_int_fn, "write");
+
+    let fd = write_int_fn.get_nth_param(0).unwrap().into_int_value();
+    let value = write_int_fn.get_nth_param(1).unwrap().into_int_value();
+    let buf = ctx.builder.build_alloca(buf_type, "buf").unwrap();
+    let pos = ctx.builder.build_alloca(i32_type, "pos").unwrap();
+    let magnitude = ctx.builder.build_alloca(i64_type, "magnitude").unwrap();
+
+    // The digits are those of the magnitude, taken as unsigned so that i64::MIN's fits
+    let negative = ctx
+        .builder
+        .build_int_compare(IntPredicate::SLT, value, i64_type.const_zero(), "negative")
+        .unwrap();
+    let negated = ctx.builder.build_int_neg(value, "negated").unwrap();
+    let magnitude_val = ctx
+        .builder
+        .build_select(negative, negated, value, "magnitude")
+        .unwrap();
+    ctx.builder.build_store(magnitude, magnitude_val).unwrap();
+    ctx.builder.build_store(pos, buf_len).unwrap();
+    ctx.builder.build_unconditional_branch(digit_block).unwrap();
+
+    // Digits are written from the end of the buffer backwards
+    ctx.builder.position_at_end(digit_block);
+    let magnitude_val = ctx
+        .builder
+        .build_load(i64_type, magnitude, "magnitude")
+        .unwrap()
+        .into_int_value();
+    let pos_val = ctx
+        .builder
+        .build_load(i32_type, pos, "pos")
+        .unwrap()
+        .into_int_value();
+    let pos_val = ctx.builder.build_int_sub(pos_val, const_1, "pos").unwrap();
+    let digit = ctx
+        .builder
+        .build_int_unsigned_rem(magnitude_val, const_10, "digit")
+        .unwrap();
+    let digit = ctx.builder.build_int_truncate(digit, i8_type, "").unwrap();
+    let digit = ctx
+        .builder
+        .build_int_add(digit, i8_type.const_int(b'0' as u64, false), "")
+        .unwrap();
+    let digit_ptr = unsafe {
+        ctx.builder
+            .build_gep(buf_type, buf, &[const_0, pos_val], "")
+            .unwrap()
+    };
+    ctx.builder.build_store(digit_ptr, digit).unwrap();
+    ctx.builder.build_store(pos, pos_val).unwrap();
+    let rest = ctx
+        .builder
+        .build_int_unsigned_div(magnitude_val, const_10, "rest")
+        .unwrap();
+    ctx.builder.build_store(magnitude, rest).unwrap();
+    let more = ctx
+        .builder
+        .build_int_compare(IntPredicate::NE, rest, i64_type.const_zero(), "more")
+        .unwrap();
+    ctx.builder
+        .build_conditional_branch(more, digit_block, sign_block)
+        .unwrap();
+
+    ctx.builder.position_at_end(sign_block);
+    let minus_pos = ctx.builder.build_int_sub(pos_val, const_1, "").unwrap();
+    let minus_ptr = unsafe {
+        ctx.builder
+            .build_gep(buf_type, buf, &[const_0, minus_pos], "")
+            .unwrap()
+    };
+    let minus_block = ctx.llvm_context.append_basic_block(write_int_fn, "minus");
+    ctx.builder
+        .build_conditional_branch(negative, minus_block, write_block)
+        .unwrap();
+    ctx.builder.position_at_end(minus_block);
+    ctx.builder
+        .build_store(minus_ptr, i8_type.const_int(b'-' as u64, false))
+        .unwrap();
+    ctx.builder.build_store(pos, minus_pos).unwrap();
+    ctx.builder.build_unconditional_branch(write_block).unwrap();
+
+    ctx.builder.position_at_end(write_block);
+    let pos_val = ctx
+        .builder
+        .build_load(i32_type, pos, "pos")
+        .unwrap()
+        .into_int_value();
+    let start = unsafe {
+        ctx.builder
+            .build_gep(buf_type, buf, &[const_0, pos_val], "start")
+            .unwrap()
+    };
+    let len = ctx.builder.build_int_sub(buf_len, pos_val, "len").unwrap();
+    ctx.builder
+        .build_call(host_write_fn, &[fd.into(), start.into(), len.into()], "")
+        .unwrap();
+    ctx.builder.build_return(None).unwrap();
+}
+
+// piet_write_str(fd, str) writes a null-terminated string
+fn build_write_str<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
+    let ptr_type = ctx.llvm_context.ptr_type(AddressSpace::default());
+    let i8_type = ctx.llvm_context.i8_type();
+    let i32_type = ctx.llvm_context.i32_type();
+    let void_type = ctx.llvm_context.void_type();
+    let host_write_fn = ctx.module.get_function("piet_host_write").unwrap();
+
+    let write_str_fn = define_function(
+        ctx,
+        "piet_write_str",
+        void_type.fn_type(&[i32_type.into(), ptr_type.into()], false),
+    );
+    let count_block = ctx.llvm_context.append_basic_block(write_str_fn, "count");
+    let next_block = ctx.llvm_context.append_basic_block(write_str_fn, "next");
+    let write_block = ctx.llvm_context.append_basic_block(write_str_fn, "write");
+
+    let fd = write_str_fn.get_nth_param(0).unwrap();
+    let string = write_str_fn.get_nth_param(1).unwrap().into_pointer_value();
+    let len = ctx.builder.build_alloca(i32_type, "len").unwrap();
+    ctx.builder.build_store(len, i32_type.const_zero()).unwrap();
+    ctx.builder.build_unconditional_branch(count_block).unwrap();
+
+    ctx.builder.position_at_end(count_block);
+    let len_val = ctx
+        .builder
+        .build_load(i32_type, len, "len")
+        .unwrap()
+        .into_int_value();
+    let byte_ptr = unsafe {
+        ctx.builder
+            .build_gep(i8_type, string, &[len_val], "")
+            .unwrap()
+    };
+    let byte = ctx
+        .builder
+        .build_load(i8_type, byte_ptr, "byte")
+        .unwrap()
+        .into_int_value();
+    let end = ctx
+        .builder
+        .build_int_compare(IntPredicate::EQ, byte, i8_type.const_zero(), "end")
+        .unwrap();
+    ctx.builder
+        .build_conditional_branch(end, write_block, next_block)
+        .unwrap();
+
+    ctx.builder.position_at_end(next_block);
+    let next_len = ctx
+        .builder
+        .build_int_add(len_val, i32_type.const_int(1, false), "")
+        .unwrap();
+    ctx.builder.build_store(len, next_len).unwrap();
+    ctx.builder.build_unconditional_branch(count_block).unwrap();
+
+    ctx.builder.position_at_end(write_block);
+    ctx.builder
+        .build_call(
+            host_write_fn,
+            &[fd.into(), string.into(), len_val.into()],
+            "",
+        )
+        .unwrap();
+    ctx.builder.build_return(None).unwrap();
+}
+
+// piet_vdprintf(fd, fmt, ap) formats as vdprintf does, but only the conversions the module's format strings
+// use: %ld, %d, %c and %s.  Anything else after a % is written as it is.
+fn build_vdprintf<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
+    let ptr_type = ctx.llvm_context.ptr_type(AddressSpace::default());
+    let i8_type = ctx.llvm_context.i8_type();
+    let i32_type = ctx.llvm_context.i32_type();
+    let i64_type = ctx.llvm_context.i64_type();
+    let void_type = ctx.llvm_context.void_type();
+    let host_write_fn = ctx.module.get_function("piet_host_write").unwrap();
+    let write_int_fn = ctx.module.get_function("piet_write_int").unwrap();
+    let write_str_fn = ctx.module.get_function("piet_write_str").unwrap();
+    let const_1 = i32_type.const_int(1, false);
+    let char_const = |c: u8| i8_type.const_int(c as u64, false);
+
+    let vdprintf_fn = define_function(
+        ctx,
+        "piet_vdprintf",
+        void_type.fn_type(&[i32_type.into(), ptr_type.into(), ptr_type.into()], false),
+    );
+    let loop_block = ctx.llvm_context.append_basic_block(vdprintf_fn, "loop");
+    let literal_block = ctx.llvm_context.append_basic_block(vdprintf_fn, "literal");
+    let spec_block = ctx.llvm_context.append_basic_block(vdprintf_fn, "spec");
+    let long_block = ctx.llvm_context.append_basic_block(vdprintf_fn, "long");
+    let int_block = ctx.llvm_context.append_basic_block(vdprintf_fn, "int");
+    let char_block = ctx.llvm_context.append_basic_block(vdprintf_fn, "char");
+    let string_block = ctx.llvm_context.append_basic_block(vdprintf_fn, "string");
+    let done_block = ctx.llvm_context.append_basic_block(vdprintf_fn, "done");
+
+    let fd = vdprintf_fn.get_nth_param(0).unwrap();
+    let fmt = vdprintf_fn.get_nth_param(1).unwrap();
+    let ap = vdprintf_fn.get_nth_param(2).unwrap().into_pointer_value();
+    let cursor = ctx.builder.build_alloca(ptr_type, "cursor").unwrap();
+    let byte = ctx.builder.build_alloca(i8_type, "byte").unwrap();
+    ctx.builder.build_store(cursor, fmt).unwrap();
+    ctx.builder.build_unconditional_branch(loop_block).unwrap();
+
+    ctx.builder.position_at_end(loop_block);
+    let current = ctx
+        .builder
+        .build_load(ptr_type, cursor, "current")
+        .unwrap()
+        .into_pointer_value();
+    let c = ctx
+        .builder
+        .build_load(i8_type, current, "c")
+        .unwrap()
+        .into_int_value();
+    ctx.builder
+        .build_switch(
+            c,
+            literal_block,
+            &[(char_const(0), done_block), (char_const(b'%'), spec_block)],
+        )
+        .unwrap();
+
+    // Moves the cursor `len` bytes past the current one and carries on
+    let advance = |len: u64| {
+        let next = unsafe {
+            ctx.builder
+                .build_gep(i8_type, current, &[i32_type.const_int(len, false)], "next")
+                .unwrap()
+        };
+        ctx.builder.build_store(cursor, next).unwrap();
+        ctx.builder.build_unconditional_branch(loop_block).unwrap();
+    };
+
+    ctx.builder.position_at_end(literal_block);
+    ctx.builder
+        .build_call(
+            host_write_fn,
+            &[fd.into(), current.into(), const_1.into()],
+            "",
+        )
+        .unwrap();
+    advance(1);
+
+    ctx.builder.position_at_end(spec_block);
+    let conversion_ptr = unsafe {
+        ctx.builder
+            .build_gep(i8_type, current, &[const_1], "")
+            .unwrap()
+    };
+    let conversion = ctx
+        .builder
+        .build_load(i8_type, conversion_ptr, "conversion")
+        .unwrap()
+        .into_int_value();
+    ctx.builder
+        .build_switch(
+            conversion,
+            literal_block,
+            &[
+                (char_const(b'l'), long_block),
+                (char_const(b'd'), int_block),
+                (char_const(b'c'), char_block),
+                (char_const(b's'), string_block),
+            ],
+        )
+        .unwrap();
+
+    ctx.builder.position_at_end(long_block);
+    let value = ctx.builder.build_va_arg(ap, i64_type, "value").unwrap();
+    ctx.builder
+        .build_call(write_int_fn, &[fd.into(), value.into()], "")
+        .unwrap();
+    advance(3);
+
+    ctx.builder.position_at_end(int_block);
+    let value = ctx
+        .builder
+        .build_va_arg(ap, i32_type, "value")
+        .unwrap()
+        .into_int_value();
+    let value = ctx.builder.build_int_s_extend(value, i64_type, "").unwrap();
+    ctx.builder
+        .build_call(write_int_fn, &[fd.into(), value.into()], "")
+        .unwrap();
+    advance(2);
+
+    // chars are passed as ints
+    ctx.builder.position_at_end(char_block);
+    let value = ctx
+        .builder
+        .build_va_arg(ap, i32_type, "value")
+        .unwrap()
+        .into_int_value();
+    let value = ctx.builder.build_int_truncate(value, i8_type, "").unwrap();
+    ctx.builder.build_store(byte, value).unwrap();
+    ctx.builder
+        .build_call(host_write_fn, &[fd.into(), byte.into(), const_1.into()], "")
+        .unwrap();
+    advance(2);
+
+    ctx.builder.position_at_end(string_block);
+    let value = ctx.builder.build_va_arg(ap, ptr_type, "value").unwrap();
+    ctx.builder
+        .build_call(write_str_fn, &[fd.into(), value.into()], "")
+        .unwrap();
+    advance(2);
+
+    ctx.builder.position_at_end(done_block);
+    ctx.builder.build_return(None).unwrap();
+}
+
+// printf(fmt, ...) and dprintf(fd, fmt, ...), which hand their arguments to piet_vdprintf.  What they
+// return is never used, so it's always 0.
+fn build_printf<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
+    let ptr_type = ctx.llvm_context.ptr_type(AddressSpace::default());
+    let i32_type = ctx.llvm_context.i32_type();
+    let vdprintf_fn = ctx.module.get_function("piet_vdprintf").unwrap();
+    let va_start_fn = Intrinsic::find("llvm.va_start")
+        .unwrap()
+        .get_declaration(&ctx.module, &[])
+        .unwrap();
+    let va_end_fn = Intrinsic::find("llvm.va_end")
+        .unwrap()
+        .get_declaration(&ctx.module, &[])
+        .unwrap();
+
+    for (name, params) in [
+        ("printf", vec![ptr_type.into()]),
+        ("dprintf", vec![i32_type.into(), ptr_type.into()]),
+    ] {
+        let printf_fn = define_function(ctx, name, i32_type.fn_type(&params, true));
+        let (fd, fmt) = match name {
+            "printf" => (
+                i32_type.const_int(1, false).into(),
+                printf_fn.get_nth_param(0).unwrap(),
+            ),
+            _ => (
+                printf_fn.get_nth_param(0).unwrap(),
+                printf_fn.get_nth_param(1).unwrap(),
+            ),
+        };
+        // A va_list is a pointer into the arguments on WebAssembly
+        let ap = ctx.builder.build_alloca(ptr_type, "ap").unwrap();
+        ctx.builder
+            .build_call(va_start_fn, &[ap.into()], "")
+            .unwrap();
+        ctx.builder
+            .build_call(vdprintf_fn, &[fd.into(), fmt.into(), ap.into()], "")
+            .unwrap();
+        ctx.builder.build_call(va_end_fn, &[ap.into()], "").unwrap();
+        ctx.builder
+            .build_return(Some(&i32_type.const_zero()))
+            .unwrap();
+    }
+}
+
+// getchar() returns EOF (-1) for anything the host reads as negative
+fn build_getchar<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
+    let i32_type = ctx.llvm_context.i32_type();
+    let read_byte_fn = ctx.module.get_function("piet_host_read_byte").unwrap();
+
+    define_function(ctx, "getchar", i32_type.fn_type(&[], false));
+    let byte = ctx
+        .builder
+        .build_call(read_byte_fn, &[], "byte")
+        .unwrap()
+        .try_as_basic_value()
+        .unwrap_basic()
+        .into_int_value();
+    let eof = ctx
+        .builder
+        .build_int_compare(IntPredicate::SLT, byte, i32_type.const_zero(), "eof")
+        .unwrap();
+    let result = ctx
+        .builder
+        .build_select(eof, i32_type.const_all_ones(), byte, "result")
+        .unwrap();
+    ctx.builder.build_return(Some(&result)).unwrap();
+}
+
+fn build_exit<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
+    let i32_type = ctx.llvm_context.i32_type();
+    let i64_type = ctx.llvm_context.i64_type();
+    let void_type = ctx.llvm_context.void_type();
+    let host_exit_fn = ctx.module.get_function("piet_host_exit").unwrap();
+
+    let exit_fn = define_function(ctx, "exit", void_type.fn_type(&[i64_type.into()], false));
+    let status = ctx
+        .builder
+        .build_int_truncate(
+            exit_fn.get_nth_param(0).unwrap().into_int_value(),
+            i32_type,
+            "status",
+        )
+        .unwrap();
+    ctx.builder
+        .build_call(host_exit_fn, &[status.into()], "")
+        .unwrap();
+    ctx.builder.build_unreachable().unwrap();
+}
+
+// The only thing allocated is the stack, once, so malloc hands out a static array of STACK_SIZE values
+fn build_malloc<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
+    let ptr_type = ctx.llvm_context.ptr_type(AddressSpace::default());
+    let i64_type = ctx.llvm_context.i64_type();
+    let heap_type = i64_type.array_type(STACK_SIZE);
+
+    let heap = ctx.module.add_global(heap_type, None, "piet_heap");
+    heap.set_linkage(Linkage::Internal);
+    heap.set_initializer(&heap_type.const_zero());
+
+    define_function(ctx, "malloc", ptr_type.fn_type(&[i64_type.into()], false));
+    ctx.builder
+        .build_return(Some(&heap.as_pointer_value()))
+        .unwrap();
+}
+
+// _start, where a WASI program begins
+fn build_start<'a, 'b>(ctx: &LoweringCtx<'a, 'b>) {
+    let i32_type = ctx.llvm_context.i32_type();
+    let void_type = ctx.llvm_context.void_type();
+    let main_fn = ctx.module.get_function("main").unwrap();
+    let host_exit_fn = ctx.module.get_function("piet_host_exit").unwrap();
+
+    let start_fn = ctx
+        .module
+        .add_function("_start", void_type.fn_type(&[], false), None);
+    let entry = ctx.llvm_context.append_basic_block(start_fn, "");
+    ctx.builder.position_at_end(entry);
+    let status = ctx
+        .builder
+        .build_call(main_fn, &[], "status")
+        .unwrap()
+        .try_as_basic_value()
+        .unwrap_basic()
+        .into_int_value();
+    let status = ctx
+        .builder
+        .build_int_truncate(status, i32_type, "")
+        .unwrap();
+    ctx.builder
+        .build_call(host_exit_fn, &[status.into()], "")
+        .unwrap();
+    ctx.builder.build_unreachable().unwrap();
+}
@@ -0,0 +1,1071 @@
+use crate::builder::counter_indices;
+use crate::consts::STACK_SIZE;
+use piet_core::cfg::{Node, CFG};
+use piet_core::flow::PietTransition;
+use piet_core::instruction::Instruction;
+use piet_core::settings::{
+    CompilerSettings, DivisionSemantics, ErrorPolicy, InputPolicy, InvalidCharPolicy, SaveOptions,
+};
+use piet_core::state::{
+    ExecutionState, EXIT_ABORTED, EXIT_COMPLETED, EXIT_INPUT_FAILED, EXIT_STACK_EXHAUSTED,
+};
+use piet_core::trace::{transition_line, EMPTY_STACK};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use strum::IntoEnumIterator;
+
+/// Lowers `cfg` to a self-contained C program that starts from `execution_state`.  It has the same runtime
+/// as the LLVM backend, written out as C functions, and `start` is a state machine with a case for every
+/// block, which switches on DP and CC to pick the transition taken as `build_transitions` does.  Only the
+/// parts of the runtime the program needs are written, so it compiles cleanly with `-Wall -Wextra`.
+///
+/// Lowered for `SaveOptions::EmitLibrary`, the program is `piet_run` instead of `main`, declared by
+/// `library_header`, and all of its I/O goes through the caller's `piet_io`.
+pub(crate) fn lower_to_c(
+    cfg: &CFG,
+    execution_state: &ExecutionState,
+    settings: &CompilerSettings,
+) -> String {
+    let mut nodes = cfg.keys().collect::<Vec<_>>();
+    nodes.sort_by_key(|node| node.get_label());
+    let uses = Uses::new(cfg, execution_state, settings);
+
+    let mut out = String::new();
+    write_prelude(&mut out, execution_state, &uses);
+    write_stack(&mut out, &uses);
+    write_io(&mut out, &uses);
+    if uses.errors {
+        write_runtime_error(&mut out, settings.error_policy, &uses);
+    }
+    if uses.reads() {
+        write_read_input(&mut out, &uses);
+    }
+    if uses.instrs.contains(&Instruction::CharOut) {
+        out.push_str(WRITE_UTF8);
+    }
+    for &instr in &uses.instrs {
+        write_instruction(&mut out, instr, settings, &uses);
+    }
+    if uses.transitions {
+        out.push_str(RETRY);
+    }
+    if settings.coverage {
+        write_coverage(&mut out, cfg);
+    }
+    if uses.trace {
+        write_trace(&mut out);
+    }
+    write_transitions(&mut out, cfg, &nodes, &execution_state.cb_label, &uses);
+    if uses.library {
+        write_run(&mut out, execution_state, &uses);
+    } else {
+        write_main(&mut out, execution_state, &uses);
+    }
+    out
+}
+
+/// The header declaring `piet_run`, and the `piet_io` it's called with, for a program lowered with
+/// `SaveOptions::EmitLibrary`
+pub(crate) fn library_header() -> String {
+    let mut header = String::from(
+        r#"/* Generated by pietcc.  A Piet program, run by calling piet_run with the input, output and stack it
+   should use.  The program keeps its state in static variables, so piet_run can be called again once it
+   has returned, but not from more than one thread at once. */
+#ifndef PIET_RUN_H
+#define PIET_RUN_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+"#,
+    );
+    for (name, code) in EXIT_STATUSES {
+        writeln!(header, "#define PIET_{name} {code}").unwrap();
+    }
+    header.push_str(
+        r#"
+typedef struct piet_io {
+    /* Returns the next byte of input, or a negative number at the end of input.  May be NULL if the
+       program is given no input. */
+    int (*read_byte)(void *ctx);
+    /* Writes len bytes of the program's output.  May be NULL to discard it. */
+    void (*write)(void *ctx, const char *buf, size_t len);
+    /* Writes a line reporting a runtime error, under the error policy the program was compiled with.
+       May be NULL to discard them. */
+    void (*write_error)(void *ctx, const char *buf, size_t len);
+    /* Passed to each of the callbacks */
+    void *ctx;
+    /* The program's stack, bottom first, which has room for stack_capacity values.  Running out of room
+       stops the program with PIET_EXIT_STACK_EXHAUSTED. */
+    int64_t *stack;
+    size_t stack_capacity;
+    /* Where the number of values left on the stack is stored when the program stops.  May be NULL. */
+    size_t *stack_size;
+} piet_io;
+
+/* Runs the program to the end, and returns the PIET_EXIT_* status it stopped with */
+int piet_run(const piet_io *io);
+
+#endif
+"#,
+    );
+    header
+}
+
+const EXIT_STATUSES: [(&str, i32); 4] = [
+    ("EXIT_COMPLETED", EXIT_COMPLETED),
+    ("EXIT_STACK_EXHAUSTED", EXIT_STACK_EXHAUSTED),
+    ("EXIT_ABORTED", EXIT_ABORTED),
+    ("EXIT_INPUT_FAILED", EXIT_INPUT_FAILED),
+];
+
+// What the program needs from the runtime
+struct Uses {
+    // Whether the program is piet_run, rather than main
+    library: bool,
+    // What the program starts by writing, from before the state it starts from
+    prefix: String,
+    // Instructions run by some transition, in a fixed order
+    instrs: Vec<Instruction>,
+    // Whether any block has a transition out of it
+    transitions: bool,
+    // Whether an instruction that can fail is reported under the error policy
+    errors: bool,
+    trace: bool,
+    coverage: bool,
+}
+
+impl Uses {
+    fn new(cfg: &CFG, execution_state: &ExecutionState, settings: &CompilerSettings) -> Self {
+        let transitions = cfg.values().flat_map(|adjs| adjs.values().flatten());
+        let used = transitions
+            .filter_map(|transition| transition.instruction)
+            .collect::<Vec<_>>();
+        let instrs = Instruction::iter()
+            .filter(|instr| used.contains(instr))
+            .collect::<Vec<_>>();
+        let transitions = cfg.values().any(|adjs| !adjs.is_empty());
+        // Push can't fail, and bad input isn't covered by the error policy
+        let errors = settings.error_policy != ErrorPolicy::Ignore
+            && instrs
+                .iter()
+                .any(|&instr| instr != Instruction::Push && !is_input(instr));
+        Self {
+            library: matches!(settings.save_options, SaveOptions::EmitLibrary),
+            prefix: execution_state
+                .stdout
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            instrs,
+            transitions,
+            errors,
+            trace: settings.trace && transitions,
+            coverage: settings.coverage,
+        }
+    }
+
+    // Whether the program writes output, through piet_write
+    fn writes(&self) -> bool {
+        !self.prefix.is_empty()
+            || self.instrs.contains(&Instruction::IntOut)
+            || self.instrs.contains(&Instruction::CharOut)
+    }
+
+    fn reads(&self) -> bool {
+        self.instrs.iter().any(|&instr| is_input(instr))
+    }
+
+    // Ends the program with `status`.  A library returns it from piet_run instead of exiting.
+    fn stop(&self, status: &str, indent: &str) -> String {
+        match self.library {
+            true => format!("exit_status = {status};\n{indent}longjmp(piet_exit, 1);"),
+            false => format!("exit({status});"),
+        }
+    }
+
+    // Whether the stack can grow, and so has to be checked against its size
+    fn grows_stack(&self) -> bool {
+        self.instrs.iter().any(|&instr| {
+            matches!(
+                instr,
+                Instruction::Push | Instruction::Dup | Instruction::IntIn | Instruction::CharIn
+            )
+        })
+    }
+}
+
+fn is_input(instr: Instruction) -> bool {
+    matches!(instr, Instruction::IntIn | Instruction::CharIn)
+}
+
+/// `value` as a C string literal.  Anything that isn't printable ASCII is written as an octal escape, so
+/// the literal means the same bytes whatever the C compiler's source character set.
+fn c_string(value: &str) -> String {
+    let mut literal = String::from("\"");
+    for byte in value.bytes() {
+        match byte {
+            b'"' => literal.push_str("\\\""),
+            b'\\' => literal.push_str("\\\\"),
+            b'\n' => literal.push_str("\\n"),
+            // A question mark could start a trigraph
+            b' '..=b'~' if byte != b'?' => literal.push(byte as char),
+            _ => write!(literal, "\\{byte:03o}").unwrap(),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+fn write_prelude(out: &mut String, execution_state: &ExecutionState, uses: &Uses) {
+    writeln!(
+        out,
+        "/* Generated by pietcc.  Each colour block of the program is a case in start(), which takes the
+   transition out of it that the direction pointer and codel chooser pick. */"
+    )
+    .unwrap();
+    out.push_str("#include <inttypes.h>\n");
+    if uses.library {
+        out.push_str("#include <setjmp.h>\n");
+    }
+    out.push_str("#include <stdint.h>\n#include <stdio.h>\n#include <stdlib.h>\n\n");
+    if uses.library {
+        // The library's header, so the object is built from a single file
+        writeln!(out, "{}", library_header()).unwrap();
+        for (name, _) in EXIT_STATUSES {
+            writeln!(out, "#define {name} PIET_{name}").unwrap();
+        }
+        out.push_str(
+            "
+/* The piet_io being run with, and where piet_run picks up once the program stops early */
+static const piet_io *host;
+static jmp_buf piet_exit;
+static int exit_status;
+static int64_t stack_capacity;
+",
+        );
+    } else {
+        writeln!(out, "#define STACK_SIZE {STACK_SIZE}").unwrap();
+        for (name, code) in EXIT_STATUSES {
+            writeln!(out, "#define {name} {code}").unwrap();
+        }
+    }
+
+    out.push_str("\nstatic int64_t *piet_stack;\nstatic int64_t stack_size;\n");
+    writeln!(
+        out,
+        "/* 0 to 3 are right, down, left and up for DP, and 0 and 1 are left and right for CC */
+static unsigned dp = {};
+static unsigned cc = {};",
+        execution_state.pointers.dp as u8, execution_state.pointers.cc as u8
+    )
+    .unwrap();
+    if uses.transitions {
+        out.push_str("/* How many times the pointers have been changed to get out of the current block */\nstatic unsigned rctr;\n");
+    }
+}
+
+fn write_stack(out: &mut String, uses: &Uses) {
+    // A library's caller reads the stack from its own buffer, so it's never printed
+    if uses.library {
+        if uses.grows_stack() {
+            writeln!(
+                out,
+                "
+static void stack_size_check(void) {{
+    if (stack_size >= stack_capacity) {{
+        {}
+    }}
+}}",
+                uses.stop("EXIT_STACK_EXHAUSTED", "        ")
+            )
+            .unwrap();
+        }
+        return;
+    }
+
+    out.push_str(
+        r#"
+static void print_piet_stack(void) {
+    int64_t i;
+    if (stack_size == 0) {
+        printf("\nStack empty");
+    } else {
+        printf("\nStack (size %" PRId64 "): ", stack_size);
+        for (i = stack_size; i > 0; i--) {
+            printf("%" PRId64 " ", piet_stack[i - 1]);
+        }
+    }
+    printf("\n");
+}
+"#,
+    );
+    if uses.grows_stack() {
+        out.push_str(
+            r#"
+static void stack_size_check(void) {
+    if (stack_size >= STACK_SIZE) {
+        printf("\nStack memory exhausted, terminating program.");
+        print_piet_stack();
+        exit(EXIT_STACK_EXHAUSTED);
+    }
+}
+"#,
+        );
+    }
+}
+
+// Writes the functions output and input go through: stdout and stdin for a program, and the piet_io
+// callbacks for a library, which holds the byte of lookahead readers put back itself
+fn write_io(out: &mut String, uses: &Uses) {
+    if uses.writes() {
+        out.push_str(match uses.library {
+            true => {
+                "
+static void piet_write(const char *buf, size_t len) {
+    if (host->write != NULL) {
+        host->write(host->ctx, buf, len);
+    }
+}
+"
+            }
+            false => {
+                "
+static void piet_write(const char *buf, size_t len) {
+    fwrite(buf, 1, len, stdout);
+}
+"
+            }
+        });
+    }
+    if uses.reads() {
+        out.push_str(match uses.library {
+            true => {
+                "
+static int pushed_back;
+
+static int piet_getc(void) {
+    int c = pushed_back;
+    if (c != EOF) {
+        pushed_back = EOF;
+        return c;
+    }
+    if (host->read_byte == NULL) {
+        return EOF;
+    }
+    c = host->read_byte(host->ctx);
+    return c < 0 ? EOF : c;
+}
+
+static void piet_ungetc(int c) {
+    pushed_back = c;
+}
+"
+            }
+            false => {
+                "
+static int piet_getc(void) {
+    return getchar();
+}
+
+static void piet_ungetc(int c) {
+    ungetc(c, stdin);
+}
+"
+            }
+        });
+    }
+}
+
+fn write_runtime_error(out: &mut String, error_policy: ErrorPolicy, uses: &Uses) {
+    let severity = match error_policy {
+        ErrorPolicy::Abort => "error",
+        _ => "warning",
+    };
+    let abort = match error_policy {
+        ErrorPolicy::Abort => format!("\n    {}", uses.stop("EXIT_ABORTED", "    ")),
+        _ => String::new(),
+    };
+    let report = match uses.library {
+        true => format!(
+            r#"char line[256];
+    int len = snprintf(line, sizeof line, "{severity}: %s on %s at step %" PRId64 " in block %s\n", kind, instr,
+                       step_count, current_block);
+    if (host->write_error != NULL && len > 0) {{
+        host->write_error(host->ctx, line, (size_t)len < sizeof line ? (size_t)len : sizeof line - 1);
+    }}"#
+        ),
+        false => format!(
+            r#"fprintf(stderr, "{severity}: %s on %s at step %" PRId64 " in block %s\n", kind, instr, step_count,
+            current_block);"#
+        ),
+    };
+    writeln!(
+        out,
+        r#"
+/* The block and step of the instruction being executed, for piet_error to report */
+static const char *current_block;
+static int64_t step_count;
+
+static void piet_error(const char *kind, const char *instr) {{
+    {report}{abort}
+}}"#
+    )
+    .unwrap();
+}
+
+fn write_read_input(out: &mut String, uses: &Uses) {
+    out.push_str(
+        r#"
+/* What piet_read_int and piet_read_char return, alongside the value they store */
+enum read_status { READ_VALUE, READ_EOF, READ_BAD };
+"#,
+    );
+    if uses.instrs.contains(&Instruction::IntIn) {
+        out.push_str(READ_INT);
+    }
+    if uses.instrs.contains(&Instruction::CharIn) {
+        out.push_str(READ_CHAR);
+    }
+}
+
+// Reads input as `InputSettings` describes, one byte at a time with piet_getc, putting back the byte of
+// lookahead with piet_ungetc
+const READ_INT: &str = r#"
+/* C's isspace, whatever the locale */
+static int piet_isspace(int c) {
+    return c == ' ' || c == '\t' || c == '\n' || c == '\v' || c == '\f' || c == '\r';
+}
+
+static enum read_status piet_read_int(int64_t *value) {
+    int c = piet_getc();
+    int negative, digits = 0;
+    int64_t n = 0;
+
+    /* Leading whitespace, including newlines, is skipped */
+    while (piet_isspace(c)) {
+        c = piet_getc();
+    }
+    if (c == EOF) {
+        return READ_EOF;
+    }
+    negative = c == '-';
+    if (c == '-' || c == '+') {
+        c = piet_getc();
+    }
+    /* Accumulated with the number's sign, so that INT64_MIN can be read.  An overflowing number is bad
+       input, starting from the digit that overflowed. */
+    for (; c >= '0' && c <= '9'; c = piet_getc()) {
+        int digit = c - '0';
+        if (negative ? n < (INT64_MIN + digit) / 10 : n > (INT64_MAX - digit) / 10) {
+            digits = 0;
+            break;
+        }
+        n = negative ? n * 10 - digit : n * 10 + digit;
+        digits = 1;
+    }
+
+    /* The number ends at EOF or a newline, or at other whitespace, which is consumed up to the end of the
+       line.  Anything else makes it bad. */
+    if (digits && (c == EOF || c == '\n')) {
+        *value = n;
+        return READ_VALUE;
+    }
+    if (digits && piet_isspace(c)) {
+        do {
+            c = piet_getc();
+        } while (piet_isspace(c) && c != '\n');
+        if (c != EOF && c != '\n') {
+            piet_ungetc(c);
+        }
+        *value = n;
+        return READ_VALUE;
+    }
+    /* Bad input discards the rest of its line, including the newline */
+    while (c != EOF && c != '\n') {
+        c = piet_getc();
+    }
+    return READ_BAD;
+}
+"#;
+
+const READ_CHAR: &str = r#"
+static enum read_status piet_read_char(int64_t *value) {
+    /* The smallest code point that needs each length, to catch overlong encodings */
+    static const int64_t min_code_point[] = {0, 0, 0x80, 0x800, 0x10000};
+    int c = piet_getc();
+    int len, i;
+    int64_t code_point;
+
+    /* The lead byte gives the length of the sequence.  A stray continuation byte or a byte that can't
+       start one is bad on its own. */
+    if (c == EOF) {
+        return READ_EOF;
+    } else if (c < 0x80) {
+        *value = c;
+        return READ_VALUE;
+    } else if (c >= 0xc0 && c <= 0xdf) {
+        len = 2;
+        code_point = c & 0x1f;
+    } else if (c >= 0xe0 && c <= 0xef) {
+        len = 3;
+        code_point = c & 0x0f;
+    } else if (c >= 0xf0 && c <= 0xf7) {
+        len = 4;
+        code_point = c & 0x07;
+    } else {
+        return READ_BAD;
+    }
+
+    /* A sequence cut short is bad, and the byte that cut it short is left to be read again */
+    for (i = 1; i < len; i++) {
+        c = piet_getc();
+        if (c == EOF || (c & 0xc0) != 0x80) {
+            if (c != EOF) {
+                piet_ungetc(c);
+            }
+            return READ_BAD;
+        }
+        code_point = code_point << 6 | (c & 0x3f);
+    }
+
+    /* Overlong encodings, surrogates and code points past U+10FFFF are bad */
+    if (code_point < min_code_point[len] || code_point > 0x10ffff
+        || (code_point >= 0xd800 && code_point <= 0xdfff)) {
+        return READ_BAD;
+    }
+    *value = code_point;
+    return READ_VALUE;
+}
+"#;
+
+const WRITE_UTF8: &str = r#"
+/* Writes a character encoded as UTF-8 */
+static void piet_write_utf8(int64_t code_point) {
+    char buf[4];
+    size_t len;
+    if (code_point < 0x80) {
+        buf[0] = (char)code_point;
+        len = 1;
+    } else if (code_point < 0x800) {
+        buf[0] = (char)(0xc0 | code_point >> 6);
+        buf[1] = (char)(0x80 | (code_point & 0x3f));
+        len = 2;
+    } else if (code_point < 0x10000) {
+        buf[0] = (char)(0xe0 | code_point >> 12);
+        buf[1] = (char)(0x80 | (code_point >> 6 & 0x3f));
+        buf[2] = (char)(0x80 | (code_point & 0x3f));
+        len = 3;
+    } else {
+        buf[0] = (char)(0xf0 | code_point >> 18);
+        buf[1] = (char)(0x80 | (code_point >> 12 & 0x3f));
+        buf[2] = (char)(0x80 | (code_point >> 6 & 0x3f));
+        buf[3] = (char)(0x80 | (code_point & 0x3f));
+        len = 4;
+    }
+    piet_write(buf, len);
+}
+"#;
+
+const RETRY: &str = r#"
+/* Toggles CC and rotates DP in turn, for when the way out of a block is blocked */
+static void retry(void) {
+    if (rctr % 2 == 1) {
+        dp = (dp + 1) % 4;
+    } else {
+        cc = (cc + 1) % 2;
+    }
+    rctr = (rctr + 1) % 8;
+}
+"#;
+
+// The body of `b / a` and `b mod a` for a nonzero `a`, following the division semantics being compiled for.
+// C's `/` and `%` truncate, like LLVM's sdiv and srem, and are undefined for INT64_MIN / -1, so a divisor
+// of -1 never reaches them.
+fn division(division: DivisionSemantics, instr: Instruction) -> &'static str {
+    match (division, instr) {
+        (DivisionSemantics::Npiet, Instruction::Div) => {
+            "result = a == -1 ? (int64_t)(0 - (uint64_t)b) : b / a;"
+        }
+        (DivisionSemantics::Npiet, _) => {
+            "/* A nonzero remainder on the other side of zero from the divisor is moved over by the divisor */
+    result = a == -1 ? 0 : b % a;
+    if (result != 0 && (result < 0) != (a < 0)) {
+        result += a;
+    }"
+        }
+        (DivisionSemantics::Euclid, Instruction::Div) => {
+            "/* A negative remainder is made positive by rounding the quotient away from the divisor's sign */
+    result = a == -1 ? (int64_t)(0 - (uint64_t)b) : b / a;
+    if (a != -1 && b % a < 0) {
+        result = a < 0 ? result + 1 : result - 1;
+    }"
+        }
+        (DivisionSemantics::Euclid, _) => {
+            "result = a == -1 ? 0 : b % a;
+    if (result < 0) {
+        result = (int64_t)((uint64_t)result + (a < 0 ? 0 - (uint64_t)a : (uint64_t)a));
+    }"
+        }
+    }
+}
+
+// What an input instruction that couldn't read a value does under `policy`: pushing a value in its place,
+// pushing nothing, or ending the program
+fn input_policy(policy: InputPolicy, uses: &Uses) -> String {
+    match (policy, policy.value()) {
+        (_, Some(value)) => format!("value = {value};\n        break;"),
+        (InputPolicy::Terminate, None) if uses.library => {
+            uses.stop("EXIT_INPUT_FAILED", "        ")
+        }
+        (InputPolicy::Terminate, None) => format!(
+            "print_piet_stack();\n        {}",
+            uses.stop("EXIT_INPUT_FAILED", "        ")
+        ),
+        (_, None) => "return;".to_owned(),
+    }
+}
+
+fn write_instruction(
+    out: &mut String,
+    instr: Instruction,
+    settings: &CompilerSettings,
+    uses: &Uses,
+) {
+    let name = instr.to_llvm_name();
+    let mnemonic = instr.mnemonic();
+    // Reports a `kind` of error on this instruction, if errors aren't ignored
+    let report = |kind: &str| match settings.error_policy {
+        ErrorPolicy::Ignore => String::new(),
+        _ => format!("piet_error(\"{kind}\", \"{mnemonic}\");\n        "),
+    };
+    // Leaves the stack as it is unless it holds `n` values
+    let needs = |n: u32| {
+        format!(
+            "if (stack_size < {n}) {{\n        {}return;\n    }}",
+            report("stack underflow")
+        )
+    };
+    let binop = |body: &str| {
+        format!(
+            "int64_t a, b;\n    {}\n    a = piet_stack[stack_size - 1];\n    b = piet_stack[stack_size - 2];\n    stack_size--;\n    piet_stack[stack_size - 1] = {body};",
+            needs(2)
+        )
+    };
+
+    let body = match instr {
+        Instruction::Push => {
+            "stack_size_check();\n    piet_stack[stack_size++] = n;".to_owned()
+        }
+        Instruction::Pop => format!("{}\n    stack_size--;", needs(1)),
+        Instruction::Add => binop("(int64_t)((uint64_t)b + (uint64_t)a)"),
+        Instruction::Sub => binop("(int64_t)((uint64_t)b - (uint64_t)a)"),
+        Instruction::Mul => binop("(int64_t)((uint64_t)b * (uint64_t)a)"),
+        Instruction::Gt => binop("b > a"),
+        Instruction::Div | Instruction::Mod => format!(
+            "int64_t a, b, result;\n    {}\n    a = piet_stack[stack_size - 1];\n    b = piet_stack[stack_size - 2];
+    /* A zero divisor leaves the stack as it was */
+    if (a == 0) {{\n        {}return;\n    }}
+    {}\n    stack_size--;\n    piet_stack[stack_size - 1] = result;",
+            needs(2),
+            report("division by zero"),
+            division(settings.division, instr)
+        ),
+        Instruction::Not => format!(
+            "{}\n    piet_stack[stack_size - 1] = piet_stack[stack_size - 1] == 0;",
+            needs(1)
+        ),
+        Instruction::Ptr => format!(
+            "{}\n    stack_size--;\n    dp = (dp + (unsigned)(piet_stack[stack_size] % 4 + 4)) % 4;",
+            needs(1)
+        ),
+        Instruction::Swi => format!(
+            "{}\n    stack_size--;\n    cc = (cc + (piet_stack[stack_size] % 2 != 0)) % 2;",
+            needs(1)
+        ),
+        Instruction::Dup => format!(
+            "stack_size_check();\n    {}\n    piet_stack[stack_size] = piet_stack[stack_size - 1];\n    stack_size++;",
+            needs(1)
+        ),
+        Instruction::Roll => format!(
+            "int64_t rolls, depth, bottom;\n    {}
+    rolls = piet_stack[stack_size - 1];\n    depth = piet_stack[stack_size - 2];\n    stack_size -= 2;
+    /* A depth out of range still pops both arguments */
+    if (depth < 0 || depth > stack_size) {{\n        {}return;\n    }}
+    /* Rolling to depth 0 does nothing */
+    if (depth == 0) {{\n        return;\n    }}
+    rolls %= depth;\n    if (rolls < 0) {{\n        rolls += depth;\n    }}
+    /* Rolling the top depth values by rolls is rotating them up, which is three reversals */
+    bottom = stack_size - depth;
+    piet_reverse(bottom, stack_size);
+    piet_reverse(bottom, bottom + rolls);
+    piet_reverse(bottom + rolls, stack_size);",
+            needs(2),
+            report("stack underflow")
+        ),
+        Instruction::IntIn | Instruction::CharIn => {
+            let (prompt, read_fn) = match instr {
+                Instruction::IntIn => ("Enter number: ", "piet_read_int"),
+                _ => ("Enter char: ", "piet_read_char"),
+            };
+            // A library's caller gives it input without being asked
+            let prompt = match uses.library {
+                true => String::new(),
+                false => format!("\n    printf({});", c_string(prompt)),
+            };
+            format!(
+                "int64_t value;{prompt}
+    switch ({read_fn}(&value)) {{
+    case READ_VALUE:\n        break;\n    case READ_EOF:\n        {}\n    case READ_BAD:\n        {}\n    }}
+    stack_size_check();\n    piet_stack[stack_size++] = value;",
+                input_policy(settings.input.on_eof, uses),
+                input_policy(settings.input.on_bad_input, uses)
+            )
+        }
+        Instruction::IntOut => format!(
+            "char buf[24];\n    int len;\n    {}\n    stack_size--;
+    len = sprintf(buf, \"%\" PRId64, piet_stack[stack_size]);\n    piet_write(buf, (size_t)len);",
+            needs(1)
+        ),
+        Instruction::CharOut => {
+            let invalid = match settings.invalid_char {
+                InvalidCharPolicy::Skip => String::new(),
+                InvalidCharPolicy::Replace => format!(
+                    " else {{\n        piet_write_utf8({});\n    }}",
+                    char::REPLACEMENT_CHARACTER as u32
+                ),
+                InvalidCharPolicy::Error => format!(
+                    " else {{\n        {}\n    }}",
+                    report("invalid character").trim_end()
+                ),
+            };
+            format!(
+                "int64_t value;\n    {}\n    value = piet_stack[--stack_size];
+    if (value >= 0 && value <= 0x10ffff && (value < 0xd800 || value > 0xdfff)) {{
+        piet_write_utf8(value);\n    }}{invalid}",
+                needs(1)
+            )
+        }
+    };
+
+    if instr == Instruction::Roll {
+        out.push_str(
+            r#"
+/* Reverses piet_stack[from] up to but not including piet_stack[to] */
+static void piet_reverse(int64_t from, int64_t to) {
+    int64_t value;
+    for (; from < to - 1; from++, to--) {
+        value = piet_stack[from];
+        piet_stack[from] = piet_stack[to - 1];
+        piet_stack[to - 1] = value;
+    }
+}
+"#,
+        );
+    }
+    let params = match instr {
+        Instruction::Push => "int64_t n",
+        _ => "void",
+    };
+    writeln!(
+        out,
+        "\n/* {mnemonic} */\nstatic void {name}({params}) {{\n    {body}\n}}"
+    )
+    .unwrap();
+}
+
+fn write_coverage(out: &mut String, cfg: &CFG) {
+    let (blocks, edges) = counter_indices(cfg);
+    let mut blocks = blocks.into_iter().collect::<Vec<_>>();
+    blocks.sort_by_key(|&(_, index)| index);
+    let mut edges = edges.into_iter().collect::<Vec<_>>();
+    edges.sort_by_key(|&(_, index)| index);
+
+    // Arrays can't be empty, so there's always room for one more
+    writeln!(
+        out,
+        "\n/* Execution counters, written to the file named by PIET_COVERAGE on exit */
+static int64_t block_counts[{}];
+static int64_t edge_counts[{}];",
+        blocks.len() + 1,
+        edges.len() + 1
+    )
+    .unwrap();
+    out.push_str("static const char *const block_labels[] = {");
+    for (label, _) in &blocks {
+        write!(out, "\n    {},", c_string(label)).unwrap();
+    }
+    out.push_str("\n    0,\n};\nstatic const char *const edge_labels[][2] = {");
+    for ((from, to), _) in &edges {
+        write!(out, "\n    {{{}, {}}},", c_string(from), c_string(to)).unwrap();
+    }
+    writeln!(
+        out,
+        r#"
+    {{0, 0}},
+}};
+
+static void dump_coverage(void) {{
+    const char *path = getenv("PIET_COVERAGE");
+    FILE *file;
+    int i;
+    /* Nothing is written unless PIET_COVERAGE names a file that can be opened */
+    if (path == NULL || (file = fopen(path, "w")) == NULL) {{
+        return;
+    }}
+    for (i = 0; i < {}; i++) {{
+        fprintf(file, "block %s %" PRId64 "\n", block_labels[i], block_counts[i]);
+    }}
+    for (i = 0; i < {}; i++) {{
+        fprintf(file, "edge %s %s %" PRId64 "\n", edge_labels[i][0], edge_labels[i][1], edge_counts[i]);
+    }}
+    fclose(file);
+}}"#,
+        blocks.len(),
+        edges.len()
+    )
+    .unwrap();
+}
+
+fn write_trace(out: &mut String) {
+    writeln!(
+        out,
+        r#"
+static FILE *trace_file;
+
+/* Writes a transition's line, made by transition_line, followed by the stack top.  The trace goes to the
+   file named by PIET_TRACE, opened on the first call, or to stderr if that's unset or can't be opened. */
+static void piet_trace(const char *line) {{
+    if (trace_file == NULL) {{
+        const char *path = getenv("PIET_TRACE");
+        if (path != NULL && (trace_file = fopen(path, "w")) != NULL) {{
+            /* Line buffered, so a program that exits early still leaves a complete trace */
+            setvbuf(trace_file, NULL, _IOLBF, BUFSIZ);
+        }} else {{
+            trace_file = stderr;
+        }}
+    }}
+    if (stack_size > 0) {{
+        fprintf(trace_file, "%s %" PRId64 "\n", line, piet_stack[stack_size - 1]);
+    }} else {{
+        fprintf(trace_file, "%s {EMPTY_STACK}\n", line);
+    }}
+}}"#
+    )
+    .unwrap();
+}
+
+// The transitions out of `adjs`, by the DP and CC they're taken with.  Each pair picks at most one.
+fn transitions_by_pointers<'a>(
+    node: &'a Node,
+    cfg: &'a CFG,
+) -> BTreeMap<(u8, u8), (&'a Node, &'a PietTransition)> {
+    let mut transitions = BTreeMap::new();
+    let mut adjs = cfg[node].iter().collect::<Vec<_>>();
+    adjs.sort_by_key(|(adj, _)| adj.get_label());
+    for (adj, dirvec) in adjs {
+        for transition in dirvec {
+            let pointers = transition.entry_state;
+            transitions
+                .entry((pointers.dp as u8, pointers.cc as u8))
+                .or_insert((adj, transition));
+        }
+    }
+    transitions
+}
+
+fn write_transitions(out: &mut String, cfg: &CFG, nodes: &[&Node], entry_label: &str, uses: &Uses) {
+    let counters = uses.coverage.then(|| counter_indices(cfg));
+
+    // Labels are made of a colour and a position, so they're C identifiers too
+    out.push_str("\nenum block {");
+    for node in nodes {
+        write!(out, "\n    {},", node.get_label()).unwrap();
+    }
+    writeln!(
+        out,
+        "\n}};\n\nstatic void start(void) {{\n    enum block block = {entry_label};"
+    )
+    .unwrap();
+    if let Some((block_counters, _)) = &counters {
+        writeln!(out, "    block_counts[{}]++;", block_counters[entry_label]).unwrap();
+    }
+    out.push_str("    for (;;) {\n        switch (block) {\n");
+
+    for node in nodes {
+        let label = node.get_label();
+        let transitions = transitions_by_pointers(node, cfg);
+        if transitions.is_empty() {
+            writeln!(out, "        case {label}:\n            return;").unwrap();
+            continue;
+        }
+
+        writeln!(
+            out,
+            "        case {label}:\n            switch (dp * 2 + cc) {{"
+        )
+        .unwrap();
+        for ((dp, cc), (adj, transition)) in transitions {
+            let pointers = transition.entry_state;
+            writeln!(
+                out,
+                "            case {}: /* {:?}/{:?} */",
+                dp * 2 + cc,
+                pointers.dp,
+                pointers.cc
+            )
+            .unwrap();
+            let indent = "                ";
+            if let Some((block_counters, edge_counters)) = &counters {
+                let edge = (label.as_str(), adj.get_label().as_str());
+                writeln!(out, "{indent}block_counts[{}]++;", block_counters[edge.1]).unwrap();
+                writeln!(out, "{indent}edge_counts[{}]++;", edge_counters[&edge]).unwrap();
+            }
+            if uses.trace {
+                let line =
+                    transition_line(node, adj, transition.instruction, transition.exit_state);
+                writeln!(out, "{indent}piet_trace({});", c_string(&line)).unwrap();
+            }
+            match transition.instruction {
+                Some(instr) => {
+                    if uses.errors {
+                        writeln!(
+                            out,
+                            "{indent}current_block = {};\n{indent}step_count++;",
+                            c_string(label)
+                        )
+                        .unwrap();
+                    }
+                    match instr {
+                        Instruction::Push => writeln!(
+                            out,
+                            "{indent}{}({});",
+                            instr.to_llvm_name(),
+                            node.get_region_size()
+                        ),
+                        _ => writeln!(out, "{indent}{}();", instr.to_llvm_name()),
+                    }
+                    .unwrap();
+                }
+                None => {
+                    let exit = transition.exit_state;
+                    writeln!(
+                        out,
+                        "{indent}dp = {};\n{indent}cc = {};",
+                        exit.dp as u8, exit.cc as u8
+                    )
+                    .unwrap();
+                }
+            }
+            writeln!(out, "{indent}block = {};\n{indent}break;", adj.get_label()).unwrap();
+        }
+        // When no transition matches, the pointers are changed and the block is tried again
+        out.push_str(
+            "            default:\n                retry();\n                continue;\n            }\n            break;\n",
+        );
+    }
+    out.push_str("        }\n");
+    // A block is left once a transition is taken, and the next starts with no retries
+    if uses.transitions {
+        out.push_str("        rctr = 0;\n");
+    }
+    out.push_str("    }\n}\n");
+}
+
+// What the program starts with: the output and stack from before the state it starts from, and that state's
+// pointers, which have already been set unless a library is being run again
+fn write_initial_state(out: &mut String, execution_state: &ExecutionState, uses: &Uses) {
+    if !uses.prefix.is_empty() {
+        writeln!(
+            out,
+            "    piet_write({}, {});",
+            c_string(&uses.prefix),
+            uses.prefix.len()
+        )
+        .unwrap();
+    }
+    if execution_state.stack.is_empty() {
+        return;
+    }
+
+    // Written bottom first.  INT64_MIN can't be written as a literal.
+    out.push_str("    {\n        static const int64_t initial_stack[] = {");
+    for value in execution_state.stack.iter().rev() {
+        match *value {
+            i64::MIN => out.push_str("\n            INT64_MIN,"),
+            value => write!(out, "\n            INT64_C({value}),").unwrap(),
+        }
+    }
+    out.push_str("\n        };\n");
+    let len = execution_state.stack.len();
+    if uses.library {
+        writeln!(
+            out,
+            "        if (stack_capacity < {len}) {{\n            {}\n        }}",
+            uses.stop("EXIT_STACK_EXHAUSTED", "            ")
+        )
+        .unwrap();
+    }
+    writeln!(
+        out,
+        "        for (stack_size = 0; stack_size < {len}; stack_size++) {{
+            piet_stack[stack_size] = initial_stack[stack_size];\n        }}\n    }}"
+    )
+    .unwrap();
+}
+
+fn write_main(out: &mut String, execution_state: &ExecutionState, uses: &Uses) {
+    out.push_str("\nint main(void) {\n    setvbuf(stdout, NULL, _IONBF, 0);\n");
+    // Coverage counts are written out on exit, including when the program is terminated early
+    if uses.coverage {
+        out.push_str("    atexit(dump_coverage);\n");
+    }
+    out.push_str("    piet_stack = malloc(STACK_SIZE * sizeof *piet_stack);\n");
+    write_initial_state(out, execution_state, uses);
+    out.push_str("    start();\n    print_piet_stack();\n    return EXIT_COMPLETED;\n}\n");
+}
+
+// piet_run resets everything the program changes as it runs, so that it can be run again
+fn write_run(out: &mut String, execution_state: &ExecutionState, uses: &Uses) {
+    writeln!(
+        out,
+        "
+int piet_run(const piet_io *io) {{
+    host = io;
+    exit_status = EXIT_COMPLETED;
+    piet_stack = io->stack;
+    stack_capacity = (uint64_t)io->stack_capacity > (uint64_t)INT64_MAX ? INT64_MAX
+                                                                   : (int64_t)io->stack_capacity;
+    stack_size = 0;
+    dp = {};
+    cc = {};",
+        execution_state.pointers.dp as u8, execution_state.pointers.cc as u8
+    )
+    .unwrap();
+    if uses.transitions {
+        out.push_str("    rctr = 0;\n");
+    }
+    if uses.errors {
+        out.push_str("    step_count = 0;\n");
+    }
+    if uses.reads() {
+        out.push_str("    pushed_back = EOF;\n");
+    }
+
+    out.push_str("    if (setjmp(piet_exit) == 0) {\n");
+    let mut run = String::new();
+    write_initial_state(&mut run, execution_state, uses);
+    run.push_str("    start();\n");
+    for line in run.lines() {
+        writeln!(out, "    {line}").unwrap();
+    }
+    out.push_str(
+        "    }
+    if (io->stack_size != NULL) {
+        *io->stack_size = (size_t)stack_size;
+    }
+    return exit_status;
+}
+",
+    );
+}
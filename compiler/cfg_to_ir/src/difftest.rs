@@ -0,0 +1,701 @@
+use crate::lowering_ctx::LoweringCtx;
+use crate::pipeline::run_piet_optimization_pipeline;
+use inkwell::context::Context;
+use inkwell::OptimizationLevel;
+use interpreter::interpreter::Interpreter;
+use parser::cfg::CFGBuilder;
+use piet_core::coverage::Coverage;
+use piet_core::program::PietSource;
+use piet_core::settings::{
+    CodelSettings, CompilerSettings, DivisionSemantics, ErrorPolicy, InputSettings,
+    InterpreterSettings, InvalidCharPolicy, SaveOptions, Verbosity,
+};
+use piet_core::state::ExecutionStatus;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const OPT_LEVELS: [OptimizationLevel; 4] = [
+    OptimizationLevel::None,
+    OptimizationLevel::Less,
+    OptimizationLevel::Default,
+    OptimizationLevel::Aggressive,
+];
+
+/// Printed by compiled programs before reading input
+pub const INPUT_PROMPTS: [&str; 2] = ["Enter number: ", "Enter char: "];
+
+/// A way of running a Piet program
+#[derive(Debug, Clone, PartialEq)]
+pub enum Backend {
+    Interpreter,
+    Compiled(OptimizationLevel),
+    /// Lowered to C, with static evaluation, and built with `cc`
+    C,
+    /// A reference implementation, run as `<command> <image>`
+    Reference(String),
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::Interpreter => write!(f, "interpreter"),
+            Backend::Compiled(opt_level) => write!(f, "compiled (O{})", *opt_level as u32),
+            Backend::C => write!(f, "C"),
+            Backend::Reference(command) => write!(f, "reference `{command}`"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DiffTestSettings<'a> {
+    pub codel_settings: CodelSettings,
+    pub reference: Option<&'a str>,
+    /// Step limit for the interpreter
+    pub max_steps: u64,
+    /// Time limit for compiled and reference programs
+    pub timeout: Duration,
+    /// Where compiled binaries are written
+    pub work_dir: &'a Path,
+    pub division: DivisionSemantics,
+    pub error_policy: ErrorPolicy,
+    pub input: InputSettings,
+    pub invalid_char: InvalidCharPolicy,
+}
+
+/// What a single backend did with the program.  Backends that can't report the final stack, step count,
+/// runtime errors or exit status leave them as `None`, and they're skipped when comparing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Observation {
+    pub stdout: String,
+    pub stack: Option<Vec<i64>>,
+    /// Transitions taken, as counted by coverage, for a program that finished
+    pub steps: Option<u64>,
+    /// Warnings and errors reported under the error policy
+    pub stderr: Option<String>,
+    /// Whether the program finished within the step or time limit
+    pub terminated: bool,
+    pub exit_code: Option<i32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DivergenceKind {
+    /// Output differs from byte `offset` onwards
+    Stdout {
+        offset: usize,
+        expected: String,
+        found: String,
+    },
+    Stack {
+        expected: Vec<i64>,
+        found: Vec<i64>,
+    },
+    Steps {
+        expected: u64,
+        found: u64,
+    },
+    /// Runtime errors differ from byte `offset` onwards
+    Stderr {
+        offset: usize,
+        expected: String,
+        found: String,
+    },
+    Termination {
+        expected: bool,
+        found: bool,
+    },
+    ExitCode {
+        expected: i32,
+        found: i32,
+    },
+}
+
+/// The first place a backend disagreed with the interpreter
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub backend: Backend,
+    pub kind: DivergenceKind,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} diverges from the interpreter: ", self.backend)?;
+        match &self.kind {
+            DivergenceKind::Stdout {
+                offset,
+                expected,
+                found,
+            } => write!(
+                f,
+                "stdout differs at byte {offset}, expected {expected:?} but found {found:?}"
+            ),
+            DivergenceKind::Stack { expected, found } => write!(
+                f,
+                "final stack differs, expected {expected:?} but found {found:?}"
+            ),
+            DivergenceKind::Steps { expected, found } => write!(
+                f,
+                "step count differs, expected {expected} but found {found}"
+            ),
+            DivergenceKind::Stderr {
+                offset,
+                expected,
+                found,
+            } => write!(
+                f,
+                "stderr differs at byte {offset}, expected {expected:?} but found {found:?}"
+            ),
+            DivergenceKind::Termination { expected, found } => {
+                let describe = |terminated: &bool| match terminated {
+                    true => "terminates",
+                    false => "does not terminate",
+                };
+                write!(
+                    f,
+                    "interpreter {} but {} {}",
+                    describe(expected),
+                    self.backend,
+                    describe(found)
+                )
+            }
+            DivergenceKind::ExitCode { expected, found } => write!(
+                f,
+                "exit status differs, expected {expected} but found {found}"
+            ),
+        }
+    }
+}
+
+// How much of the output to show around the first differing byte
+const CONTEXT_LEN: usize = 16;
+
+fn snippet(stdout: &str, offset: usize) -> String {
+    let bytes = &stdout.as_bytes()[offset.min(stdout.len())..];
+    String::from_utf8_lossy(&bytes[..bytes.len().min(CONTEXT_LEN)]).into_owned()
+}
+
+// The offset of the first differing byte, and what each side has from there
+fn first_difference(expected: &str, found: &str) -> (usize, String, String) {
+    let offset = expected
+        .bytes()
+        .zip(found.bytes())
+        .take_while(|(x, y)| x == y)
+        .count();
+    (offset, snippet(expected, offset), snippet(found, offset))
+}
+
+/// Compares `found` against the interpreter's `expected` observation.  When either side was stopped at the
+/// step or time limit, one may have got further than the other, so only the output they have in common has
+/// to agree.
+pub fn compare(
+    backend: &Backend,
+    expected: &Observation,
+    found: &Observation,
+) -> Option<Divergence> {
+    let finished = expected.terminated && found.terminated;
+    let differs = |x: &str, y: &str| match finished {
+        true => x != y,
+        false => !x.starts_with(y) && !y.starts_with(x),
+    };
+
+    let kind = match (&expected.stack, &found.stack, expected.steps, found.steps) {
+        _ if expected.terminated != found.terminated => DivergenceKind::Termination {
+            expected: expected.terminated,
+            found: found.terminated,
+        },
+        _ if differs(&expected.stdout, &found.stdout) => {
+            let (offset, expected, found) = first_difference(&expected.stdout, &found.stdout);
+            DivergenceKind::Stdout {
+                offset,
+                expected,
+                found,
+            }
+        }
+        (Some(x), Some(y), _, _) if finished && x != y => DivergenceKind::Stack {
+            expected: x.clone(),
+            found: y.clone(),
+        },
+        (_, _, Some(x), Some(y)) if finished && x != y => DivergenceKind::Steps {
+            expected: x,
+            found: y,
+        },
+        _ => match (
+            &expected.stderr,
+            &found.stderr,
+            expected.exit_code,
+            found.exit_code,
+        ) {
+            (Some(x), Some(y), _, _) if differs(x, y) => {
+                let (offset, expected, found) = first_difference(x, y);
+                DivergenceKind::Stderr {
+                    offset,
+                    expected,
+                    found,
+                }
+            }
+            (_, _, Some(x), Some(y)) if finished && x != y => DivergenceKind::ExitCode {
+                expected: x,
+                found: y,
+            },
+            _ => return None,
+        },
+    };
+
+    Some(Divergence {
+        backend: backend.clone(),
+        kind,
+    })
+}
+
+fn interpret(
+    program: &PietSource,
+    input: &str,
+    settings: &DiffTestSettings,
+) -> Result<Observation, Box<dyn Error>> {
+    let mut cfg_builder = CFGBuilder::new(program, settings.codel_settings, false);
+    cfg_builder.build();
+    let cfg = cfg_builder.get_cfg();
+
+    let interp_settings = InterpreterSettings {
+        verbosity: Verbosity::Low,
+        codel_settings: settings.codel_settings,
+        max_steps: Some(settings.max_steps),
+        print: false,
+        division: settings.division,
+        error_policy: settings.error_policy,
+        input: settings.input,
+        invalid_char: settings.invalid_char,
+        ..Default::default()
+    };
+    let mut interpreter = Interpreter::new(&cfg, interp_settings)
+        .with_input(input)
+        .with_coverage();
+    let state = interpreter.run();
+    let terminated = state.status != ExecutionStatus::MaxSteps;
+
+    Ok(Observation {
+        stdout: state.stdout.iter().map(|out| out.to_string()).collect(),
+        stack: Some(state.stack.into_iter().collect()),
+        steps: terminated
+            .then(|| interpreter.get_coverage().map(Coverage::transitions))
+            .flatten(),
+        stderr: Some(interpreter.get_stderr().into()),
+        // Programs stopped by an error or by input still finish, just not successfully
+        terminated,
+        exit_code: Some(state.status.exit_code()),
+    })
+}
+
+fn compile(
+    program: &PietSource,
+    opt_level: OptimizationLevel,
+    save_options: SaveOptions,
+    output_fname: &str,
+    coverage: bool,
+    settings: &DiffTestSettings,
+) -> Result<(), Box<dyn Error>> {
+    let context = Context::create();
+    let module = context.create_module("piet");
+    let builder = context.create_builder();
+    let compile_options = CompilerSettings {
+        opt_level,
+        codel_settings: settings.codel_settings,
+        save_options,
+        output_fname,
+        show_codel_size: false,
+        show_cfg_size: false,
+        warn_nt: false,
+        verbosity: Verbosity::Low,
+        coverage,
+        trace: false,
+        debug_info: None,
+        division: settings.division,
+        error_policy: settings.error_policy,
+        input: settings.input,
+        invalid_char: settings.invalid_char,
+        platform: Default::default(),
+    };
+
+    // Don't mistake a stale binary for a successful compile
+    let _ = fs::remove_file(output_fname);
+
+    let mut cfg_builder = CFGBuilder::new(program, settings.codel_settings, false);
+    cfg_builder.build();
+    let cfg_gen = CFGBuilder::new(program, settings.codel_settings, false);
+    let mut ctx = LoweringCtx::new(&context, module, builder, cfg_gen, compile_options);
+    run_piet_optimization_pipeline(&mut ctx, &mut cfg_builder.get_cfg(), compile_options)?;
+
+    if let SaveOptions::EmitC = save_options {
+        let output = Command::new("cc")
+            .args(["-O2", "-o", output_fname])
+            .arg(format!("{output_fname}.c"))
+            .output()?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(format!("failed to build {output_fname}.c:\n{stderr}").into());
+        }
+    }
+    if !Path::new(output_fname).exists() {
+        return Err(format!("failed to produce executable {output_fname}").into());
+    }
+    Ok(())
+}
+
+// Reads all of `pipe` on another thread, so that the child never blocks writing to it
+fn read_in_background(
+    mut pipe: impl Read + Send + 'static,
+) -> thread::JoinHandle<std::io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut buf = vec![];
+        pipe.read_to_end(&mut buf).map(|_| buf)
+    })
+}
+
+// Feeds `input` to the child, and kills it once `timeout` has elapsed.  Returns its stdout and stderr, and
+// its exit status if it exited by itself.
+fn wait_with_timeout(
+    mut child: Child,
+    input: &str,
+    timeout: Duration,
+) -> Result<(String, String, Option<ExitStatus>), Box<dyn Error>> {
+    if let Some(mut stdin) = child.stdin.take() {
+        // The program may exit without reading all of its input
+        let _ = stdin.write_all(input.as_bytes());
+    }
+    let stdout = read_in_background(child.stdout.take().unwrap());
+    let stderr = read_in_background(child.stderr.take().unwrap());
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+        if start.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+            break None;
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    let mut output = [stdout, stderr].into_iter().map(|reader| {
+        let buf = reader
+            .join()
+            .map_err(|_| "failed to read program output")??;
+        Ok::<_, Box<dyn Error>>(String::from_utf8_lossy(&buf).into_owned())
+    });
+    let stdout = output.next().unwrap()?;
+    let stderr = output.next().unwrap()?;
+    Ok((stdout, stderr, status))
+}
+
+fn spawn(command: &mut Command) -> Result<Child, Box<dyn Error>> {
+    Ok(command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?)
+}
+
+// Compiled programs print input prompts, and finish by printing the stack
+fn parse_compiled_output(stdout: &str) -> (String, Option<Vec<i64>>) {
+    let mut stdout = stdout.to_owned();
+    for prompt in INPUT_PROMPTS {
+        stdout = stdout.replace(prompt, "");
+    }
+    match stdout.rsplit_once("\nStack") {
+        Some((output, stack)) => {
+            let stack = stack
+                .split_once(": ")
+                .map_or("", |(_, vals)| vals)
+                .split_whitespace()
+                .filter_map(|val| val.parse().ok())
+                .collect();
+            (output.to_owned(), Some(stack))
+        }
+        None => (stdout, None),
+    }
+}
+
+// Counts the transitions a compiled program takes, by running a second build instrumented with coverage
+// counters.  The program compared is built without them, since instrumenting turns off static evaluation.
+// Returns None if the instrumented build doesn't finish in time.
+fn count_steps(
+    program: &PietSource,
+    opt_level: OptimizationLevel,
+    save_options: SaveOptions,
+    output_fname: &str,
+    input: &str,
+    settings: &DiffTestSettings,
+) -> Result<Option<u64>, Box<dyn Error>> {
+    let counted = format!("{output_fname}_counted");
+    let profile = format!("{counted}.coverage");
+    compile(program, opt_level, save_options, &counted, true, settings)?;
+
+    let _ = fs::remove_file(&profile);
+    let child = spawn(Command::new(&counted).env("PIET_COVERAGE", &profile))?;
+    let (_, _, status) = wait_with_timeout(child, input, settings.timeout)?;
+    if status.is_none() {
+        return Ok(None);
+    }
+    let coverage = Coverage::parse(&fs::read_to_string(&profile)?)?;
+    Ok(Some(coverage.transitions()))
+}
+
+/// Runs the program under `backend`, reading `input` as stdin
+pub fn observe(
+    backend: &Backend,
+    filename: &str,
+    program: &PietSource,
+    input: &str,
+    settings: &DiffTestSettings,
+) -> Result<Observation, Box<dyn Error>> {
+    match backend {
+        Backend::Interpreter => interpret(program, input, settings),
+        Backend::Compiled(_) | Backend::C => {
+            let stem = Path::new(filename)
+                .file_stem()
+                .map_or("piet".into(), |stem| stem.to_string_lossy());
+            let (binary, opt_level, save_options) = match backend {
+                Backend::Compiled(opt_level) => (
+                    format!("{}_{}", stem, *opt_level as u32),
+                    *opt_level,
+                    SaveOptions::EmitExecutable,
+                ),
+                _ => (
+                    format!("{stem}_c"),
+                    OptimizationLevel::Default,
+                    SaveOptions::EmitC,
+                ),
+            };
+            let binary = settings.work_dir.join(binary);
+            let output_fname = binary.to_str().ok_or("invalid work directory")?;
+            compile(
+                program,
+                opt_level,
+                save_options,
+                output_fname,
+                false,
+                settings,
+            )?;
+
+            let child = spawn(&mut Command::new(&binary))?;
+            let (stdout, stderr, status) = wait_with_timeout(child, input, settings.timeout)?;
+            let (stdout, stack) = parse_compiled_output(&stdout);
+            let steps = match status {
+                Some(_) => count_steps(
+                    program,
+                    opt_level,
+                    save_options,
+                    output_fname,
+                    input,
+                    settings,
+                )?,
+                None => None,
+            };
+            Ok(Observation {
+                stdout,
+                stack,
+                steps,
+                stderr: Some(stderr),
+                terminated: status.is_some(),
+                exit_code: status.and_then(|status| status.code()),
+            })
+        }
+        Backend::Reference(command) => {
+            let mut args = command.split_whitespace();
+            let child = spawn(
+                Command::new(args.next().ok_or("empty reference command")?)
+                    .args(args)
+                    .arg(filename),
+            )?;
+            // Reference implementations have exit statuses and error messages of their own
+            let (stdout, _, status) = wait_with_timeout(child, input, settings.timeout)?;
+            Ok(Observation {
+                stdout,
+                stack: None,
+                steps: None,
+                stderr: None,
+                terminated: status.is_some(),
+                exit_code: None,
+            })
+        }
+    }
+}
+
+/// Runs the program through the interpreter, compiled at each optimization level, lowered to C, and the
+/// reference implementation if there is one, and returns the first backend that disagrees with the interpreter
+pub fn difftest(
+    filename: &str,
+    program: &PietSource,
+    input: &str,
+    settings: &DiffTestSettings,
+) -> Result<Option<Divergence>, Box<dyn Error>> {
+    let expected = observe(&Backend::Interpreter, filename, program, input, settings)?;
+
+    let backends = OPT_LEVELS
+        .iter()
+        .map(|&opt_level| Backend::Compiled(opt_level))
+        .chain([Backend::C])
+        .chain(
+            settings
+                .reference
+                .map(|command| Backend::Reference(command.into())),
+        );
+    for backend in backends {
+        let found = observe(&backend, filename, program, input, settings)?;
+        if let Some(divergence) = compare(&backend, &expected, &found) {
+            return Ok(Some(divergence));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn observation(stdout: &str, stack: Option<Vec<i64>>) -> Observation {
+        Observation {
+            stdout: stdout.into(),
+            stack,
+            steps: Some(2),
+            stderr: None,
+            terminated: true,
+            exit_code: Some(0),
+        }
+    }
+
+    #[test]
+    fn test_parse_compiled_output() {
+        let (stdout, stack) = parse_compiled_output("Enter number: 12\nStack (size 2): 3 4 \n");
+        assert_eq!(stdout, "12");
+        assert_eq!(stack, Some(vec![3, 4]));
+
+        let (stdout, stack) = parse_compiled_output("abc\nStack empty");
+        assert_eq!(stdout, "abc");
+        assert_eq!(stack, Some(vec![]));
+    }
+
+    #[test]
+    fn test_first_divergence() {
+        let backend = Backend::Compiled(OptimizationLevel::None);
+        let expected = observation("hello", Some(vec![1]));
+        assert_eq!(compare(&backend, &expected, &expected.clone()), None);
+
+        // A backend that doesn't report the stack only diverges on output
+        assert_eq!(
+            compare(&backend, &expected, &observation("hello", None)),
+            None
+        );
+
+        let divergence = compare(&backend, &expected, &observation("help", Some(vec![1])));
+        assert_eq!(
+            divergence.map(|divergence| divergence.kind),
+            Some(DivergenceKind::Stdout {
+                offset: 3,
+                expected: "lo".into(),
+                found: "p".into(),
+            })
+        );
+
+        let divergence = compare(&backend, &expected, &observation("hello", Some(vec![2])));
+        assert_eq!(
+            divergence.map(|divergence| divergence.kind),
+            Some(DivergenceKind::Stack {
+                expected: vec![1],
+                found: vec![2],
+            })
+        );
+
+        let divergence = compare(
+            &backend,
+            &expected,
+            &Observation {
+                steps: Some(3),
+                ..expected.clone()
+            },
+        );
+        assert_eq!(
+            divergence.map(|divergence| divergence.kind),
+            Some(DivergenceKind::Steps {
+                expected: 2,
+                found: 3,
+            })
+        );
+
+        // A compiled program killed at the time limit, where the interpreter finished
+        let found = Observation {
+            terminated: false,
+            exit_code: None,
+            ..observation("hel", Some(vec![]))
+        };
+        assert_eq!(
+            compare(&backend, &expected, &found).map(|divergence| divergence.kind),
+            Some(DivergenceKind::Termination {
+                expected: true,
+                found: false,
+            })
+        );
+
+        // Neither finished, so each only has to print the start of what the other printed
+        let expected = Observation {
+            terminated: false,
+            exit_code: None,
+            ..expected
+        };
+        assert_eq!(compare(&backend, &expected, &found), None);
+        assert_eq!(compare(&backend, &found, &expected), None);
+        let found = Observation {
+            stdout: "help".into(),
+            ..found
+        };
+        assert_eq!(
+            compare(&backend, &expected, &found).map(|divergence| divergence.kind),
+            Some(DivergenceKind::Stdout {
+                offset: 3,
+                expected: "lo".into(),
+                found: "p".into(),
+            })
+        );
+        let expected = Observation {
+            terminated: true,
+            exit_code: Some(0),
+            ..expected
+        };
+
+        let expected = Observation {
+            stderr: Some("warning: stack underflow on add at step 1 in block 0\n".into()),
+            ..expected
+        };
+        let found = Observation {
+            stderr: Some(String::new()),
+            ..expected.clone()
+        };
+        assert_eq!(
+            compare(&backend, &expected, &found).map(|divergence| divergence.kind),
+            Some(DivergenceKind::Stderr {
+                offset: 0,
+                expected: "warning: stack u".into(),
+                found: "".into(),
+            })
+        );
+
+        let found = Observation {
+            exit_code: Some(5),
+            ..expected.clone()
+        };
+        assert_eq!(
+            compare(&backend, &expected, &found).map(|divergence| divergence.kind),
+            Some(DivergenceKind::ExitCode {
+                expected: 0,
+                found: 5,
+            })
+        );
+    }
+}
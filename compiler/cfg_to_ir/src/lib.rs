@@ -1,5 +1,7 @@
 mod builder;
+mod c_backend;
 mod consts;
+pub mod difftest;
 pub mod llvm_pipeline;
 pub mod lowering_ctx;
 pub mod pipeline;
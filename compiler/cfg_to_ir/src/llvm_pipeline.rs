@@ -1,13 +1,13 @@
 use std::error::Error;
 
 use crate::lowering_ctx::LoweringCtx;
-use inkwell::targets::{InitializationConfig, RelocMode, Target};
+use inkwell::targets::{InitializationConfig, RelocMode, Target, TargetTriple};
 use inkwell::OptimizationLevel;
 use inkwell::{passes::PassBuilderOptions, targets::TargetMachine};
+use piet_core::settings::{CompilerSettings, Platform};
 use piet_optimizer::error::OptimizerError;
 
 pub(crate) fn run_llvm_optimizations(ctx: &LoweringCtx) -> Result<(), Box<dyn Error>> {
-    let default_triple = TargetMachine::get_default_triple();
     let options = PassBuilderOptions::create();
     let opt_level = match ctx.settings.opt_level {
         OptimizationLevel::None => None,
@@ -17,18 +17,7 @@ pub(crate) fn run_llvm_optimizations(ctx: &LoweringCtx) -> Result<(), Box<dyn Er
     };
 
     if let Some(opt_level) = opt_level {
-        Target::initialize_native(&InitializationConfig::default())?;
-        let target = Target::from_triple(&default_triple).expect("No target for triple");
-        let tm = target
-            .create_target_machine(
-                &default_triple,
-                "native",
-                "",
-                ctx.settings.opt_level,
-                RelocMode::PIC,
-                inkwell::targets::CodeModel::Default,
-            )
-            .expect("Failed to create TargetMachine");
+        let tm = target_machine(&ctx.settings)?;
 
         Ok(ctx
             .module
@@ -38,3 +27,51 @@ pub(crate) fn run_llvm_optimizations(ctx: &LoweringCtx) -> Result<(), Box<dyn Er
         Ok(())
     }
 }
+
+/// The machine the program is compiled for.  WebAssembly gets bulk memory, so that copies LLVM makes out of
+/// the stack's loops are `memory.copy` rather than calls to a `memcpy` there's no C library to provide.
+pub(crate) fn target_machine(settings: &CompilerSettings) -> Result<TargetMachine, Box<dyn Error>> {
+    let (triple, cpu, features, reloc_mode) = match settings.platform.triple() {
+        Some(triple) => {
+            Target::initialize_webassembly(&InitializationConfig::default());
+            (
+                TargetTriple::create(triple),
+                "generic",
+                "+bulk-memory",
+                RelocMode::Default,
+            )
+        }
+        None => {
+            Target::initialize_native(&InitializationConfig::default())?;
+            (
+                TargetMachine::get_default_triple(),
+                "native",
+                "",
+                RelocMode::PIC,
+            )
+        }
+    };
+    let target = Target::from_triple(&triple).map_err(|err| err.to_string())?;
+    target
+        .create_target_machine(
+            &triple,
+            cpu,
+            features,
+            settings.opt_level,
+            reloc_mode,
+            inkwell::targets::CodeModel::Default,
+        )
+        .ok_or_else(|| format!("no target machine for {}", triple).into())
+}
+
+/// Lays the module out for the platform it's compiled for, which only needs saying for WebAssembly, whose
+/// pointers are 32 bits
+pub(crate) fn set_platform(ctx: &LoweringCtx) -> Result<(), Box<dyn Error>> {
+    if ctx.settings.platform != Platform::Native {
+        let tm = target_machine(&ctx.settings)?;
+        ctx.module.set_triple(&tm.get_triple());
+        ctx.module
+            .set_data_layout(&tm.get_target_data().get_data_layout());
+    }
+    Ok(())
+}
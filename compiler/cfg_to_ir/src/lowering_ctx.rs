@@ -1,4 +1,5 @@
-use inkwell::{builder::Builder, context::Context, module::Module};
+use crate::builder::DebugInfo;
+use inkwell::{builder::Builder, context::Context, module::Module, OptimizationLevel};
 use parser::cfg::CFGBuilder;
 use parser::decode::DecodeInstruction;
 use piet_core::settings::CompilerSettings;
@@ -10,6 +11,7 @@ pub struct LoweringCtx<'a, 'b> {
     pub(crate) builder: Builder<'b>,
     pub(crate) cfg_builder: CFGBuilder<'a>,
     pub(crate) settings: CompilerSettings<'a>,
+    pub(crate) debug_info: Option<DebugInfo<'b>>,
 }
 
 impl<'a, 'b> DecodeInstruction for LoweringCtx<'a, 'b> {}
@@ -23,12 +25,20 @@ impl<'a, 'b> LoweringCtx<'a, 'b> {
         cfg_builder: CFGBuilder<'a>,
         settings: CompilerSettings<'a>,
     ) -> Self {
+        let debug_info = settings.debug_info.map(|image| {
+            DebugInfo::new(
+                &module,
+                image,
+                settings.opt_level != OptimizationLevel::None,
+            )
+        });
         Self {
             llvm_context,
             module,
             builder,
             cfg_builder,
             settings,
+            debug_info,
         }
     }
 }
@@ -36,29 +46,191 @@ impl<'a, 'b> LoweringCtx<'a, 'b> {
 #[cfg(test)]
 mod test {
     use super::*;
-    use inkwell::{builder::Builder, context::Context, module::Module};
+    use crate::pipeline::run_piet_optimization_pipeline;
+    use inkwell::OptimizationLevel;
     use parser::{convert::UnknownPixelSettings, loader::Loader};
-    use piet_core::program::Program;
-    use std::fs;
+    use piet_core::settings::{CodelSettings, Platform, SaveOptions, Verbosity};
+    use std::{env, error::Error, fs};
 
     const SETTINGS: UnknownPixelSettings = UnknownPixelSettings::TreatAsError;
     #[test]
-    fn test_entrypoint() -> std::io::Result<()> {
+    fn test_entrypoint() -> Result<(), Box<dyn Error>> {
         let context = Context::create();
         let module = context.create_module("piet");
         let builder = context.create_builder();
         // Program
-        let program = Loader::convert("../images/alpha_filled.png", SETTINGS).unwrap();
-        let cfg_gen = CFGBuilder::new(&program, 1, true);
-        let mut cg = LoweringCtx::new(&context, module, builder, cfg_gen, 1);
-        let options = SaveOptions::EmitLLVMIR;
-        let ir = cg.build_all(
-            "../../compilation.ll",
-            OptimizationLevel::Aggressive,
-            false,
-            false,
-            options,
-        );
+        let program = Loader::convert("../../images/alpha_filled.png", SETTINGS).unwrap();
+        let output = env::temp_dir().join("alpha_filled");
+        let settings = CompilerSettings {
+            opt_level: OptimizationLevel::Aggressive,
+            codel_settings: CodelSettings::Width(1),
+            save_options: SaveOptions::EmitLLVMIR,
+            output_fname: output.to_str().unwrap(),
+            show_codel_size: false,
+            show_cfg_size: false,
+            warn_nt: false,
+            verbosity: Verbosity::Low,
+            coverage: false,
+            trace: false,
+            debug_info: None,
+            division: Default::default(),
+            error_policy: Default::default(),
+            input: Default::default(),
+            invalid_char: Default::default(),
+            platform: Default::default(),
+        };
+
+        let mut cfg_builder = CFGBuilder::new(&program, settings.codel_settings, false);
+        cfg_builder.build();
+        let cfg_gen = CFGBuilder::new(&program, settings.codel_settings, false);
+        let mut cg = LoweringCtx::new(&context, module, builder, cfg_gen, settings);
+        run_piet_optimization_pipeline(&mut cg, &mut cfg_builder.get_cfg(), settings)?;
+
+        assert!(cg.module.get_function("main").is_some());
+        assert!(cg.module.verify().is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_instrumented() -> Result<(), Box<dyn Error>> {
+        let context = Context::create();
+        let module = context.create_module("piet");
+        let builder = context.create_builder();
+        let program = Loader::convert("../../images/hw1-1.gif", SETTINGS).unwrap();
+        let output = env::temp_dir().join("hw1_instrumented");
+        let settings = CompilerSettings {
+            opt_level: OptimizationLevel::Aggressive,
+            codel_settings: CodelSettings::Width(1),
+            save_options: SaveOptions::EmitLLVMIR,
+            output_fname: output.to_str().unwrap(),
+            show_codel_size: false,
+            show_cfg_size: false,
+            warn_nt: false,
+            verbosity: Verbosity::Low,
+            coverage: true,
+            trace: true,
+            debug_info: None,
+            division: Default::default(),
+            error_policy: Default::default(),
+            input: Default::default(),
+            invalid_char: Default::default(),
+            platform: Default::default(),
+        };
+
+        let mut cfg_builder = CFGBuilder::new(&program, settings.codel_settings, false);
+        cfg_builder.build();
+        let cfg_gen = CFGBuilder::new(&program, settings.codel_settings, false);
+        let mut cg = LoweringCtx::new(&context, module, builder, cfg_gen, settings);
+        run_piet_optimization_pipeline(&mut cg, &mut cfg_builder.get_cfg(), settings)?;
+
+        // hw1 is constant, but instrumented programs aren't folded away, and tracing shares coverage's C
+        // declarations
+        assert!(cg.module.get_function("dump_coverage").is_some());
+        assert!(cg.module.get_global("block_counts").is_some());
+        assert!(cg.module.get_function("piet_trace").is_some());
+        assert!(cg.module.get_function("fopen.1").is_none());
+        assert!(cg.module.verify().is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_debug_info() -> Result<(), Box<dyn Error>> {
+        let context = Context::create();
+        let module = context.create_module("piet");
+        let builder = context.create_builder();
+        let image = "../../images/hw1-1.gif";
+        let program = Loader::convert(image, SETTINGS).unwrap();
+        let output = env::temp_dir().join("hw1_debug_info");
+        let settings = CompilerSettings {
+            opt_level: OptimizationLevel::None,
+            codel_settings: CodelSettings::Width(1),
+            save_options: SaveOptions::EmitLLVMIR,
+            output_fname: output.to_str().unwrap(),
+            show_codel_size: false,
+            show_cfg_size: false,
+            warn_nt: false,
+            verbosity: Verbosity::Low,
+            coverage: false,
+            trace: false,
+            debug_info: Some(image),
+            division: Default::default(),
+            error_policy: Default::default(),
+            input: Default::default(),
+            invalid_char: Default::default(),
+            platform: Default::default(),
+        };
+
+        let mut cfg_builder = CFGBuilder::new(&program, settings.codel_settings, false);
+        cfg_builder.build();
+        let cfg_gen = CFGBuilder::new(&program, settings.codel_settings, false);
+        let mut cg = LoweringCtx::new(&context, module, builder, cfg_gen, settings);
+        run_piet_optimization_pipeline(&mut cg, &mut cfg_builder.get_cfg(), settings)?;
+
+        // The entry block is at the top-left pixel, and the stack can be inspected
+        let ir = cg.module.print_to_string().to_string();
+        assert!(cg
+            .module
+            .get_function("start")
+            .unwrap()
+            .get_subprogram()
+            .is_some());
+        assert!(ir.contains(r#"!DIFile(filename: "hw1-1.gif""#));
+        assert!(ir.contains("!DILocation(line: 1, column: 1"));
+        assert!(ir.contains(r#"!DIGlobalVariable(name: "piet_stack""#));
+        assert!(ir.contains(r#"!DIGlobalVariable(name: "stack_size""#));
+        assert!(cg.module.verify().is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_wasm() -> Result<(), Box<dyn Error>> {
+        let program = Loader::convert("../../images/hw1-1.gif", SETTINGS).unwrap();
+        for platform in [Platform::Wasm32, Platform::Wasi] {
+            let context = Context::create();
+            let module = context.create_module("piet");
+            let builder = context.create_builder();
+            let output = env::temp_dir().join(format!("hw1_{platform:?}"));
+            let settings = CompilerSettings {
+                opt_level: OptimizationLevel::None,
+                codel_settings: CodelSettings::Width(1),
+                save_options: SaveOptions::EmitExecutable,
+                output_fname: output.to_str().unwrap(),
+                show_codel_size: false,
+                show_cfg_size: false,
+                warn_nt: false,
+                verbosity: Verbosity::Low,
+                coverage: false,
+                trace: false,
+                debug_info: None,
+                division: Default::default(),
+                error_policy: Default::default(),
+                input: Default::default(),
+                invalid_char: Default::default(),
+                platform,
+            };
+
+            let mut cfg_builder = CFGBuilder::new(&program, settings.codel_settings, false);
+            cfg_builder.build();
+            let cfg_gen = CFGBuilder::new(&program, settings.codel_settings, false);
+            let mut cg = LoweringCtx::new(&context, module, builder, cfg_gen, settings);
+            run_piet_optimization_pipeline(&mut cg, &mut cfg_builder.get_cfg(), settings)?;
+
+            // The C library functions are defined in the module, and only the host's I/O is imported
+            for name in ["printf", "dprintf", "getchar", "exit", "malloc"] {
+                let function = cg.module.get_function(name).unwrap();
+                assert!(function.get_first_basic_block().is_some(), "{name}");
+            }
+            assert!(cg.module.get_function("fdopen").is_none());
+            assert!(cg.module.get_function("setvbuf").is_none());
+            assert_eq!(
+                cg.module.get_function("_start").is_some(),
+                platform == Platform::Wasi
+            );
+            assert!(cg.module.verify().is_ok());
+
+            let object = fs::read(format!("{}.o", settings.output_fname))?;
+            assert!(object.starts_with(b"\0asm"));
+        }
         Ok(())
     }
 }
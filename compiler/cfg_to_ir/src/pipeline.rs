@@ -1,15 +1,18 @@
 use crate::builder;
-use crate::llvm_pipeline::run_llvm_optimizations;
+use crate::c_backend;
+use crate::llvm_pipeline::{run_llvm_optimizations, set_platform, target_machine};
 use crate::lowering_ctx::LoweringCtx;
-use crate::utils::vprint;
+use crate::utils::{vprint, warn};
 use crate::writer;
 use inkwell::OptimizationLevel;
 use piet_core::cfg::CFG;
-use piet_core::settings::{CompilerSettings, SaveOptions};
+use piet_core::settings::{CompilerSettings, ErrorPolicy, Platform, SaveOptions};
 use piet_core::state::ExecutionState;
+use piet_optimizer::analysis::{AbstractInterpretation, RuntimeSettings, Termination, Verdict};
+use piet_optimizer::analysis_cache::AnalysisCache;
 use piet_optimizer::manager::OptimizationPassManager;
 use piet_optimizer::result::ExecutionResult;
-use piet_optimizer::static_eval::StaticEvaluatorPass;
+use piet_optimizer::static_eval::{StaticEvaluation, StaticEvaluatorPass};
 use std::error::Error;
 
 pub fn run_piet_optimization_pipeline(
@@ -17,17 +20,91 @@ pub fn run_piet_optimization_pipeline(
     cfg: &mut CFG,
     settings: CompilerSettings,
 ) -> Result<(), Box<dyn Error>> {
+    if settings.platform != Platform::Native {
+        if let SaveOptions::EmitC | SaveOptions::EmitLibrary = settings.save_options {
+            return Err("a C program is compiled for WebAssembly by its C compiler".into());
+        }
+        // Counts and traces are written to files, which there's no C library to open
+        if settings.coverage || settings.trace {
+            return Err("a WebAssembly program can't be instrumented".into());
+        }
+        // The debug info describes the stack with 64-bit pointers
+        if settings.debug_info.is_some() {
+            return Err("debug info can't be emitted for WebAssembly".into());
+        }
+    }
+    set_platform(ctx)?;
+
     // Build globals: declares all functions (minus LLVM intrinsics) and global variables
     builder::build_globals(ctx);
 
+    // Report runtime errors the abstract interpreter can find without running the program
+    let runtime_settings = RuntimeSettings::from(&settings);
+    let mut analyses = AnalysisCache::default();
+    for diagnostic in analyses
+        .get_or_compute::<AbstractInterpretation>(cfg, &runtime_settings)
+        .diagnostics()
+    {
+        warn(ctx.settings.verbosity, &diagnostic.to_string());
+    }
+
+    if settings.warn_nt {
+        let report = analyses.get_or_compute::<Termination>(cfg, &runtime_settings);
+        for infinite_loop in &report.loops {
+            warn(ctx.settings.verbosity, &infinite_loop.to_string());
+        }
+        match report.verdict {
+            Verdict::RunsForever => warn(ctx.settings.verbosity, &report.to_string()),
+            _ => vprint(ctx.settings.verbosity, &report.to_string()),
+        }
+    }
+    // Optimized code leaves out the checks the abstract interpreter proved unnecessary
+    let states = match settings.opt_level {
+        OptimizationLevel::None => None,
+        _ => analyses.get::<AbstractInterpretation>(),
+    };
+
+    // C is lowered straight from the CFG, so none of the module is used
+    if let SaveOptions::EmitC | SaveOptions::EmitLibrary = settings.save_options {
+        if settings.debug_info.is_some() {
+            return Err("debug info can't be emitted with a C program".into());
+        }
+        // Counts and traces go to files named in the environment, which a library's caller owns
+        if let SaveOptions::EmitLibrary = settings.save_options {
+            if settings.coverage || settings.trace {
+                return Err("a library can't be instrumented".into());
+            }
+        }
+        let execution_state = c_start_state(ctx, cfg, settings);
+        let source = c_backend::lower_to_c(cfg, &execution_state, &settings);
+        return match settings.save_options {
+            SaveOptions::EmitLibrary => Ok(writer::generate_library(
+                &source,
+                &c_backend::library_header(),
+                settings.output_fname,
+                settings.opt_level,
+            )?),
+            _ => Ok(writer::generate_c(&source, settings.output_fname)?),
+        };
+    }
+
     match settings.opt_level {
-        OptimizationLevel::None => builder::build_partial(ctx, cfg, &ExecutionState::default()),
+        // Static evaluation would run part of the program at compile time, which then goes uncounted and
+        // untraced, and whose errors would never be reported
+        _ if settings.coverage
+            || settings.trace
+            || settings.error_policy != ErrorPolicy::Ignore =>
+        {
+            builder::build_partial(ctx, cfg, &ExecutionState::default(), states)
+        }
+        OptimizationLevel::None => builder::build_partial(ctx, cfg, &ExecutionState::default(), states),
         _ => {
             let mut piet_opt_manager =
                 OptimizationPassManager::new(vec![Box::new(StaticEvaluatorPass)], settings);
             piet_opt_manager.run_all(cfg);
-            if let Some(execution_result) =
-                piet_opt_manager.get_analysis_cache().get_cached_result()
+            if let Some(execution_result) = piet_opt_manager
+                .get_analysis_cache()
+                .get::<StaticEvaluation>()
             {
                 match execution_result {
                     ExecutionResult::Complete(execution_state) => {
@@ -41,22 +118,38 @@ pub fn run_piet_optimization_pipeline(
                         vprint(ctx.settings.verbosity, 
                             &format!("Compiling with partial execution state:\n{}", execution_state)
                         );
-                        builder::build_partial(ctx, cfg, execution_state)
+                        builder::build_partial(ctx, cfg, execution_state, states)
+                    }
+                    ExecutionResult::Residual(residual) => {
+                        vprint(ctx.settings.verbosity,
+                            &format!("Program reads input, partially evaluated into {} residual operations.  Resuming with execution state:\n{}",
+                            residual.ops.len(), residual.resume_state)
+                        );
+                        builder::build_residual(ctx, cfg, residual, states)
                     }
                 }
             } else {
-                builder::build_partial(ctx, cfg, &ExecutionState::default())
+                builder::build_partial(ctx, cfg, &ExecutionState::default(), states)
             }
         }
     }
 
+    if let Some(debug_info) = &ctx.debug_info {
+        debug_info.finalize();
+    }
     if let Err(err) = ctx.module.verify() {
-        eprintln!("Module verification failed:");
-        eprintln!("{}", err.to_string());
+        return Err(format!("module verification failed:\n{}", err).into());
     }
 
     run_llvm_optimizations(ctx)?;
     match settings.save_options {
+        SaveOptions::EmitExecutable if settings.platform != Platform::Native => Ok(
+            writer::generate_wasm_object(
+                &ctx.module,
+                &target_machine(&settings)?,
+                settings.output_fname,
+            )?,
+        ),
         SaveOptions::EmitExecutable => Ok(writer::generate_executable(
             &ctx.module,
             &settings.output_fname,
@@ -69,5 +162,37 @@ pub fn run_piet_optimization_pipeline(
             &ctx.module,
             &settings.output_fname,
         )?),
+        SaveOptions::EmitC | SaveOptions::EmitLibrary => unreachable!(),
+    }
+}
+
+/// The state the C program starts from.  Static evaluation is gated as it is for LLVM, and a constant
+/// program starts in the block it finished in, which has no way out.  There's no C lowering of residual
+/// operations, so a program that reads input starts from the beginning.
+fn c_start_state(ctx: &LoweringCtx, cfg: &mut CFG, settings: CompilerSettings) -> ExecutionState {
+    if settings.opt_level == OptimizationLevel::None
+        || settings.coverage
+        || settings.trace
+        || settings.error_policy != ErrorPolicy::Ignore
+    {
+        return ExecutionState::default();
+    }
+    let mut piet_opt_manager =
+        OptimizationPassManager::new(vec![Box::new(StaticEvaluatorPass)], settings);
+    piet_opt_manager.run_all(cfg);
+    match piet_opt_manager
+        .get_analysis_cache()
+        .get::<StaticEvaluation>()
+    {
+        Some(
+            ExecutionResult::Complete(execution_state) | ExecutionResult::Partial(execution_state),
+        ) => {
+            vprint(
+                ctx.settings.verbosity,
+                &format!("Compiling with execution state:\n{}", execution_state),
+            );
+            execution_state.clone()
+        }
+        _ => ExecutionState::default(),
     }
 }
@@ -1,5 +1,6 @@
 use parser::cfg::CFGBuilder;
 use piet_core::settings::Verbosity;
+use std::env;
 
 #[allow(unused)]
 pub(crate) fn generate_cfg(cfg_builder: &mut CFGBuilder) {
@@ -14,3 +15,11 @@ pub(crate) fn vprint(verbosity: Verbosity, msg: &str) {
         }
     }
 }
+
+pub(crate) fn warn(verbosity: Verbosity, msg: &str) {
+    match (verbosity, env::consts::OS) {
+        (Verbosity::Low, _) => (),
+        (_, "linux") => eprintln!("\x1B[1;37mpietcc:\x1B[0m \x1B[1;93mwarning: \x1B[0m{msg}"),
+        _ => eprintln!("pietcc: warning: {msg}"),
+    }
+}
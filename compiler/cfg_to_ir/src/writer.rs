@@ -1,4 +1,6 @@
 use inkwell::module::Module;
+use inkwell::targets::{FileType, TargetMachine};
+use inkwell::OptimizationLevel;
 use std::fs::{remove_file, OpenOptions};
 use std::io::{Error, Write};
 use std::process::Command;
@@ -40,6 +42,18 @@ pub(crate) fn generate_executable(module: &Module, filename: &str) -> Result<(),
     Ok(())
 }
 
+/// Writes `<filename>.o`, a WebAssembly object to be linked by `wasm-ld`
+pub(crate) fn generate_wasm_object(
+    module: &Module,
+    target_machine: &TargetMachine,
+    filename: &str,
+) -> Result<(), String> {
+    let object_fname = format!("{}.o", filename);
+    target_machine
+        .write_to_file(module, FileType::Object, std::path::Path::new(&object_fname))
+        .map_err(|err| err.to_string())
+}
+
 pub(crate) fn generate_llvm_bitcode(module: &Module, filename: &str) -> Result<(), Error> {
     let bitcode_file_name = &format!("{}.bc", filename);
     module.write_bitcode_to_path(bitcode_file_name);
@@ -57,3 +71,37 @@ pub(crate) fn generate_llvm_ir(module: &Module, filename: &str) -> Result<(), Er
     llvm_ir_file.write_all(module.print_to_string().to_bytes())?;
     Ok(())
 }
+
+pub(crate) fn generate_c(source: &str, filename: &str) -> Result<(), Error> {
+    let c_file_name = &format!("{}.c", filename);
+    std::fs::write(c_file_name, source)
+}
+
+/// Writes `<filename>.h`, and compiles the C program into `<filename>.o` with `cc` at `opt_level`.  The C
+/// program is kept if it doesn't compile.
+pub(crate) fn generate_library(
+    source: &str,
+    header: &str,
+    filename: &str,
+    opt_level: OptimizationLevel,
+) -> Result<(), Error> {
+    let c_fname = &format!("{}.c", filename);
+    let object_fname = &format!("{}.o", filename);
+    std::fs::write(c_fname, source)?;
+    std::fs::write(format!("{}.h", filename), header)?;
+
+    let cc_output = Command::new("cc")
+        .arg("-c")
+        .arg(format!("-O{}", opt_level as u32))
+        .arg(c_fname)
+        .arg("-o")
+        .arg(object_fname)
+        .output()?;
+
+    if !cc_output.status.success() {
+        let stderr = String::from_utf8_lossy(&cc_output.stderr);
+        return Err(Error::other(format!("cc failed to compile {}:\n{}", c_fname, stderr)));
+    }
+
+    remove_file(c_fname)
+}
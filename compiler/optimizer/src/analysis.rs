@@ -0,0 +1,49 @@
+mod abstract_interp;
+mod stack_depth;
+mod termination;
+
+pub use abstract_interp::*;
+pub use stack_depth::*;
+pub use termination::*;
+
+use crate::analysis_cache::AnalysisCache;
+use piet_core::cfg::{Node, CFG};
+use piet_core::settings::{
+    CompilerSettings, DivisionSemantics, ErrorPolicy, InputSettings, InvalidCharPolicy,
+};
+
+/// How the compiled program behaves at runtime, which analyses that evaluate instructions have to agree
+/// with.  It's given to each analysis as it runs, rather than being part of the CFG being analysed.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RuntimeSettings {
+    pub division: DivisionSemantics,
+    pub error_policy: ErrorPolicy,
+    pub input: InputSettings,
+    pub invalid_char: InvalidCharPolicy,
+}
+
+impl From<&CompilerSettings<'_>> for RuntimeSettings {
+    fn from(settings: &CompilerSettings) -> Self {
+        Self {
+            division: settings.division,
+            error_policy: settings.error_policy,
+            input: settings.input,
+            invalid_char: settings.invalid_char,
+        }
+    }
+}
+
+/// A cacheable fact about a CFG.  Results are stored in the `AnalysisCache` keyed by the
+/// implementing type, and are dropped whenever a pass that doesn't preserve them runs.
+pub trait Analysis: 'static {
+    type Result: 'static;
+
+    fn name() -> &'static str;
+    fn run(cfg: &CFG, settings: &RuntimeSettings, cache: &mut AnalysisCache) -> Self::Result;
+}
+
+pub(crate) fn entry_node(cfg: &CFG) -> Option<Node> {
+    cfg.keys()
+        .find(|node| *node.get_label() == "Entry")
+        .cloned()
+}
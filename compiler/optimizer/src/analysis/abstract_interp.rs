@@ -0,0 +1,804 @@
+use crate::analysis::{entry_node, Analysis, DepthRange, RuntimeSettings};
+use crate::analysis_cache::AnalysisCache;
+use piet_core::cfg::{next_transition, Node, CFG};
+use piet_core::flow::{PietTransition, PointerState};
+use piet_core::instruction::Instruction;
+use piet_core::settings::{
+    DivisionSemantics, ErrorPolicy, InputPolicy, InputSettings, InvalidCharPolicy,
+};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+
+/// Number of times the state at a program point may change before its intervals and depth are widened
+const WIDENING_THRESHOLD: u32 = 3;
+
+/// Maximum number of values tracked from the top of the stack.  Anything deeper is treated as unknown.
+const MAX_TRACKED_VALUES: usize = 32;
+
+/// A block together with the pointers it was entered with.  Control flow out of a block depends only on
+/// its pointer state, so these are the states of the program's control flow.
+pub type ProgramPoint = (Node, PointerState);
+
+/// An inclusive range of possible values for a stack element.  Constants are intervals with a single
+/// element, and unknown values span all of `i64`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interval {
+    pub lo: i64,
+    pub hi: i64,
+}
+
+impl Interval {
+    pub const UNKNOWN: Self = Self {
+        lo: i64::MIN,
+        hi: i64::MAX,
+    };
+
+    pub const BOOL: Self = Self { lo: 0, hi: 1 };
+
+    pub const fn constant(val: i64) -> Self {
+        Self { lo: val, hi: val }
+    }
+
+    pub fn as_constant(self) -> Option<i64> {
+        (self.lo == self.hi).then_some(self.lo)
+    }
+
+    pub fn contains(self, val: i64) -> bool {
+        self.lo <= val && val <= self.hi
+    }
+
+    pub fn join(self, other: Self) -> Self {
+        Self {
+            lo: self.lo.min(other.lo),
+            hi: self.hi.max(other.hi),
+        }
+    }
+
+    fn widen(self, next: Self) -> Self {
+        Self {
+            lo: if next.lo < self.lo { i64::MIN } else { self.lo },
+            hi: if next.hi > self.hi { i64::MAX } else { self.hi },
+        }
+    }
+
+    // Builds an interval from bounds computed without overflow.  Results that don't fit in an i64 wrap
+    // at runtime, so nothing is known about them.
+    fn from_wide(lo: i128, hi: i128) -> Self {
+        match (i64::try_from(lo), i64::try_from(hi)) {
+            (Ok(lo), Ok(hi)) => Self { lo, hi },
+            _ => Self::UNKNOWN,
+        }
+    }
+
+    fn magnitude(self) -> i128 {
+        (self.lo as i128).abs().max((self.hi as i128).abs())
+    }
+
+    fn add(b: Self, a: Self) -> Self {
+        Self::from_wide(b.lo as i128 + a.lo as i128, b.hi as i128 + a.hi as i128)
+    }
+
+    fn sub(b: Self, a: Self) -> Self {
+        Self::from_wide(b.lo as i128 - a.hi as i128, b.hi as i128 - a.lo as i128)
+    }
+
+    fn mul(b: Self, a: Self) -> Self {
+        let products = [
+            b.lo as i128 * a.lo as i128,
+            b.lo as i128 * a.hi as i128,
+            b.hi as i128 * a.lo as i128,
+            b.hi as i128 * a.hi as i128,
+        ];
+        Self::from_wide(
+            *products.iter().min().unwrap(),
+            *products.iter().max().unwrap(),
+        )
+    }
+
+    // Quotient of `b / a` for the nonzero values of `a`
+    fn div(b: Self, a: Self, division: DivisionSemantics) -> Self {
+        if let (Some(b), Some(a)) = (b.as_constant(), a.as_constant()) {
+            if let Some(quotient) = division.div(b, a) {
+                return Self::constant(quotient);
+            }
+        }
+        if a.contains(0) {
+            // Neither convention grows the dividend's magnitude, except for i64::MIN / -1
+            let bound = b.magnitude();
+            return Self::from_wide(-bound, bound);
+        }
+        // With the divisor's sign fixed, the quotient only moves one way as either operand grows
+        let quotient = |b: i64, a: i64| match division {
+            DivisionSemantics::Npiet => b as i128 / a as i128,
+            DivisionSemantics::Euclid => (b as i128).div_euclid(a as i128),
+        };
+        let quotients = [
+            quotient(b.lo, a.lo),
+            quotient(b.lo, a.hi),
+            quotient(b.hi, a.lo),
+            quotient(b.hi, a.hi),
+        ];
+        Self::from_wide(
+            *quotients.iter().min().unwrap(),
+            *quotients.iter().max().unwrap(),
+        )
+    }
+
+    // Remainder of `b mod a` for the nonzero values of `a`.  It's smaller than `|a|`, and either never
+    // negative or has the sign of `a`.
+    fn rem(b: Self, a: Self, division: DivisionSemantics) -> Self {
+        if let (Some(b), Some(a)) = (b.as_constant(), a.as_constant()) {
+            if let Some(rem) = division.rem(b, a) {
+                return Self::constant(rem);
+            }
+        }
+        let bound = a.magnitude() - 1;
+        match division {
+            DivisionSemantics::Npiet if a.hi <= 0 => Self::from_wide(-bound, 0),
+            DivisionSemantics::Npiet if a.lo < 0 => Self::from_wide(-bound, bound),
+            _ => Self::from_wide(0, bound),
+        }
+    }
+
+    fn gt(b: Self, a: Self) -> Self {
+        if b.lo > a.hi {
+            Self::constant(1)
+        } else if b.hi <= a.lo {
+            Self::constant(0)
+        } else {
+            Self::BOOL
+        }
+    }
+
+    fn not(a: Self) -> Self {
+        match a.as_constant() {
+            Some(0) => Self::constant(1),
+            _ if !a.contains(0) => Self::constant(0),
+            _ => Self::BOOL,
+        }
+    }
+}
+
+impl fmt::Display for Interval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.as_constant(), *self == Self::UNKNOWN) {
+            (Some(val), _) => write!(f, "{}", val),
+            (None, true) => write!(f, "?"),
+            (None, false) => write!(f, "[{}, {}]", self.lo, self.hi),
+        }
+    }
+}
+
+/// The Piet stack as seen by the abstract interpreter: bounds on its depth, plus intervals for the
+/// topmost elements.  `top` never holds more values than the stack is guaranteed to contain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AbstractStack {
+    pub depth: DepthRange,
+    // Last element is the top of the stack
+    top: Vec<Interval>,
+}
+
+impl Default for AbstractStack {
+    fn default() -> Self {
+        Self {
+            depth: DepthRange::EMPTY,
+            top: vec![],
+        }
+    }
+}
+
+impl AbstractStack {
+    /// The value `n` elements below the top of the stack, which is unknown unless it is tracked
+    pub fn peek(&self, n: usize) -> Interval {
+        self.top
+            .len()
+            .checked_sub(n + 1)
+            .map_or(Interval::UNKNOWN, |idx| self.top[idx])
+    }
+
+    pub fn join(&self, other: &Self) -> Self {
+        let len = self.top.len().min(other.top.len());
+        Self {
+            depth: self.depth.join(other.depth),
+            top: (0..len)
+                .rev()
+                .map(|n| self.peek(n).join(other.peek(n)))
+                .collect(),
+        }
+    }
+
+    fn widen(&self, next: &Self) -> Self {
+        let len = self.top.len().min(next.top.len());
+        let depth = self.depth.widen(next.depth);
+        let mut top = (0..len)
+            .rev()
+            .map(|n| self.peek(n).widen(next.peek(n)))
+            .collect::<Vec<_>>();
+        top.drain(..top.len().saturating_sub(depth.min as usize));
+        Self { depth, top }
+    }
+
+    /// Whether an instruction needing `pops` operands can't run, never, sometimes or always
+    pub fn underflow(&self, pops: u64) -> Certainty {
+        if matches!(self.depth.max, Some(max) if max < pops) {
+            Certainty::Always
+        } else if self.depth.min < pops {
+            Certainty::Possible
+        } else {
+            Certainty::Never
+        }
+    }
+
+    // The stack when there are fewer than `pops` elements and the instruction is skipped
+    fn skipped(&self, pops: u64) -> Option<Self> {
+        (self.depth.min < pops).then(|| Self {
+            depth: DepthRange {
+                min: self.depth.min,
+                max: Some(self.depth.max.map_or(pops - 1, |max| max.min(pops - 1))),
+            },
+            top: self.top.clone(),
+        })
+    }
+
+    // Pops `pops` operands when there are enough of them, returning the operands top first
+    fn popped(&self, pops: u64) -> Option<(Self, Vec<Interval>)> {
+        if self.underflow(pops) == Certainty::Always {
+            return None;
+        }
+        let operands = (0..pops as usize).map(|n| self.peek(n)).collect();
+        let mut top = self.top.clone();
+        top.truncate(top.len().saturating_sub(pops as usize));
+        let depth = DepthRange {
+            min: self.depth.min.max(pops) - pops,
+            max: self.depth.max.map(|max| max - pops),
+        };
+        top.drain(..top.len().saturating_sub(depth.min as usize));
+        Some((Self { depth, top }, operands))
+    }
+
+    fn push(mut self, val: Interval) -> Self {
+        self.depth = DepthRange {
+            min: self.depth.min + 1,
+            max: self.depth.max.map(|max| max + 1),
+        };
+        self.top.push(val);
+        self.top
+            .drain(..self.top.len().saturating_sub(MAX_TRACKED_VALUES));
+        self
+    }
+
+    fn roll(mut self, depth: Interval, rolls: Interval) -> Self {
+        // Depths that aren't in [1, len] are no-ops
+        if depth.hi < 1 || matches!(self.depth.max, Some(max) if (depth.lo as i128) > max as i128) {
+            return self;
+        }
+        match (depth.as_constant(), rolls.as_constant()) {
+            (Some(depth), Some(rolls)) if depth as usize <= self.top.len() => {
+                let len = self.top.len();
+                self.top[len - depth as usize..].rotate_right(rolls.rem_euclid(depth) as usize);
+            }
+            _ if depth.hi as usize <= self.top.len() => {
+                // Some permutation of the top `depth.hi` elements
+                let len = self.top.len();
+                let affected = &mut self.top[len - depth.hi as usize..];
+                let joined = affected.iter().copied().reduce(Interval::join).unwrap();
+                affected.fill(joined);
+            }
+            // Untracked values may be rolled to the top
+            _ => self.top.clear(),
+        }
+        self
+    }
+}
+
+impl fmt::Display for AbstractStack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let max = self
+            .depth
+            .max
+            .map_or("inf".to_owned(), |max| max.to_string());
+        write!(f, "depth [{}, {}], top [", self.depth.min, max)?;
+        for (i, val) in self.top.iter().rev().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", val)?;
+        }
+        write!(f, "]")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Certainty {
+    Never,
+    Possible,
+    Always,
+}
+
+impl Certainty {
+    fn of(possible: bool, certain: bool) -> Self {
+        match (possible, certain) {
+            (_, true) => Certainty::Always,
+            (true, false) => Certainty::Possible,
+            (false, false) => Certainty::Never,
+        }
+    }
+
+    // Certainty over the union of two sets of executions
+    fn merge(self, other: Self) -> Self {
+        if self == other {
+            self
+        } else {
+            Certainty::Possible
+        }
+    }
+}
+
+/// What the abstract interpreter proved about the instruction on a transition
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransitionFacts {
+    pub underflow: Certainty,
+    /// Whether the divisor of a `Div` or `Mod` is zero when the instruction runs
+    pub zero_divisor: Certainty,
+    /// The argument of a `Ptr` or `Swi` if it is the same every time the instruction runs
+    pub pointer_arg: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    StackUnderflow(Certainty),
+    DivisionByZero(Certainty),
+}
+
+/// A potential runtime error found by the abstract interpreter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub label: String,
+    pub instruction: Instruction,
+    pub kind: DiagnosticKind,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let what = match self.kind {
+            DiagnosticKind::StackUnderflow(Certainty::Always) => "always underflows the stack",
+            DiagnosticKind::StackUnderflow(_) => "may underflow the stack",
+            DiagnosticKind::DivisionByZero(Certainty::Always) => "always divides by zero",
+            DiagnosticKind::DivisionByZero(_) => "may divide by zero",
+        };
+        write!(
+            f,
+            "{:?} leaving block {} {}",
+            self.instruction, self.label, what
+        )
+    }
+}
+
+/// Abstract interpretation of the program over (block, pointer state) pairs, tracking intervals for the
+/// values on the stack until a fixpoint is reached.  Instructions follow the compiled runtime's semantics:
+/// underflowing instructions and divisions by zero leave the stack untouched, and input pushes a value
+/// unless the input policies let it push nothing.
+#[derive(Debug)]
+pub struct AbstractInterpretation;
+
+#[derive(Debug, Default)]
+pub struct AbstractStates {
+    /// Stack on entry to each reachable block, keyed by the pointers it was entered with
+    pub entry: HashMap<ProgramPoint, AbstractStack>,
+    /// Program points each reachable program point may continue to.  Terminal points have no successors.
+    pub successors: HashMap<ProgramPoint, Vec<ProgramPoint>>,
+    /// Program points whose instruction may end the program: input under `InputPolicy::Terminate`, and
+    /// instructions that may fail under `ErrorPolicy::Abort`
+    pub stops: HashSet<ProgramPoint>,
+    // Stack when leaving a block through a transition, keyed by the transition's entry state
+    exits: HashMap<ProgramPoint, (PietTransition, AbstractStack)>,
+}
+
+impl AbstractStates {
+    /// Stack right before the instruction on `transition` runs, if the transition is reachable
+    pub fn stack_at(&self, node: &Node, transition: &PietTransition) -> Option<&AbstractStack> {
+        self.exits
+            .get(&(node.clone(), transition.entry_state))
+            .map(|(_, stack)| stack)
+    }
+
+    pub fn facts(&self, node: &Node, transition: &PietTransition) -> Option<TransitionFacts> {
+        let stack = self.stack_at(node, transition)?;
+        let mut facts = TransitionFacts {
+            underflow: Certainty::Never,
+            zero_divisor: Certainty::Never,
+            pointer_arg: None,
+        };
+        if let Some(instr) = transition.instruction {
+            facts.underflow = stack.underflow(operand_count(instr));
+            if facts.underflow != Certainty::Always {
+                let arg = stack.peek(0);
+                match instr {
+                    Instruction::Div | Instruction::Mod => {
+                        facts.zero_divisor =
+                            Certainty::of(arg.contains(0), arg.as_constant() == Some(0))
+                    }
+                    Instruction::Ptr | Instruction::Swi => facts.pointer_arg = arg.as_constant(),
+                    _ => (),
+                }
+            }
+        }
+        Some(facts)
+    }
+
+    /// Runtime errors found on reachable transitions, ordered by block label.  Transitions out of the same
+    /// block with the same instruction are reported together.
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        let mut grouped = BTreeMap::<(String, u8), (Instruction, Certainty, Certainty)>::new();
+        for ((node, _), (transition, _)) in &self.exits {
+            let (Some(instr), Some(facts)) = (transition.instruction, self.facts(node, transition))
+            else {
+                continue;
+            };
+            grouped
+                .entry((node.get_label().clone(), instr as u8))
+                .and_modify(|(_, underflow, zero_divisor)| {
+                    *underflow = underflow.merge(facts.underflow);
+                    *zero_divisor = zero_divisor.merge(facts.zero_divisor);
+                })
+                .or_insert((instr, facts.underflow, facts.zero_divisor));
+        }
+
+        let mut diagnostics = vec![];
+        for ((label, _), (instruction, underflow, zero_divisor)) in grouped {
+            let mut report = |kind| {
+                diagnostics.push(Diagnostic {
+                    label: label.clone(),
+                    instruction,
+                    kind,
+                })
+            };
+            // Output instructions are often used to print whatever is left, so only report those that always underflow
+            match (instruction, underflow) {
+                (_, Certainty::Never) => (),
+                (Instruction::IntOut | Instruction::CharOut, Certainty::Possible) => (),
+                (_, certainty) => report(DiagnosticKind::StackUnderflow(certainty)),
+            }
+            if zero_divisor != Certainty::Never {
+                report(DiagnosticKind::DivisionByZero(zero_divisor))
+            }
+        }
+        diagnostics
+    }
+}
+
+fn operand_count(instr: Instruction) -> u64 {
+    match instr {
+        Instruction::Push | Instruction::IntIn | Instruction::CharIn => 0,
+        Instruction::Pop
+        | Instruction::Not
+        | Instruction::Ptr
+        | Instruction::Swi
+        | Instruction::Dup
+        | Instruction::IntOut
+        | Instruction::CharOut => 1,
+        Instruction::Add
+        | Instruction::Sub
+        | Instruction::Mul
+        | Instruction::Div
+        | Instruction::Mod
+        | Instruction::Gt
+        | Instruction::Roll => 2,
+    }
+}
+
+// Pointer states the program may continue with after `instr` ran with argument `arg`
+fn pointer_successors(
+    instr: Instruction,
+    pointers: PointerState,
+    arg: Interval,
+) -> Vec<PointerState> {
+    let modulus = match instr {
+        Instruction::Ptr => 4,
+        _ => 2,
+    };
+    let offsets = if (arg.hi as i128 - arg.lo as i128) < modulus as i128 {
+        (arg.lo..=arg.hi)
+            .map(|n| n.rem_euclid(modulus))
+            .collect::<Vec<_>>()
+    } else {
+        (0..modulus).collect()
+    };
+    let mut successors = offsets
+        .into_iter()
+        .map(|n| match instr {
+            Instruction::Ptr => PointerState::new(pointers.dp.rotate(n), pointers.cc),
+            _ => PointerState::new(pointers.dp, pointers.cc.switch(n)),
+        })
+        .collect::<Vec<_>>();
+    successors.dedup();
+    successors
+}
+
+// Whether running `instr` on `stack` may end the program rather than carrying on to the next block
+fn may_stop(stack: &AbstractStack, instr: Instruction, settings: &RuntimeSettings) -> bool {
+    let input = settings.input;
+    match instr {
+        Instruction::IntIn | Instruction::CharIn => {
+            input.on_eof == InputPolicy::Terminate || input.on_bad_input == InputPolicy::Terminate
+        }
+        _ if settings.error_policy != ErrorPolicy::Abort => false,
+        _ if stack.underflow(operand_count(instr)) != Certainty::Never => true,
+        Instruction::Div | Instruction::Mod => stack.peek(0).contains(0),
+        Instruction::CharOut if settings.invalid_char == InvalidCharPolicy::Error => {
+            // Anything but a Unicode scalar value
+            let arg = stack.peek(0);
+            arg.lo < 0 || arg.hi > 0x10FFFF || (arg.lo <= 0xDFFF && arg.hi >= 0xD800)
+        }
+        _ => false,
+    }
+}
+
+/// Runs `instr` on the abstract stack, returning every pointer state and stack the program may end up with
+fn transfer(
+    stack: &AbstractStack,
+    instr: Option<Instruction>,
+    block_size: u64,
+    pointers: PointerState,
+    division: DivisionSemantics,
+    input: InputSettings,
+) -> Vec<(PointerState, AbstractStack)> {
+    let Some(instr) = instr else {
+        return vec![(pointers, stack.clone())];
+    };
+    let pops = operand_count(instr);
+    let mut results = vec![];
+    if let Some(skipped) = stack.skipped(pops) {
+        results.push((pointers, skipped));
+    }
+    let Some((popped, args)) = stack.popped(pops) else {
+        return results;
+    };
+
+    let executed = match instr {
+        Instruction::Push => popped.push(Interval::constant(block_size as i64)),
+        Instruction::Pop | Instruction::IntOut | Instruction::CharOut => popped,
+        Instruction::IntIn | Instruction::CharIn => {
+            // Input that runs out or can't be read may push nothing
+            if input.may_skip() {
+                results.push((pointers, popped.clone()));
+            }
+            popped.push(Interval::UNKNOWN)
+        }
+        Instruction::Not => popped.push(Interval::not(args[0])),
+        Instruction::Dup => popped.push(args[0]).push(args[0]),
+        Instruction::Add => popped.push(Interval::add(args[1], args[0])),
+        Instruction::Sub => popped.push(Interval::sub(args[1], args[0])),
+        Instruction::Mul => popped.push(Interval::mul(args[1], args[0])),
+        Instruction::Gt => popped.push(Interval::gt(args[1], args[0])),
+        Instruction::Roll => popped.roll(args[1], args[0]),
+        Instruction::Div | Instruction::Mod => {
+            // A zero divisor leaves the stack as it was
+            if args[0].contains(0) {
+                results.push((pointers, stack.clone()));
+            }
+            if args[0].as_constant() == Some(0) {
+                return results;
+            }
+            match instr {
+                Instruction::Div => popped.push(Interval::div(args[1], args[0], division)),
+                _ => popped.push(Interval::rem(args[1], args[0], division)),
+            }
+        }
+        Instruction::Ptr | Instruction::Swi => {
+            results.extend(
+                pointer_successors(instr, pointers, args[0])
+                    .into_iter()
+                    .map(|successor| (successor, popped.clone())),
+            );
+            return results;
+        }
+    };
+    results.push((pointers, executed));
+    results
+}
+
+impl Analysis for AbstractInterpretation {
+    type Result = AbstractStates;
+
+    fn name() -> &'static str {
+        "abstract_interpretation"
+    }
+
+    fn run(cfg: &CFG, settings: &RuntimeSettings, _: &mut AnalysisCache) -> Self::Result {
+        let mut states = AbstractStates::default();
+        let mut visits = HashMap::<ProgramPoint, u32>::new();
+        let mut worklist = VecDeque::new();
+
+        if let Some(entry) = entry_node(cfg) {
+            let key = (entry, PointerState::default());
+            states.entry.insert(key.clone(), AbstractStack::default());
+            worklist.push_back(key);
+        }
+
+        while let Some(key) = worklist.pop_front() {
+            let (node, pointers) = &key;
+            let stack = states.entry[&key].clone();
+            let point_successors = states.successors.entry(key.clone()).or_default();
+            let Some((adj, transition)) = next_transition(cfg, node, *pointers) else {
+                continue;
+            };
+
+            let exit_key = (node.clone(), transition.entry_state);
+            let exit_stack = match states.exits.get(&exit_key) {
+                Some((_, old)) => old.join(&stack),
+                None => stack.clone(),
+            };
+            states.exits.insert(exit_key, (transition, exit_stack));
+            if matches!(transition.instruction, Some(instr) if may_stop(&stack, instr, settings)) {
+                states.stops.insert(key.clone());
+            }
+
+            let successors = transfer(
+                &stack,
+                transition.instruction,
+                node.get_region_size(),
+                transition.exit_state,
+                settings.division,
+                settings.input,
+            );
+            for (successor_pointers, incoming) in successors {
+                let successor = (adj.clone(), successor_pointers);
+                if !point_successors.contains(&successor) {
+                    point_successors.push(successor.clone());
+                }
+                let updated = match states.entry.get(&successor) {
+                    None => incoming,
+                    Some(old) => {
+                        let joined = old.join(&incoming);
+                        if joined == *old {
+                            continue;
+                        }
+                        let count = visits.entry(successor.clone()).or_default();
+                        *count += 1;
+                        if *count > WIDENING_THRESHOLD {
+                            old.widen(&joined)
+                        } else {
+                            joined
+                        }
+                    }
+                };
+
+                states.entry.insert(successor.clone(), updated);
+                if !worklist.contains(&successor) {
+                    worklist.push_back(successor);
+                }
+            }
+        }
+
+        states
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use piet_core::test_util::{block, chain};
+
+    #[test]
+    fn test_interval_arithmetic() {
+        let (x, y) = (Interval { lo: -3, hi: 5 }, Interval { lo: 2, hi: 4 });
+        assert_eq!(Interval::add(x, y), Interval { lo: -1, hi: 9 });
+        assert_eq!(Interval::sub(x, y), Interval { lo: -7, hi: 3 });
+        assert_eq!(Interval::mul(x, y), Interval { lo: -12, hi: 20 });
+        assert_eq!(
+            Interval::div(x, y, DivisionSemantics::Npiet),
+            Interval { lo: -1, hi: 2 }
+        );
+        assert_eq!(
+            Interval::div(x, y, DivisionSemantics::Euclid),
+            Interval { lo: -2, hi: 2 }
+        );
+        assert_eq!(
+            Interval::rem(x, y, DivisionSemantics::Npiet),
+            Interval { lo: 0, hi: 3 }
+        );
+
+        // Npiet remainders take the divisor's sign, Euclidean ones are never negative
+        let negative = Interval { lo: -4, hi: -2 };
+        assert_eq!(
+            Interval::rem(x, negative, DivisionSemantics::Npiet),
+            Interval { lo: -3, hi: 0 }
+        );
+        assert_eq!(
+            Interval::rem(x, negative, DivisionSemantics::Euclid),
+            Interval { lo: 0, hi: 3 }
+        );
+        assert_eq!(
+            Interval::div(x, negative, DivisionSemantics::Euclid),
+            Interval { lo: -2, hi: 2 }
+        );
+        assert_eq!(
+            Interval::gt(y, Interval::constant(1)),
+            Interval::constant(1)
+        );
+        assert_eq!(
+            Interval::add(Interval::constant(i64::MAX), y),
+            Interval::UNKNOWN
+        );
+    }
+
+    #[test]
+    fn test_finds_zero_divisor_and_underflow() {
+        let blocks = [
+            block("Entry", 3),
+            block("a", 1),
+            block("b", 1),
+            block("c", 1),
+            block("d", 1),
+        ];
+        // Pushes 3 and 1, negates the 1 and divides by the resulting 0
+        let cfg = chain(
+            &blocks,
+            &[
+                Instruction::Push,
+                Instruction::Push,
+                Instruction::Not,
+                Instruction::Div,
+            ],
+        );
+        let states =
+            AbstractInterpretation::run(&cfg, &Default::default(), &mut AnalysisCache::default());
+        let transition = cfg[&blocks[3]][&blocks[4]][0];
+        let facts = states.facts(&blocks[3], &transition).unwrap();
+        assert_eq!(facts.zero_divisor, Certainty::Always);
+        assert_eq!(facts.underflow, Certainty::Never);
+        assert_eq!(
+            states.diagnostics(),
+            vec![Diagnostic {
+                label: "c".into(),
+                instruction: Instruction::Div,
+                kind: DiagnosticKind::DivisionByZero(Certainty::Always),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_constant_pointer_argument() {
+        let blocks = [block("Entry", 2), block("a", 1), block("b", 1)];
+        let cfg = chain(&blocks, &[Instruction::Push, Instruction::Ptr]);
+        let states =
+            AbstractInterpretation::run(&cfg, &Default::default(), &mut AnalysisCache::default());
+        let transition = cfg[&blocks[1]][&blocks[2]][0];
+        let facts = states.facts(&blocks[1], &transition).unwrap();
+        assert_eq!(facts.pointer_arg, Some(2));
+        let entered = states
+            .entry
+            .keys()
+            .filter(|(node, _)| *node == blocks[2])
+            .map(|(_, pointers)| pointers.dp)
+            .collect::<Vec<_>>();
+        assert_eq!(entered, vec![piet_core::flow::DirPointer::Left]);
+    }
+
+    #[test]
+    fn test_input_may_push_nothing() {
+        let blocks = [block("Entry", 1), block("a", 1), block("b", 1)];
+        let cfg = chain(&blocks, &[Instruction::IntIn, Instruction::Pop]);
+        let transition = cfg[&blocks[1]][&blocks[2]][0];
+
+        // Skipped input leaves nothing to pop
+        let states =
+            AbstractInterpretation::run(&cfg, &Default::default(), &mut AnalysisCache::default());
+        let facts = states.facts(&blocks[1], &transition).unwrap();
+        assert_eq!(facts.underflow, Certainty::Possible);
+
+        let input = InputSettings {
+            on_eof: InputPolicy::PushZero,
+            on_bad_input: InputPolicy::PushZero,
+        };
+        let settings = RuntimeSettings {
+            input,
+            ..Default::default()
+        };
+        let states = AbstractInterpretation::run(&cfg, &settings, &mut AnalysisCache::default());
+        let facts = states.facts(&blocks[1], &transition).unwrap();
+        assert_eq!(facts.underflow, Certainty::Never);
+        assert!(states.stops.is_empty());
+    }
+}
@@ -0,0 +1,31 @@
+/// Bounds on the number of elements on the Piet stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DepthRange {
+    pub min: u64,
+    /// `None` if the stack may grow without bound
+    pub max: Option<u64>,
+}
+
+impl DepthRange {
+    pub const EMPTY: Self = Self {
+        min: 0,
+        max: Some(0),
+    };
+
+    pub fn join(self, other: Self) -> Self {
+        Self {
+            min: self.min.min(other.min),
+            max: self.max.zip(other.max).map(|(x, y)| x.max(y)),
+        }
+    }
+
+    pub(crate) fn widen(self, next: Self) -> Self {
+        Self {
+            min: if next.min < self.min { 0 } else { self.min },
+            max: match (self.max, next.max) {
+                (Some(x), Some(y)) if y <= x => Some(x),
+                _ => None,
+            },
+        }
+    }
+}
@@ -0,0 +1,319 @@
+use crate::analysis::{
+    entry_node, AbstractInterpretation, Analysis, ProgramPoint, RuntimeSettings,
+};
+use crate::analysis_cache::AnalysisCache;
+use piet_core::cfg::{next_transition, CFG};
+use piet_core::flow::PointerState;
+use piet_core::instruction::Instruction;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+
+/// Decides whether a program halts where it can, and finds loops the program can never leave.  Control
+/// flow in Piet only depends on the stack through `Ptr` and `Swi`, so the program's control states are its
+/// program points, and a loop among them with no path to a terminal block, nor to an instruction that may
+/// end the program, is a definite infinite loop.  Pointer changes are over-approximated using `AbstractInterpretation`.
+#[derive(Debug)]
+pub struct Termination;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// No reachable control state can repeat
+    Terminates,
+    /// The program can never reach a terminal block
+    RunsForever,
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopKind {
+    /// Slides through white blocks without running any instructions
+    White,
+    /// Runs instructions, but none that change the pointers
+    Straight,
+    /// Changes the pointers, but always ends up back in the loop
+    PointerCycle,
+}
+
+/// A set of program points that loops forever once any of them is reached
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InfiniteLoop {
+    pub kind: LoopKind,
+    /// Labels of the blocks in the loop, sorted
+    pub blocks: Vec<String>,
+    pub reads_input: bool,
+}
+
+impl fmt::Display for InfiniteLoop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            LoopKind::White => "through white blocks",
+            LoopKind::Straight => "without changing DP or CC",
+            LoopKind::PointerCycle => "through a DP/CC cycle with no exit",
+        };
+        write!(
+            f,
+            "{} {} loop{} forever once entered ({})",
+            if self.blocks.len() == 1 {
+                "block"
+            } else {
+                "blocks"
+            },
+            self.blocks.join(", "),
+            if self.blocks.len() == 1 { "s" } else { "" },
+            kind
+        )?;
+        if self.reads_input {
+            write!(f, ", reading input on every iteration")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct TerminationReport {
+    pub verdict: Verdict,
+    pub loops: Vec<InfiniteLoop>,
+}
+
+impl fmt::Display for TerminationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.verdict {
+            Verdict::Terminates => write!(f, "program definitely terminates"),
+            Verdict::RunsForever => write!(f, "program never terminates"),
+            Verdict::Unknown => write!(f, "could not determine whether the program terminates"),
+        }
+    }
+}
+
+// Strongly connected components of `graph` restricted to `points`, using Kosaraju's algorithm with
+// explicit stacks since control state graphs can be large
+fn components(
+    graph: &HashMap<ProgramPoint, Vec<ProgramPoint>>,
+    points: &HashSet<ProgramPoint>,
+) -> Vec<Vec<ProgramPoint>> {
+    let edges = |point: &ProgramPoint| {
+        graph[point]
+            .iter()
+            .filter(|&next| points.contains(next))
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+
+    let mut order = vec![];
+    let mut visited = HashSet::new();
+    for root in points {
+        if !visited.insert(root.clone()) {
+            continue;
+        }
+        let mut stack = vec![(root.clone(), edges(root))];
+        while let Some((point, pending)) = stack.last_mut() {
+            match pending.pop() {
+                Some(next) => {
+                    if visited.insert(next.clone()) {
+                        let next_edges = edges(&next);
+                        stack.push((next, next_edges));
+                    }
+                }
+                None => {
+                    order.push(point.clone());
+                    stack.pop();
+                }
+            }
+        }
+    }
+
+    let mut reversed = HashMap::<ProgramPoint, Vec<ProgramPoint>>::new();
+    for point in points {
+        for next in edges(point) {
+            reversed.entry(next).or_default().push(point.clone());
+        }
+    }
+
+    let mut assigned = HashSet::new();
+    let mut components = vec![];
+    for root in order.into_iter().rev() {
+        if !assigned.insert(root.clone()) {
+            continue;
+        }
+        let mut component = vec![];
+        let mut stack = vec![root];
+        while let Some(point) = stack.pop() {
+            for prev in reversed.get(&point).into_iter().flatten() {
+                if assigned.insert(prev.clone()) {
+                    stack.push(prev.clone());
+                }
+            }
+            component.push(point);
+        }
+        components.push(component);
+    }
+    components
+}
+
+fn classify(cfg: &CFG, component: &[ProgramPoint]) -> InfiniteLoop {
+    let instructions = component
+        .iter()
+        .filter_map(|(node, pointers)| next_transition(cfg, node, *pointers))
+        .map(|(_, transition)| transition.instruction)
+        .collect::<Vec<_>>();
+
+    let kind = if instructions.iter().all(Option::is_none) {
+        LoopKind::White
+    } else if instructions
+        .iter()
+        .flatten()
+        .any(|instr| matches!(instr, Instruction::Ptr | Instruction::Swi))
+    {
+        LoopKind::PointerCycle
+    } else {
+        LoopKind::Straight
+    };
+
+    let blocks = component
+        .iter()
+        .map(|(node, _)| node.get_label().clone())
+        .collect::<BTreeSet<_>>();
+
+    InfiniteLoop {
+        kind,
+        blocks: blocks.into_iter().collect(),
+        reads_input: instructions
+            .iter()
+            .flatten()
+            .any(|instr| matches!(instr, Instruction::IntIn | Instruction::CharIn)),
+    }
+}
+
+impl Analysis for Termination {
+    type Result = TerminationReport;
+
+    fn name() -> &'static str {
+        "termination"
+    }
+
+    fn run(cfg: &CFG, settings: &RuntimeSettings, cache: &mut AnalysisCache) -> Self::Result {
+        let states = cache.get_or_compute::<AbstractInterpretation>(cfg, settings);
+        let graph = &states.successors;
+        let points = graph.keys().cloned().collect::<HashSet<_>>();
+
+        // Points with a path to a terminal block or an instruction that may end the program, found by
+        // searching backwards from them
+        let mut predecessors = HashMap::<&ProgramPoint, Vec<&ProgramPoint>>::new();
+        for (point, successors) in graph {
+            for next in successors {
+                predecessors.entry(next).or_default().push(point);
+            }
+        }
+        let mut halting = graph
+            .iter()
+            .filter(|(point, successors)| successors.is_empty() || states.stops.contains(*point))
+            .map(|(point, _)| point)
+            .collect::<HashSet<_>>();
+        let mut stack = halting.iter().copied().collect::<Vec<_>>();
+        while let Some(point) = stack.pop() {
+            for &prev in predecessors.get(point).into_iter().flatten() {
+                if halting.insert(prev) {
+                    stack.push(prev);
+                }
+            }
+        }
+
+        let cyclic = components(graph, &points)
+            .into_iter()
+            .filter(|component| component.len() > 1 || graph[&component[0]].contains(&component[0]))
+            .collect::<Vec<_>>();
+
+        let mut loops = cyclic
+            .iter()
+            .filter(|component| component.iter().all(|point| !halting.contains(&point)))
+            .map(|component| classify(cfg, component))
+            .collect::<Vec<_>>();
+        loops.sort_by(|x, y| x.blocks.cmp(&y.blocks));
+        loops.dedup();
+
+        let entry = entry_node(cfg).map(|node| (node, PointerState::default()));
+        let verdict = match entry {
+            _ if cyclic.is_empty() => Verdict::Terminates,
+            Some(entry) if !halting.contains(&&entry) => Verdict::RunsForever,
+            _ => Verdict::Unknown,
+        };
+
+        TerminationReport { verdict, loops }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use piet_core::settings::{ErrorPolicy, InputPolicy, InputSettings};
+    use piet_core::test_util::{block, connect};
+
+    #[test]
+    fn test_acyclic_terminates() {
+        let (entry, a) = (block("Entry", 1), block("a", 1));
+        let mut cfg = CFG::new();
+        connect(&mut cfg, &entry, &a, Some(Instruction::Push));
+        let report = Termination::run(&cfg, &Default::default(), &mut AnalysisCache::default());
+        assert_eq!(report.verdict, Verdict::Terminates);
+        assert!(report.loops.is_empty());
+    }
+
+    #[test]
+    fn test_finds_inescapable_loop() {
+        let (entry, a, b) = (block("Entry", 1), block("a", 1), block("b", 1));
+        let mut cfg = CFG::new();
+        connect(&mut cfg, &entry, &a, None);
+        connect(&mut cfg, &a, &b, Some(Instruction::Push));
+        connect(&mut cfg, &b, &a, Some(Instruction::Pop));
+        let report = Termination::run(&cfg, &Default::default(), &mut AnalysisCache::default());
+        assert_eq!(report.verdict, Verdict::RunsForever);
+        assert_eq!(
+            report.loops,
+            vec![InfiniteLoop {
+                kind: LoopKind::Straight,
+                blocks: vec!["a".into(), "b".into()],
+                reads_input: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_stopping_instructions_leave_loop() {
+        let (entry, a, b) = (block("Entry", 1), block("a", 1), block("b", 1));
+        let mut cfg = CFG::new();
+        connect(&mut cfg, &entry, &a, None);
+        connect(&mut cfg, &a, &b, Some(Instruction::IntIn));
+        connect(&mut cfg, &b, &a, Some(Instruction::Pop));
+        let report = Termination::run(&cfg, &Default::default(), &mut AnalysisCache::default());
+        assert_eq!(report.verdict, Verdict::RunsForever);
+        assert!(report.loops[0].reads_input);
+
+        // The program stops when its input runs out
+        let input = InputSettings {
+            on_eof: InputPolicy::Terminate,
+            ..Default::default()
+        };
+        let report = Termination::run(
+            &cfg,
+            &RuntimeSettings {
+                input,
+                ..Default::default()
+            },
+            &mut AnalysisCache::default(),
+        );
+        assert_eq!(report.verdict, Verdict::Unknown);
+        assert!(report.loops.is_empty());
+
+        // Pop underflows when input is skipped, which stops the program
+        let report = Termination::run(
+            &cfg,
+            &RuntimeSettings {
+                error_policy: ErrorPolicy::Abort,
+                ..Default::default()
+            },
+            &mut AnalysisCache::default(),
+        );
+        assert_eq!(report.verdict, Verdict::Unknown);
+        assert!(report.loops.is_empty());
+    }
+}
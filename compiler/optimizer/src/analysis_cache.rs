@@ -1,22 +1,199 @@
-use crate::result::ExecutionResult;
+use crate::analysis::{Analysis, RuntimeSettings};
+use piet_core::cfg::CFG;
+use std::any::{Any, TypeId};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 
-#[derive(Debug, Clone)]
+/// Set of analyses that are still valid after a pass has run.  Anything not listed here is dropped
+/// from the cache by the pass manager, mirroring LLVM's `PreservedAnalyses`.
+#[derive(Debug, Clone, Default)]
+pub struct PreservedAnalyses {
+    all: bool,
+    preserved: HashSet<TypeId>,
+}
+
+impl PreservedAnalyses {
+    /// The pass did not touch the CFG, so every cached analysis stays valid
+    pub fn all() -> Self {
+        Self {
+            all: true,
+            preserved: HashSet::new(),
+        }
+    }
+
+    /// The pass may have changed anything, so every cached analysis is invalidated
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    pub fn preserve<A: Analysis>(mut self) -> Self {
+        self.preserved.insert(TypeId::of::<A>());
+        self
+    }
+
+    pub fn preserves_all(&self) -> bool {
+        self.all
+    }
+
+    pub fn is_preserved<A: Analysis>(&self) -> bool {
+        self.all || self.preserved.contains(&TypeId::of::<A>())
+    }
+
+    fn is_preserved_id(&self, id: &TypeId) -> bool {
+        self.all || self.preserved.contains(id)
+    }
+}
+
+struct CachedAnalysis {
+    name: &'static str,
+    // What the result was computed under
+    settings: RuntimeSettings,
+    result: Box<dyn Any>,
+}
+
+/// Typed map from an analysis to its most recent result on the CFG being optimized
+#[derive(Default)]
 pub struct AnalysisCache {
-    pub(crate) result: Option<ExecutionResult>,
+    results: HashMap<TypeId, CachedAnalysis>,
 }
 
 impl AnalysisCache {
-    pub fn get_cached_result(&self) -> Option<&ExecutionResult> {
-        self.result.as_ref()
+    pub fn get<A: Analysis>(&self) -> Option<&A::Result> {
+        self.results
+            .get(&TypeId::of::<A>())
+            .and_then(|cached| cached.result.downcast_ref::<A::Result>())
+    }
+
+    /// Returns the cached result for `A`, running the analysis first if it isn't cached, or was cached
+    /// under different settings
+    pub fn get_or_compute<A: Analysis>(
+        &mut self,
+        cfg: &CFG,
+        settings: &RuntimeSettings,
+    ) -> &A::Result {
+        let cached = self.results.get(&TypeId::of::<A>());
+        if cached.is_none_or(|cached| cached.settings != *settings) {
+            let result = A::run(cfg, settings, self);
+            self.insert::<A>(settings, result);
+        }
+        self.get::<A>().unwrap()
+    }
+
+    pub fn insert<A: Analysis>(&mut self, settings: &RuntimeSettings, result: A::Result) {
+        self.results.insert(
+            TypeId::of::<A>(),
+            CachedAnalysis {
+                name: A::name(),
+                settings: *settings,
+                result: Box::new(result),
+            },
+        );
+    }
+
+    pub fn contains<A: Analysis>(&self) -> bool {
+        self.results.contains_key(&TypeId::of::<A>())
+    }
+
+    pub fn invalidate<A: Analysis>(&mut self) {
+        self.results.remove(&TypeId::of::<A>());
     }
 
-    pub fn update_result(&mut self, result: ExecutionResult) {
-        self.result = Some(result)
+    /// Drops every cached result that isn't in `preserved`
+    pub fn retain(&mut self, preserved: &PreservedAnalyses) {
+        if !preserved.preserves_all() {
+            self.results.retain(|id, _| preserved.is_preserved_id(id));
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.results.clear()
+    }
+}
+
+impl fmt::Debug for AnalysisCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set()
+            .entries(self.results.values().map(|cached| cached.name))
+            .finish()
     }
 }
 
-impl Default for AnalysisCache {
-    fn default() -> Self {
-        Self { result: None }
+#[cfg(test)]
+mod test {
+    use super::*;
+    use piet_core::settings::DivisionSemantics;
+
+    struct Counter;
+    struct Doubled;
+
+    impl Analysis for Counter {
+        type Result = usize;
+
+        fn name() -> &'static str {
+            "counter"
+        }
+
+        fn run(cfg: &CFG, settings: &RuntimeSettings, _: &mut AnalysisCache) -> Self::Result {
+            cfg.len() + 1 + settings.division as usize
+        }
+    }
+
+    impl Analysis for Doubled {
+        type Result = usize;
+
+        fn name() -> &'static str {
+            "doubled"
+        }
+
+        fn run(cfg: &CFG, settings: &RuntimeSettings, cache: &mut AnalysisCache) -> Self::Result {
+            cache.get_or_compute::<Counter>(cfg, settings) * 2
+        }
+    }
+
+    #[test]
+    fn test_get_or_compute_caches_dependencies() {
+        let cfg = CFG::new();
+        let mut cache = AnalysisCache::default();
+
+        assert_eq!(cache.get::<Counter>(), None);
+        assert_eq!(
+            *cache.get_or_compute::<Doubled>(&cfg, &Default::default()),
+            2
+        );
+        assert_eq!(cache.get::<Counter>(), Some(&1));
+    }
+
+    #[test]
+    fn test_recompute_under_other_settings() {
+        let cfg = CFG::new();
+        let mut cache = AnalysisCache::default();
+        let euclidean = RuntimeSettings {
+            division: DivisionSemantics::Euclid,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            *cache.get_or_compute::<Counter>(&cfg, &Default::default()),
+            1
+        );
+        assert_eq!(*cache.get_or_compute::<Counter>(&cfg, &euclidean), 2);
+        assert_eq!(cache.get::<Counter>(), Some(&2));
+    }
+
+    #[test]
+    fn test_retain_preserved() {
+        let cfg = CFG::new();
+        let mut cache = AnalysisCache::default();
+        cache.get_or_compute::<Doubled>(&cfg, &Default::default());
+
+        cache.retain(&PreservedAnalyses::all());
+        assert!(cache.contains::<Counter>() && cache.contains::<Doubled>());
+
+        cache.retain(&PreservedAnalyses::none().preserve::<Counter>());
+        assert!(cache.contains::<Counter>());
+        assert!(!cache.contains::<Doubled>());
+
+        cache.retain(&PreservedAnalyses::none());
+        assert!(!cache.contains::<Counter>());
     }
 }
@@ -1,6 +1,8 @@
-mod analysis_cache;
+pub mod analysis;
+pub mod analysis_cache;
 pub mod error;
 pub mod manager;
+pub mod partial_eval;
 pub mod pass;
 pub mod result;
 pub mod static_eval;
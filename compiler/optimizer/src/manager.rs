@@ -1,3 +1,4 @@
+use crate::analysis::RuntimeSettings;
 use crate::{analysis_cache::AnalysisCache, pass::Pass};
 use piet_core::cfg::CFG;
 use piet_core::settings::CompilerSettings;
@@ -12,10 +13,13 @@ pub struct OptimizationPassManager<'a> {
 
 impl<'a> OptimizationPassManager<'a> {
     pub fn run_all(&mut self, cfg: &mut CFG) {
+        let runtime_settings = RuntimeSettings::from(&self.settings);
         for pass in &mut self.passes {
-            if let Err(err) = pass.run(cfg, &mut self.analysis_cache) {
+            if let Err(err) = pass.run(cfg, &runtime_settings, &mut self.analysis_cache) {
                 eprintln!("Error while running optimization pass: {}", err)
             }
+            // Even a failed pass may have partially rewritten the CFG
+            self.analysis_cache.retain(&pass.preserved_analyses());
         }
     }
 
@@ -30,4 +34,8 @@ impl<'a> OptimizationPassManager<'a> {
     pub fn get_analysis_cache(&self) -> &AnalysisCache {
         &self.analysis_cache
     }
+
+    pub fn get_analysis_cache_mut(&mut self) -> &mut AnalysisCache {
+        &mut self.analysis_cache
+    }
 }
@@ -0,0 +1,415 @@
+use crate::result::{ResidualOp, ResidualProgram};
+use piet_core::cfg::{next_transition, CFG};
+use piet_core::flow::{PointerState, DIRECTIONS};
+use piet_core::instruction::Instruction;
+use piet_core::settings::{DivisionSemantics, InputSettings, InvalidCharPolicy};
+use piet_core::state::{ExecutionState, ExecutionStatus};
+use std::collections::VecDeque;
+
+/// Upper bound on the number of residual operations, so a long-running program doesn't unroll into a
+/// huge straight-line function
+pub const MAX_RESIDUAL_OPS: usize = 10000;
+
+/// The Piet stack during partial evaluation.  Values read from input are only known at runtime and live
+/// on the runtime stack, while everything computed at compile time is kept in `known`.  Known values are
+/// always above dynamic ones: before anything touches the runtime stack, `known` is materialized by pushing
+/// it, so the runtime stack always holds the bottom elements.  How many it holds is only known to be
+/// within `dynamic` once the runtime may have skipped an instruction, e.g. a read at EOF.
+#[derive(Debug, Default)]
+struct SymbolicStack {
+    // Least and greatest number of elements on the runtime stack
+    dynamic: (usize, usize),
+    // Front is the top of the stack, as in `ExecutionState`
+    known: VecDeque<i64>,
+}
+
+impl SymbolicStack {
+    // Least and greatest depth of the whole stack
+    fn depth(&self) -> (usize, usize) {
+        let (min, max) = self.dynamic;
+        (min + self.known.len(), max + self.known.len())
+    }
+}
+
+enum Step {
+    Continue,
+    // The transition can't be evaluated without knowing runtime values
+    Blocked,
+}
+
+/// Evaluates a program as far as possible at compile time, treating input as unknown.  Execution continues
+/// past input as long as control flow doesn't depend on it, and every operation on input-derived values
+/// is emitted as residual code specialised on the values that are known.
+///
+/// Residual code runs on the compiled runtime, so operations are folded with its semantics: instructions
+/// with too few operands and divisions by zero leave the stack untouched.  When the runtime may skip an
+/// instruction, as with input that can be skipped at EOF or on bad input, the depth of the stack afterwards
+/// is only known to be in a range.  Evaluation carries on regardless, and instructions that might underflow
+/// are emitted as residual code, which checks the depth at runtime.
+pub struct PartialEvaluator<'a> {
+    cfg: &'a CFG,
+    max_steps: u64,
+    stack: SymbolicStack,
+    pointers: PointerState,
+    ops: Vec<ResidualOp>,
+    steps: u64,
+    division: DivisionSemantics,
+    input: InputSettings,
+    invalid_char: InvalidCharPolicy,
+}
+
+impl<'a> PartialEvaluator<'a> {
+    pub fn new(cfg: &'a CFG, max_steps: u64) -> Self {
+        Self {
+            cfg,
+            max_steps,
+            stack: SymbolicStack::default(),
+            pointers: PointerState::default(),
+            ops: vec![],
+            steps: 0,
+            division: DivisionSemantics::default(),
+            input: InputSettings::default(),
+            invalid_char: InvalidCharPolicy::default(),
+        }
+    }
+
+    /// Folds `Div` and `Mod` with `division` rather than the default semantics
+    pub fn with_division(mut self, division: DivisionSemantics) -> Self {
+        self.division = division;
+        self
+    }
+
+    /// Reads input the way `input` says the compiled program will
+    pub fn with_input(mut self, input: InputSettings) -> Self {
+        self.input = input;
+        self
+    }
+
+    /// Prints values that aren't characters the way `invalid_char` says the compiled program will
+    pub fn with_invalid_char(mut self, invalid_char: InvalidCharPolicy) -> Self {
+        self.invalid_char = invalid_char;
+        self
+    }
+
+    pub fn run(mut self) -> ResidualProgram {
+        let mut block = self
+            .cfg
+            .keys()
+            .find(|node| *node.get_label() == "Entry")
+            .unwrap()
+            .clone();
+        // As in the interpreter, a run of transitions without instructions longer than the number of
+        // control states can only be a loop
+        let idle_limit = DIRECTIONS.len() * self.cfg.len();
+        let mut idle = 0;
+
+        let status = loop {
+            if self.steps == self.max_steps || self.ops.len() >= MAX_RESIDUAL_OPS {
+                break ExecutionStatus::MaxSteps;
+            }
+
+            let Some((next, transition)) = next_transition(self.cfg, &block, self.pointers) else {
+                break ExecutionStatus::Completed;
+            };
+
+            if let Some(instr) = transition.instruction {
+                let pointers = self.pointers;
+                self.pointers = transition.exit_state;
+                if let Step::Blocked = self.exec(instr, block.get_region_size()) {
+                    // Generic code will pick the same transition again and run the instruction itself
+                    self.pointers = pointers;
+                    break ExecutionStatus::Running;
+                }
+                self.steps += 1;
+                idle = 0;
+            } else if idle == idle_limit {
+                break ExecutionStatus::MaxSteps;
+            } else {
+                self.pointers = transition.exit_state;
+                idle += 1;
+            }
+
+            block = next;
+        };
+
+        self.materialize();
+
+        ResidualProgram {
+            ops: self.ops,
+            resume_state: ExecutionState {
+                pointers: self.pointers,
+                cb_count: block.get_region_size(),
+                cb_label: block.get_label().clone(),
+                steps: self.steps,
+                status,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn materialize(&mut self) {
+        while let Some(val) = self.stack.known.pop_back() {
+            self.ops.push(ResidualOp::Push(val));
+            self.stack.dynamic.0 += 1;
+            self.stack.dynamic.1 += 1;
+        }
+    }
+
+    fn print(&mut self, output: String) {
+        match self.ops.last_mut() {
+            Some(ResidualOp::Print(prev)) => prev.push_str(&output),
+            _ => self.ops.push(ResidualOp::Print(output)),
+        }
+    }
+
+    fn exec(&mut self, instr: Instruction, cb_count: u64) -> Step {
+        let (pops, pushes) = match instr {
+            Instruction::Push | Instruction::CharIn | Instruction::IntIn => (0, 1),
+            Instruction::Pop | Instruction::Ptr | Instruction::Swi => (1, 0),
+            Instruction::CharOut | Instruction::IntOut => (1, 0),
+            Instruction::Not => (1, 1),
+            Instruction::Dup => (1, 2),
+            Instruction::Roll => (2, 0),
+            Instruction::Add
+            | Instruction::Sub
+            | Instruction::Mul
+            | Instruction::Div
+            | Instruction::Mod
+            | Instruction::Gt => (2, 1),
+        };
+
+        // Instructions that are sure to underflow are skipped
+        let (min, max) = self.stack.depth();
+        if max < pops {
+            return Step::Continue;
+        }
+
+        let is_input = matches!(instr, Instruction::CharIn | Instruction::IntIn);
+        if !is_input && self.stack.known.len() >= pops && !self.roll_reaches_dynamic(instr) {
+            self.fold(instr, cb_count);
+            return Step::Continue;
+        }
+
+        // The greatest depth the stack is left at if the runtime skips the instruction, leaving it as is
+        let skipped = match instr {
+            // Either of these would make the control flow depend on runtime values
+            Instruction::Ptr | Instruction::Swi => return Step::Blocked,
+            Instruction::CharIn | Instruction::IntIn if self.input.may_skip() => Some(max),
+            Instruction::Div | Instruction::Mod if self.stack.known.is_empty() => Some(max),
+            Instruction::Div | Instruction::Mod if self.stack.known[0] == 0 => {
+                return Step::Continue
+            }
+            _ if min < pops => Some(pops - 1),
+            _ => None,
+        };
+
+        // Roll pops both its arguments whether or not the depth is valid
+        let runs = |depth: usize| depth - pops + pushes;
+        let (least, greatest) = (runs(min.max(pops)), runs(max));
+        self.materialize();
+        self.ops.push(ResidualOp::Exec(instr));
+        self.stack.dynamic = match skipped {
+            Some(skipped) => (least.min(min), greatest.max(skipped)),
+            None => (least, greatest),
+        };
+        Step::Continue
+    }
+
+    // Whether a roll with known arguments might permute values that are only known at runtime
+    fn roll_reaches_dynamic(&self, instr: Instruction) -> bool {
+        let known = &self.stack.known;
+        if instr != Instruction::Roll || known.len() < 2 {
+            return false;
+        }
+        let depth = known[1];
+        let (_, max) = self.stack.depth();
+        depth > 0 && depth as usize <= max - 2 && depth as usize > known.len() - 2
+    }
+
+    // Evaluates an instruction whose operands are all known
+    fn fold(&mut self, instr: Instruction, cb_count: u64) {
+        let stack = &mut self.stack.known;
+        match instr {
+            Instruction::Push => stack.push_front(cb_count as i64),
+            Instruction::Pop => {
+                stack.pop_front();
+            }
+            Instruction::Add => {
+                let (a, b) = (stack.pop_front().unwrap(), stack.pop_front().unwrap());
+                stack.push_front(b.wrapping_add(a))
+            }
+            Instruction::Sub => {
+                let (a, b) = (stack.pop_front().unwrap(), stack.pop_front().unwrap());
+                stack.push_front(b.wrapping_sub(a))
+            }
+            Instruction::Mul => {
+                let (a, b) = (stack.pop_front().unwrap(), stack.pop_front().unwrap());
+                stack.push_front(b.wrapping_mul(a))
+            }
+            Instruction::Div | Instruction::Mod if stack[0] == 0 => (),
+            Instruction::Div => {
+                let (a, b) = (stack.pop_front().unwrap(), stack.pop_front().unwrap());
+                stack.push_front(self.division.div(b, a).unwrap())
+            }
+            Instruction::Mod => {
+                let (a, b) = (stack.pop_front().unwrap(), stack.pop_front().unwrap());
+                stack.push_front(self.division.rem(b, a).unwrap())
+            }
+            Instruction::Not => {
+                let a = stack.pop_front().unwrap();
+                stack.push_front((a == 0) as i64)
+            }
+            Instruction::Gt => {
+                let (a, b) = (stack.pop_front().unwrap(), stack.pop_front().unwrap());
+                stack.push_front((b > a) as i64)
+            }
+            Instruction::Ptr => {
+                let a = stack.pop_front().unwrap();
+                self.pointers.dp = self.pointers.dp.rotate(a)
+            }
+            Instruction::Swi => {
+                let a = stack.pop_front().unwrap();
+                self.pointers.cc = self.pointers.cc.switch(a)
+            }
+            Instruction::Dup => stack.push_front(stack[0]),
+            Instruction::Roll => {
+                let (rolls, depth) = (stack.pop_front().unwrap(), stack.pop_front().unwrap());
+                if depth > 0 && depth as usize <= stack.len() {
+                    let rolls = rolls.rem_euclid(depth) as usize;
+                    stack.make_contiguous()[..depth as usize].rotate_left(rolls);
+                }
+            }
+            Instruction::CharOut => {
+                let a = stack.pop_front().unwrap();
+                if let Some(c) = self.invalid_char.char(a) {
+                    self.print(c.to_string())
+                }
+            }
+            Instruction::IntOut => {
+                let a = stack.pop_front().unwrap();
+                self.print(a.to_string())
+            }
+            Instruction::CharIn | Instruction::IntIn => unreachable!("input is never known"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use piet_core::settings::InputPolicy;
+    use piet_core::test_util::{block, chain};
+
+    // Input that always pushes, so evaluation can carry on past it
+    const PUSH_ZERO: InputSettings = InputSettings {
+        on_eof: InputPolicy::PushZero,
+        on_bad_input: InputPolicy::PushZero,
+    };
+
+    #[test]
+    fn test_residualizes_past_input() {
+        let blocks = [
+            block("Entry", 1),
+            block("a", 72),
+            block("b", 1),
+            block("c", 1),
+            block("d", 1),
+        ];
+        let cfg = chain(
+            &blocks,
+            &[
+                Instruction::IntIn,
+                Instruction::Push,
+                Instruction::CharOut,
+                Instruction::IntOut,
+            ],
+        );
+        let residual = PartialEvaluator::new(&cfg, 100).with_input(PUSH_ZERO).run();
+
+        assert_eq!(
+            residual.ops,
+            vec![
+                ResidualOp::Exec(Instruction::IntIn),
+                ResidualOp::Print("H".into()),
+                ResidualOp::Exec(Instruction::IntOut),
+            ]
+        );
+        assert_eq!(residual.resume_state.cb_label, "d");
+        assert_eq!(residual.resume_state.status, ExecutionStatus::Completed);
+    }
+
+    #[test]
+    fn test_materializes_known_values_below_input() {
+        let blocks = [
+            block("Entry", 3),
+            block("a", 1),
+            block("b", 1),
+            block("c", 1),
+        ];
+        let cfg = chain(
+            &blocks,
+            &[Instruction::Push, Instruction::CharIn, Instruction::Add],
+        );
+        let residual = PartialEvaluator::new(&cfg, 100).with_input(PUSH_ZERO).run();
+
+        assert_eq!(
+            residual.ops,
+            vec![
+                ResidualOp::Push(3),
+                ResidualOp::Exec(Instruction::CharIn),
+                ResidualOp::Exec(Instruction::Add),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stops_on_input_dependent_control_flow() {
+        let blocks = [block("Entry", 1), block("a", 1), block("b", 1)];
+        let cfg = chain(&blocks, &[Instruction::IntIn, Instruction::Ptr]);
+        let residual = PartialEvaluator::new(&cfg, 100).with_input(PUSH_ZERO).run();
+
+        assert_eq!(residual.ops, vec![ResidualOp::Exec(Instruction::IntIn)]);
+        assert_eq!(residual.resume_state.cb_label, "a");
+        assert_eq!(residual.resume_state.status, ExecutionStatus::Running);
+    }
+
+    #[test]
+    fn test_residualizes_past_input_that_may_be_skipped() {
+        let blocks = [
+            block("Entry", 2),
+            block("a", 1),
+            block("b", 72),
+            block("c", 1),
+            block("d", 1),
+            block("e", 1),
+            block("f", 1),
+        ];
+        let cfg = chain(
+            &blocks,
+            &[
+                Instruction::Push,
+                Instruction::IntIn,
+                Instruction::Push,
+                Instruction::CharOut,
+                Instruction::Add,
+                Instruction::IntOut,
+            ],
+        );
+        // With the default settings the read may push nothing
+        let residual = PartialEvaluator::new(&cfg, 100).run();
+
+        // Whether the add has two operands is only known at runtime
+        assert_eq!(
+            residual.ops,
+            vec![
+                ResidualOp::Push(2),
+                ResidualOp::Exec(Instruction::IntIn),
+                ResidualOp::Print("H".into()),
+                ResidualOp::Exec(Instruction::Add),
+                ResidualOp::Exec(Instruction::IntOut),
+            ]
+        );
+        assert_eq!(residual.resume_state.cb_label, "f");
+        assert_eq!(residual.resume_state.status, ExecutionStatus::Completed);
+    }
+}
@@ -1,8 +1,20 @@
-use crate::analysis_cache::AnalysisCache;
+use crate::analysis::RuntimeSettings;
+use crate::analysis_cache::{AnalysisCache, PreservedAnalyses};
 use piet_core::cfg::CFG;
 use std::{error::Error, fmt::Debug};
 
 pub trait Pass: Debug {
     fn name(&self) -> &'static str;
-    fn run(&mut self, cfg: &mut CFG, manager: &mut AnalysisCache) -> Result<(), Box<dyn Error>>;
+    fn run(
+        &mut self,
+        cfg: &mut CFG,
+        settings: &RuntimeSettings,
+        manager: &mut AnalysisCache,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Analyses that remain valid after this pass runs.  Passes that mutate the CFG must leave out
+    /// anything their changes could affect, so the default conservatively preserves nothing.
+    fn preserved_analyses(&self) -> PreservedAnalyses {
+        PreservedAnalyses::none()
+    }
 }
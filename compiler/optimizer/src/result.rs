@@ -1,7 +1,29 @@
+use piet_core::instruction::Instruction;
 use piet_core::state::ExecutionState;
 
 #[derive(Debug, Clone)]
 pub enum ExecutionResult {
     Complete(ExecutionState),
     Partial(ExecutionState),
+    Residual(ResidualProgram),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResidualOp {
+    /// Pushes a value that was computed at compile time onto the runtime stack
+    Push(i64),
+    /// Runs an instruction against the runtime stack
+    Exec(Instruction),
+    /// Prints output that was computed at compile time
+    Print(String),
+}
+
+/// Straight-line code left over after partially evaluating a program whose control flow doesn't depend on
+/// its input until `resume_state`.  The generic transition code takes over from there.
+#[derive(Debug, Clone)]
+pub struct ResidualProgram {
+    pub ops: Vec<ResidualOp>,
+    /// Block and pointers to resume from.  Its stack and stdout are always empty since `ops`
+    /// materializes everything that was computed at compile time.
+    pub resume_state: ExecutionState,
 }
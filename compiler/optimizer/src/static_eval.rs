@@ -1,6 +1,8 @@
 use std::error::Error;
 
-use crate::error::OptimizerError;
+use crate::analysis::{Analysis, RuntimeSettings};
+use crate::analysis_cache::PreservedAnalyses;
+use crate::partial_eval::PartialEvaluator;
 
 use crate::{analysis_cache::AnalysisCache, pass::Pass, result::ExecutionResult};
 use interpreter::interpreter::Interpreter as StaticEvaluator;
@@ -10,8 +12,42 @@ use piet_core::{cfg::CFG, state::ExecutionStatus};
 #[derive(Debug)]
 pub struct StaticEvaluatorPass;
 
+/// Result of running the program at compile time for up to `MAX_STEPS` steps.  Programs that read input are
+/// partially evaluated into residual code instead.
+#[derive(Debug)]
+pub struct StaticEvaluation;
+
 pub const MAX_STEPS: u64 = 200000;
 
+impl Analysis for StaticEvaluation {
+    type Result = ExecutionResult;
+
+    fn name() -> &'static str {
+        "static_eval"
+    }
+
+    fn run(cfg: &CFG, settings: &RuntimeSettings, _: &mut AnalysisCache) -> Self::Result {
+        let codel_settings = piet_core::settings::CodelSettings::Default;
+        let static_eval_settings = StaticEvaluatorSettings {
+            invalid_char: settings.invalid_char,
+            ..StaticEvaluatorSettings::abstract_interp(MAX_STEPS, codel_settings, settings.division)
+        };
+        let mut static_eval = StaticEvaluator::new(cfg, static_eval_settings);
+        let execution_state = static_eval.run();
+        match execution_state.status {
+            ExecutionStatus::Completed => ExecutionResult::Complete(execution_state),
+            ExecutionStatus::NeedsInput => {
+                let partial_eval = PartialEvaluator::new(cfg, MAX_STEPS)
+                    .with_division(settings.division)
+                    .with_input(settings.input)
+                    .with_invalid_char(settings.invalid_char);
+                ExecutionResult::Residual(partial_eval.run())
+            }
+            _ => ExecutionResult::Partial(execution_state),
+        }
+    }
+}
+
 impl Pass for StaticEvaluatorPass {
     fn name(&self) -> &'static str {
         "static_eval"
@@ -20,25 +56,14 @@ impl Pass for StaticEvaluatorPass {
     fn run(
         &mut self,
         cfg: &mut CFG,
+        settings: &RuntimeSettings,
         analysis_cache: &mut AnalysisCache,
     ) -> Result<(), Box<dyn Error>> {
-        let codel_settings = piet_core::settings::CodelSettings::Default;
-        let static_eval_settings =
-            StaticEvaluatorSettings::abstract_interp(MAX_STEPS, codel_settings);
-        let mut static_eval = StaticEvaluator::new(cfg, static_eval_settings);
-        let execution_state = static_eval.run();
-        match execution_state.status {
-            ExecutionStatus::Completed => {
-                Ok(analysis_cache.update_result(ExecutionResult::Complete(execution_state)))
-            }
-            ExecutionStatus::MaxSteps => {
-                Ok(analysis_cache.update_result(ExecutionResult::Partial(execution_state)))
-            }
-            ExecutionStatus::NeedsInput => Err(Box::new(OptimizerError::StaticEvaluationError(
-                "Static evaluation on input-dependent programs not yet implemented.  Will compile with partial execution result.".into(),
-                ExecutionResult::Partial(execution_state),
-            ))),
-            _ => Ok(()),
-        }
+        analysis_cache.get_or_compute::<StaticEvaluation>(cfg, settings);
+        Ok(())
+    }
+
+    fn preserved_analyses(&self) -> PreservedAnalyses {
+        PreservedAnalyses::all()
     }
 }
@@ -0,0 +1,175 @@
+use piet_core::cfg::Node;
+use piet_core::coverage::Coverage;
+use piet_core::flow::PointerState;
+use piet_core::instruction::Instruction;
+use piet_core::state::{ExecutionState, ExecutionStatus};
+use std::collections::VecDeque;
+
+/// How many elements from the top of `stack` executing `instr` can remove or change.  Everything below them
+/// is left as it was.
+fn operands(instr: Instruction, stack: &VecDeque<i64>) -> usize {
+    let count = match instr {
+        Instruction::Push | Instruction::Dup | Instruction::CharIn | Instruction::IntIn => 0,
+        Instruction::Pop
+        | Instruction::Not
+        | Instruction::Ptr
+        | Instruction::Swi
+        | Instruction::CharOut
+        | Instruction::IntOut => 1,
+        Instruction::Add
+        | Instruction::Sub
+        | Instruction::Mul
+        | Instruction::Div
+        | Instruction::Mod
+        | Instruction::Gt => 2,
+        // Roll pops its two arguments, then rotates the next n elements if n is in range
+        Instruction::Roll => match stack.get(1) {
+            Some(&n) if n >= 0 && n as usize <= stack.len() - 2 => 2 + n as usize,
+            _ => 2,
+        },
+    };
+    count.min(stack.len())
+}
+
+/// What's needed to put the interpreter back to how it was before one step.  Rather than the whole stack,
+/// only the elements the step's instruction could have touched are kept.
+#[derive(Debug, Clone)]
+pub(crate) struct Undo {
+    block: Option<Node>,
+    idle: usize,
+    pointers: PointerState,
+    cb_count: u64,
+    cb_label: String,
+    steps: u64,
+    status: ExecutionStatus,
+    stdout_len: usize,
+    stderr_len: usize,
+    stack_len: usize,
+    operands: Vec<i64>,
+    // Input read by the step, along with the buffer it was read from
+    input: Option<(String, Option<VecDeque<u8>>)>,
+    // Visits the step counted in the coverage, to each block from the block before it, if any
+    visits: Vec<(Option<Node>, Node)>,
+}
+
+impl Undo {
+    pub(crate) fn new(
+        state: &ExecutionState,
+        block: Option<Node>,
+        idle: usize,
+        stderr_len: usize,
+    ) -> Self {
+        Self {
+            block,
+            idle,
+            pointers: state.pointers,
+            cb_count: state.cb_count,
+            cb_label: state.cb_label.clone(),
+            steps: state.steps,
+            status: state.status,
+            stdout_len: state.stdout.len(),
+            stderr_len,
+            stack_len: state.stack.len(),
+            operands: Vec::new(),
+            input: None,
+            visits: Vec::new(),
+        }
+    }
+
+    /// Keeps what executing `instr` is about to change.  Must be called before the instruction runs.
+    pub(crate) fn save_operands(
+        &mut self,
+        instr: Instruction,
+        state: &ExecutionState,
+        input: &Option<VecDeque<u8>>,
+    ) {
+        let count = operands(instr, &state.stack);
+        self.operands = state.stack.range(..count).copied().collect();
+        if let Instruction::CharIn | Instruction::IntIn = instr {
+            self.input = Some((state.stdin.clone(), input.clone()));
+        }
+    }
+
+    /// Keeps a visit the step counted in the coverage, from `from` or from nowhere at the start of the run
+    pub(crate) fn save_visit(&mut self, from: Option<&Node>, to: &Node) {
+        self.visits.push((from.cloned(), to.clone()));
+    }
+
+    /// Restores `state`, `coverage` and the errors reported in `stderr`, and returns the block and idle count
+    /// the interpreter had before the step
+    pub(crate) fn restore(
+        self,
+        state: &mut ExecutionState,
+        input: &mut Option<VecDeque<u8>>,
+        coverage: &mut Option<Coverage>,
+        stderr: &mut String,
+    ) -> (Option<Node>, usize) {
+        // Whatever the instruction left on top replaced the operands it took
+        let below = self.stack_len - self.operands.len();
+        state.stack.drain(..state.stack.len() - below);
+        for &n in self.operands.iter().rev() {
+            state.stack.push_front(n);
+        }
+
+        if let Some((stdin, buffer)) = self.input {
+            state.stdin = stdin;
+            if buffer.is_some() {
+                *input = buffer;
+            }
+        }
+        if let Some(coverage) = coverage {
+            for (from, to) in &self.visits {
+                match from {
+                    Some(from) => coverage.forget_transition(from, to),
+                    None => coverage.forget_entry(to),
+                }
+            }
+        }
+        state.stdout.truncate(self.stdout_len);
+        stderr.truncate(self.stderr_len);
+        state.pointers = self.pointers;
+        state.cb_count = self.cb_count;
+        state.cb_label = self.cb_label;
+        state.steps = self.steps;
+        state.status = self.status;
+        (self.block, self.idle)
+    }
+}
+
+/// The undo records of the most recent steps, oldest first, dropping the oldest past `limit`
+#[derive(Debug, Clone)]
+pub(crate) struct History {
+    limit: usize,
+    undos: VecDeque<Undo>,
+}
+
+impl History {
+    pub(crate) fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            undos: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn push(&mut self, undo: Undo) {
+        if self.limit == 0 {
+            return;
+        }
+        if self.undos.len() == self.limit {
+            self.undos.pop_front();
+        }
+        self.undos.push_back(undo);
+    }
+
+    pub(crate) fn last_mut(&mut self) -> Option<&mut Undo> {
+        self.undos.back_mut()
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<Undo> {
+        self.undos.pop_back()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.undos.len()
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::VecDeque;
+use std::io::{self, BufRead};
+
+/// What an input instruction read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Input {
+    Value(i64),
+    Eof,
+    Bad,
+}
+
+/// Reads input a byte at a time from the interpreter's input buffer, or stdin if there isn't one, keeping
+/// the bytes it consumes
+pub(crate) struct Reader<'a> {
+    input: &'a mut Option<VecDeque<u8>>,
+    pub(crate) consumed: Vec<u8>,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(input: &'a mut Option<VecDeque<u8>>) -> Self {
+        Self {
+            input,
+            consumed: Vec::new(),
+        }
+    }
+
+    // A failure to read stdin is treated as the end of input
+    fn peek(&mut self) -> Option<u8> {
+        match self.input {
+            Some(input) => input.front().copied(),
+            None => io::stdin()
+                .lock()
+                .fill_buf()
+                .ok()
+                .and_then(|buf| buf.first().copied()),
+        }
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let byte = self.peek()?;
+        match self.input {
+            Some(input) => {
+                input.pop_front();
+            }
+            None => io::stdin().lock().consume(1),
+        }
+        self.consumed.push(byte);
+        Some(byte)
+    }
+
+    /// Reads an integer for `IntIn`, as described by `InputSettings`
+    pub(crate) fn read_int(&mut self) -> Input {
+        let mut byte = self.next();
+        while byte.is_some_and(is_space) {
+            byte = self.next();
+        }
+        let Some(first) = byte else {
+            return Input::Eof;
+        };
+
+        let negative = first == b'-';
+        if first == b'-' || first == b'+' {
+            byte = self.next();
+        }
+        // Accumulated with the number's sign, so that i64::MIN can be read
+        let mut value = None;
+        while let Some(digit) = byte.filter(u8::is_ascii_digit) {
+            let digit = (digit - b'0') as i64;
+            value = match value {
+                None if negative => Some(-digit),
+                None => Some(digit),
+                Some(value) if negative => value
+                    .checked_mul(10)
+                    .and_then(|value| value.checked_sub(digit)),
+                Some(value) => value
+                    .checked_mul(10)
+                    .and_then(|value| value.checked_add(digit)),
+            };
+            if value.is_none() {
+                break;
+            }
+            byte = self.next();
+        }
+
+        match (value, byte) {
+            (Some(value), None) => Input::Value(value),
+            (Some(value), Some(b'\n')) => Input::Value(value),
+            (Some(value), Some(byte)) if is_space(byte) => {
+                while let Some(byte) = self.peek().filter(|&byte| is_space(byte)) {
+                    self.next();
+                    if byte == b'\n' {
+                        break;
+                    }
+                }
+                Input::Value(value)
+            }
+            _ => {
+                while byte.is_some_and(|byte| byte != b'\n') {
+                    byte = self.next();
+                }
+                Input::Bad
+            }
+        }
+    }
+
+    /// Reads a UTF-8 encoded character for `CharIn`, as described by `InputSettings`
+    pub(crate) fn read_char(&mut self) -> Input {
+        let Some(lead) = self.next() else {
+            return Input::Eof;
+        };
+        let len = match lead {
+            0x00..=0x7f => 1,
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => return Input::Bad,
+        };
+
+        let mut bytes = vec![lead];
+        while bytes.len() < len {
+            match self.peek() {
+                Some(byte) if byte & 0xc0 == 0x80 => {
+                    self.next();
+                    bytes.push(byte);
+                }
+                _ => return Input::Bad,
+            }
+        }
+        // Rejects overlong encodings, surrogates and code points that are too large
+        match std::str::from_utf8(&bytes) {
+            Ok(c) => Input::Value(c.chars().next().unwrap() as i64),
+            Err(_) => Input::Bad,
+        }
+    }
+}
+
+// Whitespace as C's isspace has it, which includes vertical tab
+fn is_space(byte: u8) -> bool {
+    matches!(byte, b' ' | b'\t' | b'\n' | 0x0b | 0x0c | b'\r')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Reads everything in `input` with `read`, returning what was read and what was left after each
+    fn read_all(input: &[u8], read: impl Fn(&mut Reader) -> Input) -> Vec<(Input, String)> {
+        let mut buffer = Some(input.iter().copied().collect::<VecDeque<_>>());
+        let mut results = vec![];
+        loop {
+            let read = read(&mut Reader::new(&mut buffer));
+            let rest = buffer.as_ref().unwrap().iter().copied().collect::<Vec<_>>();
+            let done = read == Input::Eof;
+            results.push((read, String::from_utf8_lossy(&rest).into_owned()));
+            if done {
+                return results;
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_int() {
+        use Input::*;
+
+        let results = read_all(
+            b" 12 -3\n+4\t \n\nx5 6\n-\n99999999999999999999 7\n12a 8",
+            |reader| reader.read_int(),
+        );
+        let expected = [
+            (
+                Value(12),
+                "-3\n+4\t \n\nx5 6\n-\n99999999999999999999 7\n12a 8",
+            ),
+            (Value(-3), "+4\t \n\nx5 6\n-\n99999999999999999999 7\n12a 8"),
+            (Value(4), "\nx5 6\n-\n99999999999999999999 7\n12a 8"),
+            (Bad, "-\n99999999999999999999 7\n12a 8"),
+            (Bad, "99999999999999999999 7\n12a 8"),
+            (Bad, "12a 8"),
+            (Bad, ""),
+            (Eof, ""),
+        ];
+        for ((read, rest), (expected_read, expected_rest)) in results.iter().zip(expected) {
+            assert_eq!(*read, expected_read);
+            assert_eq!(rest, expected_rest);
+        }
+        assert_eq!(results.len(), expected.len());
+
+        let results = read_all(b"-9223372036854775808 9223372036854775807", |reader| {
+            reader.read_int()
+        });
+        assert_eq!(results[0].0, Value(i64::MIN));
+        assert_eq!(results[1].0, Value(i64::MAX));
+    }
+
+    #[test]
+    fn test_read_char() {
+        use Input::*;
+
+        let results = read_all("a é€😀".as_bytes(), |reader| reader.read_char());
+        let chars = results.iter().map(|(read, _)| read).collect::<Vec<_>>();
+        assert_eq!(
+            chars,
+            [
+                &Value(97),
+                &Value(32),
+                &Value(0xe9),
+                &Value(0x20ac),
+                &Value(0x1f600),
+                &Eof
+            ]
+        );
+
+        // A stray continuation byte, a sequence cut short, an overlong encoding, a surrogate, and a code point
+        // past U+10FFFF
+        let results = read_all(b"\x80\xc3a\xc0\xaf\xed\xa0\x80\xf4\x90\x80\x80", |reader| {
+            reader.read_char()
+        });
+        let chars = results.iter().map(|(read, _)| read).collect::<Vec<_>>();
+        assert_eq!(chars, [&Bad, &Bad, &Value(97), &Bad, &Bad, &Bad, &Eof]);
+    }
+}
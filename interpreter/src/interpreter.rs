@@ -1,19 +1,49 @@
-use piet_core::cfg::{Node, CFG};
+use crate::history::{History, Undo};
+use crate::input::{Input, Reader};
+use piet_core::cfg::{next_transition, Node, CFG};
+use piet_core::coverage::Coverage;
 use piet_core::error::ExecutionError;
-use piet_core::flow::find_offset;
+use piet_core::flow::DIRECTIONS;
 use piet_core::instruction::*;
-use piet_core::settings::{InterpreterSettings, Verbosity};
+use piet_core::settings::{
+    ErrorPolicy, InputPolicy, InterpreterSettings, InvalidCharPolicy, Verbosity,
+};
 use piet_core::state::{ExecutionState, ExecutionStatus};
+use piet_core::trace::{trace_line, transition_line};
 use std::collections::VecDeque;
 use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
 use std::io::Write;
-use std::{io, io::Read};
+
+// Where transitions are logged when tracing
+struct Trace(Box<dyn Write>);
+
+impl fmt::Debug for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Trace")
+    }
+}
 
 #[derive(Debug)]
 pub struct Interpreter<'a> {
     cfg: &'a CFG,
     state: ExecutionState,
     settings: InterpreterSettings,
+    // Input to read from instead of stdin, if any
+    input: Option<VecDeque<u8>>,
+    // Block and edge counts, if they're being collected
+    coverage: Option<Coverage>,
+    // Block the program is in, once it has started
+    block: Option<Node>,
+    // Transitions without an instruction since the last one with
+    idle: usize,
+    // Undo records for stepping backwards, if they're being kept
+    history: Option<History>,
+    trace: Option<Trace>,
+    // Lines reported under the error policy
+    stderr: String,
 }
 
 impl<'a> Interpreter<'a> {
@@ -22,35 +52,78 @@ impl<'a> Interpreter<'a> {
             cfg,
             state: ExecutionState::default(),
             settings,
+            input: None,
+            coverage: None,
+            block: None,
+            idle: 0,
+            history: None,
+            trace: None,
+            stderr: String::new(),
+        }
+    }
+
+    /// Reads program input from `input` instead of stdin
+    pub fn with_input(mut self, input: &str) -> Self {
+        self.input = Some(input.bytes().collect());
+        self
+    }
+
+    /// Counts how many times each block is entered and each edge taken while running
+    pub fn with_coverage(mut self) -> Self {
+        self.coverage = Some(Coverage::new());
+        self
+    }
+
+    /// Writes a line to `out` for every transition taken, before its instruction runs, in the format of
+    /// `piet_core::trace`.  Programs compiled with tracing write the same lines.
+    pub fn with_trace(mut self, out: impl Write + 'static) -> Self {
+        self.trace = Some(Trace(Box::new(out)));
+        self
+    }
+
+    /// Keeps enough of each of the last `limit` steps to undo them with `step_back`
+    pub fn with_history(mut self, limit: usize) -> Self {
+        self.history = Some(History::new(limit));
+        self
+    }
+
+    /// Starts from `state` instead of the entry, e.g. one restored from a snapshot.  A state that stopped at
+    /// a step limit carries on from where it was.  Fails if the CFG has no block labelled `state.cb_label`.
+    pub fn with_state(mut self, mut state: ExecutionState) -> Result<Self, String> {
+        let block = self
+            .cfg
+            .keys()
+            .find(|node| *node.get_label() == state.cb_label)
+            .ok_or_else(|| format!("program has no block `{}`", state.cb_label))?;
+        if state.status == ExecutionStatus::MaxSteps {
+            state.status = ExecutionStatus::Running;
         }
+
+        self.block = Some(block.clone());
+        self.state = state;
+        Ok(self)
+    }
+
+    /// Writes the current state to `filename`, to be resumed with `with_state`
+    pub fn save_snapshot(&self, filename: &str) -> io::Result<()> {
+        fs::write(filename, self.state.to_snapshot())
+    }
+
+    pub fn get_coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// The warnings and errors reported under the error policy so far, one per line, as compiled programs
+    /// write them to stderr
+    pub fn get_stderr(&self) -> &str {
+        &self.stderr
     }
 
     pub fn next_block(&mut self, block: Node) -> (Option<Node>, Option<Instruction>) {
-        let bordering = &mut self.cfg.get(&block).unwrap();
-        let mut directions = vec![];
-
-        for adj in bordering.keys() {
-            directions.extend(bordering.get(&*adj).unwrap().iter().map(|transition| {
-                return (
-                    transition.entry_state,
-                    transition.exit_state,
-                    adj,
-                    transition.instruction,
-                );
-            }));
-        }
-
-        // Calculates the block who is the minimum amount of rotations away from the current entry direction
-        // Want min exit conditioned on min entry
-        let curr = self.state.pointers;
-        match directions.into_iter().min_by_key(|&(entry, exit, _, _)| {
-            let entry_offset = find_offset(curr, entry);
-            let exit_offset = find_offset(entry, exit);
-            (entry_offset, exit_offset)
-        }) {
-            Some((_, exit, adj, instr)) => {
-                self.state.pointers = exit;
-                (Some(adj.clone()), instr)
+        match next_transition(self.cfg, &block, self.state.pointers) {
+            Some((adj, transition)) => {
+                self.state.pointers = transition.exit_state;
+                (Some(adj), transition.instruction)
             }
             None => (None, None),
         }
@@ -67,7 +140,7 @@ impl<'a> Interpreter<'a> {
     pub fn exec_instr(&mut self, instr: Instruction) -> Result<(), ExecutionError> {
         Ok(match instr {
             Instruction::Push => self.push(self.state.cb_count),
-            Instruction::Pop => self.pop(),
+            Instruction::Pop => self.pop()?,
             Instruction::Add => self.add()?,
             Instruction::Sub => self.sub()?,
             Instruction::Mul => self.mul()?,
@@ -80,9 +153,9 @@ impl<'a> Interpreter<'a> {
             Instruction::Dup => self.dup()?,
             Instruction::Roll => self.roll()?,
             Instruction::CharIn => self.char_in()?,
-            Instruction::CharOut => self.char_out(),
+            Instruction::CharOut => self.char_out()?,
             Instruction::IntIn => self.int_in()?,
-            Instruction::IntOut => self.int_out(),
+            Instruction::IntOut => self.int_out()?,
         })
     }
 
@@ -92,8 +165,15 @@ impl<'a> Interpreter<'a> {
     }
 
     #[inline]
-    pub(crate) fn pop(&mut self) {
-        self.state.stack.pop_front();
+    pub(crate) fn pop(&mut self) -> Result<(), ExecutionError> {
+        if self.state.stack.pop_front().is_some() {
+            Ok(())
+        } else {
+            Err(ExecutionError::StackOutOfBoundsError(
+                Instruction::Pop,
+                "Skipping Pop since Pop requires at least 1 element on stack but found none".into(),
+            ))
+        }
     }
 
     #[inline]
@@ -101,7 +181,7 @@ impl<'a> Interpreter<'a> {
         if self.state.stack.len() >= 2 {
             let a = self.state.stack.pop_front().unwrap();
             let b = self.state.stack.pop_front().unwrap();
-            Ok(self.state.stack.push_front(a + b))
+            Ok(self.state.stack.push_front(a.wrapping_add(b)))
         } else {
             Err(ExecutionError::StackOutOfBoundsError(
                 Instruction::Add,
@@ -118,7 +198,7 @@ impl<'a> Interpreter<'a> {
         if self.state.stack.len() >= 2 {
             let a = self.state.stack.pop_front().unwrap();
             let b = self.state.stack.pop_front().unwrap();
-            Ok(self.state.stack.push_front(b - a))
+            Ok(self.state.stack.push_front(b.wrapping_sub(a)))
         } else {
             Err(ExecutionError::StackOutOfBoundsError(
                 Instruction::Sub,
@@ -135,7 +215,7 @@ impl<'a> Interpreter<'a> {
         if self.state.stack.len() >= 2 {
             let a = self.state.stack.pop_front().unwrap();
             let b = self.state.stack.pop_front().unwrap();
-            Ok(self.state.stack.push_front(b * a))
+            Ok(self.state.stack.push_front(b.wrapping_mul(a)))
         } else {
             Err(ExecutionError::StackOutOfBoundsError(
                 Instruction::Mul,
@@ -149,51 +229,45 @@ impl<'a> Interpreter<'a> {
 
     #[inline]
     pub(crate) fn div(&mut self) -> Result<(), ExecutionError> {
-        if self.state.stack.len() >= 2 {
-            let a = self.state.stack.pop_front().unwrap();
-            let b = self.state.stack.pop_front().unwrap();
-
-            if a > 0 {
-                Ok(self.state.stack.push_front(b / a))
-            } else {
-                Err(ExecutionError::DivisionByZeroError(
-                    Instruction::Div,
-                    format!("Attempted to divide {b} by 0"),
-                ))
-            }
-        } else {
-            Err(ExecutionError::StackOutOfBoundsError(
-                Instruction::Div,
-                format!(
-                    "Skipping Div since Div requires at least 2 elements on stack but found {}",
-                    self.state.stack.len()
-                ),
-            ))
-        }
+        self.divide(Instruction::Div)
     }
 
     #[inline]
     pub(crate) fn rem(&mut self) -> Result<(), ExecutionError> {
-        if self.state.stack.len() >= 2 {
-            let a = self.state.stack.pop_front().unwrap();
-            let b = self.state.stack.pop_front().unwrap();
+        self.divide(Instruction::Mod)
+    }
 
-            if a > 0 {
-                Ok(self.state.stack.push_front(b.rem_euclid(a)))
-            } else {
-                Err(ExecutionError::DivisionByZeroError(
-                    Instruction::Mod,
-                    format!("Attempted to divide {b} by 0"),
-                ))
-            }
-        } else {
-            Err(ExecutionError::StackOutOfBoundsError(
-                Instruction::Mod,
+    // Div and Mod follow the configured division semantics, and leave the stack untouched when the divisor
+    // is zero
+    fn divide(&mut self, instr: Instruction) -> Result<(), ExecutionError> {
+        if self.state.stack.len() < 2 {
+            return Err(ExecutionError::StackOutOfBoundsError(
+                instr,
                 format!(
-                    "Skipping Mod since Mod requires at least 2 elements on stack but found {}",
+                    "Skipping {:?} since {:?} requires at least 2 elements on stack but found {}",
+                    instr,
+                    instr,
                     self.state.stack.len()
                 ),
-            ))
+            ));
+        }
+
+        let (a, b) = (self.state.stack[0], self.state.stack[1]);
+        let division = self.settings.division;
+        let result = match instr {
+            Instruction::Div => division.div(b, a),
+            _ => division.rem(b, a),
+        };
+        match result {
+            Some(result) => {
+                self.state.stack.pop_front();
+                self.state.stack[0] = result;
+                Ok(())
+            }
+            None => Err(ExecutionError::DivisionByZeroError(
+                instr,
+                format!("Attempted to divide {b} by 0"),
+            )),
         }
     }
 
@@ -203,7 +277,7 @@ impl<'a> Interpreter<'a> {
             Ok(self.state.stack.push_front(if a != 0 { 0 } else { 1 }))
         } else {
             Err(ExecutionError::StackOutOfBoundsError(
-                Instruction::Ptr,
+                Instruction::Not,
                 "Skipping Not since not requires at least 1 element on stack but found none".into(),
             ))
         }
@@ -277,10 +351,9 @@ impl<'a> Interpreter<'a> {
                 .map(|&x| x)
                 .collect::<VecDeque<_>>();
             let rest = self.state.stack.range(n as usize..);
-            if a < 0 {
-                top_n.rotate_right((a.abs() % top_n.len() as i64) as usize);
-            } else {
-                top_n.rotate_left((a % top_n.len() as i64) as usize);
+            // Rolling to depth 0 does nothing
+            if !top_n.is_empty() {
+                top_n.rotate_left(a.rem_euclid(top_n.len() as i64) as usize);
             }
 
             top_n.extend(rest);
@@ -303,63 +376,86 @@ impl<'a> Interpreter<'a> {
             self.state.status = ExecutionStatus::NeedsInput;
             return Ok(());
         }
-        io::stdin()
-            .read_line(&mut self.state.stdin)
-            .expect("Failed to read input");
-        if let Ok(n) = self.state.stdin.trim().parse::<i64>() {
-            Ok(self.state.stack.push_front(n))
-        } else {
-            Err(ExecutionError::ParseError(
-                Instruction::IntIn,
-                "Error parsing int input".into(),
-            ))
-        }
+        let mut reader = Reader::new(&mut self.input);
+        let input = reader.read_int();
+        self.state.stdin = String::from_utf8_lossy(&reader.consumed).into_owned();
+        self.push_input(Instruction::IntIn, input)
     }
 
     #[inline]
     pub(crate) fn char_in(&mut self) -> Result<(), ExecutionError> {
         self.state.stdin.clear();
-
         if self.settings.abstract_interp {
             self.state.status = ExecutionStatus::NeedsInput;
             return Ok(());
         }
+        let mut reader = Reader::new(&mut self.input);
+        let input = reader.read_char();
+        self.state.stdin = String::from_utf8_lossy(&reader.consumed).into_owned();
+        self.push_input(Instruction::CharIn, input)
+    }
 
-        let char = io::stdin()
-            .bytes()
-            .next()
-            .and_then(|result| result.ok())
-            .map(|byte| byte as i64);
-
-        if let Some(c) = char {
-            Ok(self.state.stack.push_front(c))
-        } else {
-            Err(ExecutionError::ParseError(
-                Instruction::IntIn,
-                "Error parsing char input".into(),
-            ))
+    // Pushes what an input instruction read, or does what the input settings say at EOF or on bad input
+    fn push_input(&mut self, instr: Instruction, input: Input) -> Result<(), ExecutionError> {
+        let (policy, reason) = match input {
+            Input::Value(n) => {
+                self.state.stack.push_front(n);
+                return Ok(());
+            }
+            Input::Eof => (self.settings.input.on_eof, "No input left"),
+            Input::Bad => (self.settings.input.on_bad_input, "Error parsing input"),
+        };
+        match (policy, policy.value()) {
+            (_, Some(n)) => self.state.stack.push_front(n),
+            (InputPolicy::Terminate, None) => self.state.status = ExecutionStatus::InputFailed,
+            (_, None) => return Err(ExecutionError::ParseError(instr, reason.into())),
         }
+        Ok(())
     }
 
     #[inline]
-    pub(crate) fn int_out(&mut self) {
+    pub(crate) fn int_out(&mut self) -> Result<(), ExecutionError> {
         if let Some(n) = self.state.stack.pop_front() {
             self.state.stdout.push(StdOutWrapper::Int(n));
             if self.settings.print {
                 print!("{n}");
             }
+            Ok(())
+        } else {
+            Err(ExecutionError::StackOutOfBoundsError(
+                Instruction::IntOut,
+                "Skipping IntOut since IntOut requires at least 1 element on stack but found none"
+                    .into(),
+            ))
         }
     }
 
     #[inline]
-    pub(crate) fn char_out(&mut self) {
+    pub(crate) fn char_out(&mut self) -> Result<(), ExecutionError> {
         if let Some(n) = self.state.stack.pop_front() {
-            if let Some(c) = char::from_u32(n as u32) {
-                self.state.stdout.push(StdOutWrapper::Char(c));
-                if self.settings.print {
-                    print!("{c}");
+            let policy = self.settings.invalid_char;
+            match policy.char(n) {
+                Some(c) => {
+                    self.state.stdout.push(StdOutWrapper::Char(c));
+                    if self.settings.print {
+                        print!("{c}");
+                    }
+                }
+                None if policy == InvalidCharPolicy::Error => {
+                    return Err(ExecutionError::InvalidCharError(
+                        Instruction::CharOut,
+                        format!("Skipping CharOut since {n} isn't a Unicode character"),
+                    ))
                 }
+                None => (),
             }
+            Ok(())
+        } else {
+            Err(ExecutionError::StackOutOfBoundsError(
+                Instruction::CharOut,
+                "Skipping CharOut since CharOut requires at least 1 element on stack but found none"
+                    .into(),
+            ))
         }
     }
 
@@ -371,6 +467,169 @@ impl<'a> Interpreter<'a> {
             .clone()
     }
 
+    /// The block the program is in, once it has started
+    pub fn current_block(&self) -> Option<&Node> {
+        self.block.as_ref()
+    }
+
+    /// Takes one transition out of the current block, starting from the entry block, and executes its
+    /// instruction if it has one.  Returns false once the program has stopped, without doing anything.
+    pub fn step(&mut self) -> bool {
+        if self.state.status != ExecutionStatus::Running {
+            return false;
+        }
+        if let Some(history) = &mut self.history {
+            history.push(Undo::new(
+                &self.state,
+                self.block.clone(),
+                self.idle,
+                self.stderr.len(),
+            ));
+        }
+        let block = match &self.block {
+            Some(block) => block.clone(),
+            None => {
+                let entry = self.get_entry();
+                if let Some(coverage) = &mut self.coverage {
+                    coverage.record_entry(&entry);
+                    if let Some(undo) = self.history.as_mut().and_then(History::last_mut) {
+                        undo.save_visit(None, &entry);
+                    }
+                }
+                self.block = Some(entry.clone());
+                entry
+            }
+        };
+
+        io::stdout().flush().unwrap();
+        self.state.cb_count = block.get_region().len() as u64;
+        self.state.cb_label = block.get_label().clone();
+
+        if let Some(max_steps) = self.settings.max_steps {
+            if self.state.steps == max_steps {
+                self.state.status = ExecutionStatus::MaxSteps;
+                return false;
+            }
+        }
+
+        let (next, maybe_instr) = self.next_block(block.clone());
+
+        let Some(next) = next else {
+            self.state.status = ExecutionStatus::Completed;
+            return false;
+        };
+
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record_transition(&block, &next);
+            if let Some(undo) = self.history.as_mut().and_then(History::last_mut) {
+                undo.save_visit(Some(&block), &next);
+            }
+        }
+        // A trace that can't be written to doesn't stop the program
+        if let Some(Trace(out)) = &mut self.trace {
+            let line = transition_line(&block, &next, maybe_instr, self.state.pointers);
+            let top = self.state.stack.front().copied();
+            writeln!(out, "{}", trace_line(&line, top)).ok();
+        }
+        self.block = Some(next.clone());
+
+        // Transitions without an instruction don't count as steps, so bound them separately.  Once
+        // there have been more in a row than there are control states, the program is stuck in a loop.
+        let idle_limit = DIRECTIONS.len() * self.cfg.len();
+        if let Some(instr) = maybe_instr {
+            if let Some(undo) = self.history.as_mut().and_then(History::last_mut) {
+                undo.save_operands(instr, &self.state, &self.input);
+            }
+            let res = self.exec_instr(instr);
+            self.state.steps += 1;
+            if let Err(err) = res {
+                self.report(&block, err);
+            }
+            self.idle = 0;
+        } else if self.idle == idle_limit {
+            self.state.status = ExecutionStatus::MaxSteps;
+            return false;
+        } else {
+            self.idle += 1;
+        }
+
+        // Instructions use the size of the block being left, but the state describes the one entered
+        self.state.cb_count = next.get_region().len() as u64;
+        self.state.cb_label = next.get_label().clone();
+        true
+    }
+
+    // Handles an instruction that couldn't be executed according to the error policy.  Bad input isn't
+    // covered by the policy, and is only reported when verbose.  Warnings and errors are kept for
+    // `get_stderr`, and printed along with the program's output.
+    fn report(&mut self, block: &Node, err: ExecutionError) {
+        let policy = match err {
+            ExecutionError::ParseError(..) => ErrorPolicy::Ignore,
+            _ => self.settings.error_policy,
+        };
+        let message = format!(
+            "{} on {} at step {} in block {}",
+            err.kind(),
+            err.instruction().mnemonic(),
+            self.state.steps,
+            block.get_label()
+        );
+        let line = match policy {
+            ErrorPolicy::Ignore => {
+                if self.settings.verbosity == Verbosity::Verbose {
+                    eprintln!("{:?}", err);
+                }
+                return;
+            }
+            ErrorPolicy::Warn => format!("warning: {message}\n"),
+            ErrorPolicy::Abort => {
+                self.state.status = ExecutionStatus::Aborted;
+                format!("error: {message}\n")
+            }
+        };
+        if self.settings.print {
+            eprint!("{line}");
+        }
+        self.stderr.push_str(&line);
+    }
+
+    /// Undoes the last step taken, including the one that stopped the program.  Returns false without
+    /// doing anything if there's no step left in the history.  Coverage counts and reported errors are
+    /// undone along with the state, but output and trace lines that have been written stay written.
+    pub fn step_back(&mut self) -> bool {
+        let Some(undo) = self.history.as_mut().and_then(History::pop) else {
+            return false;
+        };
+        let (block, idle) = undo.restore(
+            &mut self.state,
+            &mut self.input,
+            &mut self.coverage,
+            &mut self.stderr,
+        );
+        self.block = block;
+        self.idle = idle;
+        true
+    }
+
+    /// Steps back to the last time the program was in the block labelled `label`.  Returns false if the
+    /// history runs out first, leaving the interpreter at the oldest step it has.
+    pub fn step_back_to(&mut self, label: &str) -> bool {
+        while self.step_back() {
+            if self
+                .current_block()
+                .is_some_and(|block| *block.get_label() == label)
+            {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Number of steps that can be undone
+    pub fn history_len(&self) -> usize {
+        self.history.as_ref().map_or(0, History::len)
+    }
+
     pub fn run(&mut self) -> ExecutionState {
         match self.settings.verbosity {
             Verbosity::Verbose => match env::consts::OS {
@@ -390,43 +649,7 @@ impl<'a> Interpreter<'a> {
             _ => (),
         }
 
-        let mut block = self.get_entry();
-
-        loop {
-            io::stdout().flush().unwrap();
-            self.state.cb_count = block.get_region().len() as u64;
-            self.state.cb_label = block.get_label().clone();
-
-            if let Some(max_steps) = self.settings.max_steps {
-                if self.state.steps == max_steps {
-                    self.state.status = ExecutionStatus::MaxSteps;
-                    break;
-                }
-            }
-
-            if self.settings.abstract_interp && self.state.status == ExecutionStatus::NeedsInput {
-                break;
-            }
-
-            let (next, maybe_instr) = self.next_block(block.clone());
-
-            if next.is_none() {
-                self.state.status = ExecutionStatus::Completed;
-                break;
-            }
-
-            block = next.unwrap();
-
-            if let Some(instr) = maybe_instr {
-                let res = self.exec_instr(instr);
-                if let Err(res) = res {
-                    if self.settings.verbosity == Verbosity::Verbose {
-                        eprintln!("{:?}", res);
-                    }
-                }
-                self.state.steps += 1;
-            }
-        }
+        while self.step() {}
 
         self.state.clone()
     }
@@ -435,45 +658,261 @@ impl<'a> Interpreter<'a> {
 #[allow(unused)]
 mod test {
     use super::*;
-    use piet_core::color::Lightness;
 
     #[test]
     fn test_roll() {
         // Setup
-        let vec = Vec::<Lightness>::new();
-        let program = PietSource::new(&vec, 0, 0);
-        let mut interpreter = Interpreter::new(&program, InterpreterSettings::default());
+        let cfg = CFG::new();
+        let mut interpreter = Interpreter::new(&cfg, InterpreterSettings::default());
 
         // Positive roll to depth 2
-        interpreter.stack = VecDeque::from([1, 2, 6, 5]);
-        interpreter.roll();
-        assert_eq!(interpreter.stack.pop_front(), Some(5));
-        assert_eq!(interpreter.stack.pop_front(), Some(6));
+        interpreter.state.stack = VecDeque::from([1, 2, 6, 5]);
+        interpreter.roll().unwrap();
+        assert_eq!(interpreter.state.stack.pop_front(), Some(5));
+        assert_eq!(interpreter.state.stack.pop_front(), Some(6));
 
         // Negative roll to depth 3
-        interpreter.stack = VecDeque::from([-1, 3, 6, 5, 4]);
-        interpreter.roll();
-        assert_eq!(interpreter.stack.pop_front(), Some(4));
-        assert_eq!(interpreter.stack.pop_front(), Some(6));
-        assert_eq!(interpreter.stack.pop_front(), Some(5));
+        interpreter.state.stack = VecDeque::from([-1, 3, 6, 5, 4]);
+        interpreter.roll().unwrap();
+        assert_eq!(interpreter.state.stack.pop_front(), Some(4));
+        assert_eq!(interpreter.state.stack.pop_front(), Some(6));
+        assert_eq!(interpreter.state.stack.pop_front(), Some(5));
 
         // Negative roll to depth 2
-        interpreter.stack = VecDeque::from([-1, 2, 6, 5, 4]);
-        interpreter.roll();
-        assert_eq!(interpreter.stack.pop_front(), Some(5));
-        assert_eq!(interpreter.stack.pop_front(), Some(6));
-        assert_eq!(interpreter.stack.pop_front(), Some(4));
-
-        interpreter.stack = VecDeque::from([1, 3, 6, 5, 4]);
-        interpreter.roll();
-        assert_eq!(interpreter.stack.pop_front(), Some(5));
-        assert_eq!(interpreter.stack.pop_front(), Some(4));
-        assert_eq!(interpreter.stack.pop_front(), Some(6));
-
-        interpreter.stack = VecDeque::from([-1, 2, 6, 5, 4]);
-        interpreter.roll();
-        assert_eq!(interpreter.stack.pop_front(), Some(5));
-        assert_eq!(interpreter.stack.pop_front(), Some(6));
-        assert_eq!(interpreter.stack.pop_front(), Some(4));
+        interpreter.state.stack = VecDeque::from([-1, 2, 6, 5, 4]);
+        interpreter.roll().unwrap();
+        assert_eq!(interpreter.state.stack.pop_front(), Some(5));
+        assert_eq!(interpreter.state.stack.pop_front(), Some(6));
+        assert_eq!(interpreter.state.stack.pop_front(), Some(4));
+
+        interpreter.state.stack = VecDeque::from([1, 3, 6, 5, 4]);
+        interpreter.roll().unwrap();
+        assert_eq!(interpreter.state.stack.pop_front(), Some(5));
+        assert_eq!(interpreter.state.stack.pop_front(), Some(4));
+        assert_eq!(interpreter.state.stack.pop_front(), Some(6));
+
+        interpreter.state.stack = VecDeque::from([-1, 2, 6, 5, 4]);
+        interpreter.roll().unwrap();
+        assert_eq!(interpreter.state.stack.pop_front(), Some(5));
+        assert_eq!(interpreter.state.stack.pop_front(), Some(6));
+        assert_eq!(interpreter.state.stack.pop_front(), Some(4));
+    }
+
+    #[test]
+    fn test_coverage() {
+        use parser::cfg::CFGBuilder;
+        use parser::convert::UnknownPixelSettings;
+        use parser::loader::Loader;
+        use piet_core::settings::CodelSettings;
+
+        // Bounces between the top two blocks, so the dark red block is never reached
+        let program =
+            Loader::convert("../images/bounce.png", UnknownPixelSettings::TreatAsError).unwrap();
+        let mut cfg_builder = CFGBuilder::new(&program, CodelSettings::Width(1), false);
+        cfg_builder.build();
+        let cfg = cfg_builder.get_cfg();
+
+        let settings = InterpreterSettings {
+            max_steps: Some(10),
+            print: false,
+            ..Default::default()
+        };
+        let mut interpreter = Interpreter::new(&cfg, settings).with_coverage();
+        interpreter.run();
+
+        let coverage = interpreter.get_coverage().unwrap();
+        assert_eq!(coverage.block_count("Entry"), 6);
+        assert_eq!(coverage.block_count("RegRed_0_2"), 5);
+        assert_eq!(coverage.block_count("DarkRed_1_0"), 0);
+        assert_eq!(coverage.edge_count("Entry", "RegRed_0_2"), 5);
+        assert_eq!(coverage.edge_count("RegRed_0_2", "Entry"), 5);
+        assert_eq!(coverage.hottest_blocks(1), [("Entry", 6)]);
+        assert_eq!(
+            Coverage::parse(&coverage.to_string()).as_ref(),
+            Ok(coverage)
+        );
+    }
+
+    #[test]
+    fn test_resume_from_snapshot() {
+        use parser::cfg::CFGBuilder;
+        use parser::convert::UnknownPixelSettings;
+        use parser::loader::Loader;
+        use piet_core::settings::CodelSettings;
+
+        let program =
+            Loader::convert("../images/hw1-1.gif", UnknownPixelSettings::TreatAsError).unwrap();
+        let mut cfg_builder = CFGBuilder::new(&program, CodelSettings::Width(1), false);
+        cfg_builder.build();
+        let cfg = cfg_builder.get_cfg();
+        let settings = InterpreterSettings {
+            print: false,
+            ..Default::default()
+        };
+        let expected = Interpreter::new(&cfg, settings).run();
+
+        // Stop part way, save, and carry on from the saved state
+        let path = env::temp_dir().join("piet_snapshot_test.txt");
+        let mut interpreter = Interpreter::new(
+            &cfg,
+            InterpreterSettings {
+                max_steps: Some(20),
+                ..settings
+            },
+        );
+        assert_eq!(interpreter.run().status, ExecutionStatus::MaxSteps);
+        interpreter.save_snapshot(path.to_str().unwrap()).unwrap();
+
+        let snapshot = fs::read_to_string(&path).unwrap();
+        let state = ExecutionState::from_snapshot(&snapshot).unwrap();
+        let resumed = Interpreter::new(&cfg, settings)
+            .with_state(state)
+            .unwrap()
+            .run();
+        assert_eq!(resumed.to_snapshot(), expected.to_snapshot());
+
+        let state = ExecutionState {
+            cb_label: "Nowhere".into(),
+            ..Default::default()
+        };
+        assert!(Interpreter::new(&cfg, settings).with_state(state).is_err());
+    }
+
+    #[test]
+    fn test_step_back() {
+        use parser::cfg::CFGBuilder;
+        use parser::convert::UnknownPixelSettings;
+        use parser::loader::Loader;
+        use piet_core::settings::CodelSettings;
+
+        // Reads input and rolls, so the stack, pointers and input all need undoing
+        let program =
+            Loader::convert("../images/power2.png", UnknownPixelSettings::TreatAsError).unwrap();
+        let mut cfg_builder = CFGBuilder::new(&program, CodelSettings::Infer, false);
+        cfg_builder.build();
+        let cfg = cfg_builder.get_cfg();
+        let settings = InterpreterSettings {
+            print: false,
+            ..Default::default()
+        };
+        let mut interpreter = Interpreter::new(&cfg, settings)
+            .with_input("5\n")
+            .with_history(usize::MAX)
+            .with_coverage();
+
+        // Coverage counts come off with the steps that made them
+        let snapshot = |interpreter: &Interpreter| {
+            let coverage = interpreter.get_coverage().unwrap().clone();
+            (interpreter.state.to_snapshot(), coverage)
+        };
+        let mut states = vec![snapshot(&interpreter)];
+        let mut labels = vec![];
+        while interpreter.step() {
+            states.push(snapshot(&interpreter));
+            labels.push(interpreter.current_block().unwrap().get_label().clone());
+        }
+        assert_eq!(interpreter.state.status, ExecutionStatus::Completed);
+        assert_eq!(interpreter.history_len(), states.len());
+
+        // The step that stopped the program comes off first
+        assert!(interpreter.step_back());
+        assert_eq!(interpreter.state.status, ExecutionStatus::Running);
+        while let Some(state) = states.pop() {
+            assert_eq!(snapshot(&interpreter), state);
+            if !states.is_empty() {
+                assert!(interpreter.step_back());
+            }
+        }
+        assert!(!interpreter.step_back());
+        assert!(interpreter.current_block().is_none());
+        assert_eq!(interpreter.get_coverage(), Some(&Coverage::new()));
+
+        // Going forward again reads the same input and gets to the same place
+        let expected = Interpreter::new(&cfg, settings).with_input("5\n").run();
+        assert_eq!(interpreter.run().to_snapshot(), expected.to_snapshot());
+
+        // After n steps the program is in labels[n - 1]
+        let label = &labels[labels.len() / 2];
+        let last_visit = labels.iter().rposition(|other| other == label).unwrap();
+        assert!(interpreter.step_back_to(label));
+        assert_eq!(interpreter.current_block().unwrap().get_label(), label);
+        assert_eq!(interpreter.history_len(), last_visit + 1);
+        assert!(!interpreter.step_back_to("Nowhere"));
+        assert_eq!(interpreter.history_len(), 0);
+
+        // Only the most recent steps are kept
+        let mut interpreter = Interpreter::new(&cfg, settings)
+            .with_input("5\n")
+            .with_history(3);
+        interpreter.run();
+        assert_eq!(interpreter.history_len(), 3);
+        assert!(interpreter.step_back() && interpreter.step_back() && interpreter.step_back());
+        assert!(!interpreter.step_back());
+
+        // A roll in range only touches its arguments and the elements it rotates
+        interpreter.state.stack = VecDeque::from([1, 3, 6, 5, 4, 7]);
+        let before = interpreter.state.clone();
+        let mut undo = Undo::new(&before, None, 0, 0);
+        undo.save_operands(Instruction::Roll, &before, &None);
+        interpreter.roll().unwrap();
+        assert_eq!(interpreter.state.stack, [5, 4, 6, 7]);
+        undo.restore(
+            &mut interpreter.state,
+            &mut None,
+            &mut None,
+            &mut String::new(),
+        );
+        assert_eq!(interpreter.state.stack, before.stack);
+    }
+
+    #[test]
+    fn test_retry_order() {
+        use piet_core::flow::{PietTransition, PointerState, DIRECTIONS};
+        use piet_core::test_util::block;
+        use std::collections::HashMap;
+
+        // The pointers compiled programs leave a blocked block with: `retry` toggles CC when its counter is
+        // even and rotates DP when it's odd, and the counter starts at 0 in each block
+        let compiled_exit = |mut pointers: PointerState, open: &[PointerState]| {
+            let mut rctr = 0;
+            while !open.contains(&pointers) {
+                if rctr % 2 == 1 {
+                    pointers.dp = pointers.dp.rotate(1);
+                } else {
+                    pointers.cc = pointers.cc.switch(1);
+                }
+                rctr += 1;
+            }
+            pointers
+        };
+
+        // Every start state against every pair of ways out of the block
+        for start in DIRECTIONS {
+            for (i, &x) in DIRECTIONS.iter().enumerate() {
+                for &y in &DIRECTIONS[i + 1..] {
+                    let (entry, a, b) = (block("Entry", 1), block("a", 1), block("b", 1));
+                    let mut cfg = CFG::new();
+                    cfg.insert(
+                        entry.clone(),
+                        HashMap::from([
+                            (a.clone(), vec![PietTransition::new(x, x, None)]),
+                            (b.clone(), vec![PietTransition::new(y, y, None)]),
+                        ]),
+                    );
+                    cfg.insert(a.clone(), HashMap::new());
+                    cfg.insert(b.clone(), HashMap::new());
+
+                    let mut interpreter = Interpreter::new(&cfg, InterpreterSettings::default());
+                    interpreter.state.pointers = start;
+                    let (next, _) = interpreter.next_block(entry);
+                    let expected = compiled_exit(start, &[x, y]);
+                    assert_eq!(
+                        interpreter.state.pointers, expected,
+                        "{start:?}, {x:?}, {y:?}"
+                    );
+                    assert_eq!(next, Some(if expected == x { a } else { b }));
+                }
+            }
+        }
     }
 }
@@ -1 +1,4 @@
+mod history;
+mod input;
 pub mod interpreter;
+pub mod visualise;
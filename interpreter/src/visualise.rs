@@ -0,0 +1,341 @@
+use crate::interpreter::Interpreter;
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::error::{ImageFormatHint, UnsupportedError, UnsupportedErrorKind};
+use image::{Delay, DynamicImage, Frame, ImageError, ImageFormat, Rgb, RgbImage};
+use parser::render::Renderer;
+use piet_core::cfg::Node;
+use piet_core::flow::{DirPointer, FURTHEST, MOVE_IN};
+use piet_core::program::PietSource;
+use piet_core::state::ExecutionState;
+use std::fs::File;
+use std::path::Path;
+use std::time::Duration;
+
+/// 3x5 glyphs for the characters the stack panel needs, one row per byte
+const GLYPHS: [(char, [u8; 5]); 12] = [
+    ('0', [0b111, 0b101, 0b101, 0b101, 0b111]),
+    ('1', [0b010, 0b110, 0b010, 0b010, 0b111]),
+    ('2', [0b111, 0b001, 0b111, 0b100, 0b111]),
+    ('3', [0b111, 0b001, 0b111, 0b001, 0b111]),
+    ('4', [0b101, 0b101, 0b111, 0b001, 0b001]),
+    ('5', [0b111, 0b100, 0b111, 0b001, 0b111]),
+    ('6', [0b111, 0b100, 0b111, 0b101, 0b111]),
+    ('7', [0b111, 0b001, 0b001, 0b001, 0b001]),
+    ('8', [0b111, 0b101, 0b111, 0b101, 0b111]),
+    ('9', [0b111, 0b101, 0b111, 0b001, 0b111]),
+    ('-', [0b000, 0b000, 0b111, 0b000, 0b000]),
+    ('.', [0b000, 0b000, 0b000, 0b000, 0b010]),
+];
+
+/// Size of each pixel of a glyph
+const GLYPH_SCALE: u32 = 2;
+const CHAR_WIDTH: u32 = 4 * GLYPH_SCALE;
+const LINE_HEIGHT: u32 = 7 * GLYPH_SCALE;
+const PADDING: u32 = 3 * GLYPH_SCALE;
+/// Wide enough for any i64
+const PANEL_WIDTH: u32 = 2 * PADDING + 20 * CHAR_WIDTH;
+
+const BLACK: Rgb<u8> = Rgb([0x00, 0x00, 0x00]);
+const WHITE: Rgb<u8> = Rgb([0xFF, 0xFF, 0xFF]);
+const PANEL: Rgb<u8> = Rgb([0xEE, 0xEE, 0xEE]);
+const RULE: Rgb<u8> = Rgb([0xAA, 0xAA, 0xAA]);
+
+/// Draws frames of a program's execution.  Each frame has the program with the current block outlined and
+/// everything else faded, an arrow out of the codel the pointers will leave the block from, with a tick on
+/// the side the codel chooser points to, and a panel with the step count above the stack, top first.
+pub struct Visualiser {
+    codel_size: u32,
+    scale: u32,
+    program: RgbImage,
+    faded: RgbImage,
+}
+
+impl Visualiser {
+    /// Visualises `program`, whose codels are `codel_size` pixels wide, drawing codels `scale` pixels wide.
+    /// Arrows need a scale of at least 4 or so to be visible.
+    pub fn new(program: &PietSource, codel_size: u32, scale: u32) -> Self {
+        let program = Renderer::render(program, codel_size, scale);
+        let mut faded = program.clone();
+        for pixel in faded.pixels_mut() {
+            for channel in pixel.0.iter_mut() {
+                *channel = ((*channel as u32 + 0xFF) / 2) as u8;
+            }
+        }
+
+        Self {
+            codel_size,
+            scale,
+            program,
+            faded,
+        }
+    }
+
+    /// Position of the codel at `pos` in the frame
+    fn frame_pos(&self, (r, c): (u32, u32)) -> (u32, u32) {
+        (
+            r / self.codel_size * self.scale,
+            c / self.codel_size * self.scale,
+        )
+    }
+
+    fn fill(frame: &mut RgbImage, (r, c): (i64, i64), (h, w): (i64, i64), colour: Rgb<u8>) {
+        for y in r.max(0)..(r + h).min(frame.height() as i64) {
+            for x in c.max(0)..(c + w).min(frame.width() as i64) {
+                frame.put_pixel(x as u32, y as u32, colour);
+            }
+        }
+    }
+
+    fn draw_block(&self, frame: &mut RgbImage, block: &Node) {
+        let scale = self.scale as i64;
+        let thickness = (scale / 8).max(1);
+
+        for &pos in block.get_region() {
+            let (r, c) = self.frame_pos(pos);
+            for y in r..r + self.scale {
+                for x in c..c + self.scale {
+                    frame.put_pixel(x, y, *self.program.get_pixel(x, y));
+                }
+            }
+
+            // Outline every side that doesn't border another codel of the block
+            let (r, c) = (r as i64, c as i64);
+            for (dp, (offset, size)) in [
+                ((0, scale - thickness), (scale, thickness)),
+                ((scale - thickness, 0), (thickness, scale)),
+                ((0, 0), (scale, thickness)),
+                ((0, 0), (thickness, scale)),
+            ]
+            .into_iter()
+            .enumerate()
+            {
+                let (y, x) = pos;
+                if !block.contains(MOVE_IN[dp]((y, x, self.codel_size))) {
+                    Self::fill(frame, (r + offset.0, c + offset.1), size, BLACK);
+                }
+            }
+        }
+    }
+
+    fn draw_pointers(&self, frame: &mut RgbImage, block: &Node, state: &ExecutionState) {
+        let pointers = state.pointers;
+        let Some(&exit) = block
+            .get_region()
+            .iter()
+            .max_by_key(FURTHEST[pointers.dp as usize * 2 + pointers.cc as usize])
+        else {
+            return;
+        };
+
+        let scale = self.scale as i64;
+        let (r, c) = self.frame_pos(exit);
+        let centre = (r as i64 + scale / 2, c as i64 + scale / 2);
+        let (dr, dc) = match pointers.dp {
+            DirPointer::Right => (0, 1),
+            DirPointer::Down => (1, 0),
+            DirPointer::Left => (0, -1),
+            DirPointer::Up => (-1, 0),
+        };
+        // Perpendicular pointing to the codel chooser's side, relative to the direction of travel
+        let (pr, pc) = match pointers.cc as u8 {
+            0 => (-dc, dr),
+            _ => (dc, -dr),
+        };
+
+        let len = scale;
+        let head = (scale / 3).max(2);
+        let width = (scale / 12).max(1);
+        // Along the arrow, then across it, outlined in white so it shows up against any colour
+        for (colour, grow) in [(WHITE, 1), (BLACK, 0)] {
+            for u in -grow..=len + grow {
+                let half_width = match len - u < head {
+                    true => (len - u).max(0) + grow,
+                    false => width + grow,
+                };
+                for v in -half_width..=half_width {
+                    let pos = (centre.0 + u * dr + v * pr, centre.1 + u * dc + v * pc);
+                    Self::fill(frame, pos, (1, 1), colour);
+                }
+            }
+            for u in -width - grow..=width + grow {
+                for v in 0..=head + grow {
+                    let pos = (centre.0 + u * dr + v * pr, centre.1 + u * dc + v * pc);
+                    Self::fill(frame, pos, (1, 1), colour);
+                }
+            }
+        }
+    }
+
+    fn draw_text(frame: &mut RgbImage, (r, c): (u32, u32), text: &str) {
+        for (i, ch) in text.chars().enumerate() {
+            let Some((_, rows)) = GLYPHS.iter().find(|(glyph, _)| *glyph == ch) else {
+                continue;
+            };
+            let left = c + i as u32 * CHAR_WIDTH;
+            for (y, bits) in rows.iter().enumerate() {
+                for x in 0..3 {
+                    if bits & (0b100 >> x) != 0 {
+                        let pos = (
+                            (r + y as u32 * GLYPH_SCALE) as i64,
+                            (left + x * GLYPH_SCALE) as i64,
+                        );
+                        let size = (GLYPH_SCALE as i64, GLYPH_SCALE as i64);
+                        Self::fill(frame, pos, size, BLACK);
+                    }
+                }
+            }
+        }
+    }
+
+    fn draw_panel(&self, frame: &mut RgbImage, state: &ExecutionState) {
+        let left = self.program.width();
+        let size = (frame.height() as i64, PANEL_WIDTH as i64);
+        Self::fill(frame, (0, left as i64), size, PANEL);
+
+        Self::draw_text(frame, (PADDING, left + PADDING), &state.steps.to_string());
+        let rule = PADDING + LINE_HEIGHT;
+        Self::fill(
+            frame,
+            (rule as i64, left as i64),
+            (GLYPH_SCALE as i64, PANEL_WIDTH as i64),
+            RULE,
+        );
+
+        let lines = ((frame.height() - rule - PADDING) / LINE_HEIGHT) as usize;
+        for (i, value) in state.stack.iter().enumerate().take(lines) {
+            let text = match i + 1 == lines && state.stack.len() > lines {
+                true => "...".to_string(),
+                false => value.to_string(),
+            };
+            let top = rule + PADDING + i as u32 * LINE_HEIGHT;
+            Self::draw_text(frame, (top, left + PADDING), &text);
+        }
+    }
+
+    /// Draws the program in `block` with `state`
+    pub fn frame(&self, block: &Node, state: &ExecutionState) -> RgbImage {
+        let height = self
+            .program
+            .height()
+            .max(2 * PADDING + 3 * LINE_HEIGHT + GLYPH_SCALE);
+        let mut frame = RgbImage::from_pixel(self.program.width() + PANEL_WIDTH, height, WHITE);
+        for (x, y, pixel) in self.faded.enumerate_pixels() {
+            frame.put_pixel(x, y, *pixel);
+        }
+
+        self.draw_block(&mut frame, block);
+        self.draw_pointers(&mut frame, block, state);
+        self.draw_panel(&mut frame, state);
+        frame
+    }
+
+    /// Runs `interpreter` for up to `max_frames` frames, one for the start and one after each step, and
+    /// writes them to `filename`.  A GIF is written as an animation showing each frame for `delay`, and a
+    /// PNG as a numbered sequence, `<name>_0000.png` onwards.  Returns how many frames were written.
+    pub fn record(
+        &self,
+        interpreter: &mut Interpreter,
+        max_frames: usize,
+        delay: Duration,
+        filename: &str,
+    ) -> Result<usize, ImageError> {
+        let path = Path::new(filename);
+        let format = ImageFormat::from_path(path)?;
+        let mut encoder = match format {
+            ImageFormat::Gif => {
+                let mut encoder = GifEncoder::new_with_speed(File::create(path)?, 30);
+                encoder.set_repeat(Repeat::Infinite)?;
+                Some(encoder)
+            }
+            ImageFormat::Png => None,
+            format => {
+                return Err(ImageError::Unsupported(
+                    UnsupportedError::from_format_and_kind(
+                        format.into(),
+                        UnsupportedErrorKind::Format(ImageFormatHint::Exact(format)),
+                    ),
+                ))
+            }
+        };
+        let stem = path.with_extension("");
+
+        let mut frames = 0;
+        while frames < max_frames {
+            if frames > 0 && !interpreter.step() {
+                break;
+            }
+            let block = interpreter
+                .current_block()
+                .cloned()
+                .unwrap_or_else(|| interpreter.get_entry());
+            let frame = self.frame(&block, &interpreter.get_state());
+
+            match &mut encoder {
+                Some(encoder) => {
+                    let frame = DynamicImage::ImageRgb8(frame).to_rgba8();
+                    let delay = Delay::from_saturating_duration(delay);
+                    encoder.encode_frame(Frame::from_parts(frame, 0, 0, delay))?;
+                }
+                None => frame.save(format!("{}_{:04}.png", stem.display(), frames))?,
+            }
+            frames += 1;
+        }
+
+        Ok(frames)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Visualiser;
+    use crate::interpreter::Interpreter;
+    use image::codecs::gif::GifDecoder;
+    use image::AnimationDecoder;
+    use parser::cfg::CFGBuilder;
+    use parser::convert::UnknownPixelSettings;
+    use parser::loader::Loader;
+    use piet_core::settings::{CodelSettings, InterpreterSettings};
+    use std::env;
+    use std::fs::File;
+    use std::time::Duration;
+
+    #[test]
+    fn test_record() {
+        // Pushes 2, pops it and then does the same again until the frames run out
+        let program =
+            Loader::convert("../images/bounce.png", UnknownPixelSettings::TreatAsError).unwrap();
+        let mut cfg_builder = CFGBuilder::new(&program, CodelSettings::Width(1), false);
+        cfg_builder.build();
+        let cfg = cfg_builder.get_cfg();
+        let settings = InterpreterSettings {
+            print: false,
+            ..Default::default()
+        };
+        let visualiser = Visualiser::new(&program, 1, 16);
+
+        let path = env::temp_dir().join("piet_visualise_test.gif");
+        let mut interpreter = Interpreter::new(&cfg, settings);
+        let frames = visualiser
+            .record(
+                &mut interpreter,
+                5,
+                Duration::from_millis(100),
+                path.to_str().unwrap(),
+            )
+            .unwrap();
+        assert_eq!(frames, 5);
+        let decoded = GifDecoder::new(File::open(&path).unwrap())
+            .unwrap()
+            .into_frames()
+            .collect_frames()
+            .unwrap();
+        assert_eq!(decoded.len(), 5);
+
+        // The entry block is drawn as it is, the rest of the program faded, and the stack is shown
+        let entry = interpreter.get_entry();
+        let frame = visualiser.frame(&entry, &interpreter.get_state());
+        assert_eq!(frame.get_pixel(3, 3).0, [0xFF, 0xC0, 0xC0]);
+        assert_eq!(frame.get_pixel(8, 24).0, [0xDF, 0x7F, 0x7F]);
+        assert_eq!(frame.dimensions(), (48 + super::PANEL_WIDTH, 56));
+    }
+}
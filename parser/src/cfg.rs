@@ -81,27 +81,27 @@ impl<'a> CFGBuilder<'a> {
             .collect::<Vec<_>>()
     }
 
+    // Slides through white codels from `entry` until reaching a coloured block, returning the first codel of
+    // that block and the pointers on entry.  Returns None if the slide revisits a codel with the same DP, which
+    // per the spec means the program terminates.
     pub(crate) fn trace_white(
         &self,
         entry: Position,
         dir: PointerState,
     ) -> Option<(Position, PointerState)> {
-        let (mut x, mut y) = entry;
-        let mut retries = 0;
-
+        let mut pos = entry;
         let mut dp = dir.dp;
         let mut cc = dir.cc;
-        while retries < 8 {
-            let next_pos = Some((x, y, self.codel_width))
-                .map(MOVE_IN[dir.dp as usize])
-                .unwrap();
+        let mut visited = HashSet::new();
 
+        while visited.insert((pos, dp)) {
+            let (x, y) = pos;
+            let next_pos = MOVE_IN[dp as usize]((x, y, self.codel_width));
             let lightness = self.source.get(next_pos);
 
             if lightness.is_none() || lightness == Some(&Black) {
-                dp = dp.rotate(1);
                 cc = cc.switch(1);
-                retries += 1;
+                dp = dp.rotate(1);
                 continue;
             }
 
@@ -109,9 +109,9 @@ impl<'a> CFGBuilder<'a> {
                 return Some((next_pos, PointerState::new(dp, cc)));
             }
 
-            (x, y) = next_pos
+            pos = next_pos
         }
-        return None;
+        None
     }
 
     fn explore_region(&self, entry: Position) -> Node {
@@ -188,6 +188,14 @@ impl<'a> CFGBuilder<'a> {
                                 discovered_regions.insert(new_adj_block.clone());
                                 queue.push_back(new_adj_block)
                             }
+                        } else {
+                            // The trace loops, so leaving through this exit terminates the program
+                            let halt = Rc::new(ColorBlock::halt());
+                            self.cfg.entry(halt.clone()).or_default();
+                            bordering
+                                .entry(halt)
+                                .or_default()
+                                .push(PietTransition::new(dir, dir, None));
                         }
                     }
                     _ => {
@@ -229,12 +237,22 @@ impl<'a> CFGBuilder<'a> {
 mod test {
     use super::*;
     use crate::{convert::UnknownPixelSettings, loader::Loader};
+    use piet_core::color::Hue::*;
+    use piet_core::flow::{CodelChooser, DirPointer};
     use std::{
         collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque},
         fs,
-        hash::Hasher,
+        hash::{Hash, Hasher},
     };
 
+    const SETTINGS: UnknownPixelSettings = UnknownPixelSettings::TreatAsError;
+
+    fn get_hash<T: Hash>(val: &T) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        val.hash(&mut hasher);
+        hasher.finish()
+    }
+
     #[test]
     fn test_colorblock_eq_hash() {
         let cb1 = ColorBlock::new(
@@ -285,4 +303,45 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn test_white_trace_turns() {
+        let prog = Loader::convert("../images/white_turn.png", SETTINGS).unwrap();
+        let mut cfg_gen = CFGBuilder::new(&prog, CodelSettings::Width(1), false);
+        cfg_gen.build();
+
+        let cfg = cfg_gen.get_cfg();
+        let entry = cfg.keys().find(|node| node.get_label() == "Entry").unwrap();
+        let (green, transitions) = cfg[entry].iter().next().unwrap();
+
+        assert_eq!(green.get_label(), "RegGreen_2_0");
+        let right_left = PointerState::new(DirPointer::Right, CodelChooser::Left);
+        let transition = transitions
+            .iter()
+            .find(|transition| transition.entry_state == right_left)
+            .unwrap();
+        assert_eq!(
+            transition.exit_state,
+            PointerState::new(DirPointer::Left, CodelChooser::Left)
+        );
+        // Every exit from the green block slides into a white loop
+        assert!(cfg[green].keys().all(|node| node.is_halt()));
+    }
+
+    #[test]
+    fn test_white_loop_terminates() {
+        let prog = Loader::convert("../images/white_loop.png", SETTINGS).unwrap();
+        let mut cfg_gen = CFGBuilder::new(&prog, CodelSettings::Width(1), false);
+        cfg_gen.build();
+
+        let cfg = cfg_gen.get_cfg();
+        let entry = cfg.keys().find(|node| node.get_label() == "Entry").unwrap();
+        let adjs = &cfg[entry];
+
+        assert_eq!(adjs.len(), 1);
+        let (halt, transitions) = adjs.iter().next().unwrap();
+        assert!(halt.is_halt());
+        assert_eq!(transitions.len(), 2);
+        assert!(cfg[halt].is_empty());
+    }
 }
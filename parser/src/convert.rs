@@ -42,3 +42,31 @@ pub trait ConvertToLightness {
         }
     }
 }
+
+// The inverse of `ConvertToLightness`, for writing programs back out as images
+pub trait ConvertToRgb {
+    fn lightness_to_rgb(lightness: Lightness) -> Rgb<u8> {
+        Rgb(match lightness {
+            Black => [0x00, 0x00, 0x00],
+            White => [0xFF, 0xFF, 0xFF],
+            Light(Red) => [0xFF, 0xC0, 0xC0],
+            Light(Yellow) => [0xFF, 0xFF, 0xC0],
+            Light(Green) => [0xC0, 0xFF, 0xC0],
+            Light(Cyan) => [0xC0, 0xFF, 0xFF],
+            Light(Blue) => [0xC0, 0xC0, 0xFF],
+            Light(Magenta) => [0xFF, 0xC0, 0xFF],
+            Reg(Red) => [0xFF, 0x00, 0x00],
+            Reg(Yellow) => [0xFF, 0xFF, 0x00],
+            Reg(Green) => [0x00, 0xFF, 0x00],
+            Reg(Cyan) => [0x00, 0xFF, 0xFF],
+            Reg(Blue) => [0x00, 0x00, 0xFF],
+            Reg(Magenta) => [0xFF, 0x00, 0xFF],
+            Dark(Red) => [0xC0, 0x00, 0x00],
+            Dark(Yellow) => [0xC0, 0xC0, 0x00],
+            Dark(Green) => [0x00, 0xC0, 0x00],
+            Dark(Cyan) => [0x00, 0xC0, 0xC0],
+            Dark(Blue) => [0x00, 0x00, 0xC0],
+            Dark(Magenta) => [0xC0, 0x00, 0xC0],
+        })
+    }
+}
@@ -32,7 +32,7 @@ pub trait DecodeInstruction {
 #[cfg(test)]
 mod test_parse {
     use super::DecodeInstruction;
-    use crate::convert::ConvertToLightness;
+    use crate::convert::{ConvertToLightness, UnknownPixelSettings};
     use image::Rgb;
     use piet_core::instruction::Instruction;
 
@@ -47,14 +47,15 @@ mod test_parse {
         let l1 = Test::rgb_to_lightness(&pix1, SETTINGS);
         let l2 = Test::rgb_to_lightness(&pix2, SETTINGS);
 
-        assert_eq!(Test::decode_instr(l1, l2), Some(Instruction::Add));
+        // Magenta to blue is 5 steps around the hue cycle
+        assert_eq!(Test::decode_instr(l1, l2), Some(Instruction::CharIn));
 
         let pix1 = Rgb::<u8>([0xFF, 0xC0, 0xFF]);
         let pix2 = Rgb::<u8>([0xC0, 0xFF, 0xFF]);
         let l1 = Test::rgb_to_lightness(&pix1, SETTINGS);
         let l2 = Test::rgb_to_lightness(&pix2, SETTINGS);
 
-        assert_eq!(Test::decode_instr(l1, l2), Some(Instruction::Div))
+        assert_eq!(Test::decode_instr(l1, l2), Some(Instruction::Dup))
     }
 
     #[test]
@@ -81,10 +82,8 @@ mod test_parse {
         let l1 = Test::rgb_to_lightness(&pix1, SETTINGS);
         let l2 = Test::rgb_to_lightness(&pix2, SETTINGS);
 
-        println!("{:?}, {:?}", l1, l2);
-        println!("{:?}", l1 - l2);
-
-        assert_eq!(Test::decode_instr(l1, l2), Some(Instruction::CharOut))
+        // One hue step and two steps darker
+        assert_eq!(Test::decode_instr(l1, l2), Some(Instruction::Mul))
     }
 
     #[test]
@@ -0,0 +1,284 @@
+use piet_core::cfg::{next_transition, Node, CFG};
+use piet_core::flow::{PietTransition, PointerState, DIRECTIONS};
+use piet_core::instruction::Instruction;
+use piet_core::snapshot::{cc_name, dp_name};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Write;
+
+/// Column that comments and transition targets line up at
+const ALIGN: usize = 28;
+
+fn pointers(state: PointerState) -> String {
+    format!("{}/{}", dp_name(state.dp), cc_name(state.cc))
+}
+
+fn describe(node: &Node) -> String {
+    // The halt sink has no codels to give the bounds of
+    if node.is_halt() {
+        return format!("{}: no codels", node.get_label());
+    }
+    let region = node.get_region();
+    let (min_r, min_c) = region
+        .iter()
+        .fold((u32::MAX, u32::MAX), |(r, c), &(pr, pc)| {
+            (r.min(pr), c.min(pc))
+        });
+    let (max_r, max_c) = region
+        .iter()
+        .fold((0, 0), |(r, c), &(pr, pc)| (r.max(pr), c.max(pc)));
+    let size = region.len();
+    let codels = if size == 1 { "codel" } else { "codels" };
+
+    format!(
+        "{}: {}, {} {}, ({},{})..({},{})",
+        node.get_label(),
+        node.get_lightness().to_string(),
+        size,
+        codels,
+        min_r,
+        min_c,
+        max_r,
+        max_c
+    )
+}
+
+fn instruction(node: &Node, transition: &PietTransition) -> String {
+    match transition.instruction {
+        Some(instr @ Instruction::Push) => {
+            format!("{} {}", instr.mnemonic(), node.get_region_size())
+        }
+        Some(instr) => instr.mnemonic().to_string(),
+        None => "nop".to_string(),
+    }
+}
+
+/// The transition taken out of `node` for each pointer state, if any
+fn exits(cfg: &CFG, node: &Node) -> Vec<(PointerState, Node, PietTransition)> {
+    DIRECTIONS
+        .iter()
+        .filter_map(|&state| {
+            next_transition(cfg, node, state).map(|(adj, transition)| (state, adj, transition))
+        })
+        .collect()
+}
+
+/// Pointer states each block can be entered in when running from the entry.  `ptr` and `swi` can leave
+/// the pointers in any direction or either way round.
+fn reachable_states(cfg: &CFG, entry: &Node) -> HashMap<Node, HashSet<PointerState>> {
+    let mut reached: HashMap<Node, HashSet<PointerState>> = HashMap::new();
+    let mut queue = VecDeque::from([(entry.clone(), PointerState::default())]);
+
+    while let Some((node, state)) = queue.pop_front() {
+        if !reached.entry(node.clone()).or_default().insert(state) {
+            continue;
+        }
+        let Some((adj, transition)) = next_transition(cfg, &node, state) else {
+            continue;
+        };
+        let arrival = transition.exit_state;
+        let arrivals = match transition.instruction {
+            Some(Instruction::Ptr) => (0..4)
+                .map(|n| PointerState::new(arrival.dp.rotate(n), arrival.cc))
+                .collect(),
+            Some(Instruction::Swi) => {
+                vec![arrival, PointerState::new(arrival.dp, arrival.cc.switch(1))]
+            }
+            _ => vec![arrival],
+        };
+        if !adj.is_halt() {
+            queue.extend(arrivals.into_iter().map(|state| (adj.clone(), state)));
+        }
+    }
+
+    reached
+}
+
+/// Returns the block `node` always goes to and the instruction it executes on the way, if it does the same
+/// thing in every state it can be entered in
+fn linear_exit(
+    cfg: &CFG,
+    node: &Node,
+    states: Option<&HashSet<PointerState>>,
+) -> Option<(Node, String)> {
+    let mut exits = DIRECTIONS
+        .iter()
+        .filter(|state| states.is_some_and(|states| states.contains(state)))
+        .map(|&state| {
+            next_transition(cfg, node, state)
+                .map(|(adj, transition)| (adj, instruction(node, &transition)))
+        });
+    let first = exits.next()??;
+    exits
+        .all(|exit| exit.as_ref() == Some(&first))
+        .then_some(first)
+}
+
+fn line(listing: &mut String, code: &str, comment: &str) {
+    writeln!(
+        listing,
+        "    {:<width$}; {}",
+        code,
+        comment,
+        width = ALIGN - 4
+    )
+    .unwrap();
+}
+
+fn list_transitions(listing: &mut String, cfg: &CFG, node: &Node) {
+    let exits = exits(cfg, node);
+    if exits.is_empty() {
+        line(listing, "halt", &describe(node));
+    } else {
+        writeln!(listing, "    ; {}", describe(node)).unwrap();
+    }
+
+    for (state, adj, transition) in exits {
+        let code = format!("{:<13}{}", pointers(state), instruction(node, &transition));
+        let arrival = match transition.exit_state == state {
+            true => String::new(),
+            false => format!(" ({})", pointers(transition.exit_state)),
+        };
+        writeln!(
+            listing,
+            "    {:<width$}-> {}{}",
+            code,
+            adj.get_label(),
+            arrival,
+            width = ALIGN - 4
+        )
+        .unwrap();
+    }
+}
+
+/// Produces a listing of every block in the CFG, in the order they're discovered from the entry.  Runs of
+/// blocks that always go on to the next one when the program is run, where the next one has no other way
+/// in, are listed as straight-line code with one instruction per block.  Every other block gets the
+/// transition taken for each pointer state, listed as the state, the instruction and the block it leads
+/// to, along with the state it arrives in when a blocked exit was retried or a white region turned it.
+pub fn disassemble(cfg: &CFG) -> String {
+    let Some(entry) = cfg.keys().find(|node| *node.get_label() == "Entry") else {
+        return String::new();
+    };
+
+    // Blocks in discovery order, following every transition whether or not it can be taken
+    let mut order = vec![entry.clone()];
+    let mut seen = HashSet::from([entry.clone()]);
+    let mut queue = VecDeque::from([entry.clone()]);
+    while let Some(node) = queue.pop_front() {
+        for (_, adj, _) in exits(cfg, &node) {
+            if !adj.is_halt() && seen.insert(adj.clone()) {
+                order.push(adj.clone());
+                queue.push_back(adj);
+            }
+        }
+    }
+
+    // Blocks that can be entered from each block when running the program
+    let reached = reachable_states(cfg, entry);
+    let mut preds: HashMap<Node, HashSet<Node>> = HashMap::new();
+    for (node, states) in &reached {
+        for &state in states {
+            if let Some((adj, _)) = next_transition(cfg, node, state) {
+                preds.entry(adj).or_default().insert(node.clone());
+            }
+        }
+    }
+
+    let mut listing = String::new();
+    let mut listed = HashSet::new();
+    for start in &order {
+        if listed.contains(start) {
+            continue;
+        }
+        writeln!(listing, "{}:", start.get_label()).unwrap();
+
+        let mut node = start.clone();
+        loop {
+            listed.insert(node.clone());
+            let Some((adj, instr)) = linear_exit(cfg, &node, reached.get(&node)) else {
+                list_transitions(&mut listing, cfg, &node);
+                break;
+            };
+
+            line(&mut listing, &instr, &describe(&node));
+            if !adj.is_halt()
+                && !listed.contains(&adj)
+                && preds.get(&adj).map(HashSet::len) == Some(1)
+            {
+                node = adj;
+                continue;
+            }
+            match adj.is_halt() {
+                true => writeln!(listing, "    halt").unwrap(),
+                false => writeln!(listing, "    jmp {}", adj.get_label()).unwrap(),
+            }
+            break;
+        }
+        listing.push('\n');
+    }
+
+    listing
+}
+
+#[cfg(test)]
+mod test {
+    use super::{describe, disassemble};
+    use crate::cfg::CFGBuilder;
+    use crate::convert::UnknownPixelSettings;
+    use crate::loader::Loader;
+    use piet_core::cfg::ColorBlock;
+    use piet_core::settings::CodelSettings;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_disassemble() {
+        // The dark red block is never reached from the entry, so it gets a listing of its own
+        let program =
+            Loader::convert("../images/bounce.png", UnknownPixelSettings::TreatAsError).unwrap();
+        let mut cfg_builder = CFGBuilder::new(&program, CodelSettings::Width(1), false);
+        cfg_builder.build();
+
+        let listing = disassemble(&cfg_builder.get_cfg());
+        let expected = [
+            "Entry:",
+            "    push 2                  ; Entry: LightRed, 2 codels, (0,0)..(0,1)",
+            "    pop                     ; RegRed_0_2: RegRed, 1 codel, (0,2)..(0,2)",
+            "    jmp Entry",
+            "",
+            "DarkRed_1_0:",
+            "    ; DarkRed_1_0: DarkRed, 1 codel, (1,0)..(1,0)",
+            "    right/left   push 1     -> Entry (up/right)",
+            "    right/right  push 1     -> Entry (up/left)",
+            "    down/left    push 1     -> Entry (up/left)",
+            "    down/right   push 1     -> Entry (up/right)",
+            "    left/left    push 1     -> Entry (up/right)",
+            "    left/right   push 1     -> Entry (up/left)",
+            "    up/left      push 1     -> Entry",
+            "    up/right     push 1     -> Entry",
+            "",
+        ];
+        assert_eq!(listing.lines().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_disassemble_halt() {
+        // The entry slides into a white loop, which ends the program in the halt sink
+        let program = Loader::convert(
+            "../images/white_loop.png",
+            UnknownPixelSettings::TreatAsError,
+        )
+        .unwrap();
+        let mut cfg_builder = CFGBuilder::new(&program, CodelSettings::Width(1), false);
+        cfg_builder.build();
+
+        let listing = disassemble(&cfg_builder.get_cfg());
+        let expected = [
+            "Entry:",
+            "    nop                     ; Entry: RegRed, 1 codel, (0,0)..(0,0)",
+            "    halt",
+            "",
+        ];
+        assert_eq!(listing.lines().collect::<Vec<_>>(), expected);
+        assert_eq!(describe(&Rc::new(ColorBlock::halt())), "Halt: no codels");
+    }
+}
@@ -0,0 +1,188 @@
+use crate::render::Renderer;
+use image::RgbImage;
+use piet_core::color::{Hue, Hue::*, Lightness, Lightness::*};
+use piet_core::program::PietSource;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+const HUES: [Hue; 6] = [Red, Yellow, Green, Cyan, Blue, Magenta];
+
+#[derive(Debug, Clone)]
+pub struct GeneratorSettings {
+    /// Program height in codels
+    pub height: u32,
+    /// Program width in codels
+    pub width: u32,
+    pub codel_size: u32,
+    /// Chance that a codel is white
+    pub white_density: f64,
+    /// Chance that a codel is black
+    pub black_density: f64,
+    /// Chance that a codel extends the block above or to the left of it instead of starting a new one
+    pub block_density: f64,
+    /// Relative weights of red, yellow, green, cyan, blue and magenta codels
+    pub hue_weights: [u32; 6],
+    /// Relative weights of light, regular and dark codels
+    pub lightness_weights: [u32; 3],
+}
+
+impl Default for GeneratorSettings {
+    fn default() -> Self {
+        Self {
+            height: 16,
+            width: 16,
+            codel_size: 1,
+            white_density: 0.1,
+            black_density: 0.1,
+            block_density: 0.3,
+            hue_weights: [1; 6],
+            lightness_weights: [1; 3],
+        }
+    }
+}
+
+/// Produces random programs made only of valid Piet colours.  The top left codel is always coloured,
+/// since that's where execution starts.
+pub struct ProgramGenerator {
+    settings: GeneratorSettings,
+    rng: StdRng,
+    hues: WeightedIndex<u32>,
+    lightnesses: WeightedIndex<u32>,
+}
+
+/// A generated program, drawn at its codel size
+#[derive(Debug, Clone)]
+pub struct GeneratedProgram {
+    /// Program height in pixels
+    pub height: u32,
+    /// Program width in pixels
+    pub width: u32,
+    pub codel_size: u32,
+    pub pixels: Vec<Lightness>,
+}
+
+impl GeneratedProgram {
+    pub fn source(&self) -> PietSource<'_> {
+        PietSource::new(&self.pixels, self.height, self.width)
+    }
+
+    pub fn to_image(&self) -> RgbImage {
+        Renderer::render(&self.source(), self.codel_size, self.codel_size)
+    }
+}
+
+impl ProgramGenerator {
+    /// Panics if the program would be empty, or all of the weights for hue or lightness are zero
+    pub fn new(settings: GeneratorSettings, seed: u64) -> Self {
+        assert!(
+            settings.height > 0 && settings.width > 0 && settings.codel_size > 0,
+            "generated programs must have at least one pixel"
+        );
+        let hues = WeightedIndex::new(settings.hue_weights).expect("invalid hue weights");
+        let lightnesses =
+            WeightedIndex::new(settings.lightness_weights).expect("invalid lightness weights");
+
+        Self {
+            settings,
+            rng: StdRng::seed_from_u64(seed),
+            hues,
+            lightnesses,
+        }
+    }
+
+    fn colour(&mut self) -> Lightness {
+        let hue = HUES[self.hues.sample(&mut self.rng)];
+        match self.lightnesses.sample(&mut self.rng) {
+            0 => Light(hue),
+            1 => Reg(hue),
+            _ => Dark(hue),
+        }
+    }
+
+    fn codel(&mut self, above: Option<Lightness>, left: Option<Lightness>) -> Lightness {
+        let neighbour = match (above, left) {
+            (Some(above), Some(left)) => Some(if self.rng.gen_bool(0.5) { above } else { left }),
+            (neighbour, None) | (None, neighbour) => neighbour,
+        };
+        if let Some(neighbour) = neighbour {
+            if self.rng.gen_bool(self.settings.block_density) {
+                return neighbour;
+            }
+        }
+
+        let roll = self.rng.gen::<f64>();
+        if roll < self.settings.white_density {
+            White
+        } else if roll < self.settings.white_density + self.settings.black_density {
+            Black
+        } else {
+            self.colour()
+        }
+    }
+
+    pub fn generate(&mut self) -> GeneratedProgram {
+        let (height, width) = (self.settings.height, self.settings.width);
+        let mut codels = Vec::with_capacity((height * width) as usize);
+
+        for r in 0..height {
+            for c in 0..width {
+                let codel = match (r, c) {
+                    (0, 0) => self.colour(),
+                    _ => {
+                        let above = (r > 0).then(|| codels[((r - 1) * width + c) as usize]);
+                        let left = (c > 0).then(|| codels[(r * width + c - 1) as usize]);
+                        self.codel(above, left)
+                    }
+                };
+                codels.push(codel);
+            }
+        }
+
+        // Scale each codel up to a square of pixels
+        let codel_size = self.settings.codel_size;
+        let pixels = (0..height * codel_size)
+            .flat_map(|y| (0..width * codel_size).map(move |x| (y / codel_size, x / codel_size)))
+            .map(|(r, c)| codels[(r * width + c) as usize])
+            .collect();
+
+        GeneratedProgram {
+            height: height * codel_size,
+            width: width * codel_size,
+            codel_size,
+            pixels,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::convert::{ConvertToLightness, UnknownPixelSettings};
+
+    struct Test;
+    impl ConvertToLightness for Test {}
+
+    #[test]
+    fn test_generate_deterministic() {
+        let settings = GeneratorSettings {
+            height: 5,
+            width: 7,
+            codel_size: 3,
+            ..Default::default()
+        };
+        let program = ProgramGenerator::new(settings.clone(), 42).generate();
+        let again = ProgramGenerator::new(settings, 42).generate();
+        assert_eq!(program.pixels, again.pixels);
+        assert!(!matches!(program.pixels[0], White | Black));
+
+        // The image round trips back to the program
+        let image = program.to_image();
+        assert_eq!(image.dimensions(), (21, 15));
+        let pixels = image
+            .pixels()
+            .map(|pix| Test::rgb_to_lightness(pix, UnknownPixelSettings::TreatAsError))
+            .collect::<Vec<_>>();
+        assert_eq!(pixels, program.pixels);
+    }
+}
@@ -1,6 +1,9 @@
 pub mod convert;
 pub mod decode;
+pub mod disasm;
+pub mod generate;
 pub mod infer;
 pub mod loader;
+pub mod render;
 pub mod cfg;
 pub mod consts;
\ No newline at end of file
@@ -0,0 +1,179 @@
+use crate::convert::ConvertToRgb;
+use crate::infer::InferCodelWidth;
+use image::error::{ImageFormatHint, UnsupportedError, UnsupportedErrorKind};
+use image::{ImageError, ImageFormat, Rgb, RgbImage};
+use piet_core::cfg::CFG;
+use piet_core::coverage::Coverage;
+use piet_core::flow::FindAdj;
+use piet_core::program::PietSource;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Colour the most visited blocks are tinted towards in coverage heatmaps
+const HOT: [u8; 3] = [255, 64, 0];
+
+/// Inverse of `Loader`, for writing programs back out as images
+pub struct Renderer;
+
+impl ConvertToRgb for Renderer {}
+impl FindAdj for Renderer {}
+impl InferCodelWidth for Renderer {}
+
+impl Renderer {
+    /// Draws `program`, whose codels are `codel_size` pixels wide, with codels `scale` pixels wide.  Panics
+    /// if `codel_size` doesn't divide the program's height and width.
+    pub fn render(program: &PietSource, codel_size: u32, scale: u32) -> RgbImage {
+        let (height, width) = program.dimensions();
+        assert!(
+            height % codel_size == 0 && width % codel_size == 0,
+            "codel size must divide program height and width"
+        );
+
+        RgbImage::from_fn(
+            width / codel_size * scale,
+            height / codel_size * scale,
+            |x, y| {
+                let pos = (y / scale * codel_size, x / scale * codel_size);
+                Self::lightness_to_rgb(*program.get(pos).unwrap())
+            },
+        )
+    }
+
+    /// Draws `program` like `render`, but with the blocks of `cfg` that were never executed greyed out and
+    /// the rest tinted towards orange by how often they were entered.  Heat is logarithmic in the visit
+    /// count, so the hot blocks don't wash out everything else.  White and black codels are left alone.
+    pub fn render_coverage(
+        program: &PietSource,
+        cfg: &CFG,
+        coverage: &Coverage,
+        codel_size: u32,
+        scale: u32,
+    ) -> RgbImage {
+        let max = (coverage.max_block_count() as f64).ln_1p();
+        let heat = cfg
+            .keys()
+            .filter(|node| !node.is_halt())
+            .flat_map(|node| {
+                let count = coverage.block_count(node.get_label());
+                let heat = match count {
+                    0 => None,
+                    count => Some((count as f64).ln_1p() / max),
+                };
+                node.get_region().iter().map(move |&pos| (pos, heat))
+            })
+            .collect::<HashMap<_, _>>();
+
+        let mut image = Self::render(program, codel_size, scale);
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            let pos = (y / scale * codel_size, x / scale * codel_size);
+            let Rgb([r, g, b]) = *pixel;
+            match heat.get(&pos) {
+                Some(None) => {
+                    let luma = (r as f64 * 0.299 + g as f64 * 0.587 + b as f64 * 0.114) / 255.0;
+                    pixel.0 = [(160.0 + luma * 64.0) as u8; 3];
+                }
+                Some(Some(heat)) => {
+                    let weight = 0.75 * heat;
+                    for (channel, hot) in pixel.0.iter_mut().zip(HOT) {
+                        *channel = (*channel as f64 * (1.0 - weight) + hot as f64 * weight) as u8;
+                    }
+                }
+                None => (),
+            }
+        }
+
+        image
+    }
+
+    /// Writes `program` to `filename` as a PNG, GIF or PPM image, picked by its extension.  Other formats are
+    /// refused, since lossy ones don't keep the colours exact.
+    pub fn save(
+        program: &PietSource,
+        codel_size: u32,
+        scale: u32,
+        filename: &str,
+    ) -> Result<(), ImageError> {
+        let path = Path::new(filename);
+        match ImageFormat::from_path(path)? {
+            ImageFormat::Png | ImageFormat::Gif | ImageFormat::Pnm => {
+                Self::render(program, codel_size, scale).save(path)
+            }
+            format => Err(ImageError::Unsupported(
+                UnsupportedError::from_format_and_kind(
+                    format.into(),
+                    UnsupportedErrorKind::Format(ImageFormatHint::Exact(format)),
+                ),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Renderer;
+    use crate::cfg::CFGBuilder;
+    use crate::convert::UnknownPixelSettings;
+    use crate::infer::InferCodelWidth;
+    use crate::loader::Loader;
+    use piet_core::coverage::Coverage;
+    use piet_core::settings::CodelSettings;
+    use std::env;
+
+    #[test]
+    fn test_rescale_round_trip() {
+        let program =
+            Loader::convert("../images/hw1-11.gif", UnknownPixelSettings::TreatAsError).unwrap();
+        assert_eq!(Renderer::infer_codel_width(&program), 11);
+
+        for ext in ["png", "gif", "ppm"] {
+            let path = env::temp_dir().join(format!("piet_render_test.{ext}"));
+            let filename = path.to_str().unwrap();
+
+            // Normalising to codel size 1 and scaling back up gives the original image
+            Renderer::save(&program, 11, 1, filename).unwrap();
+            let normalised = Loader::convert(filename, UnknownPixelSettings::TreatAsError).unwrap();
+            assert_eq!(
+                normalised.dimensions(),
+                (program.dimensions().0 / 11, program.dimensions().1 / 11)
+            );
+
+            Renderer::save(&normalised, 1, 11, filename).unwrap();
+            let rescaled = Loader::convert(filename, UnknownPixelSettings::TreatAsError).unwrap();
+            assert_eq!(rescaled.get_underlying_vec(), program.get_underlying_vec());
+        }
+
+        assert!(Renderer::save(&program, 11, 1, "program.jpg").is_err());
+    }
+
+    #[test]
+    fn test_render_coverage() {
+        let program =
+            Loader::convert("../images/bounce.png", UnknownPixelSettings::TreatAsError).unwrap();
+        let mut cfg_builder = CFGBuilder::new(&program, CodelSettings::Width(1), false);
+        cfg_builder.build();
+        let cfg = cfg_builder.get_cfg();
+
+        let nodes = |label: &str| {
+            cfg.keys()
+                .find(|node| node.get_label() == label)
+                .unwrap()
+                .clone()
+        };
+        let mut coverage = Coverage::new();
+        coverage.record_entry(&nodes("Entry"));
+        for _ in 0..3 {
+            coverage.record_transition(&nodes("Entry"), &nodes("RegRed_0_2"));
+            coverage.record_transition(&nodes("RegRed_0_2"), &nodes("Entry"));
+        }
+
+        let plain = Renderer::render(&program, 1, 2);
+        let image = Renderer::render_coverage(&program, &cfg, &coverage, 1, 2);
+        assert_eq!(image.dimensions(), (6, 4));
+        // The hottest block gets the strongest tint, the dark red block is greyed out
+        assert_eq!(image.get_pixel(0, 0).0, [255, 96, 48]);
+        assert!(image.get_pixel(4, 0).0[1] > plain.get_pixel(4, 0).0[1]);
+        let grey = image.get_pixel(1, 3).0;
+        assert!(grey[0] == grey[1] && grey[1] == grey[2]);
+        assert_eq!(image.get_pixel(3, 3), plain.get_pixel(3, 3));
+    }
+}
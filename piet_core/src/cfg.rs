@@ -5,13 +5,15 @@ use std::hash::Hash;
 use std::rc::Rc;
 
 use crate::color::Lightness;
-use crate::flow::PietTransition;
+use crate::flow::{PietTransition, PointerState};
 use crate::state::Position;
 
 pub type Node = Rc<ColorBlock>;
 pub type NodeAdj = HashMap<Node, Vec<PietTransition>>;
 pub type CFG = HashMap<Node, NodeAdj>;
 
+pub const HALT_LABEL: &str = "Halt";
+
 #[allow(unused)]
 #[derive(Eq)]
 pub struct ColorBlock {
@@ -49,6 +51,34 @@ impl ColorBlock {
     pub fn get_lightness(&self) -> Lightness {
         self.lightness
     }
+
+    // Sink for transitions that end the program rather than entering another block, e.g. a white trace
+    // that retraces its path.  It has no codels and no outgoing transitions.
+    pub fn halt() -> Self {
+        Self::new(HALT_LABEL.to_owned(), Lightness::White, HashSet::new())
+    }
+
+    pub fn is_halt(&self) -> bool {
+        self.label == HALT_LABEL
+    }
+}
+
+// Finds the transition taken out of `node` when the pointers are in state `pointers`, retrying blocked
+// states in spec order.  Returns None if every exit is blocked, i.e. the program terminates in `node`.
+pub fn next_transition(
+    cfg: &CFG,
+    node: &Node,
+    pointers: PointerState,
+) -> Option<(Node, PietTransition)> {
+    let adjs = cfg.get(node)?;
+    pointers.retry_sequence().find_map(|attempt| {
+        adjs.iter().find_map(|(adj, transitions)| {
+            transitions
+                .iter()
+                .find(|transition| transition.entry_state == attempt)
+                .map(|transition| (adj.clone(), *transition))
+        })
+    })
 }
 
 impl fmt::Debug for ColorBlock {
@@ -0,0 +1,206 @@
+use crate::cfg::{Node, CFG};
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::hash::Hash;
+
+// Takes one off `key`'s count, dropping it once it gets to zero
+fn decrement<K: Eq + Hash>(counts: &mut HashMap<K, u64>, key: K) {
+    if let Some(count) = counts.get_mut(&key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(&key);
+        }
+    }
+}
+
+/// Execution counts for each block and each edge between blocks, keyed by block label.  Blocks count
+/// every time they're entered, including the entry at the start of the run and the `Halt` sink at the end.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Coverage {
+    blocks: HashMap<String, u64>,
+    edges: HashMap<(String, String), u64>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts a visit to `node` without coming from anywhere, i.e. the start of the run
+    pub fn record_entry(&mut self, node: &Node) {
+        *self.blocks.entry(node.get_label().clone()).or_default() += 1;
+    }
+
+    /// Counts a transition from `from` to `to`, and the visit to `to` it leads to
+    pub fn record_transition(&mut self, from: &Node, to: &Node) {
+        *self.blocks.entry(to.get_label().clone()).or_default() += 1;
+        *self
+            .edges
+            .entry((from.get_label().clone(), to.get_label().clone()))
+            .or_default() += 1;
+    }
+
+    /// Takes back a `record_entry` of `node`, e.g. for a step that's been undone
+    pub fn forget_entry(&mut self, node: &Node) {
+        decrement(&mut self.blocks, node.get_label().clone());
+    }
+
+    /// Takes back a `record_transition` from `from` to `to`
+    pub fn forget_transition(&mut self, from: &Node, to: &Node) {
+        decrement(&mut self.blocks, to.get_label().clone());
+        decrement(
+            &mut self.edges,
+            (from.get_label().clone(), to.get_label().clone()),
+        );
+    }
+
+    pub fn block_count(&self, label: &str) -> u64 {
+        self.blocks.get(label).copied().unwrap_or_default()
+    }
+
+    pub fn edge_count(&self, from: &str, to: &str) -> u64 {
+        self.edges
+            .get(&(from.to_owned(), to.to_owned()))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// The number of transitions taken over all edges, i.e. how many steps the run took, counting the
+    /// ones without an instruction
+    pub fn transitions(&self) -> u64 {
+        self.edges.values().sum()
+    }
+
+    pub fn max_block_count(&self) -> u64 {
+        self.blocks.values().copied().max().unwrap_or_default()
+    }
+
+    /// The `n` most visited blocks that were visited at all, most visited first and by label on ties
+    pub fn hottest_blocks(&self, n: usize) -> Vec<(&str, u64)> {
+        let mut blocks = self
+            .blocks
+            .iter()
+            .filter(|(_, &count)| count > 0)
+            .map(|(label, &count)| (label.as_str(), count))
+            .collect::<Vec<_>>();
+        blocks.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        blocks.truncate(n);
+        blocks
+    }
+
+    /// The `n` most taken edges that were taken at all, most taken first and by labels on ties
+    pub fn hottest_edges(&self, n: usize) -> Vec<(&str, &str, u64)> {
+        let mut edges = self
+            .edges
+            .iter()
+            .filter(|(_, &count)| count > 0)
+            .map(|((from, to), &count)| (from.as_str(), to.as_str(), count))
+            .collect::<Vec<_>>();
+        edges.sort_by(|a, b| b.2.cmp(&a.2).then((a.0, a.1).cmp(&(b.0, b.1))));
+        edges.truncate(n);
+        edges
+    }
+
+    /// Summarises how much of `cfg` was executed, followed by the `top` most visited blocks and most taken
+    /// edges
+    pub fn report(&self, cfg: &CFG, top: usize) -> String {
+        let blocks = cfg
+            .keys()
+            .filter(|node| !node.is_halt())
+            .collect::<Vec<_>>();
+        let executed = blocks
+            .iter()
+            .filter(|node| self.block_count(node.get_label()) > 0)
+            .count();
+        let visits = blocks
+            .iter()
+            .map(|node| self.block_count(node.get_label()))
+            .sum::<u64>();
+        let percent = |count: u64, total: u64| match total {
+            0 => 0.0,
+            total => count as f64 * 100.0 / total as f64,
+        };
+
+        let mut report = String::new();
+        writeln!(
+            report,
+            "executed {} of {} blocks ({:.1}%), {} block visits",
+            executed,
+            blocks.len(),
+            percent(executed as u64, blocks.len() as u64),
+            visits
+        )
+        .unwrap();
+
+        writeln!(report, "\nhottest blocks:").unwrap();
+        for (label, count) in self.hottest_blocks(top) {
+            let info = blocks
+                .iter()
+                .find(|node| node.get_label() == label)
+                .map(|node| {
+                    let size = node.get_region_size();
+                    let codels = if size == 1 { "codel" } else { "codels" };
+                    format!("{}, {} {}", node.get_lightness().to_string(), size, codels)
+                })
+                .unwrap_or_default();
+            writeln!(
+                report,
+                "  {:>12}  {:>5.1}%  {:<24}{}",
+                count,
+                percent(count, visits),
+                label,
+                info
+            )
+            .unwrap();
+        }
+
+        writeln!(report, "\nhottest edges:").unwrap();
+        for (from, to, count) in self.hottest_edges(top) {
+            writeln!(report, "  {:>12}  {} -> {}", count, from, to).unwrap();
+        }
+
+        report
+    }
+
+    /// Reads counts written out by `Display`, one `block <label> <count>` or `edge <from> <to> <count>` per
+    /// line.  Counts for the same block or edge are added together.
+    pub fn parse(profile: &str) -> Result<Self, String> {
+        let mut coverage = Self::new();
+
+        for (idx, line) in profile.lines().enumerate() {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let count = |field: &str| {
+                field
+                    .parse::<u64>()
+                    .map_err(|_| format!("line {}: invalid count `{}`", idx + 1, field))
+            };
+            match fields[..] {
+                [] => (),
+                ["block", label, n] => {
+                    *coverage.blocks.entry(label.to_owned()).or_default() += count(n)?
+                }
+                ["edge", from, to, n] => {
+                    *coverage
+                        .edges
+                        .entry((from.to_owned(), to.to_owned()))
+                        .or_default() += count(n)?
+                }
+                _ => return Err(format!("line {}: expected a block or edge count", idx + 1)),
+            }
+        }
+
+        Ok(coverage)
+    }
+}
+
+impl fmt::Display for Coverage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (label, count) in self.hottest_blocks(self.blocks.len()) {
+            writeln!(f, "block {} {}", label, count)?;
+        }
+        for (from, to, count) in self.hottest_edges(self.edges.len()) {
+            writeln!(f, "edge {} {} {}", from, to, count)?;
+        }
+        Ok(())
+    }
+}
@@ -5,4 +5,27 @@ pub enum ExecutionError {
     ParseError(Instruction, String),
     StackOutOfBoundsError(Instruction, String),
     DivisionByZeroError(Instruction, String),
-}
\ No newline at end of file
+    InvalidCharError(Instruction, String),
+}
+
+impl ExecutionError {
+    /// The instruction that couldn't be executed
+    pub fn instruction(&self) -> Instruction {
+        match self {
+            Self::ParseError(instr, _)
+            | Self::StackOutOfBoundsError(instr, _)
+            | Self::DivisionByZeroError(instr, _)
+            | Self::InvalidCharError(instr, _) => *instr,
+        }
+    }
+
+    /// A short description of what went wrong, shared with the compiled runtime's error messages
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::ParseError(..) => "invalid input",
+            Self::StackOutOfBoundsError(..) => "stack underflow",
+            Self::DivisionByZeroError(..) => "division by zero",
+            Self::InvalidCharError(..) => "invalid character",
+        }
+    }
+}
@@ -80,7 +80,7 @@ pub fn find_offset(curr: PointerState, target: PointerState) -> u8 {
     )
 }
 
-#[derive(Copy, Clone, Debug, Default)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
 pub struct PointerState {
     pub dp: DirPointer,
     pub cc: CodelChooser,
@@ -90,6 +90,19 @@ impl PointerState {
     pub const fn new(dp: DirPointer, cc: CodelChooser) -> Self {
         Self { dp, cc }
     }
+
+    // Order in which pointer states are attempted when the current one is blocked: per the spec, CC is toggled
+    // first, then DP is rotated clockwise, alternating until all 8 states have been tried.
+    pub fn retry_sequence(self) -> impl Iterator<Item = PointerState> {
+        (0..8).scan(self, |state, attempt| {
+            if attempt % 2 == 1 {
+                state.cc = state.cc.switch(1);
+            } else if attempt > 0 {
+                state.dp = state.dp.rotate(1);
+            }
+            Some(*state)
+        })
+    }
 }
 
 pub const DIRECTIONS: [PointerState; 8] = [
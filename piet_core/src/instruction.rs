@@ -60,4 +60,27 @@ impl Instruction {
             Self::IntOut => "piet_intout",
         }
     }
+
+    /// Name used in listings and by the assembler
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Self::Push => "push",
+            Self::Pop => "pop",
+            Self::Add => "add",
+            Self::Sub => "sub",
+            Self::Mul => "mul",
+            Self::Div => "div",
+            Self::Mod => "mod",
+            Self::Not => "not",
+            Self::Gt => "gt",
+            Self::Ptr => "ptr",
+            Self::Swi => "swi",
+            Self::Dup => "dup",
+            Self::Roll => "roll",
+            Self::CharIn => "in(char)",
+            Self::CharOut => "out(char)",
+            Self::IntIn => "in(int)",
+            Self::IntOut => "out(int)",
+        }
+    }
 }
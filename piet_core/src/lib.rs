@@ -1,8 +1,13 @@
 pub mod cfg;
 pub mod color;
+pub mod coverage;
 pub mod error;
 pub mod flow;
 pub mod instruction;
 pub mod program;
 pub mod settings;
+pub mod snapshot;
 pub mod state;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+pub mod trace;
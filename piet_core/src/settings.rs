@@ -14,6 +14,233 @@ pub enum SaveOptions {
     EmitExecutable,
     EmitLLVMBitcode,
     EmitLLVMIR,
+    /// A self-contained C program, which needs no LLVM to build
+    EmitC,
+    /// An object file exporting `piet_run`, which runs the program with I/O through callbacks, and a
+    /// header declaring it
+    EmitLibrary,
+}
+
+/// How `Div` and `Mod` treat negative operands.  Both conventions agree whenever the divisor is positive and
+/// the dividend isn't negative, and under both:
+///
+/// - a zero divisor is an error, and the instruction leaves the stack as it was
+/// - `i64::MIN / -1` wraps around to `i64::MIN`, and `i64::MIN mod -1` is 0
+///
+/// | `b`  | `a`  | npiet `b / a` | npiet `b mod a` | euclid `b / a` | euclid `b mod a` |
+/// |------|------|---------------|-----------------|----------------|------------------|
+/// |  7   |  2   |  3            |  1              |  3             |  1               |
+/// | -7   |  2   | -3            |  1              | -4             |  1               |
+/// |  7   | -2   | -3            | -1              | -3             |  1               |
+/// | -7   | -2   |  3            | -1              |  4             |  1               |
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq)]
+pub enum DivisionSemantics {
+    /// Division truncates towards zero as in C, and the remainder has the sign of the divisor as the
+    /// Piet spec asks for.  This is what npiet does.
+    #[default]
+    Npiet,
+    /// Euclidean division: the remainder is never negative, and division rounds so that
+    /// `b == a * (b / a) + b mod a` always holds.  For positive divisors this is floored division.
+    Euclid,
+}
+
+impl DivisionSemantics {
+    /// `b / a`, or `None` if `a` is zero
+    pub fn div(self, b: i64, a: i64) -> Option<i64> {
+        match (self, a) {
+            (_, 0) => None,
+            (Self::Npiet, _) => Some(b.wrapping_div(a)),
+            (Self::Euclid, _) => Some(b.wrapping_div_euclid(a)),
+        }
+    }
+
+    /// `b mod a`, or `None` if `a` is zero
+    pub fn rem(self, b: i64, a: i64) -> Option<i64> {
+        match (self, a) {
+            (_, 0) => None,
+            (Self::Npiet, _) => {
+                let rem = b.wrapping_rem(a);
+                if rem != 0 && (rem < 0) != (a < 0) {
+                    Some(rem + a)
+                } else {
+                    Some(rem)
+                }
+            }
+            (Self::Euclid, _) => Some(b.wrapping_rem_euclid(a)),
+        }
+    }
+}
+
+impl std::str::FromStr for DivisionSemantics {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "npiet" => Ok(Self::Npiet),
+            "euclid" => Ok(Self::Euclid),
+            _ => Err(format!("unknown division semantics `{s}`")),
+        }
+    }
+}
+
+/// What happens when an instruction can't be executed, because the stack is too small for it or it divides
+/// by zero.  The spec says to ignore such instructions and carry on, which is the default.  Either way the
+/// instruction leaves the stack as the spec says.
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    #[default]
+    Ignore,
+    /// Carries on after printing a warning to stderr, naming the instruction, step and block
+    Warn,
+    /// Prints the same message as an error and stops the program with a non-zero exit status
+    Abort,
+}
+
+impl std::str::FromStr for ErrorPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(Self::Ignore),
+            "warn" => Ok(Self::Warn),
+            "abort" => Ok(Self::Abort),
+            _ => Err(format!("unknown error policy `{s}`")),
+        }
+    }
+}
+
+/// What an input instruction does when there's no input left, or the input can't be read
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq)]
+pub enum InputPolicy {
+    /// Pushes nothing and carries on, as for any instruction that can't be executed
+    #[default]
+    Skip,
+    PushZero,
+    PushMinusOne,
+    /// Ends the program as if it had run to completion
+    Terminate,
+}
+
+impl InputPolicy {
+    /// The value pushed in place of the input, if any
+    pub fn value(self) -> Option<i64> {
+        match self {
+            Self::PushZero => Some(0),
+            Self::PushMinusOne => Some(-1),
+            Self::Skip | Self::Terminate => None,
+        }
+    }
+}
+
+impl std::str::FromStr for InputPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "push-zero" => Ok(Self::PushZero),
+            "push-minus-one" => Ok(Self::PushMinusOne),
+            "terminate" => Ok(Self::Terminate),
+            _ => Err(format!("unknown input policy `{s}`")),
+        }
+    }
+}
+
+/// How `IntIn` and `CharIn` read input, which the interpreter and compiled programs do alike:
+///
+/// - `IntIn` skips whitespace, then reads an optional sign and decimal digits.  The number has to fit in an
+///   i64 and be followed by whitespace or the end of input, and the whitespace after it is consumed up to
+///   and including the end of its line.  If only whitespace is left, that's the end of input.  Anything
+///   else is bad input, and the rest of the line it's on is discarded.
+/// - `CharIn` reads one UTF-8 encoded character and pushes its code point.  No bytes left is the end of
+///   input.  A byte that can't start a character is bad input and is discarded, as is a sequence cut short
+///   by a byte that can't continue it, which is left to be read next.  Overlong encodings, surrogates and
+///   code points past U+10FFFF are bad input too.
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq)]
+pub struct InputSettings {
+    pub on_eof: InputPolicy,
+    pub on_bad_input: InputPolicy,
+}
+
+impl InputSettings {
+    /// Whether an input instruction can push nothing and carry on
+    pub fn may_skip(self) -> bool {
+        self.on_eof == InputPolicy::Skip || self.on_bad_input == InputPolicy::Skip
+    }
+}
+
+/// What `CharOut` does with a value that isn't a Unicode scalar value: negative, past U+10FFFF, or a
+/// surrogate.  The value is popped whichever is chosen.
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq)]
+pub enum InvalidCharPolicy {
+    /// Prints nothing
+    #[default]
+    Skip,
+    /// Prints U+FFFD REPLACEMENT CHARACTER
+    Replace,
+    /// Prints nothing, and reports a runtime error under the `ErrorPolicy`
+    Error,
+}
+
+impl InvalidCharPolicy {
+    /// The character `CharOut` prints for `n`, if any
+    pub fn char(self, n: i64) -> Option<char> {
+        match u32::try_from(n).ok().and_then(char::from_u32) {
+            Some(c) => Some(c),
+            None if self == Self::Replace => Some(char::REPLACEMENT_CHARACTER),
+            None => None,
+        }
+    }
+}
+
+impl std::str::FromStr for InvalidCharPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "replace" => Ok(Self::Replace),
+            "error" => Ok(Self::Error),
+            _ => Err(format!("unknown invalid character policy `{s}`")),
+        }
+    }
+}
+
+/// The platform a compiled program runs on.  The C program and library are left to the C compiler that
+/// builds them.
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq)]
+pub enum Platform {
+    /// The host, with I/O through the C library
+    #[default]
+    Native,
+    /// `wasm32-unknown-unknown`, with I/O through functions imported from the embedder
+    Wasm32,
+    /// `wasm32-wasi`, with I/O through WASI's `fd_write`, `fd_read` and `proc_exit`
+    Wasi,
+}
+
+impl Platform {
+    /// The LLVM target triple, or `None` for the host's
+    pub fn triple(self) -> Option<&'static str> {
+        match self {
+            Self::Native => None,
+            Self::Wasm32 => Some("wasm32-unknown-unknown"),
+            Self::Wasi => Some("wasm32-wasi"),
+        }
+    }
+}
+
+impl std::str::FromStr for Platform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "native" => Ok(Self::Native),
+            "wasm32-unknown-unknown" => Ok(Self::Wasm32),
+            "wasm32-wasi" => Ok(Self::Wasi),
+            _ => Err(format!("unknown target `{s}`")),
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -26,6 +253,19 @@ pub struct CompilerSettings<'a> {
     pub show_cfg_size: bool,
     pub warn_nt: bool,
     pub verbosity: Verbosity,
+    /// Counts block visits and edges taken, written to the file named by `PIET_COVERAGE` on exit
+    pub coverage: bool,
+    /// Logs every transition taken, as the interpreter's trace does, to the file named by `PIET_TRACE` or
+    /// else to stderr
+    pub trace: bool,
+    /// The image the program was compiled from, if it's compiled with DWARF debug info that places each
+    /// block's code at the block's pixels in the image
+    pub debug_info: Option<&'a str>,
+    pub division: DivisionSemantics,
+    pub error_policy: ErrorPolicy,
+    pub input: InputSettings,
+    pub invalid_char: InvalidCharPolicy,
+    pub platform: Platform,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -36,6 +276,10 @@ pub struct InterpreterSettings {
     pub partial_eval: bool,
     pub abstract_interp: bool,
     pub print: bool,
+    pub division: DivisionSemantics,
+    pub error_policy: ErrorPolicy,
+    pub input: InputSettings,
+    pub invalid_char: InvalidCharPolicy,
 }
 
 impl Default for InterpreterSettings {
@@ -47,12 +291,20 @@ impl Default for InterpreterSettings {
             partial_eval: Default::default(),
             abstract_interp: Default::default(),
             print: true,
+            division: Default::default(),
+            error_policy: Default::default(),
+            input: Default::default(),
+            invalid_char: Default::default(),
         }
     }
 }
 
 impl InterpreterSettings {
-    pub fn abstract_interp(max_steps: u64, codel_settings: CodelSettings) -> Self {
+    pub fn abstract_interp(
+        max_steps: u64,
+        codel_settings: CodelSettings,
+        division: DivisionSemantics,
+    ) -> Self {
         InterpreterSettings {
             verbosity: Verbosity::Low,
             codel_settings,
@@ -60,6 +312,10 @@ impl InterpreterSettings {
             partial_eval: true,
             abstract_interp: true,
             print: false,
+            division,
+            error_policy: ErrorPolicy::Ignore,
+            input: Default::default(),
+            invalid_char: Default::default(),
         }
     }
 }
@@ -71,3 +327,54 @@ pub enum Verbosity {
     Normal,
     Verbose,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_division_semantics() {
+        use DivisionSemantics::*;
+
+        // (b, a, npiet b / a, npiet b mod a, euclid b / a, euclid b mod a)
+        let table = [
+            (7, 2, 3, 1, 3, 1),
+            (-7, 2, -3, 1, -4, 1),
+            (7, -2, -3, -1, -3, 1),
+            (-7, -2, 3, -1, 4, 1),
+            (6, -3, -2, 0, -2, 0),
+            (-6, 3, -2, 0, -2, 0),
+            (0, -5, 0, 0, 0, 0),
+            (i64::MIN, -1, i64::MIN, 0, i64::MIN, 0),
+            (i64::MIN, 2, i64::MIN / 2, 0, i64::MIN / 2, 0),
+            (i64::MAX, i64::MIN, 0, -1, 0, i64::MAX),
+            (i64::MIN, i64::MAX, -1, i64::MAX - 1, -2, i64::MAX - 1),
+        ];
+        for (b, a, npiet_div, npiet_rem, euclid_div, euclid_rem) in table {
+            assert_eq!(Npiet.div(b, a), Some(npiet_div), "npiet {b} / {a}");
+            assert_eq!(Npiet.rem(b, a), Some(npiet_rem), "npiet {b} mod {a}");
+            assert_eq!(Euclid.div(b, a), Some(euclid_div), "euclid {b} / {a}");
+            assert_eq!(Euclid.rem(b, a), Some(euclid_rem), "euclid {b} mod {a}");
+        }
+        for semantics in [Npiet, Euclid] {
+            assert_eq!(semantics.div(1, 0), None);
+            assert_eq!(semantics.rem(i64::MIN, 0), None);
+        }
+    }
+
+    #[test]
+    fn test_invalid_char_policy() {
+        use InvalidCharPolicy::*;
+
+        for policy in [Skip, Replace, Error] {
+            assert_eq!(policy.char(0x41), Some('A'));
+            assert_eq!(policy.char(0x10ffff), Some('\u{10ffff}'));
+        }
+        // The low 32 bits of the first are a valid character
+        for n in [0x1_0000_0041, -1, 0x110000, 0xd800, 0xdfff] {
+            assert_eq!(Skip.char(n), None, "{n}");
+            assert_eq!(Replace.char(n), Some(char::REPLACEMENT_CHARACTER), "{n}");
+            assert_eq!(Error.char(n), None, "{n}");
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use crate::flow::{CodelChooser, DirPointer};
+use crate::instruction::StdOutWrapper;
+use crate::state::{ExecutionState, ExecutionStatus};
+use std::fmt::Write;
+
+/// How DP is written in snapshots, traces and disassembly
+pub fn dp_name(dp: DirPointer) -> &'static str {
+    match dp {
+        DirPointer::Right => "right",
+        DirPointer::Down => "down",
+        DirPointer::Left => "left",
+        DirPointer::Up => "up",
+    }
+}
+
+/// How CC is written in snapshots, traces and disassembly
+pub fn cc_name(cc: CodelChooser) -> &'static str {
+    match cc {
+        CodelChooser::Left => "left",
+        CodelChooser::Right => "right",
+    }
+}
+
+fn status_name(status: ExecutionStatus) -> &'static str {
+    match status {
+        ExecutionStatus::Running => "running",
+        ExecutionStatus::Completed => "completed",
+        ExecutionStatus::MaxSteps => "max_steps",
+        ExecutionStatus::NeedsInput => "needs_input",
+        ExecutionStatus::Aborted => "aborted",
+        ExecutionStatus::InputFailed => "input_failed",
+    }
+}
+
+/// Characters are written as code points so whitespace survives the round trip
+fn encode_chars(s: &str) -> String {
+    s.chars()
+        .map(|c| format!("c{}", c as u32))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn decode_char(token: &str) -> Option<char> {
+    token
+        .strip_prefix('c')?
+        .parse()
+        .ok()
+        .and_then(char::from_u32)
+}
+
+impl ExecutionState {
+    /// Writes the state out as text, one `<field> <value>` line per field.  The stack is listed top first,
+    /// and output as `i<n>` for integers and `c<code point>` for characters.
+    pub fn to_snapshot(&self) -> String {
+        let mut snapshot = String::new();
+        writeln!(snapshot, "dp {}", dp_name(self.pointers.dp)).unwrap();
+        writeln!(snapshot, "cc {}", cc_name(self.pointers.cc)).unwrap();
+        writeln!(snapshot, "block {}", self.cb_label).unwrap();
+        writeln!(snapshot, "block_size {}", self.cb_count).unwrap();
+        writeln!(snapshot, "steps {}", self.steps).unwrap();
+        writeln!(snapshot, "status {}", status_name(self.status)).unwrap();
+
+        let stack = self.stack.iter().map(i64::to_string).collect::<Vec<_>>();
+        writeln!(snapshot, "stack {}", stack.join(" ")).unwrap();
+        let stdout = self
+            .stdout
+            .iter()
+            .map(|out| match out {
+                StdOutWrapper::Int(n) => format!("i{n}"),
+                StdOutWrapper::Char(c) => format!("c{}", *c as u32),
+            })
+            .collect::<Vec<_>>();
+        writeln!(snapshot, "stdout {}", stdout.join(" ")).unwrap();
+        writeln!(snapshot, "stdin {}", encode_chars(&self.stdin)).unwrap();
+        snapshot
+    }
+
+    /// Reads a state written by `to_snapshot`.  Fields that are left out keep their default values.
+    pub fn from_snapshot(snapshot: &str) -> Result<Self, String> {
+        let mut state = ExecutionState::default();
+
+        for (idx, line) in snapshot.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (field, value) = line.split_once(' ').unwrap_or((line, ""));
+            let value = value.trim();
+            let invalid = || format!("line {}: invalid {} `{}`", idx + 1, field, value);
+
+            match field {
+                "dp" => {
+                    state.pointers.dp = match value {
+                        "right" => DirPointer::Right,
+                        "down" => DirPointer::Down,
+                        "left" => DirPointer::Left,
+                        "up" => DirPointer::Up,
+                        _ => return Err(invalid()),
+                    }
+                }
+                "cc" => {
+                    state.pointers.cc = match value {
+                        "left" => CodelChooser::Left,
+                        "right" => CodelChooser::Right,
+                        _ => return Err(invalid()),
+                    }
+                }
+                "block" if value.is_empty() => return Err(invalid()),
+                "block" => state.cb_label = value.to_owned(),
+                "block_size" => state.cb_count = value.parse().map_err(|_| invalid())?,
+                "steps" => state.steps = value.parse().map_err(|_| invalid())?,
+                "status" => {
+                    state.status = match value {
+                        "running" => ExecutionStatus::Running,
+                        "completed" => ExecutionStatus::Completed,
+                        "max_steps" => ExecutionStatus::MaxSteps,
+                        "needs_input" => ExecutionStatus::NeedsInput,
+                        "aborted" => ExecutionStatus::Aborted,
+                        "input_failed" => ExecutionStatus::InputFailed,
+                        _ => return Err(invalid()),
+                    }
+                }
+                "stack" => {
+                    state.stack = value
+                        .split_whitespace()
+                        .map(str::parse)
+                        .collect::<Result<_, _>>()
+                        .map_err(|_| invalid())?
+                }
+                "stdout" => {
+                    state.stdout = value
+                        .split_whitespace()
+                        .map(|token| match token.strip_prefix('i') {
+                            Some(n) => n.parse().ok().map(StdOutWrapper::Int),
+                            None => decode_char(token).map(StdOutWrapper::Char),
+                        })
+                        .collect::<Option<_>>()
+                        .ok_or_else(invalid)?
+                }
+                "stdin" => {
+                    state.stdin = value
+                        .split_whitespace()
+                        .map(decode_char)
+                        .collect::<Option<_>>()
+                        .ok_or_else(invalid)?
+                }
+                _ => return Err(format!("line {}: unknown field `{}`", idx + 1, field)),
+            }
+        }
+
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::flow::PointerState;
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let state = ExecutionState {
+            pointers: PointerState::new(DirPointer::Up, CodelChooser::Right),
+            cb_count: 3,
+            cb_label: "DarkRed_1_0".into(),
+            stdin: "12 \n".into(),
+            stdout: vec![StdOutWrapper::Int(-7), StdOutWrapper::Char(' ')],
+            steps: 42,
+            status: ExecutionStatus::MaxSteps,
+            stack: [5, -1, i64::MIN].into(),
+        };
+
+        let snapshot = state.to_snapshot();
+        assert!(snapshot.contains("stack 5 -1 -9223372036854775808\n"));
+        assert!(snapshot.contains("stdout i-7 c32\n"));
+
+        let restored = ExecutionState::from_snapshot(&snapshot).unwrap();
+        assert_eq!(restored.to_snapshot(), snapshot);
+        assert_eq!(restored.pointers, state.pointers);
+        assert_eq!(restored.stack, state.stack);
+
+        assert!(ExecutionState::from_snapshot("dp sideways").is_err());
+        assert!(ExecutionState::from_snapshot("stack 1 x").is_err());
+        assert!(ExecutionState::from_snapshot("heap 1").is_err());
+    }
+}
@@ -12,6 +12,31 @@ pub enum ExecutionStatus {
     Completed,
     MaxSteps,
     NeedsInput,
+    /// Stopped by an instruction that couldn't be executed, under `ErrorPolicy::Abort`
+    Aborted,
+    /// Stopped by an input instruction at EOF or on bad input, under `InputPolicy::Terminate`
+    InputFailed,
+}
+
+/// Exit statuses shared by `pietcc -i` and compiled programs, so that scripts can tell how a program
+/// stopped.  1 and 2 are left to pietcc's own errors and to bad command lines.
+pub const EXIT_COMPLETED: i32 = 0;
+pub const EXIT_MAX_STEPS: i32 = 3;
+/// Only compiled programs, whose stack has a fixed size, can run out of stack
+pub const EXIT_STACK_EXHAUSTED: i32 = 4;
+pub const EXIT_ABORTED: i32 = 5;
+pub const EXIT_INPUT_FAILED: i32 = 6;
+
+impl ExecutionStatus {
+    /// The exit status of a program that stopped with this status
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ExecutionStatus::Running | ExecutionStatus::Completed => EXIT_COMPLETED,
+            ExecutionStatus::MaxSteps => EXIT_MAX_STEPS,
+            ExecutionStatus::Aborted => EXIT_ABORTED,
+            ExecutionStatus::NeedsInput | ExecutionStatus::InputFailed => EXIT_INPUT_FAILED,
+        }
+    }
 }
 
 /// Immmediate state information
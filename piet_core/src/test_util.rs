@@ -0,0 +1,44 @@
+// CFGs built by hand for unit tests, available to other crates' tests through the `test-util` feature
+
+use crate::cfg::{ColorBlock, Node, CFG};
+use crate::color::{Hue, Lightness};
+use crate::flow::{PietTransition, PointerState};
+use crate::instruction::Instruction;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+/// A red block of `size` codels in a row
+pub fn block(label: &str, size: u32) -> Node {
+    let region = (0..size).map(|c| (0, c)).collect::<HashSet<_>>();
+    Rc::new(ColorBlock::new(
+        label.into(),
+        Lightness::Reg(Hue::Red),
+        region,
+    ))
+}
+
+/// A straight line of blocks, each connected to the next by the given instruction
+pub fn chain(blocks: &[Node], instrs: &[Instruction]) -> CFG {
+    let mut cfg = CFG::new();
+    for (i, node) in blocks.iter().enumerate() {
+        let mut adjs = HashMap::new();
+        if let Some(&instr) = instrs.get(i) {
+            let dir = PointerState::default();
+            adjs.insert(
+                blocks[i + 1].clone(),
+                vec![PietTransition::new(dir, dir, Some(instr))],
+            );
+        }
+        cfg.insert(node.clone(), adjs);
+    }
+    cfg
+}
+
+/// Adds an edge from `from` to `to` that keeps the default pointers, adding either block if it's missing
+pub fn connect(cfg: &mut CFG, from: &Node, to: &Node, instr: Option<Instruction>) {
+    let dir = PointerState::default();
+    cfg.entry(from.clone())
+        .or_default()
+        .insert(to.clone(), vec![PietTransition::new(dir, dir, instr)]);
+    cfg.entry(to.clone()).or_default();
+}
@@ -0,0 +1,42 @@
+use crate::cfg::Node;
+use crate::flow::PointerState;
+use crate::instruction::Instruction;
+use crate::snapshot::{cc_name, dp_name};
+
+/// Written in place of the stack top when the stack is empty
+pub const EMPTY_STACK: &str = "empty";
+
+/// The part of a trace line that's fixed for a transition: `<from> -> <to> <instr> <dp>/<cc>`, with the
+/// pointers the next block is entered with.  Push is followed by the value it pushes, and a transition
+/// without an instruction is a `nop`.  Both backends finish the line with the stack top before the
+/// instruction runs, or `EMPTY_STACK`.
+pub fn transition_line(
+    from: &Node,
+    to: &Node,
+    instr: Option<Instruction>,
+    pointers: PointerState,
+) -> String {
+    let instr = match instr {
+        Some(instr @ Instruction::Push) => {
+            format!("{} {}", instr.mnemonic(), from.get_region_size())
+        }
+        Some(instr) => instr.mnemonic().to_string(),
+        None => "nop".to_string(),
+    };
+    format!(
+        "{} -> {} {} {}/{}",
+        from.get_label(),
+        to.get_label(),
+        instr,
+        dp_name(pointers.dp),
+        cc_name(pointers.cc)
+    )
+}
+
+/// A whole trace line, for the transition described by `transition_line` with `top` on the stack
+pub fn trace_line(transition: &str, top: Option<i64>) -> String {
+    match top {
+        Some(top) => format!("{transition} {top}"),
+        None => format!("{transition} {EMPTY_STACK}"),
+    }
+}
@@ -1,20 +1,31 @@
 pub mod verbosity;
 
 use crate::Verbosity;
+use cfg_to_ir::difftest::{self, DiffTestSettings};
 use cfg_to_ir::lowering_ctx::LoweringCtx;
 use cfg_to_ir::pipeline;
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
 use inkwell::context::Context;
 use inkwell::OptimizationLevel;
 use interpreter::interpreter::Interpreter;
+use interpreter::visualise::Visualiser;
 use parser::cfg::CFGBuilder;
 use parser::convert::UnknownPixelSettings;
+use parser::disasm;
+use parser::infer::InferCodelWidth;
 use parser::loader::Loader;
+use parser::render::Renderer;
+use piet_core::cfg::CFG;
+use piet_core::coverage::Coverage;
 use piet_core::program::PietSource;
 use piet_core::settings::*;
+use piet_core::state::ExecutionState;
 use std::env;
-use std::io::Error;
-use std::process::exit;
+use std::fs;
+use std::io::{self, Error, LineWriter};
+use std::path::Path;
+use std::process::{self, exit};
+use std::time::Duration;
 
 fn main() -> Result<(), Error> {
     let matches = App::new("pietcc")
@@ -69,6 +80,42 @@ fn main() -> Result<(), Error> {
                 .conflicts_with("emit-llvm")
                 .help("Emit LLVM bitcode for a given Piet program"),
         )
+        .arg(
+            Arg::with_name("emit-c")
+                .long("emit-c")
+                .takes_value(false)
+                .conflicts_with_all(&["emit-llvm", "emit-llvm-bitcode", "debug_info"])
+                .help("Emit a self-contained C program for a given Piet program, which builds without LLVM"),
+        )
+        .arg(
+            Arg::with_name("library")
+                .long("library")
+                .takes_value(false)
+                .conflicts_with_all(&[
+                    "emit-llvm",
+                    "emit-llvm-bitcode",
+                    "emit-c",
+                    "debug_info",
+                    "instrument",
+                    "instrument_trace",
+                ])
+                .help("Emit an object file exporting piet_run, which runs the program with I/O through callbacks, and a header declaring it.  The object is compiled from the C program by cc rather than by LLVM"),
+        )
+        .arg(
+            Arg::with_name("target")
+                .long("target")
+                .takes_value(true)
+                .possible_values(&["native", "wasm32-unknown-unknown", "wasm32-wasi"])
+                .conflicts_with_all(&[
+                    "interpret",
+                    "emit-c",
+                    "library",
+                    "debug_info",
+                    "instrument",
+                    "instrument_trace",
+                ])
+                .help("Compiles for <target>: the host (native), or a WebAssembly object doing its I/O through functions imported from the host (wasm32-unknown-unknown) or through WASI (wasm32-wasi)"),
+        )
         .arg(
             Arg::with_name("verbosity")
                 .short('v')
@@ -127,8 +174,330 @@ fn main() -> Result<(), Error> {
                 .conflicts_with("interpret")
                 .help("Attempts to detect nontermination behavior in a Piet program during compilation"),
         )
+        .arg(
+            Arg::with_name("max_steps")
+                .long("max-steps")
+                .takes_value(true)
+                .requires("interpret")
+                .help("Stops interpreting after <n> steps"),
+        )
+        .arg(
+            Arg::with_name("snapshot")
+                .long("snapshot")
+                .takes_value(true)
+                .requires("interpret")
+                .help("Saves the interpreter's state to <file> when it stops, to be carried on from with --resume"),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .takes_value(true)
+                .requires("interpret")
+                .help("Interprets from the state saved in <file> instead of from the start"),
+        )
+        .arg(
+            Arg::with_name("coverage")
+                .long("coverage")
+                .takes_value(true)
+                .help("Renders a heatmap of the blocks executed into <file> and reports the hottest ones, from an interpreted run (-i) or a --profile"),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .takes_value(true)
+                .requires("coverage")
+                .conflicts_with("interpret")
+                .help("Reads execution counts for --coverage from <file>, written by a program compiled with --instrument"),
+        )
+        .arg(
+            Arg::with_name("top")
+                .long("top")
+                .takes_value(true)
+                .default_value("10")
+                .help("Number of blocks and edges reported by --coverage"),
+        )
+        .arg(
+            Arg::with_name("instrument")
+                .long("instrument")
+                .takes_value(false)
+                .conflicts_with("interpret")
+                .help("Compiles with execution counters, which the program writes to the file named by PIET_COVERAGE when it exits"),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .takes_value(false)
+                .requires("interpret")
+                .help("Logs every transition taken to the file named by PIET_TRACE, or to stderr"),
+        )
+        .arg(
+            Arg::with_name("instrument_trace")
+                .long("instrument-trace")
+                .takes_value(false)
+                .conflicts_with("interpret")
+                .help("Compiles a program that logs every transition taken, as --trace does, to the file named by PIET_TRACE or to stderr"),
+        )
+        .arg(
+            Arg::with_name("debug_info")
+                .short('g')
+                .long("debug-info")
+                .takes_value(false)
+                .conflicts_with("interpret")
+                .help("Compiles with DWARF debug info that places each block's code at its pixels, with the row as the line and the column as the column"),
+        )
+        .args(runtime_args())
+        .subcommand(
+            App::new("difftest")
+                .about("Runs a program through the interpreter and the compiler at each optimization level, and reports the first divergence")
+                .arg(
+                    Arg::with_name("input")
+                        .required(true)
+                        .takes_value(true)
+                        .help("Piet source file to test")
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("stdin")
+                        .long("stdin")
+                        .takes_value(true)
+                        .help("Feeds the contents of <file> to the program as input"),
+                )
+                .arg(
+                    Arg::with_name("reference")
+                        .long("reference")
+                        .takes_value(true)
+                        .help("Also compares against a reference implementation, run as <cmd> <input>"),
+                )
+                .arg(
+                    Arg::with_name("codel_size")
+                        .short('s')
+                        .long("size")
+                        .takes_value(true)
+                        .help("Run with a supplied codel size (must divide program height and width)"),
+                )
+                .arg(
+                    Arg::with_name("max_steps")
+                        .long("max-steps")
+                        .takes_value(true)
+                        .default_value("1000000")
+                        .help("Step limit for the interpreter"),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .long("timeout")
+                        .takes_value(true)
+                        .default_value("10")
+                        .help("Time limit in seconds for compiled and reference programs"),
+                )
+                .args(runtime_args())
+                .arg(
+                    Arg::with_name("treat_white")
+                        .long("uw")
+                        .takes_value(false)
+                        .conflicts_with("treat_black")
+                        .help("Treats unknown pixels as white (default: error)"),
+                )
+                .arg(
+                    Arg::with_name("treat_black")
+                        .long("ub")
+                        .takes_value(false)
+                        .conflicts_with("treat_white")
+                        .help("Treats unknown pixels as black (default: error)"),
+                ),
+        )
+        .subcommand(
+            App::new("asm")
+                .about("Assembles a textual program into a Piet image")
+                .arg(
+                    Arg::with_name("input")
+                        .required(true)
+                        .takes_value(true)
+                        .help("Assembly source file")
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("out")
+                        .short('o')
+                        .takes_value(true)
+                        .help("Output PNG, GIF or PPM image file (default: <input>.png)"),
+                )
+                .arg(
+                    Arg::with_name("codel_size")
+                        .short('s')
+                        .long("size")
+                        .takes_value(true)
+                        .default_value("1")
+                        .help("Width in pixels of each codel in the image"),
+                ),
+        )
+        .subcommand(
+            App::new("disasm")
+                .about("Prints a listing of a program's colour blocks and the transitions out of them")
+                .arg(
+                    Arg::with_name("input")
+                        .required(true)
+                        .takes_value(true)
+                        .help("Piet source file to disassemble")
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("codel_size")
+                        .short('s')
+                        .long("size")
+                        .takes_value(true)
+                        .help("Disassemble with a supplied codel size (must divide program height and width)"),
+                )
+                .arg(
+                    Arg::with_name("treat_white")
+                        .long("uw")
+                        .takes_value(false)
+                        .conflicts_with("treat_black")
+                        .help("Treats unknown pixels as white (default: error)"),
+                )
+                .arg(
+                    Arg::with_name("treat_black")
+                        .long("ub")
+                        .takes_value(false)
+                        .conflicts_with("treat_white")
+                        .help("Treats unknown pixels as black (default: error)"),
+                ),
+        )
+        .subcommand(
+            App::new("render")
+                .about("Redraws a program as a PNG, GIF or PPM image with a different codel size")
+                .arg(
+                    Arg::with_name("input")
+                        .required(true)
+                        .takes_value(true)
+                        .help("Piet source file to redraw")
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("out")
+                        .short('o')
+                        .required(true)
+                        .takes_value(true)
+                        .help("Output image file, whose extension picks the format"),
+                )
+                .arg(
+                    Arg::with_name("codel_size")
+                        .short('s')
+                        .long("size")
+                        .takes_value(true)
+                        .help("Codel size of the input (default: inferred)"),
+                )
+                .arg(
+                    Arg::with_name("scale")
+                        .long("scale")
+                        .takes_value(true)
+                        .default_value("1")
+                        .help("Codel size of the output"),
+                )
+                .arg(
+                    Arg::with_name("treat_white")
+                        .long("uw")
+                        .takes_value(false)
+                        .conflicts_with("treat_black")
+                        .help("Treats unknown pixels as white (default: error)"),
+                )
+                .arg(
+                    Arg::with_name("treat_black")
+                        .long("ub")
+                        .takes_value(false)
+                        .conflicts_with("treat_white")
+                        .help("Treats unknown pixels as black (default: error)"),
+                ),
+        )
+        .subcommand(
+            App::new("animate")
+                .about("Renders a program's execution as an animated GIF or a sequence of PNG frames")
+                .arg(
+                    Arg::with_name("input")
+                        .required(true)
+                        .takes_value(true)
+                        .help("Piet source file to run")
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("out")
+                        .short('o')
+                        .required(true)
+                        .takes_value(true)
+                        .help("Output file, a .gif for an animation or a .png for numbered frames"),
+                )
+                .arg(
+                    Arg::with_name("codel_size")
+                        .short('s')
+                        .long("size")
+                        .takes_value(true)
+                        .help("Codel size of the input (default: inferred)"),
+                )
+                .arg(
+                    Arg::with_name("scale")
+                        .long("scale")
+                        .takes_value(true)
+                        .default_value("16")
+                        .help("Codel size of the frames"),
+                )
+                .arg(
+                    Arg::with_name("frames")
+                        .long("frames")
+                        .takes_value(true)
+                        .default_value("200")
+                        .help("Maximum number of frames, one per step"),
+                )
+                .arg(
+                    Arg::with_name("delay")
+                        .long("delay")
+                        .takes_value(true)
+                        .default_value("250")
+                        .help("Time each frame of an animation is shown for, in milliseconds"),
+                )
+                .arg(
+                    Arg::with_name("stdin")
+                        .long("stdin")
+                        .takes_value(true)
+                        .help("Feeds the contents of <file> to the program as input"),
+                )
+                .args(runtime_args())
+                .arg(
+                    Arg::with_name("treat_white")
+                        .long("uw")
+                        .takes_value(false)
+                        .conflicts_with("treat_black")
+                        .help("Treats unknown pixels as white (default: error)"),
+                )
+                .arg(
+                    Arg::with_name("treat_black")
+                        .long("ub")
+                        .takes_value(false)
+                        .conflicts_with("treat_white")
+                        .help("Treats unknown pixels as black (default: error)"),
+                ),
+        )
+        .subcommand_negates_reqs(true)
         .get_matches();
 
+    if let Some(matches) = matches.subcommand_matches("difftest") {
+        run_difftest(matches);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("asm") {
+        run_asm(matches);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("disasm") {
+        run_disasm(matches);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("render") {
+        run_render(matches);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("animate") {
+        run_animate(matches);
+    }
+
     let filename = matches.value_of("input").unwrap();
     let mut interpreter: Interpreter;
     let program: PietSource;
@@ -148,7 +517,7 @@ fn main() -> Result<(), Error> {
         program = prog;
         let mut codel_settings = CodelSettings::Infer;
         let mut verbosity = Verbosity::Normal;
-        let mut interp_settings = InterpreterSettings::default();
+        let mut interp_settings = interpreter_settings(&matches);
 
         if let Some(val) = matches.value_of("codel_size") {
             if let Ok(val) = val.parse::<u32>() {
@@ -197,11 +566,65 @@ fn main() -> Result<(), Error> {
         cfg_builder.build();
         let cfg = cfg_builder.get_cfg();
 
+        if let Some(profile) = matches.value_of("profile") {
+            let coverage = fs::read_to_string(profile)
+                .map_err(|err| err.to_string())
+                .and_then(|profile| Coverage::parse(&profile))
+                .unwrap_or_else(|err| fatal_error(&format!("{}: {}", profile, err)));
+            report_coverage(&matches, &program, codel_settings, &cfg, &coverage);
+            exit(0);
+        }
+
         if matches.is_present("interpret") {
+            let state = matches.value_of("resume").map(|resume| {
+                fs::read_to_string(resume)
+                    .map_err(|err| err.to_string())
+                    .and_then(|snapshot| ExecutionState::from_snapshot(&snapshot))
+                    .unwrap_or_else(|err| fatal_error(&format!("{}: {}", resume, err)))
+            });
+
             interp_settings.codel_settings = codel_settings;
+            if let Some(val) = matches.value_of("max_steps") {
+                let max_steps = val
+                    .parse::<u64>()
+                    .unwrap_or_else(|_| fatal_error("--max-steps must be a non-negative integer"));
+                // Counted from where a resumed run left off
+                let resumed_steps = state.as_ref().map_or(0, |state| state.steps);
+                interp_settings.max_steps = Some(resumed_steps + max_steps);
+            }
+
             interpreter = Interpreter::new(&cfg, interp_settings);
+            if let Some(state) = state {
+                interpreter = interpreter
+                    .with_state(state)
+                    .unwrap_or_else(|err| fatal_error(&format!("{}: {}", filename, err)));
+            }
+            if matches.is_present("coverage") {
+                interpreter = interpreter.with_coverage();
+            }
+            if matches.is_present("trace") {
+                interpreter = match env::var("PIET_TRACE") {
+                    Ok(trace) => interpreter.with_trace(LineWriter::new(
+                        fs::File::create(&trace)
+                            .unwrap_or_else(|err| fatal_error(&format!("{}: {}", trace, err))),
+                    )),
+                    Err(_) => interpreter.with_trace(io::stderr()),
+                };
+            }
             println!("\n{}", interpreter.run());
-            exit(0);
+            if let Some(snapshot) = matches.value_of("snapshot") {
+                interpreter
+                    .save_snapshot(snapshot)
+                    .unwrap_or_else(|err| fatal_error(&format!("{}: {}", snapshot, err)));
+            }
+            if let Some(coverage) = interpreter.get_coverage() {
+                report_coverage(&matches, &program, codel_settings, &cfg, coverage);
+            }
+            exit(interpreter.is_complete().exit_code());
+        }
+
+        if matches.is_present("coverage") {
+            fatal_error("--coverage needs an interpreted run (-i) or a --profile");
         }
 
         if let Some(output_fname) = matches.value_of("out") {
@@ -217,6 +640,10 @@ fn main() -> Result<(), Error> {
                 save_options = SaveOptions::EmitLLVMIR
             } else if matches.is_present("emit-llvm-bitcode") {
                 save_options = SaveOptions::EmitLLVMBitcode
+            } else if matches.is_present("emit-c") {
+                save_options = SaveOptions::EmitC
+            } else if matches.is_present("library") {
+                save_options = SaveOptions::EmitLibrary
             }
 
             if matches.is_present("o1") {
@@ -248,6 +675,18 @@ fn main() -> Result<(), Error> {
                 show_cfg_size,
                 show_codel_size,
                 verbosity,
+                coverage: matches.is_present("instrument"),
+                trace: matches.is_present("instrument_trace"),
+                debug_info: matches.is_present("debug_info").then_some(filename),
+                division: interp_settings.division,
+                error_policy: interp_settings.error_policy,
+                input: interp_settings.input,
+                invalid_char: interp_settings.invalid_char,
+                platform: matches.value_of("target").map_or(Platform::Native, |target| {
+                    target
+                        .parse()
+                        .unwrap_or_else(|err: String| fatal_error(&err))
+                }),
             };
 
             let cfg_gen = CFGBuilder::new(&program, codel_settings, show_codel_size);
@@ -258,7 +697,7 @@ fn main() -> Result<(), Error> {
                 &mut cfg_builder.get_cfg(),
                 compile_options,
             ) {
-                println!("{:?}", e);
+                fatal_error(&e.to_string());
             }
         }
     } else {
@@ -279,3 +718,357 @@ fn main() -> Result<(), Error> {
     }
     Ok(())
 }
+
+// Options for how the program behaves at runtime, shared by the compiler, the interpreter, difftest and
+// animate
+fn runtime_args() -> Vec<Arg<'static>> {
+    vec![
+        Arg::with_name("division")
+            .long("division")
+            .takes_value(true)
+            .possible_values(&["npiet", "euclid"])
+            .default_value("npiet")
+            .help("How div and mod treat negative numbers: truncating with the divisor's sign (npiet) or Euclidean (euclid)"),
+        Arg::with_name("on_error")
+            .long("on-error")
+            .takes_value(true)
+            .possible_values(&["ignore", "warn", "abort"])
+            .default_value("ignore")
+            .help("What to do when an instruction underflows the stack or divides by zero: skip it (ignore), skip it and print a warning (warn), or stop with a non-zero exit status (abort)"),
+        Arg::with_name("on_eof")
+            .long("on-eof")
+            .takes_value(true)
+            .possible_values(&["skip", "push-zero", "push-minus-one", "terminate"])
+            .default_value("skip")
+            .help("What in(int) and in(char) do when there's no input left: push nothing (skip), push 0 or -1, or end the program (terminate)"),
+        Arg::with_name("on_bad_input")
+            .long("on-bad-input")
+            .takes_value(true)
+            .possible_values(&["skip", "push-zero", "push-minus-one", "terminate"])
+            .default_value("skip")
+            .help("What in(int) and in(char) do with input that isn't a number or a valid UTF-8 character: push nothing (skip), push 0 or -1, or end the program (terminate)"),
+        Arg::with_name("on_invalid_char")
+            .long("on-invalid-char")
+            .takes_value(true)
+            .possible_values(&["skip", "replace", "error"])
+            .default_value("skip")
+            .help("What out(char) does with a value that isn't a Unicode character: print nothing (skip), print U+FFFD (replace), or report a runtime error under --on-error (error)"),
+    ]
+}
+
+// An interpreter that behaves as the runtime options in `matches` ask
+fn interpreter_settings(matches: &ArgMatches) -> InterpreterSettings {
+    InterpreterSettings {
+        division: division_semantics(matches),
+        error_policy: error_policy(matches),
+        input: input_settings(matches),
+        invalid_char: invalid_char_policy(matches),
+        ..Default::default()
+    }
+}
+
+fn division_semantics(matches: &ArgMatches) -> DivisionSemantics {
+    matches
+        .value_of("division")
+        .unwrap()
+        .parse()
+        .unwrap_or_else(|err: String| fatal_error(&err))
+}
+
+fn error_policy(matches: &ArgMatches) -> ErrorPolicy {
+    matches
+        .value_of("on_error")
+        .unwrap()
+        .parse()
+        .unwrap_or_else(|err: String| fatal_error(&err))
+}
+
+fn input_settings(matches: &ArgMatches) -> InputSettings {
+    let policy = |name| {
+        matches
+            .value_of(name)
+            .unwrap()
+            .parse()
+            .unwrap_or_else(|err: String| fatal_error(&err))
+    };
+    InputSettings {
+        on_eof: policy("on_eof"),
+        on_bad_input: policy("on_bad_input"),
+    }
+}
+
+fn invalid_char_policy(matches: &ArgMatches) -> InvalidCharPolicy {
+    matches
+        .value_of("on_invalid_char")
+        .unwrap()
+        .parse()
+        .unwrap_or_else(|err: String| fatal_error(&err))
+}
+
+fn fatal_error(msg: &str) -> ! {
+    match env::consts::OS {
+        "linux" => eprintln!(
+            "\x1B[1;37mpietcc: \x1B[0m\x1B[1;31mfatal error: \x1B[0m{}",
+            msg
+        ),
+        _ => eprintln!("pietcc: fatal error: {}", msg),
+    }
+    eprintln!("pietcc terminated.");
+    exit(1);
+}
+
+fn run_asm(matches: &ArgMatches) -> ! {
+    let filename = matches.value_of("input").unwrap();
+    let source = fs::read_to_string(filename)
+        .unwrap_or_else(|err| fatal_error(&format!("{}: {}", filename, err)));
+
+    let codel_size = match matches.value_of("codel_size").unwrap().parse::<u32>() {
+        Ok(val) if val > 0 => val,
+        _ => fatal_error("codel size must be a positive integer"),
+    };
+
+    let output = match matches.value_of("out") {
+        Some(out) => out.to_string(),
+        None => Path::new(filename)
+            .with_extension("png")
+            .to_string_lossy()
+            .into_owned(),
+    };
+
+    let assembled =
+        asm::assemble(&source).unwrap_or_else(|err| fatal_error(&format!("{}: {}", filename, err)));
+    match Renderer::save(&assembled.source(), 1, codel_size, &output) {
+        Ok(()) => exit(0),
+        Err(err) => fatal_error(&format!("{}: {}", output, err)),
+    }
+}
+
+fn report_coverage(
+    matches: &ArgMatches,
+    program: &PietSource,
+    codel_settings: CodelSettings,
+    cfg: &CFG,
+    coverage: &Coverage,
+) {
+    let output = matches.value_of("coverage").unwrap();
+    let top = matches
+        .value_of("top")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|_| fatal_error("--top must be a non-negative integer"));
+    let codel_size = match codel_settings {
+        CodelSettings::Default => 1,
+        CodelSettings::Infer => Renderer::infer_codel_width(program),
+        CodelSettings::Width(val) => val,
+    };
+
+    // Drawn at the size of the original image, so it can be laid over it
+    Renderer::render_coverage(program, cfg, coverage, codel_size, codel_size)
+        .save(output)
+        .unwrap_or_else(|err| fatal_error(&format!("{}: {}", output, err)));
+    print!("\n{}", coverage.report(cfg, top));
+}
+
+fn run_render(matches: &ArgMatches) -> ! {
+    let filename = matches.value_of("input").unwrap();
+    let output = matches.value_of("out").unwrap();
+    let mut behavior = UnknownPixelSettings::TreatAsError;
+
+    if matches.is_present("treat_white") {
+        behavior = UnknownPixelSettings::TreatAsWhite
+    }
+
+    if matches.is_present("treat_black") {
+        behavior = UnknownPixelSettings::TreatAsBlack
+    }
+
+    let program = Loader::convert(filename, behavior)
+        .unwrap_or_else(|_| fatal_error(&format!("{}: No such file or directory.", filename)));
+    let (height, width) = program.dimensions();
+
+    let codel_size = match matches.value_of("codel_size").map(str::parse::<u32>) {
+        Some(Ok(val)) if val > 0 && height % val == 0 && width % val == 0 => val,
+        Some(Ok(_)) => fatal_error("codel size must divide program height and width"),
+        Some(Err(_)) => fatal_error("codel size must be a positive integer"),
+        None => Renderer::infer_codel_width(&program),
+    };
+
+    let scale = match matches.value_of("scale").unwrap().parse::<u32>() {
+        Ok(val) if val > 0 => val,
+        _ => fatal_error("scale must be a positive integer"),
+    };
+
+    match Renderer::save(&program, codel_size, scale, output) {
+        Ok(()) => exit(0),
+        Err(err) => fatal_error(&format!("{}: {}", output, err)),
+    }
+}
+
+fn run_animate(matches: &ArgMatches) -> ! {
+    let filename = matches.value_of("input").unwrap();
+    let output = matches.value_of("out").unwrap();
+    let mut behavior = UnknownPixelSettings::TreatAsError;
+
+    if matches.is_present("treat_white") {
+        behavior = UnknownPixelSettings::TreatAsWhite
+    }
+
+    if matches.is_present("treat_black") {
+        behavior = UnknownPixelSettings::TreatAsBlack
+    }
+
+    let program = Loader::convert(filename, behavior)
+        .unwrap_or_else(|_| fatal_error(&format!("{}: No such file or directory.", filename)));
+    let (height, width) = program.dimensions();
+
+    let codel_size = match matches.value_of("codel_size").map(str::parse::<u32>) {
+        Some(Ok(val)) if val > 0 && height % val == 0 && width % val == 0 => val,
+        Some(Ok(_)) => fatal_error("codel size must divide program height and width"),
+        Some(Err(_)) => fatal_error("codel size must be a positive integer"),
+        None => Renderer::infer_codel_width(&program),
+    };
+
+    let scale = match matches.value_of("scale").unwrap().parse::<u32>() {
+        Ok(val) if val > 0 => val,
+        _ => fatal_error("scale must be a positive integer"),
+    };
+    let frames = matches
+        .value_of("frames")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|_| fatal_error("frames must be a non-negative integer"));
+    let delay = matches
+        .value_of("delay")
+        .unwrap()
+        .parse::<u64>()
+        .map(Duration::from_millis)
+        .unwrap_or_else(|_| fatal_error("delay must be a non-negative integer"));
+
+    let mut cfg_builder = CFGBuilder::new(&program, CodelSettings::Width(codel_size), false);
+    cfg_builder.build();
+    let cfg = cfg_builder.get_cfg();
+
+    let settings = InterpreterSettings {
+        codel_settings: CodelSettings::Width(codel_size),
+        ..interpreter_settings(matches)
+    };
+    let mut interpreter = Interpreter::new(&cfg, settings);
+    if let Some(stdin) = matches.value_of("stdin") {
+        let input = fs::read_to_string(stdin)
+            .unwrap_or_else(|err| fatal_error(&format!("{}: {}", stdin, err)));
+        interpreter = interpreter.with_input(&input);
+    }
+
+    let visualiser = Visualiser::new(&program, codel_size, scale);
+    match visualiser.record(&mut interpreter, frames, delay, output) {
+        Ok(_) => exit(0),
+        Err(err) => fatal_error(&format!("{}: {}", output, err)),
+    }
+}
+
+fn run_disasm(matches: &ArgMatches) -> ! {
+    let filename = matches.value_of("input").unwrap();
+    let mut behavior = UnknownPixelSettings::TreatAsError;
+
+    if matches.is_present("treat_white") {
+        behavior = UnknownPixelSettings::TreatAsWhite
+    }
+
+    if matches.is_present("treat_black") {
+        behavior = UnknownPixelSettings::TreatAsBlack
+    }
+
+    let program = Loader::convert(filename, behavior)
+        .unwrap_or_else(|_| fatal_error(&format!("{}: No such file or directory.", filename)));
+
+    let (height, width) = program.dimensions();
+
+    let codel_settings = match matches.value_of("codel_size").map(str::parse::<u32>) {
+        Some(Ok(val)) if val > 0 && height % val == 0 && width % val == 0 => {
+            CodelSettings::Width(val)
+        }
+        Some(Ok(val)) if val > 0 => fatal_error("codel size must divide program height and width"),
+        Some(_) => fatal_error("codel size must be a positive integer"),
+        None => CodelSettings::Infer,
+    };
+
+    let mut cfg_builder = CFGBuilder::new(&program, codel_settings, false);
+    cfg_builder.build();
+    let cfg = cfg_builder.get_cfg();
+
+    // The halt sink isn't a block of the program
+    let blocks = cfg.keys().filter(|node| !node.is_halt()).count();
+    println!("; {}: {} blocks\n", filename, blocks);
+    print!("{}", disasm::disassemble(&cfg));
+    exit(0)
+}
+
+fn run_difftest(matches: &ArgMatches) -> ! {
+    let filename = matches.value_of("input").unwrap();
+    let mut behavior = UnknownPixelSettings::TreatAsError;
+
+    if matches.is_present("treat_white") {
+        behavior = UnknownPixelSettings::TreatAsWhite
+    }
+
+    if matches.is_present("treat_black") {
+        behavior = UnknownPixelSettings::TreatAsBlack
+    }
+
+    let program = Loader::convert(filename, behavior)
+        .unwrap_or_else(|_| fatal_error(&format!("{}: No such file or directory.", filename)));
+
+    let input = match matches.value_of("stdin") {
+        Some(stdin) => fs::read_to_string(stdin)
+            .unwrap_or_else(|err| fatal_error(&format!("{}: {}", stdin, err))),
+        None => String::new(),
+    };
+
+    let (height, width) = program.dimensions();
+    let codel_settings = match matches.value_of("codel_size").map(str::parse::<u32>) {
+        Some(Ok(val)) if val > 0 && height % val == 0 && width % val == 0 => {
+            CodelSettings::Width(val)
+        }
+        Some(Ok(val)) if val > 0 => fatal_error("codel size must divide program height and width"),
+        Some(_) => fatal_error("codel size must be a positive integer"),
+        None => CodelSettings::Infer,
+    };
+
+    let (Ok(max_steps), Ok(timeout)) = (
+        matches.value_of("max_steps").unwrap().parse::<u64>(),
+        matches.value_of("timeout").unwrap().parse::<u64>(),
+    ) else {
+        fatal_error("step and time limits must be positive integers")
+    };
+
+    // Binaries are named after the image, so each run builds them in a directory of its own
+    let work_dir = env::temp_dir().join(format!("pietcc-difftest-{}", process::id()));
+    if let Err(err) = fs::create_dir_all(&work_dir) {
+        fatal_error(&format!("{}: {}", work_dir.display(), err))
+    }
+    let settings = DiffTestSettings {
+        codel_settings,
+        reference: matches.value_of("reference"),
+        max_steps,
+        timeout: Duration::from_secs(timeout),
+        work_dir: &work_dir,
+        division: division_semantics(matches),
+        error_policy: error_policy(matches),
+        input: input_settings(matches),
+        invalid_char: invalid_char_policy(matches),
+    };
+
+    let result = difftest::difftest(filename, &program, &input, &settings);
+    let _ = fs::remove_dir_all(&work_dir);
+    match result {
+        Ok(Some(divergence)) => {
+            println!("{}", divergence);
+            exit(1)
+        }
+        Ok(None) => {
+            println!("{}: all backends agree", filename);
+            exit(0)
+        }
+        Err(err) => fatal_error(&format!("{}: {}", filename, err)),
+    }
+}
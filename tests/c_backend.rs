@@ -0,0 +1,291 @@
+// Programs lowered to C and built with cc.  Every program has to build without warnings, and run like the
+// interpreter: the same output and stack, the same errors on stderr under each error policy, and the same
+// trace and execution counts when it's traced or instrumented.
+
+mod common;
+
+use asm::assemble;
+use cfg_to_ir::difftest::{compare, observe, Backend, DiffTestSettings};
+use cfg_to_ir::lowering_ctx::LoweringCtx;
+use cfg_to_ir::pipeline::run_piet_optimization_pipeline;
+use common::{c_compiler_available, work_dir};
+use inkwell::context::Context;
+use inkwell::OptimizationLevel;
+use interpreter::interpreter::Interpreter;
+use parser::cfg::CFGBuilder;
+use piet_core::coverage::Coverage;
+use piet_core::program::PietSource;
+use piet_core::settings::{
+    CodelSettings, CompilerSettings, ErrorPolicy, InterpreterSettings, SaveOptions, Verbosity,
+};
+use piet_core::state::{EXIT_ABORTED, EXIT_COMPLETED};
+use std::fs;
+use std::process::Command;
+use std::time::Duration;
+
+// Add underflows at step 1, and div divides 7 by 0 at step 6
+const ERRORS: &str = "add\npush 7\npush 1\npush 1\nsub\ndiv\nout(int)";
+
+// Counts down from 3, printing each number, so some blocks and edges run more than once
+const LOOP: &str = "\
+push 3
+loop:
+dup
+out(int)
+push 1
+sub
+dup
+jnz loop
+pop";
+
+// Lowers the program to C and builds it into `output_fname`, failing on any warning
+fn build(program: &PietSource, settings: CompilerSettings) {
+    let context = Context::create();
+    let module = context.create_module("piet");
+    let builder = context.create_builder();
+
+    let mut cfg_builder = CFGBuilder::new(program, settings.codel_settings, false);
+    cfg_builder.build();
+    let cfg_gen = CFGBuilder::new(program, settings.codel_settings, false);
+    let mut ctx = LoweringCtx::new(&context, module, builder, cfg_gen, settings);
+    run_piet_optimization_pipeline(&mut ctx, &mut cfg_builder.get_cfg(), settings).unwrap();
+
+    let output = Command::new("cc")
+        .args([
+            "-std=c99",
+            "-pedantic",
+            "-Wall",
+            "-Wextra",
+            "-Werror",
+            "-O2",
+        ])
+        .arg("-o")
+        .arg(settings.output_fname)
+        .arg(format!("{}.c", settings.output_fname))
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+fn compiler_settings(output_fname: &str) -> CompilerSettings<'_> {
+    CompilerSettings {
+        opt_level: OptimizationLevel::None,
+        codel_settings: CodelSettings::Width(1),
+        save_options: SaveOptions::EmitC,
+        output_fname,
+        show_codel_size: false,
+        show_cfg_size: false,
+        warn_nt: false,
+        verbosity: Verbosity::Low,
+        coverage: false,
+        trace: false,
+        debug_info: None,
+        division: Default::default(),
+        error_policy: Default::default(),
+        input: Default::default(),
+        invalid_char: Default::default(),
+        platform: Default::default(),
+    }
+}
+
+#[test]
+fn test_c_roll() {
+    if !c_compiler_available() {
+        return;
+    }
+    let dir = work_dir("c_backend");
+    let settings = DiffTestSettings {
+        codel_settings: CodelSettings::Width(1),
+        reference: None,
+        max_steps: 1000,
+        timeout: Duration::from_secs(10),
+        work_dir: &dir,
+        division: Default::default(),
+        error_policy: Default::default(),
+        input: Default::default(),
+        invalid_char: Default::default(),
+    };
+
+    // Rolls of each direction, further than the depth, to depth 0, and deeper than the stack.  The
+    // operands are read, so static evaluation can't roll them at compile time.
+    let source = "in(int)\nin(int)\nin(int)\nin(int)\nin(int)\nin(int)\nroll";
+    let assembled = assemble(source).unwrap();
+    let program = assembled.source();
+    for input in [
+        "1 2 3 4 3 1",
+        "1 2 3 4 3 -1",
+        "1 2 3 4 4 7",
+        "1 2 3 4 0 5",
+        "1 2 3 4 5 1",
+        "1 2 3 4 -1 1",
+    ] {
+        let input = input.replace(' ', "\n");
+        let filename = dir.join("roll.png");
+        let filename = filename.to_str().unwrap();
+        let expected =
+            observe(&Backend::Interpreter, filename, &program, &input, &settings).unwrap();
+        let found = observe(&Backend::C, filename, &program, &input, &settings).unwrap();
+        let divergence = compare(&Backend::C, &expected, &found);
+        assert!(
+            divergence.is_none(),
+            "input {input:?}: {}",
+            divergence.unwrap()
+        );
+    }
+}
+
+#[test]
+fn test_c_error_policy() {
+    if !c_compiler_available() {
+        return;
+    }
+    let assembled = assemble(ERRORS).unwrap();
+    let program = assembled.source();
+    let dir = work_dir("c_backend");
+
+    for error_policy in [ErrorPolicy::Ignore, ErrorPolicy::Warn, ErrorPolicy::Abort] {
+        let binary = dir.join(format!("errors_{error_policy:?}"));
+        let binary = binary.to_str().unwrap();
+        build(
+            &program,
+            CompilerSettings {
+                error_policy,
+                ..compiler_settings(binary)
+            },
+        );
+        let output = Command::new(binary).output().unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stderr = stderr.lines().collect::<Vec<_>>();
+
+        match error_policy {
+            ErrorPolicy::Ignore => {
+                assert_eq!(output.status.code(), Some(EXIT_COMPLETED));
+                assert!(stdout.starts_with('0'));
+                assert!(stderr.is_empty());
+            }
+            ErrorPolicy::Warn => {
+                assert_eq!(output.status.code(), Some(EXIT_COMPLETED));
+                assert!(stdout.starts_with('0'));
+                assert_eq!(stderr.len(), 2);
+                assert!(
+                    stderr[0].starts_with("warning: stack underflow on add at step 1 in block ")
+                );
+                assert!(
+                    stderr[1].starts_with("warning: division by zero on div at step 6 in block ")
+                );
+            }
+            ErrorPolicy::Abort => {
+                assert_eq!(output.status.code(), Some(EXIT_ABORTED));
+                assert!(stdout.is_empty());
+                assert_eq!(stderr.len(), 1);
+                assert!(stderr[0].starts_with("error: stack underflow on add at step 1 in block "));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_c_trace() {
+    if !c_compiler_available() {
+        return;
+    }
+    let assembled = assemble(LOOP).unwrap();
+    let program = assembled.source();
+    let mut cfg_builder = CFGBuilder::new(&program, CodelSettings::Width(1), false);
+    cfg_builder.build();
+    let cfg = cfg_builder.get_cfg();
+    let dir = work_dir("c_backend");
+
+    let expected = dir.join("interpreted.trace");
+    let settings = InterpreterSettings {
+        codel_settings: CodelSettings::Width(1),
+        print: false,
+        ..Default::default()
+    };
+    Interpreter::new(&cfg, settings)
+        .with_trace(fs::File::create(&expected).unwrap())
+        .run();
+
+    let binary = dir.join("traced");
+    let binary = binary.to_str().unwrap();
+    build(
+        &program,
+        CompilerSettings {
+            trace: true,
+            ..compiler_settings(binary)
+        },
+    );
+    let trace = dir.join("c.trace");
+    let output = Command::new(binary)
+        .env("PIET_TRACE", &trace)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(&trace).unwrap(),
+        fs::read_to_string(&expected).unwrap()
+    );
+}
+
+#[test]
+fn test_c_coverage() {
+    if !c_compiler_available() {
+        return;
+    }
+    let assembled = assemble(LOOP).unwrap();
+    let program = assembled.source();
+    let mut cfg_builder = CFGBuilder::new(&program, CodelSettings::Width(1), false);
+    cfg_builder.build();
+    let cfg = cfg_builder.get_cfg();
+    let dir = work_dir("c_backend");
+
+    let settings = InterpreterSettings {
+        codel_settings: CodelSettings::Width(1),
+        print: false,
+        ..Default::default()
+    };
+    let mut interpreter = Interpreter::new(&cfg, settings).with_coverage();
+    interpreter.run();
+    let expected = interpreter.get_coverage().unwrap();
+
+    let binary = dir.join("instrumented");
+    let binary = binary.to_str().unwrap();
+    build(
+        &program,
+        CompilerSettings {
+            coverage: true,
+            ..compiler_settings(binary)
+        },
+    );
+    let profile = dir.join("c.profile");
+    let output = Command::new(binary)
+        .env("PIET_COVERAGE", &profile)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let found = Coverage::parse(&fs::read_to_string(&profile).unwrap()).unwrap();
+
+    // The program writes out every counter, including the ones that stayed at zero
+    assert_eq!(found.block_count("Entry"), 1);
+    for (node, adjs) in &cfg {
+        let label = node.get_label();
+        assert_eq!(
+            found.block_count(label),
+            expected.block_count(label),
+            "{label}"
+        );
+        for adj in adjs.keys() {
+            let adj = adj.get_label();
+            assert_eq!(
+                found.edge_count(label, adj),
+                expected.edge_count(label, adj),
+                "{label} -> {adj}"
+            );
+        }
+    }
+}
@@ -0,0 +1,63 @@
+// Printing characters with out(char), including values that aren't characters, under each invalid
+// character policy, on every backend `common::backends` finds.  Values are pushed as constants, which
+// optimized builds print at compile time, and read from input, which they can't.
+
+mod common;
+
+use asm::assemble;
+use cfg_to_ir::difftest::{observe, DiffTestSettings};
+use common::{backends, work_dir};
+use piet_core::settings::{CodelSettings, InvalidCharPolicy};
+use std::time::Duration;
+
+// ASCII, two, three and four byte characters, then a negative value, one past U+10FFFF and a surrogate
+const VALUES: [i64; 7] = [104, 233, 0x20ac, 0x1f600, -1, 0x110000, 0xd800];
+
+fn check(invalid_char: InvalidCharPolicy, stdout: &str) {
+    let backends = backends();
+
+    let dir = work_dir("char_output");
+    let settings = DiffTestSettings {
+        codel_settings: CodelSettings::Width(1),
+        reference: None,
+        max_steps: 1000,
+        timeout: Duration::from_secs(10),
+        work_dir: &dir,
+        division: Default::default(),
+        error_policy: Default::default(),
+        input: Default::default(),
+        invalid_char,
+    };
+    let pushed = VALUES.map(|n| format!("push {n}\nout(char)")).join("\n");
+    let read = VALUES.map(|_| "in(int)\nout(char)").join("\n");
+    let input = VALUES.map(|n| format!("{n}\n")).concat();
+    let programs = [(pushed, String::new()), (read, input)];
+
+    for (source, input) in programs {
+        let assembled = assemble(&source).unwrap();
+        let program = assembled.source();
+        for backend in &backends {
+            let filename = dir.join(format!("{invalid_char:?}.png"));
+            let observed = observe(
+                backend,
+                filename.to_str().unwrap(),
+                &program,
+                &input,
+                &settings,
+            )
+            .unwrap();
+            let case = format!("{invalid_char:?}, {backend}, input {input:?}");
+            assert!(observed.terminated, "{case} didn't terminate");
+            assert_eq!(observed.stdout, stdout, "{case}");
+            assert_eq!(observed.stack.as_deref(), Some(&[][..]), "{case}");
+        }
+    }
+}
+
+#[test]
+fn test_char_output() {
+    check(InvalidCharPolicy::Skip, "hé€😀");
+    check(InvalidCharPolicy::Replace, "hé€😀\u{fffd}\u{fffd}\u{fffd}");
+    // Errors are ignored by default, so nothing is printed for them either
+    check(InvalidCharPolicy::Error, "hé€😀");
+}
@@ -0,0 +1,85 @@
+// Helpers shared by the integration tests.  Checks that need tools outside of Rust are skipped when the
+// tools are missing, with a notice on stderr, unless PIET_REQUIRE_TOOLCHAIN is set, in which case they
+// fail, so a CI run can't pass without running every backend.
+
+// Each test binary uses only some of these
+#![allow(dead_code)]
+
+use cfg_to_ir::difftest::{Backend, OPT_LEVELS};
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::OnceLock;
+
+/// A directory for the test binary's files, under Cargo's directory for integration test scratch files
+pub fn work_dir(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Whether every one of `tools` runs, reporting what's skipped if they don't
+fn tools_available(tools: &[&str], skipped: &str) -> bool {
+    let missing = tools
+        .iter()
+        .filter(|tool| {
+            !Command::new(tool)
+                .arg("--version")
+                .output()
+                .is_ok_and(|output| output.status.success())
+        })
+        .copied()
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        return true;
+    }
+    let missing = missing.join(", ");
+    if env::var_os("PIET_REQUIRE_TOOLCHAIN").is_some() {
+        panic!(
+            "{missing} not found, and PIET_REQUIRE_TOOLCHAIN is set, so {skipped} can't be skipped"
+        );
+    }
+    // Written straight to stderr, since the test harness captures eprintln!
+    std::io::stderr()
+        .write_all(format!("note: skipping {skipped}: {missing} not found\n").as_bytes())
+        .unwrap();
+    false
+}
+
+/// Whether llc and clang are available to build compiled programs
+pub fn toolchain_available() -> bool {
+    static AVAILABLE: OnceLock<bool> = OnceLock::new();
+    *AVAILABLE.get_or_init(|| tools_available(&["llc", "clang"], "compiled programs"))
+}
+
+/// Whether cc is available to build programs lowered to C
+pub fn c_compiler_available() -> bool {
+    static AVAILABLE: OnceLock<bool> = OnceLock::new();
+    *AVAILABLE.get_or_init(|| tools_available(&["cc"], "programs lowered to C"))
+}
+
+/// Whether wasm-ld, wasm-tools and wasmtime are available to link, validate and run WebAssembly programs
+pub fn wasm_toolchain_available() -> bool {
+    static AVAILABLE: OnceLock<bool> = OnceLock::new();
+    *AVAILABLE.get_or_init(|| {
+        tools_available(
+            &["wasm-ld", "wasm-tools", "wasmtime"],
+            "WebAssembly programs",
+        )
+    })
+}
+
+/// The interpreter, followed by each backend whose tools are available: the program compiled at each
+/// optimization level, and lowered to C
+pub fn backends() -> Vec<Backend> {
+    let mut backends = vec![Backend::Interpreter];
+    if toolchain_available() {
+        backends.extend(OPT_LEVELS.map(Backend::Compiled));
+    }
+    if c_compiler_available() {
+        backends.push(Backend::C);
+    }
+    backends
+}
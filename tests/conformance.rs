@@ -0,0 +1,286 @@
+// Golden-output tests: every case in tests/golden is run under the interpreter and compiled at each
+// optimization level, and the program's stdout, final stack and exit status are compared against the
+// checked-in expectations.
+//
+// A case is a `.golden` file of `key: value` lines:
+//   image:  path to the program, relative to the repository root
+//   args:   extra pietcc arguments, e.g. a codel size (optional)
+//   stdin:  input fed to the program, with \n escapes (optional)
+//   stdout: expected output, with \n escapes
+//   stack:  expected final stack, top first, space separated
+//   status: expected exit status
+//
+// Every image in images has a case, unless it's listed in `EXCLUDED`.
+
+mod common;
+
+use cfg_to_ir::difftest::INPUT_PROMPTS;
+use common::{toolchain_available, work_dir};
+use piet_core::state::ExecutionState;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+const PIETCC: &str = env!("CARGO_BIN_EXE_pietcc");
+const OPT_LEVELS: [&str; 4] = ["", "--o1", "--o2", "--o3"];
+
+/// Images without a case, which have no final state to compare
+const EXCLUDED: [(&str, &str); 17] = [
+    ("ILoveYouLaura.png", "never terminates"),
+    ("Piet-1.gif", "loops forever after printing"),
+    ("Piet-4.gif", "loops forever after printing"),
+    ("bounce.png", "bounces between two blocks forever"),
+    (
+        "erat2.png",
+        "never terminates, and has pixels that aren't Piet colours",
+    ),
+    ("fibbig.gif", "prints forever"),
+    ("hw2-1.gif", "loops forever after printing"),
+    ("hw2-11.gif", "loops forever after printing"),
+    ("hw2-anim-11.gif", "loops forever after printing"),
+    ("hw5.png", "prints forever"),
+    ("hw5_big.png", "prints forever"),
+    ("japh_big.png", "prints forever"),
+    (
+        "pietquest.png",
+        "an interactive game that keeps asking for input",
+    ),
+    (
+        "primetest.png",
+        "the pixel it starts from isn't a Piet colour, so it has no entry block",
+    ),
+    ("test3.png", "never terminates"),
+    ("test_break.png", "never terminates"),
+    ("test_push.png", "never terminates"),
+];
+
+#[derive(Debug)]
+struct Case {
+    name: String,
+    image: PathBuf,
+    args: Vec<String>,
+    stdin: String,
+    expected: Outcome,
+}
+
+#[derive(Debug, PartialEq)]
+struct Outcome {
+    stdout: String,
+    stack: Vec<i64>,
+    status: i32,
+}
+
+fn unescape(val: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = val.chars();
+    while let Some(c) = chars.next() {
+        match (c, c == '\\') {
+            (_, true) => match chars.next() {
+                Some('n') => unescaped.push('\n'),
+                Some('t') => unescaped.push('\t'),
+                Some(other) => unescaped.push(other),
+                None => unescaped.push('\\'),
+            },
+            (c, false) => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+fn parse_stack(val: &str) -> Vec<i64> {
+    val.split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|elem| !elem.is_empty())
+        .map(|elem| elem.parse().unwrap())
+        .collect()
+}
+
+fn load_cases() -> Vec<Case> {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut paths = fs::read_dir(root.join("tests/golden"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "golden"))
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    paths
+        .into_iter()
+        .map(|path| {
+            let contents = fs::read_to_string(&path).unwrap();
+            let fields = contents
+                .lines()
+                .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+                .map(|line| {
+                    let (key, val) = line.split_once(':').unwrap();
+                    (key.trim(), val.trim())
+                })
+                .collect::<HashMap<_, _>>();
+            let field = |key: &str| fields.get(key).copied().unwrap_or_default();
+
+            Case {
+                name: path.file_stem().unwrap().to_string_lossy().into_owned(),
+                image: root.join(field("image")),
+                args: field("args").split_whitespace().map(String::from).collect(),
+                stdin: unescape(field("stdin")),
+                expected: Outcome {
+                    stdout: unescape(field("stdout")),
+                    stack: parse_stack(field("stack")),
+                    status: field("status").parse().unwrap_or(0),
+                },
+            }
+        })
+        .collect()
+}
+
+fn run(command: &mut Command, stdin: &str) -> Output {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+// The interpreter saves its final state, including what the program printed, to a snapshot.  What it
+// printed has to have reached stdout too.
+fn interpret(case: &Case, dir: &Path) -> Result<Outcome, String> {
+    let snapshot = dir.join(format!("{}.snapshot", case.name));
+    let output = run(
+        Command::new(PIETCC)
+            .arg(&case.image)
+            .arg("-i")
+            .arg("--snapshot")
+            .arg(&snapshot)
+            .args(&case.args),
+        &case.stdin,
+    );
+    let state = fs::read_to_string(&snapshot)
+        .map_err(|err| err.to_string())
+        .and_then(|snapshot| ExecutionState::from_snapshot(&snapshot))
+        .map_err(|err| format!("no snapshot: {}", err))?;
+    let program_stdout = state
+        .stdout
+        .iter()
+        .map(ToString::to_string)
+        .collect::<String>();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !stdout.starts_with(&program_stdout) {
+        return Err(format!(
+            "output {:?} not printed: {:?}",
+            program_stdout, stdout
+        ));
+    }
+
+    Ok(Outcome {
+        stdout: program_stdout,
+        stack: state.stack.into_iter().collect(),
+        status: output.status.code().unwrap_or(-1),
+    })
+}
+
+// Compiled programs print their output followed by the final stack
+fn compile_and_run(case: &Case, opt_level: &str, dir: &Path) -> Result<Outcome, String> {
+    let binary = dir.join(format!("{}{}", case.name, opt_level.replace('-', "_")));
+    let compiled = run(
+        Command::new(PIETCC)
+            .arg(&case.image)
+            .arg("-o")
+            .arg(&binary)
+            .args(opt_level.split_whitespace())
+            .args(&case.args),
+        "",
+    );
+    if !compiled.status.success() || !binary.exists() {
+        return Err(format!(
+            "compilation failed: {}",
+            String::from_utf8_lossy(&compiled.stderr)
+        ));
+    }
+
+    let output = run(&mut Command::new(&binary), &case.stdin);
+    let mut stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    for prompt in INPUT_PROMPTS {
+        stdout = stdout.replace(prompt, "");
+    }
+    let (program_stdout, stack) = stdout
+        .rsplit_once("\nStack")
+        .ok_or_else(|| format!("no stack in output: {:?}", stdout))?;
+    let stack = stack.split_once(": ").map_or("", |(_, vals)| vals);
+
+    Ok(Outcome {
+        stdout: program_stdout.to_owned(),
+        stack: parse_stack(stack),
+        status: output.status.code().unwrap_or(-1),
+    })
+}
+
+fn check(case: &Case, backend: &str, outcome: Result<Outcome, String>, failures: &mut Vec<String>) {
+    match outcome {
+        Ok(outcome) if outcome == case.expected => (),
+        Ok(outcome) => failures.push(format!(
+            "{} ({}): expected {:?}, got {:?}",
+            case.name, backend, case.expected, outcome
+        )),
+        Err(err) => failures.push(format!("{} ({}): {}", case.name, backend, err)),
+    }
+}
+
+#[test]
+fn test_every_image_has_a_case() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let cases = load_cases()
+        .into_iter()
+        .map(|case| case.image)
+        .collect::<HashSet<_>>();
+    let mut missing = fs::read_dir(root.join("images"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|image| {
+            let name = image.file_name().unwrap().to_string_lossy();
+            !cases.contains(image) && !EXCLUDED.iter().any(|(excluded, _)| name == *excluded)
+        })
+        .collect::<Vec<_>>();
+    missing.sort();
+    assert!(missing.is_empty(), "no golden case for {:?}", missing);
+}
+
+#[test]
+fn test_interpreter_golden() {
+    let dir = work_dir("golden");
+    let mut failures = vec![];
+    for case in load_cases() {
+        check(&case, "interpreter", interpret(&case, &dir), &mut failures);
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[test]
+fn test_compiled_golden() {
+    if !toolchain_available() {
+        return;
+    }
+
+    let dir = work_dir("golden");
+    let mut failures = vec![];
+    for case in load_cases() {
+        for opt_level in OPT_LEVELS {
+            let backend = format!("compiled {}", opt_level);
+            check(
+                &case,
+                &backend,
+                compile_and_run(&case, opt_level, &dir),
+                &mut failures,
+            );
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
@@ -0,0 +1,113 @@
+// Div and Mod on negative operands, zero divisors and i64::MIN / -1, under each division semantics.
+// Operands are pushed as constants, which optimized builds fold at compile time, and read from input,
+// which they can't.
+
+mod common;
+
+use asm::assemble;
+use cfg_to_ir::difftest::{observe, DiffTestSettings};
+use common::{backends, work_dir};
+use piet_core::settings::{CodelSettings, DivisionSemantics};
+use std::time::Duration;
+
+// (b, a, b / a, b mod a), where None is a zero divisor
+type Row = (i64, i64, Option<i64>, Option<i64>);
+
+const NPIET: [Row; 9] = [
+    (7, 2, Some(3), Some(1)),
+    (-7, 2, Some(-3), Some(1)),
+    (7, -2, Some(-3), Some(-1)),
+    (-7, -2, Some(3), Some(-1)),
+    (6, -3, Some(-2), Some(0)),
+    (i64::MIN, -1, Some(i64::MIN), Some(0)),
+    (i64::MAX, i64::MIN, Some(0), Some(-1)),
+    (5, 0, None, None),
+    (-5, 0, None, None),
+];
+
+const EUCLID: [Row; 9] = [
+    (7, 2, Some(3), Some(1)),
+    (-7, 2, Some(-4), Some(1)),
+    (7, -2, Some(-3), Some(1)),
+    (-7, -2, Some(4), Some(1)),
+    (6, -3, Some(-2), Some(0)),
+    (i64::MIN, -1, Some(i64::MIN), Some(0)),
+    (i64::MAX, i64::MIN, Some(0), Some(i64::MAX)),
+    (5, 0, None, None),
+    (-5, 0, None, None),
+];
+
+// The assembler can't push i64::MIN directly
+fn push(n: i64) -> String {
+    match n {
+        i64::MIN => format!("push {}\npush 1\nsub", i64::MIN + 1),
+        n => format!("push {n}"),
+    }
+}
+
+// Runs `b <instr> a` on every backend, checking the output and final stack.  A zero divisor leaves both
+// operands, so the divisor is what gets printed.
+fn check(division: DivisionSemantics, b: i64, a: i64, instr: &str, expected: Option<i64>) {
+    let (stdout, stack) = match expected {
+        Some(result) => (result.to_string(), vec![]),
+        None => (a.to_string(), vec![b]),
+    };
+
+    let backends = backends();
+
+    let dir = work_dir("division");
+    let settings = DiffTestSettings {
+        codel_settings: CodelSettings::Width(1),
+        reference: None,
+        max_steps: 1000,
+        timeout: Duration::from_secs(10),
+        work_dir: &dir,
+        division,
+        error_policy: Default::default(),
+        input: Default::default(),
+        invalid_char: Default::default(),
+    };
+    let programs = [
+        (
+            format!("{}\n{}\n{instr}\nout(int)", push(b), push(a)),
+            String::new(),
+        ),
+        (
+            format!("in(int)\nin(int)\n{instr}\nout(int)"),
+            format!("{b}\n{a}\n"),
+        ),
+    ];
+
+    for (source, input) in programs {
+        let assembled = assemble(&source).unwrap();
+        let program = assembled.source();
+        for backend in &backends {
+            let filename = dir.join(format!("{instr}_{:?}.png", division));
+            let observed = observe(
+                backend,
+                filename.to_str().unwrap(),
+                &program,
+                &input,
+                &settings,
+            )
+            .unwrap();
+            let case = format!("{b} {instr} {a} ({division:?}, {backend}, input {input:?})");
+            assert!(observed.terminated, "{case} didn't terminate");
+            assert_eq!(observed.stdout, stdout, "{case}");
+            assert_eq!(observed.stack.as_ref(), Some(&stack), "{case}");
+        }
+    }
+}
+
+#[test]
+fn test_division_semantics() {
+    for (division, table) in [
+        (DivisionSemantics::Npiet, NPIET),
+        (DivisionSemantics::Euclid, EUCLID),
+    ] {
+        for (b, a, quot, rem) in table {
+            check(division, b, a, "div", quot);
+            check(division, b, a, "mod", rem);
+        }
+    }
+}
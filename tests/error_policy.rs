@@ -0,0 +1,193 @@
+// A program that underflows the stack and divides by zero, run under each error policy by the interpreter
+// and compiled at each optimization level, checking what it writes to stderr and its exit status, and that
+// difftest compares both across backends.
+
+mod common;
+
+use asm::assemble;
+use cfg_to_ir::difftest::{compare, observe, Backend, DiffTestSettings, OPT_LEVELS};
+use cfg_to_ir::lowering_ctx::LoweringCtx;
+use cfg_to_ir::pipeline::run_piet_optimization_pipeline;
+use common::{backends, toolchain_available, work_dir};
+use inkwell::context::Context;
+use inkwell::OptimizationLevel;
+use interpreter::interpreter::Interpreter;
+use parser::cfg::CFGBuilder;
+use piet_core::program::PietSource;
+use piet_core::settings::{
+    CodelSettings, CompilerSettings, ErrorPolicy, InterpreterSettings, SaveOptions, Verbosity,
+};
+use piet_core::state::{ExecutionStatus, EXIT_ABORTED, EXIT_COMPLETED};
+use std::process::Command;
+use std::time::Duration;
+
+// Add underflows at step 1, and div divides 7 by 0 at step 6
+const SOURCE: &str = "add\npush 7\npush 1\npush 1\nsub\ndiv\nout(int)";
+
+fn compile(
+    program: &PietSource,
+    opt_level: OptimizationLevel,
+    error_policy: ErrorPolicy,
+    output_fname: &str,
+) {
+    let context = Context::create();
+    let module = context.create_module("piet");
+    let builder = context.create_builder();
+    let settings = CompilerSettings {
+        opt_level,
+        codel_settings: CodelSettings::Width(1),
+        save_options: SaveOptions::EmitExecutable,
+        output_fname,
+        show_codel_size: false,
+        show_cfg_size: false,
+        warn_nt: false,
+        verbosity: Verbosity::Low,
+        coverage: false,
+        trace: false,
+        debug_info: None,
+        division: Default::default(),
+        error_policy,
+        input: Default::default(),
+        invalid_char: Default::default(),
+        platform: Default::default(),
+    };
+
+    let mut cfg_builder = CFGBuilder::new(program, settings.codel_settings, false);
+    cfg_builder.build();
+    let cfg_gen = CFGBuilder::new(program, settings.codel_settings, false);
+    let mut ctx = LoweringCtx::new(&context, module, builder, cfg_gen, settings);
+    run_piet_optimization_pipeline(&mut ctx, &mut cfg_builder.get_cfg(), settings).unwrap();
+}
+
+#[test]
+fn test_interpreter_error_policy() {
+    let assembled = assemble(SOURCE).unwrap();
+    let program = assembled.source();
+    let mut cfg_builder = CFGBuilder::new(&program, CodelSettings::Width(1), false);
+    cfg_builder.build();
+    let cfg = cfg_builder.get_cfg();
+
+    for error_policy in [ErrorPolicy::Ignore, ErrorPolicy::Warn, ErrorPolicy::Abort] {
+        let settings = InterpreterSettings {
+            codel_settings: CodelSettings::Width(1),
+            print: false,
+            error_policy,
+            ..Default::default()
+        };
+        let mut interpreter = Interpreter::new(&cfg, settings);
+        let state = interpreter.run();
+        let stderr = interpreter.get_stderr().lines().collect::<Vec<_>>();
+        match error_policy {
+            ErrorPolicy::Ignore => assert!(stderr.is_empty()),
+            ErrorPolicy::Warn => {
+                assert_eq!(stderr.len(), 2);
+                assert!(
+                    stderr[0].starts_with("warning: stack underflow on add at step 1 in block ")
+                );
+                assert!(
+                    stderr[1].starts_with("warning: division by zero on div at step 6 in block ")
+                );
+            }
+            ErrorPolicy::Abort => {
+                assert_eq!(stderr.len(), 1);
+                assert!(stderr[0].starts_with("error: stack underflow on add at step 1 in block "));
+            }
+        }
+        if error_policy == ErrorPolicy::Abort {
+            assert_eq!(state.status, ExecutionStatus::Aborted);
+            assert_eq!(state.status.exit_code(), EXIT_ABORTED);
+            assert_eq!(state.steps, 1);
+            assert!(state.stdout.is_empty());
+        } else {
+            // Both bad instructions are skipped, and the zero divisor is what gets printed
+            assert_eq!(state.status, ExecutionStatus::Completed, "{error_policy:?}");
+            assert_eq!(state.status.exit_code(), EXIT_COMPLETED);
+            assert_eq!(state.stdout.len(), 1);
+            assert_eq!(state.stdout[0].to_string(), "0");
+            assert_eq!(state.stack, [7]);
+        }
+    }
+}
+
+#[test]
+fn test_compiled_error_policy() {
+    if !toolchain_available() {
+        return;
+    }
+    let assembled = assemble(SOURCE).unwrap();
+    let program = assembled.source();
+    let dir = work_dir("error_policy");
+
+    for opt_level in OPT_LEVELS {
+        for error_policy in [ErrorPolicy::Ignore, ErrorPolicy::Warn, ErrorPolicy::Abort] {
+            let binary = dir.join(format!("{opt_level:?}_{error_policy:?}"));
+            let binary = binary.to_str().unwrap();
+            compile(&program, opt_level, error_policy, binary);
+            let output = Command::new(binary).output().unwrap();
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let stderr = stderr.lines().collect::<Vec<_>>();
+            let case = format!("{opt_level:?}, {error_policy:?}");
+
+            match error_policy {
+                ErrorPolicy::Ignore => {
+                    assert!(output.status.success(), "{case}");
+                    assert!(stdout.starts_with('0'), "{case}");
+                    assert!(stderr.is_empty(), "{case}");
+                }
+                ErrorPolicy::Warn => {
+                    assert!(output.status.success(), "{case}");
+                    assert!(stdout.starts_with('0'), "{case}");
+                    assert_eq!(stderr.len(), 2, "{case}");
+                    assert!(stderr[0]
+                        .starts_with("warning: stack underflow on add at step 1 in block "));
+                    assert!(stderr[1]
+                        .starts_with("warning: division by zero on div at step 6 in block "));
+                }
+                ErrorPolicy::Abort => {
+                    assert_eq!(output.status.code(), Some(EXIT_ABORTED), "{case}");
+                    assert!(stdout.is_empty(), "{case}");
+                    assert_eq!(stderr.len(), 1, "{case}");
+                    assert!(
+                        stderr[0].starts_with("error: stack underflow on add at step 1 in block ")
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_difftest_error_policy() {
+    let assembled = assemble(SOURCE).unwrap();
+    let program = assembled.source();
+    let dir = work_dir("error_policy");
+
+    for error_policy in [ErrorPolicy::Ignore, ErrorPolicy::Warn, ErrorPolicy::Abort] {
+        let settings = DiffTestSettings {
+            codel_settings: CodelSettings::Width(1),
+            reference: None,
+            max_steps: 1000,
+            timeout: Duration::from_secs(10),
+            work_dir: &dir,
+            division: Default::default(),
+            error_policy,
+            input: Default::default(),
+            invalid_char: Default::default(),
+        };
+        let filename = dir.join(format!("{error_policy:?}.png"));
+        let filename = filename.to_str().unwrap();
+        let expected = observe(&Backend::Interpreter, filename, &program, "", &settings).unwrap();
+        assert_eq!(
+            expected.stderr.as_deref() == Some(""),
+            error_policy == ErrorPolicy::Ignore
+        );
+
+        // Warnings and errors are compared along with the output and exit status
+        for backend in backends() {
+            let found = observe(&backend, filename, &program, "", &settings).unwrap();
+            let divergence = compare(&backend, &expected, &found);
+            assert_eq!(divergence, None, "{error_policy:?}");
+        }
+    }
+}
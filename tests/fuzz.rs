@@ -0,0 +1,182 @@
+// Property tests over randomly generated programs.  Every program is written out as an image and loaded
+// back through `Loader`, then run through the interpreter and lowered to LLVM IR at each optimization
+// level.  A panic anywhere, a module that fails verification, or a compiled binary that disagrees with
+// the interpreter fails the test, and proptest reports the generator settings, seed and input that
+// reproduce it.
+//
+// The default number of cases keeps `cargo test` fast; set PROPTEST_CASES to fuzz for longer.  Building
+// and running binaries is much slower than lowering, so compiled programs are only compared against the
+// interpreter when PIET_FUZZ_COMPILED is set, and so are programs lowered to C.
+
+mod common;
+
+use cfg_to_ir::difftest::{compare, observe, Backend, DiffTestSettings, OPT_LEVELS};
+use cfg_to_ir::lowering_ctx::LoweringCtx;
+use cfg_to_ir::pipeline::run_piet_optimization_pipeline;
+use common::{c_compiler_available, toolchain_available, work_dir};
+use inkwell::context::Context;
+use inkwell::OptimizationLevel;
+use parser::cfg::CFGBuilder;
+use parser::convert::UnknownPixelSettings;
+use parser::generate::{GeneratorSettings, ProgramGenerator};
+use parser::loader::Loader;
+use piet_core::program::PietSource;
+use piet_core::settings::{CodelSettings, CompilerSettings, SaveOptions, Verbosity};
+use proptest::prelude::*;
+use std::env;
+use std::error::Error;
+use std::time::Duration;
+
+const MAX_STEPS: u64 = 10000;
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+fn generator_settings() -> impl Strategy<Value = GeneratorSettings> {
+    (
+        1u32..12,
+        1u32..12,
+        1u32..4,
+        0.0..0.3,
+        0.0..0.3,
+        0.0..0.8,
+        prop::array::uniform6(1u32..4),
+        prop::array::uniform3(1u32..4),
+    )
+        .prop_map(
+            |(
+                height,
+                width,
+                codel_size,
+                white_density,
+                black_density,
+                block_density,
+                hue_weights,
+                lightness_weights,
+            )| GeneratorSettings {
+                height,
+                width,
+                codel_size,
+                white_density,
+                black_density,
+                block_density,
+                hue_weights,
+                lightness_weights,
+            },
+        )
+}
+
+// Numbers and characters for `IntIn` and `CharIn` to read, one per line
+fn program_input() -> impl Strategy<Value = String> {
+    prop::collection::vec(0i64..100, 0..4).prop_map(|nums| {
+        nums.iter()
+            .map(|num| format!("{num}\n"))
+            .collect::<String>()
+    })
+}
+
+fn compare_compiled() -> bool {
+    env::var_os("PIET_FUZZ_COMPILED").is_some() && toolchain_available()
+}
+
+fn compare_c() -> bool {
+    env::var_os("PIET_FUZZ_COMPILED").is_some() && c_compiler_available()
+}
+
+// Lowers the program to LLVM IR, which only needs LLVM itself
+fn emit_llvm_ir(
+    program: &PietSource,
+    codel_settings: CodelSettings,
+    opt_level: OptimizationLevel,
+    output_fname: &str,
+) -> Result<(), Box<dyn Error>> {
+    let context = Context::create();
+    let module = context.create_module("piet");
+    let builder = context.create_builder();
+    let settings = CompilerSettings {
+        opt_level,
+        codel_settings,
+        save_options: SaveOptions::EmitLLVMIR,
+        output_fname,
+        show_codel_size: false,
+        show_cfg_size: false,
+        warn_nt: false,
+        verbosity: Verbosity::Low,
+        coverage: false,
+        trace: false,
+        debug_info: None,
+        division: Default::default(),
+        error_policy: Default::default(),
+        input: Default::default(),
+        invalid_char: Default::default(),
+        platform: Default::default(),
+    };
+
+    let mut cfg_builder = CFGBuilder::new(program, codel_settings, false);
+    cfg_builder.build();
+    let cfg_gen = CFGBuilder::new(program, codel_settings, false);
+    let mut ctx = LoweringCtx::new(&context, module, builder, cfg_gen, settings);
+    run_piet_optimization_pipeline(&mut ctx, &mut cfg_builder.get_cfg(), settings)
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn test_random_programs(
+        settings in generator_settings(),
+        seed in any::<u64>(),
+        input in program_input(),
+    ) {
+        let generated = ProgramGenerator::new(settings, seed).generate();
+        let dir = work_dir("fuzz");
+        let compare_compiled = compare_compiled();
+        let image = dir.join(format!("{seed:016x}.png"));
+        generated.to_image().save(&image).unwrap();
+        let filename = image.to_str().unwrap();
+
+        let program = Loader::convert(filename, UnknownPixelSettings::TreatAsError).unwrap();
+        prop_assert_eq!(program.get_underlying_vec(), &generated.pixels);
+
+        let difftest_settings = DiffTestSettings {
+            codel_settings: CodelSettings::Width(generated.codel_size),
+            reference: None,
+            max_steps: MAX_STEPS,
+            timeout: TIMEOUT,
+            work_dir: &dir,
+            division: Default::default(),
+            error_policy: Default::default(),
+            input: Default::default(),
+            invalid_char: Default::default(),
+        };
+        let expected = observe(&Backend::Interpreter, filename, &program, &input, &difftest_settings)
+            .unwrap();
+
+        for opt_level in OPT_LEVELS {
+            let output = dir.join(format!("{seed:016x}_{}", opt_level as u32));
+            let lowered = emit_llvm_ir(
+                &program,
+                difftest_settings.codel_settings,
+                opt_level,
+                output.to_str().unwrap(),
+            );
+            prop_assert!(lowered.is_ok(), "{}", lowered.unwrap_err());
+
+            // Programs that outrun the interpreter would only be compared on their output so far
+            if expected.terminated && compare_compiled {
+                let backend = Backend::Compiled(opt_level);
+                let found = observe(&backend, filename, &program, &input, &difftest_settings);
+                let found = found.map_err(|err| err.to_string());
+                prop_assert!(found.is_ok(), "{}", found.unwrap_err());
+                let divergence = compare(&backend, &expected, &found.unwrap());
+                prop_assert!(divergence.is_none(), "{}", divergence.unwrap());
+            }
+        }
+
+        if expected.terminated && compare_c() {
+            let found = observe(&Backend::C, filename, &program, &input, &difftest_settings);
+            let found = found.map_err(|err| err.to_string());
+            prop_assert!(found.is_ok(), "{}", found.unwrap_err());
+            let divergence = compare(&Backend::C, &expected, &found.unwrap());
+            prop_assert!(divergence.is_none(), "{}", divergence.unwrap());
+        }
+    }
+}
@@ -0,0 +1,109 @@
+// Input instructions at EOF and on bad input, under each input policy.  Malformed UTF-8 can't be passed
+// as program input here, so bad characters are only covered by the interpreter's reader tests.
+
+mod common;
+
+use asm::assemble;
+use cfg_to_ir::difftest::{observe, DiffTestSettings};
+use common::{backends, work_dir};
+use piet_core::settings::{CodelSettings, InputPolicy, InputSettings};
+use piet_core::state::{EXIT_COMPLETED, EXIT_INPUT_FAILED};
+use std::time::Duration;
+
+// Reads three numbers from a line holding one, a line that isn't a number, and then the end of input
+const INTS: &str = "in(int)\nin(int)\nin(int)\nout(int)\nout(int)\nout(int)";
+const INT_INPUT: &str = "  12 \nabc 4\n";
+
+// Reads four characters from two multi-byte ones and then the end of input
+const CHARS: &str =
+    "in(char)\nin(char)\nin(char)\nin(char)\nout(int)\nout(int)\nout(int)\nout(int)";
+const CHAR_INPUT: &str = "é€";
+
+// Runs `source` on every backend, checking what it prints and what's left on the stack.  Every case run
+// with `on_eof` set to terminate stops on input, and exits with `EXIT_INPUT_FAILED`.
+fn check(source: &str, input: &str, settings: InputSettings, stdout: &str, stack: &[i64]) {
+    let backends = backends();
+
+    let dir = work_dir("input");
+    let difftest_settings = DiffTestSettings {
+        codel_settings: CodelSettings::Width(1),
+        reference: None,
+        max_steps: 1000,
+        timeout: Duration::from_secs(10),
+        work_dir: &dir,
+        division: Default::default(),
+        error_policy: Default::default(),
+        input: settings,
+        invalid_char: Default::default(),
+    };
+
+    let assembled = assemble(source).unwrap();
+    let program = assembled.source();
+    for backend in &backends {
+        let filename = dir.join("input.png");
+        let observed = observe(
+            backend,
+            filename.to_str().unwrap(),
+            &program,
+            input,
+            &difftest_settings,
+        )
+        .unwrap();
+        let case = format!("{settings:?}, {backend}, input {input:?}");
+        assert!(observed.terminated, "{case} didn't terminate");
+        assert_eq!(observed.stdout, stdout, "{case}");
+        assert_eq!(observed.stack.as_deref(), Some(stack), "{case}");
+        let exit_code = if settings.on_eof == InputPolicy::Terminate {
+            EXIT_INPUT_FAILED
+        } else {
+            EXIT_COMPLETED
+        };
+        assert_eq!(observed.exit_code, Some(exit_code), "{case}");
+    }
+}
+
+fn both(policy: InputPolicy) -> InputSettings {
+    InputSettings {
+        on_eof: policy,
+        on_bad_input: policy,
+    }
+}
+
+#[test]
+fn test_int_input() {
+    check(INTS, INT_INPUT, both(InputPolicy::Skip), "12", &[]);
+    check(INTS, INT_INPUT, both(InputPolicy::PushZero), "0012", &[]);
+    check(
+        INTS,
+        INT_INPUT,
+        both(InputPolicy::PushMinusOne),
+        "-1-112",
+        &[],
+    );
+    check(INTS, INT_INPUT, both(InputPolicy::Terminate), "", &[12]);
+
+    let settings = InputSettings {
+        on_eof: InputPolicy::PushZero,
+        on_bad_input: InputPolicy::Skip,
+    };
+    check(INTS, INT_INPUT, settings, "012", &[]);
+}
+
+#[test]
+fn test_char_input() {
+    check(CHARS, CHAR_INPUT, both(InputPolicy::Skip), "8364233", &[]);
+    check(
+        CHARS,
+        CHAR_INPUT,
+        both(InputPolicy::PushMinusOne),
+        "-1-18364233",
+        &[],
+    );
+    check(
+        CHARS,
+        CHAR_INPUT,
+        both(InputPolicy::Terminate),
+        "",
+        &[8364, 233],
+    );
+}
@@ -0,0 +1,342 @@
+// Programs compiled into a library with `--library`, and linked with cc into a host that runs them through
+// piet_run.  The host gives the program its input from memory and collects its output, errors and stack,
+// and runs it twice to check a second run starts afresh.
+
+mod common;
+
+use asm::assemble;
+use cfg_to_ir::difftest::{observe, Backend, DiffTestSettings};
+use cfg_to_ir::lowering_ctx::LoweringCtx;
+use cfg_to_ir::pipeline::run_piet_optimization_pipeline;
+use common::{c_compiler_available, work_dir};
+use inkwell::context::Context;
+use inkwell::OptimizationLevel;
+use parser::cfg::CFGBuilder;
+use piet_core::program::PietSource;
+use piet_core::settings::{CodelSettings, CompilerSettings, ErrorPolicy, SaveOptions, Verbosity};
+use piet_core::state::{EXIT_ABORTED, EXIT_COMPLETED, EXIT_STACK_EXHAUSTED};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+// Reads all of stdin up front, runs the program twice on it with a stack of argv[1] values, and prints
+// what each run wrote, its status, and its stack from the top down.  Errors go to stderr as they're
+// reported.
+const HOST: &str = r#"
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include "piet.h"
+
+struct buffers {
+    char input[4096];
+    size_t input_len;
+    size_t input_pos;
+    char output[4096];
+    size_t output_len;
+};
+
+static int read_byte(void *ctx) {
+    struct buffers *b = ctx;
+    return b->input_pos < b->input_len ? (unsigned char)b->input[b->input_pos++] : -1;
+}
+
+static void write_output(void *ctx, const char *buf, size_t len) {
+    struct buffers *b = ctx;
+    memcpy(b->output + b->output_len, buf, len);
+    b->output_len += len;
+}
+
+static void write_error(void *ctx, const char *buf, size_t len) {
+    (void)ctx;
+    fwrite(buf, 1, len, stderr);
+}
+
+int main(int argc, char **argv) {
+    static struct buffers b;
+    size_t capacity = argc > 1 ? (size_t)atoi(argv[1]) : 1024;
+    int64_t *stack = malloc((capacity + 1) * sizeof *stack);
+    size_t stack_size, i;
+    int run, status;
+    piet_io io;
+
+    b.input_len = fread(b.input, 1, sizeof b.input, stdin);
+    io.read_byte = read_byte;
+    io.write = write_output;
+    io.write_error = write_error;
+    io.ctx = &b;
+    io.stack = stack;
+    io.stack_capacity = capacity;
+    io.stack_size = &stack_size;
+    for (run = 0; run < 2; run++) {
+        b.input_pos = 0;
+        b.output_len = 0;
+        status = piet_run(&io);
+        printf("output %zu\n", b.output_len);
+        fwrite(b.output, 1, b.output_len, stdout);
+        printf("\nstatus %d\nstack", status);
+        for (i = stack_size; i > 0; i--) {
+            printf(" %lld", (long long)stack[i - 1]);
+        }
+        printf("\n");
+    }
+    return 0;
+}
+"#;
+
+// What one call to piet_run did
+#[derive(Debug, PartialEq)]
+struct Run {
+    output: String,
+    status: i32,
+    stack: Vec<i64>,
+}
+
+fn compiler_settings(output_fname: &str) -> CompilerSettings<'_> {
+    CompilerSettings {
+        opt_level: OptimizationLevel::None,
+        codel_settings: CodelSettings::Width(1),
+        save_options: SaveOptions::EmitLibrary,
+        output_fname,
+        show_codel_size: false,
+        show_cfg_size: false,
+        warn_nt: false,
+        verbosity: Verbosity::Low,
+        coverage: false,
+        trace: false,
+        debug_info: None,
+        division: Default::default(),
+        error_policy: Default::default(),
+        input: Default::default(),
+        invalid_char: Default::default(),
+        platform: Default::default(),
+    }
+}
+
+// Compiles the program into a library, and links it into a host called `name` in `dir`
+fn build(dir: &Path, name: &str, program: &PietSource, settings: CompilerSettings) -> PathBuf {
+    let context = Context::create();
+    let module = context.create_module("piet");
+    let builder = context.create_builder();
+
+    let mut cfg_builder = CFGBuilder::new(program, settings.codel_settings, false);
+    cfg_builder.build();
+    let cfg_gen = CFGBuilder::new(program, settings.codel_settings, false);
+    let mut ctx = LoweringCtx::new(&context, module, builder, cfg_gen, settings);
+    run_piet_optimization_pipeline(&mut ctx, &mut cfg_builder.get_cfg(), settings).unwrap();
+
+    // Each host includes its own program's header, so tests can build at once
+    let host = dir.join(format!("{name}.c"));
+    let header = format!("{}.h", settings.output_fname);
+    fs::write(&host, HOST.replace("piet.h", &header)).unwrap();
+    let binary = dir.join(name);
+    let output = Command::new("cc")
+        .args(["-std=c99", "-Wall", "-Wextra", "-Werror", "-o"])
+        .arg(&binary)
+        .arg(&host)
+        .arg(format!("{}.o", settings.output_fname))
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    binary
+}
+
+// Runs the host, and returns both of its runs and everything written to stderr
+fn run(binary: &Path, capacity: usize, input: &str) -> (Run, Run, String) {
+    let mut child = Command::new(binary)
+        .arg(capacity.to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mut rest = stdout.as_str();
+    let mut runs = Vec::new();
+    for _ in 0..2 {
+        let (len, after) = rest
+            .strip_prefix("output ")
+            .unwrap()
+            .split_once('\n')
+            .unwrap();
+        let (program_output, after) = after.split_at(len.parse().unwrap());
+        let mut lines = after.strip_prefix('\n').unwrap().splitn(3, '\n');
+        let status = lines.next().unwrap().strip_prefix("status ").unwrap();
+        let stack = lines.next().unwrap().strip_prefix("stack").unwrap();
+        runs.push(Run {
+            output: program_output.to_string(),
+            status: status.parse().unwrap(),
+            stack: stack
+                .split_whitespace()
+                .map(|value| value.parse().unwrap())
+                .collect(),
+        });
+        rest = lines.next().unwrap_or("");
+    }
+    let second = runs.pop().unwrap();
+    let first = runs.pop().unwrap();
+    (first, second, String::from_utf8(output.stderr).unwrap())
+}
+
+#[test]
+fn test_library_matches_interpreter() {
+    if !c_compiler_available() {
+        return;
+    }
+    let dir = work_dir("library");
+    let settings = DiffTestSettings {
+        codel_settings: CodelSettings::Width(1),
+        reference: None,
+        max_steps: 1000,
+        timeout: Duration::from_secs(10),
+        work_dir: &dir,
+        division: Default::default(),
+        error_policy: Default::default(),
+        input: Default::default(),
+        invalid_char: Default::default(),
+    };
+
+    let programs = [
+        // Counts down from 3, printing each number
+        (
+            "push 3\nloop:\ndup\nout(int)\npush 1\nsub\ndup\njnz loop\npop",
+            "",
+        ),
+        // Reads a number and a character, and writes them back
+        ("in(int)\ndup\nout(int)\nin(char)\ndup\nout(char)", "42é"),
+        // Input that runs out leaves the stack as it was
+        ("push 5\nin(int)\nin(char)", ""),
+    ];
+    for (i, (source, input)) in programs.into_iter().enumerate() {
+        let assembled = assemble(source).unwrap();
+        let program = assembled.source();
+        let filename = dir.join(format!("program_{i}.png"));
+        let expected = observe(
+            &Backend::Interpreter,
+            filename.to_str().unwrap(),
+            &program,
+            input,
+            &settings,
+        )
+        .unwrap();
+
+        for opt_level in [OptimizationLevel::None, OptimizationLevel::Default] {
+            let library = dir.join(format!("program_{i}_{opt_level:?}"));
+            let binary = build(
+                &dir,
+                &format!("host_{i}_{opt_level:?}"),
+                &program,
+                CompilerSettings {
+                    opt_level,
+                    ..compiler_settings(library.to_str().unwrap())
+                },
+            );
+            let (first, second, stderr) = run(&binary, 1024, input);
+            assert_eq!(first.output, expected.stdout, "{source:?}");
+            assert_eq!(Some(first.stack.clone()), expected.stack, "{source:?}");
+            assert_eq!(first.status, EXIT_COMPLETED);
+            assert_eq!(first, second, "{source:?}");
+            assert!(stderr.is_empty());
+        }
+    }
+}
+
+#[test]
+fn test_library_errors() {
+    if !c_compiler_available() {
+        return;
+    }
+    // Add underflows at step 1, and div divides 7 by 0 at step 6
+    let assembled = assemble("add\npush 7\npush 1\npush 1\nsub\ndiv\nout(int)").unwrap();
+    let program = assembled.source();
+    let dir = work_dir("library");
+
+    for error_policy in [ErrorPolicy::Warn, ErrorPolicy::Abort] {
+        let library = dir.join(format!("errors_{error_policy:?}"));
+        let binary = build(
+            &dir,
+            &format!("host_errors_{error_policy:?}"),
+            &program,
+            CompilerSettings {
+                error_policy,
+                ..compiler_settings(library.to_str().unwrap())
+            },
+        );
+        let (first, second, stderr) = run(&binary, 1024, "");
+        assert_eq!(first, second);
+        let stderr = stderr.lines().collect::<Vec<_>>();
+
+        // Each run reports its errors, counting steps from the start
+        match error_policy {
+            ErrorPolicy::Warn => {
+                assert_eq!(first.status, EXIT_COMPLETED);
+                assert!(first.output.starts_with('0'));
+                assert_eq!(stderr.len(), 4);
+                for pair in stderr.chunks(2) {
+                    assert!(
+                        pair[0].starts_with("warning: stack underflow on add at step 1 in block ")
+                    );
+                    assert!(
+                        pair[1].starts_with("warning: division by zero on div at step 6 in block ")
+                    );
+                }
+            }
+            _ => {
+                assert_eq!(first.status, EXIT_ABORTED);
+                assert!(first.output.is_empty());
+                assert_eq!(stderr.len(), 2);
+                for line in stderr {
+                    assert!(line.starts_with("error: stack underflow on add at step 1 in block "));
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_library_stack_capacity() {
+    if !c_compiler_available() {
+        return;
+    }
+    let assembled = assemble("push 1\npush 2\npush 3\npush 4\nout(int)").unwrap();
+    let program = assembled.source();
+    let dir = work_dir("library");
+
+    // Without optimizations the program runs out of room pushing, and a constant program runs out of room
+    // for the stack it starts with
+    for opt_level in [OptimizationLevel::None, OptimizationLevel::Default] {
+        let library = dir.join(format!("capacity_{opt_level:?}"));
+        let binary = build(
+            &dir,
+            &format!("host_capacity_{opt_level:?}"),
+            &program,
+            CompilerSettings {
+                opt_level,
+                ..compiler_settings(library.to_str().unwrap())
+            },
+        );
+        let (first, _, _) = run(&binary, 4, "");
+        assert_eq!(first.status, EXIT_COMPLETED);
+        assert_eq!(first.output, "4");
+        assert_eq!(first.stack, vec![3, 2, 1]);
+
+        let (first, second, _) = run(&binary, 2, "");
+        assert_eq!(first.status, EXIT_STACK_EXHAUSTED);
+        assert_eq!(first, second);
+    }
+}
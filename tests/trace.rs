@@ -0,0 +1,120 @@
+// The trace of a small program, written by the interpreter and by the program compiled with tracing at
+// each optimization level.  Both have to write exactly the same lines.
+
+mod common;
+
+use asm::assemble;
+use cfg_to_ir::difftest::OPT_LEVELS;
+use cfg_to_ir::lowering_ctx::LoweringCtx;
+use cfg_to_ir::pipeline::run_piet_optimization_pipeline;
+use common::{toolchain_available, work_dir};
+use inkwell::context::Context;
+use inkwell::OptimizationLevel;
+use interpreter::interpreter::Interpreter;
+use parser::cfg::CFGBuilder;
+use piet_core::program::PietSource;
+use piet_core::settings::{
+    CodelSettings, CompilerSettings, InterpreterSettings, SaveOptions, Verbosity,
+};
+use std::fs;
+use std::process::Command;
+
+const SOURCE: &str = "push 3\ndup\nmul\nout(int)\npush 1\nadd";
+
+// Each line is written before the transition's instruction runs, so it shows the stack top it starts with
+const TRACE: &str = "\
+Entry -> LightRed_2_6 nop right/left empty
+LightRed_2_6 -> RegRed_2_9 push 3 right/left empty
+RegRed_2_9 -> RegBlue_2_10 dup right/left 3
+RegBlue_2_10 -> LightMagenta_2_11 mul right/left 3
+LightMagenta_2_11 -> RegBlue_2_12 out(int) right/left 9
+RegBlue_2_12 -> DarkBlue_2_13 push 1 right/left empty
+DarkBlue_2_13 -> DarkMagenta_2_14 add right/left 1
+DarkMagenta_2_14 -> LightRed_2_17 nop right/left 1
+";
+
+fn compile(program: &PietSource, opt_level: OptimizationLevel, output_fname: &str) {
+    let context = Context::create();
+    let module = context.create_module("piet");
+    let builder = context.create_builder();
+    let settings = CompilerSettings {
+        opt_level,
+        codel_settings: CodelSettings::Width(1),
+        save_options: SaveOptions::EmitExecutable,
+        output_fname,
+        show_codel_size: false,
+        show_cfg_size: false,
+        warn_nt: false,
+        verbosity: Verbosity::Low,
+        coverage: false,
+        trace: true,
+        debug_info: None,
+        division: Default::default(),
+        error_policy: Default::default(),
+        input: Default::default(),
+        invalid_char: Default::default(),
+        platform: Default::default(),
+    };
+
+    let mut cfg_builder = CFGBuilder::new(program, settings.codel_settings, false);
+    cfg_builder.build();
+    let cfg_gen = CFGBuilder::new(program, settings.codel_settings, false);
+    let mut ctx = LoweringCtx::new(&context, module, builder, cfg_gen, settings);
+    run_piet_optimization_pipeline(&mut ctx, &mut cfg_builder.get_cfg(), settings).unwrap();
+}
+
+#[test]
+fn test_interpreter_trace() {
+    let assembled = assemble(SOURCE).unwrap();
+    let program = assembled.source();
+    let mut cfg_builder = CFGBuilder::new(&program, CodelSettings::Width(1), false);
+    cfg_builder.build();
+    let cfg = cfg_builder.get_cfg();
+
+    let trace = work_dir("trace").join("interpreted.trace");
+    let settings = InterpreterSettings {
+        codel_settings: CodelSettings::Width(1),
+        print: false,
+        ..Default::default()
+    };
+    Interpreter::new(&cfg, settings)
+        .with_trace(fs::File::create(&trace).unwrap())
+        .run();
+    assert_eq!(fs::read_to_string(&trace).unwrap(), TRACE);
+}
+
+#[test]
+fn test_compiled_trace() {
+    if !toolchain_available() {
+        return;
+    }
+    let assembled = assemble(SOURCE).unwrap();
+    let program = assembled.source();
+    let dir = work_dir("trace");
+
+    for opt_level in OPT_LEVELS {
+        let binary = dir.join(format!("{opt_level:?}"));
+        let binary = binary.to_str().unwrap();
+        compile(&program, opt_level, binary);
+
+        let trace = dir.join(format!("{opt_level:?}.trace"));
+        let output = Command::new(binary)
+            .env("PIET_TRACE", &trace)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{opt_level:?}");
+        assert!(output.stderr.is_empty(), "{opt_level:?}");
+        assert_eq!(fs::read_to_string(&trace).unwrap(), TRACE, "{opt_level:?}");
+
+        // Without PIET_TRACE, the trace goes to stderr
+        let output = Command::new(binary)
+            .env_remove("PIET_TRACE")
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stderr),
+            TRACE,
+            "{opt_level:?}"
+        );
+    }
+}
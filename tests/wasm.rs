@@ -0,0 +1,111 @@
+// Programs compiled for WASI, linked with wasm-ld, validated with wasm-tools and run under wasmtime.  The
+// module has to print the same output and final stack as the interpreter, and exit with the same status.
+
+mod common;
+
+use cfg_to_ir::difftest::{observe, Backend, DiffTestSettings};
+use cfg_to_ir::lowering_ctx::LoweringCtx;
+use cfg_to_ir::pipeline::run_piet_optimization_pipeline;
+use common::{wasm_toolchain_available, work_dir};
+use inkwell::context::Context;
+use inkwell::OptimizationLevel;
+use parser::cfg::CFGBuilder;
+use parser::convert::UnknownPixelSettings;
+use parser::loader::Loader;
+use piet_core::program::PietSource;
+use piet_core::settings::{CodelSettings, CompilerSettings, Platform, SaveOptions, Verbosity};
+use std::path::Path;
+use std::process::{Command, Output};
+use std::time::Duration;
+
+fn compile(program: &PietSource, opt_level: OptimizationLevel, output_fname: &str) {
+    let context = Context::create();
+    let module = context.create_module("piet");
+    let builder = context.create_builder();
+    let settings = CompilerSettings {
+        opt_level,
+        codel_settings: CodelSettings::Width(1),
+        save_options: SaveOptions::EmitExecutable,
+        output_fname,
+        show_codel_size: false,
+        show_cfg_size: false,
+        warn_nt: false,
+        verbosity: Verbosity::Low,
+        coverage: false,
+        trace: false,
+        debug_info: None,
+        division: Default::default(),
+        error_policy: Default::default(),
+        input: Default::default(),
+        invalid_char: Default::default(),
+        platform: Platform::Wasi,
+    };
+
+    let mut cfg_builder = CFGBuilder::new(program, settings.codel_settings, false);
+    cfg_builder.build();
+    let cfg_gen = CFGBuilder::new(program, settings.codel_settings, false);
+    let mut ctx = LoweringCtx::new(&context, module, builder, cfg_gen, settings);
+    run_piet_optimization_pipeline(&mut ctx, &mut cfg_builder.get_cfg(), settings).unwrap();
+}
+
+fn run_tool(command: &mut Command) -> Output {
+    let output = command.output().unwrap();
+    assert!(
+        output.status.success(),
+        "{:?}: {}",
+        command,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+#[test]
+fn test_wasi_matches_interpreter() {
+    if !wasm_toolchain_available() {
+        return;
+    }
+    let dir = work_dir("wasm");
+    let image = Path::new(env!("CARGO_MANIFEST_DIR")).join("images/hw1-1.gif");
+    let filename = image.to_str().unwrap();
+    let program = Loader::convert(filename, UnknownPixelSettings::TreatAsError).unwrap();
+    let settings = DiffTestSettings {
+        codel_settings: CodelSettings::Width(1),
+        reference: None,
+        max_steps: 10000,
+        timeout: Duration::from_secs(10),
+        work_dir: &dir,
+        division: Default::default(),
+        error_policy: Default::default(),
+        input: Default::default(),
+        invalid_char: Default::default(),
+    };
+    let expected = observe(&Backend::Interpreter, filename, &program, "", &settings).unwrap();
+
+    // Without optimizations the program runs block by block, and with them it's folded to its output
+    for opt_level in [OptimizationLevel::None, OptimizationLevel::Default] {
+        let output_fname = dir.join(format!("hw1_{}", opt_level as u32));
+        let output_fname = output_fname.to_str().unwrap();
+        compile(&program, opt_level, output_fname);
+        let module = format!("{output_fname}.wasm");
+        run_tool(
+            Command::new("wasm-ld")
+                .arg(format!("{output_fname}.o"))
+                .arg("-o")
+                .arg(&module),
+        );
+        run_tool(Command::new("wasm-tools").arg("validate").arg(&module));
+
+        let output = Command::new("wasmtime").arg(&module).output().unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let (program_stdout, stack) = stdout.rsplit_once("\nStack").unwrap();
+        let stack = stack
+            .split_once(": ")
+            .map_or("", |(_, vals)| vals)
+            .split_whitespace()
+            .map(|val| val.parse().unwrap())
+            .collect::<Vec<i64>>();
+        assert_eq!(program_stdout, expected.stdout, "O{}", opt_level as u32);
+        assert_eq!(Some(stack), expected.stack, "O{}", opt_level as u32);
+        assert_eq!(output.status.code(), expected.exit_code);
+    }
+}